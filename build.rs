@@ -0,0 +1,48 @@
+//! Generates the gRPC service code of the `grpc` feature, and embeds the
+//! Windows application manifest
+//!
+//! The service is described here instead of compiling proto/pusher.proto,
+//! so building doesn't need protoc; the messages are hand-written in
+//! src/server/grpc.rs.
+//!
+//! windows/docker-image-pusher.manifest opts the binary into long paths and
+//! UTF-8; it is embedded by the MSVC linker, other Windows toolchains build
+//! without it.
+
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        use tonic_build::manual::{Builder, Method, Service};
+
+        let method = |name: &str, route: &str, input: &str| {
+            Method::builder()
+                .name(name)
+                .route_name(route)
+                .input_type(format!("crate::server::grpc::{}", input))
+                .output_type("crate::server::grpc::Job")
+                .codec_path("tonic_prost::ProstCodec")
+        };
+        let service = Service::builder()
+            .name("JobService")
+            .package("docker_image_pusher.v1")
+            .method(method("submit", "Submit", "SubmitRequest").build())
+            .method(method("watch", "Watch", "JobId").server_streaming().build())
+            .method(method("cancel", "Cancel", "JobId").build())
+            .build();
+        Builder::new().build_client(false).compile(&[service]);
+    }
+    let target_os = std::env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
+    let target_env = std::env::var("CARGO_CFG_TARGET_ENV").unwrap_or_default();
+    if target_os == "windows" && target_env == "msvc" {
+        let manifest = std::path::Path::new(&std::env::var("CARGO_MANIFEST_DIR").unwrap())
+            .join("windows")
+            .join("docker-image-pusher.manifest");
+        println!("cargo:rustc-link-arg-bins=/MANIFEST:EMBED");
+        println!(
+            "cargo:rustc-link-arg-bins=/MANIFESTINPUT:{}",
+            manifest.display()
+        );
+        println!("cargo:rerun-if-changed=windows/docker-image-pusher.manifest");
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,64 @@
+//! Synchronous API (enabled with the `blocking` cargo feature)
+//!
+//! Each call starts a private tokio runtime, runs the async operation to
+//! completion and shuts the runtime down again, so applications and build
+//! scripts without their own async runtime can use the crate directly.
+//!
+//! These functions must not be called from within an async context; use the
+//! async API there instead.
+
+use crate::cache::PullOptions;
+use crate::push::PushOptions;
+use crate::report::TransferReport;
+use crate::{PusherError, TransferBuilder};
+use oci_client::secrets::RegistryAuth;
+use std::future::Future;
+
+/// Runs `future` to completion on a fresh multi-threaded runtime
+fn block_on<F: Future>(future: F) -> Result<F::Output, PusherError> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| PusherError::ConfigError(format!("Failed to start tokio runtime: {}", e)))?;
+    Ok(runtime.block_on(future))
+}
+
+/// Blocking version of [`crate::cache::cache_image`]
+pub fn cache_image(source_image: &str, options: &PullOptions) -> Result<(), PusherError> {
+    let client = crate::new_client();
+    block_on(crate::cache::cache_image(&client, source_image, options))?
+}
+
+/// Blocking version of [`crate::push::push_cached_image`]
+pub fn push_cached_image(
+    source_image: &str,
+    target_image: &str,
+    auth: &RegistryAuth,
+    options: &PushOptions,
+) -> Result<TransferReport, PusherError> {
+    let client = crate::new_client();
+    block_on(crate::push::push_cached_image(
+        &client,
+        source_image,
+        target_image,
+        auth,
+        options,
+    ))?
+}
+
+/// Blocking version of [`crate::import::import_tar_file`]
+#[cfg(feature = "import")]
+pub fn import_tar_file(
+    tar_path: &str,
+    image_name: &str,
+    options: &crate::import::ImportOptions,
+) -> Result<(), PusherError> {
+    block_on(crate::import::import_tar_file(
+        tar_path, image_name, options,
+    ))?
+}
+
+/// Blocking version of [`TransferBuilder::run`]
+pub fn run(transfer: TransferBuilder) -> Result<Option<TransferReport>, PusherError> {
+    block_on(transfer.run())?
+}
@@ -1,9 +1,109 @@
+use crate::concurrency::RateLimiter;
+use crate::credentials::{AuthChain, AuthProvider, exchange_identity_token};
 use crate::image;
-use crate::PusherError;
-use oci_client::{Client, Reference};
+use crate::metrics;
+use crate::monitor::StallWatch;
+use crate::popularity::LayerRanking;
+use crate::progress::{ProgressEvent, ProgressReporter, ProgressStream};
+use crate::retry::{Reauthentication, RetryPolicy};
+use crate::store::{self, BlobStore, FsBlobStore};
+use crate::transport::{Timeouts, TransportError, TransportErrorKind};
+use crate::{CACHE_DIR, PusherError};
+use oci_client::secrets::RegistryAuth;
+use oci_client::errors::OciDistributionError;
+use oci_client::manifest::{OciImageManifest, OciManifest};
+use oci_client::{Client, Reference, RegistryOperation};
+use sha2::{Digest, Sha256};
 
-use std::path::Path;
-use tokio::io::AsyncWriteExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::RwLock;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio_util::sync::CancellationToken;
+
+/// Minimum number of bytes between two `LayerBytes` events while downloading
+const PROGRESS_EVENT_BYTES: u64 = 1024 * 1024; // 1MB
+
+/// Tunables for the pull phase that come from command-line options
+#[derive(Debug, Clone)]
+pub struct PullOptions {
+    /// Local cache directory the image is stored in
+    pub cache_dir: PathBuf,
+    /// Credentials for the source registry (anonymous by default)
+    pub auth: RegistryAuth,
+    /// Asked again for the registry's credentials when a fresh token is
+    /// rejected, e.g. after a password rotation
+    pub credentials: AuthChain,
+    /// Registry API request rate limit
+    pub requests_per_second: Option<f64>,
+    /// How failed registry requests (manifest, layers, config) are retried
+    pub retry: RetryPolicy,
+    /// Timeouts for the download; `idle` stops layer downloads that receive no data
+    ///
+    /// The connect timeout has to be set on the client, see [`crate::new_client_with_timeouts`].
+    pub timeouts: Timeouts,
+    /// Receives layer progress events
+    pub progress: ProgressReporter,
+    /// Cancels the pull between and during layer downloads
+    pub cancel: CancellationToken,
+    /// Downloads layers other pending images share first, so a copy can upload them early
+    pub layer_ranking: LayerRanking,
+    /// Pull even though `DOCKER_CONTENT_TRUST` requires signed images (see [`crate::trust`])
+    pub disable_content_trust: bool,
+    /// Only cache the image if it carries a cosign signature by this key
+    #[cfg(feature = "cosign")]
+    pub trusted_key: Option<crate::cosign::VerifyingKey>,
+}
+
+impl Default for PullOptions {
+    fn default() -> Self {
+        Self {
+            cache_dir: PathBuf::from(CACHE_DIR),
+            auth: RegistryAuth::Anonymous,
+            credentials: AuthChain::new(),
+            requests_per_second: None,
+            retry: RetryPolicy::default(),
+            timeouts: Timeouts::default(),
+            progress: ProgressReporter::default(),
+            cancel: CancellationToken::new(),
+            layer_ranking: LayerRanking::default(),
+            disable_content_trust: false,
+            #[cfg(feature = "cosign")]
+            trusted_key: None,
+        }
+    }
+}
+
+impl PullOptions {
+    /// Registers a callback receiving [`ProgressEvent`]s for this pull
+    pub fn on_progress(mut self, callback: Box<dyn Fn(ProgressEvent) + Send + Sync>) -> Self {
+        self.progress = ProgressReporter::new(callback);
+        self
+    }
+
+    /// Returns a stream of [`ProgressEvent`]s for this pull, replacing any callback
+    ///
+    /// The stream ends when these options are dropped.
+    pub fn progress_stream(&mut self) -> ProgressStream {
+        let (reporter, stream) = ProgressReporter::stream();
+        self.progress = reporter;
+        stream
+    }
+
+    /// Refuses the pull when `DOCKER_CONTENT_TRUST` requires signature checks these options don't make
+    pub(crate) fn check_content_trust(&self) -> Result<(), PusherError> {
+        if self.disable_content_trust {
+            return Ok(());
+        }
+        #[cfg(feature = "cosign")]
+        let verifying = self.trusted_key.is_some();
+        #[cfg(not(feature = "cosign"))]
+        let verifying = false;
+        crate::trust::check_content_trust(verifying)
+    }
+}
+use tracing::{Instrument, debug, info, info_span, instrument};
 
 /// Downloads and caches a Docker image using memory-efficient streaming with parallel processing
 ///
@@ -16,7 +116,7 @@ use tokio::io::AsyncWriteExt;
 /// # Cache Structure
 ///
 /// Images are cached in `.cache/{sanitized_image_name}/` with:
-/// - `manifest.json` - The OCI image manifest
+/// - `manifest.json` - The image manifest, byte for byte as the registry served it
 /// - `config_{digest}.json` - The image configuration
 /// - `{layer_digest}` - Individual layer files  
 /// - `index.json` - Metadata for quick lookup
@@ -25,41 +125,228 @@ use tokio::io::AsyncWriteExt;
 ///
 /// * `client` - OCI client for registry operations
 /// * `source_image` - Image reference to pull (e.g., "nginx:latest")
+/// * `options` - Rate limit and progress settings for the download
 ///
 /// # Returns
 ///
 /// `Result<(), PusherError>` - Success or detailed error information
-pub async fn cache_image(client: &Client, source_image: &str) -> Result<(), PusherError> {
-    // Use anonymous authentication for public registries
-    let auth = oci_client::secrets::RegistryAuth::Anonymous;
+pub async fn cache_image(
+    client: &Client,
+    source_image: &str,
+    options: &PullOptions,
+) -> Result<(), PusherError> {
+    let mut store = FsBlobStore::new(
+        options
+            .cache_dir
+            .join(image::sanitize_image_name(source_image)),
+    );
+    if let Some(shared) = crate::namespace::shared_blobs_dir(&options.cache_dir) {
+        store = store.sharing(shared);
+    }
+    cache_image_with_store(client, source_image, options, &store).await
+}
+
+/// Downloads and caches an image like [`cache_image`], writing layers into `store`
+///
+/// The manifest, config and index are still written to the cache directory;
+/// only layer blobs go through the [`BlobStore`].
+#[instrument(name = "pull", skip_all, fields(image = %source_image, registry = tracing::field::Empty))]
+pub async fn cache_image_with_store<S: BlobStore>(
+    client: &Client,
+    source_image: &str,
+    options: &PullOptions,
+    store: &S,
+) -> Result<(), PusherError> {
+    options.check_content_trust()?;
+    let limiter = RateLimiter::new(options.requests_per_second);
+
+    // Replaced when the registry rejects the credentials and new ones are found
+    let auth = RwLock::new(options.auth.clone());
+    let current_auth = || auth.read().unwrap_or_else(|e| e.into_inner()).clone();
 
     // Parse the image reference to validate format and extract components
     let image_ref: Reference = source_image
         .parse()
         .map_err(|e| PusherError::PullError(format!("Invalid image reference: {}", e)))?;
 
-    println!("📋 Pulling image: {}", source_image);
-    println!("🔍 Parsed reference: {}", image_ref);
+    tracing::Span::current().record("registry", image_ref.resolve_registry());
+    let options = &PullOptions {
+        retry: options.retry.for_registry(image_ref.resolve_registry()),
+        ..options.clone()
+    };
+
+    // What is sent for the credentials: an identity token is traded for an access token
+    let login = RwLock::new(
+        options
+            .retry
+            .run("Authentication", &options.cancel, || {
+                exchange_identity_token(&image_ref, &options.auth, RegistryOperation::Pull)
+            })
+            .await?,
+    );
+    let current_login = || login.read().unwrap_or_else(|e| e.into_inner()).clone();
+    info!("📋 Pulling image: {}", source_image);
+    info!("🔍 Parsed reference: {}", image_ref);
+    if let Some(pinned) = image_ref.digest() {
+        image::digest::validate(pinned, &format!("Image reference {}", source_image))?;
+        // oci-client rejects a manifest whose content doesn't hash to the pinned digest
+        info!("📌 Pinned to digest {}", pinned);
+    }
+
+    // An expired token is replaced by a fresh one before retrying; if that is
+    // rejected too, the credentials may have been rotated
+    let reauthenticate = |stage| {
+        let (image_ref, auth, login, limiter) = (&image_ref, &auth, &login, &limiter);
+        async move {
+            if stage == Reauthentication::Credentials {
+                let registry = image_ref.registry();
+                let Some(renewed) = options.credentials.credentials_for(registry).await? else {
+                    return Ok(false);
+                };
+                if renewed == current_auth() {
+                    return Ok(false);
+                }
+                info!("🔑 Picked up new credentials for {}", registry);
+                *auth.write().unwrap_or_else(|e| e.into_inner()) = renewed;
+            }
+            let renewed_login =
+                exchange_identity_token(image_ref, &current_auth(), RegistryOperation::Pull)
+                    .await?;
+            *login.write().unwrap_or_else(|e| e.into_inner()) = renewed_login.clone();
+            limiter.acquire().await;
+            client
+                .auth(image_ref, &renewed_login, RegistryOperation::Pull)
+                .await
+                .map(|_| true)
+                .map_err(|e| PusherError::registry("Re-authentication failed", e))
+        }
+    };
+
+    // A cached image whose reference still resolves to the same manifest costs one HEAD request
+    let image_cache_dir = options
+        .cache_dir
+        .join(image::sanitize_image_name(source_image));
+    let reference_digest = match options
+        .retry
+        .run_authenticated(
+            "Manifest check",
+            &options.cancel,
+            || async {
+                limiter.acquire().await;
+                let result = client
+                    .fetch_manifest_digest(&image_ref, &current_login())
+                    .await
+                    .map_err(|e| PusherError::registry("Failed to resolve manifest digest", e));
+                if let Err(e) = &result {
+                    limiter.back_off(e).await;
+                }
+                result
+            },
+            reauthenticate,
+        )
+        .await
+    {
+        Ok(digest) => {
+            image::digest::validate(&digest, &format!("Registry response for {}", source_image))?;
+            Some(digest)
+        }
+        Err(PusherError::Cancelled) => return Err(PusherError::Cancelled),
+        // Only the shortcut is lost; the manifest pull reports real problems
+        Err(e) => {
+            debug!(error = %e, "manifest digest unavailable, pulling in full");
+            None
+        }
+    };
+    #[cfg(feature = "cosign")]
+    let require_verified = options.trusted_key.is_some();
+    #[cfg(not(feature = "cosign"))]
+    let require_verified = false;
+    if let Some(digest) = &reference_digest
+        && is_cache_current(&image_cache_dir, digest, require_verified, store).await
+    {
+        info!(
+            digest = %digest,
+            "✅ {} is unchanged since it was cached, nothing to download",
+            source_image
+        );
+        return Ok(());
+    }
 
     // Step 1: Pull only the manifest (small metadata, ~1-5KB typically)
     // This gives us the list of layers and config without downloading everything
-    println!("📄 Fetching manifest...");
-    let (manifest, _digest) = client
-        .pull_image_manifest(&image_ref, &auth)
-        .await
-        .map_err(|e| PusherError::PullError(format!("Failed to pull manifest: {}", e)))?;
+    info!("📄 Fetching manifest...");
+    let (manifest_bytes, manifest, manifest_digest) = options
+        .retry
+        .run_authenticated(
+            "Manifest pull",
+            &options.cancel,
+            || async {
+                limiter.acquire().await;
+                let result = pull_manifest_bytes(client, &image_ref, &current_login())
+                    .await
+                    .map_err(|e| PusherError::registry("Failed to pull manifest", e));
+                if let Err(e) = &result {
+                    limiter.back_off(e).await;
+                }
+                result
+            },
+            reauthenticate,
+        )
+        .await?;
+
+    image::validate_manifest(&manifest, &format!("Manifest of {}", source_image))?;
+    image::digest::validate(&manifest_digest, &format!("Registry response for {}", source_image))?;
+
+    // Refuse unsigned or tampered images before anything is written to the cache
+    #[cfg(feature = "cosign")]
+    let verified_digest = match &options.trusted_key {
+        Some(key) => {
+            // Multi-arch images are usually signed by their index digest
+            let index_digest = match &reference_digest {
+                Some(digest) => digest.clone(),
+                None => {
+                    limiter.acquire().await;
+                    client
+                        .fetch_manifest_digest(&image_ref, &current_login())
+                        .await
+                        .map_err(|e| PusherError::registry("Failed to resolve manifest digest", e))?
+                }
+            };
+            let mut digests = vec![manifest_digest.clone()];
+            if index_digest != manifest_digest {
+                digests.push(index_digest);
+            }
+            Some(
+                crate::cosign::verify_image(client, &image_ref, &current_login(), &digests, key)
+                    .await?,
+            )
+        }
+        None => None,
+    };
+    #[cfg(not(feature = "cosign"))]
+    let verified_digest: Option<String> = None;
 
     // Step 2: Set up local cache directory structure
-    let cache_dir = Path::new(".cache");
+    let cache_dir = options.cache_dir.as_path();
     std::fs::create_dir_all(cache_dir)
         .map_err(|e| PusherError::CacheError(format!("Failed to create cache directory: {}", e)))?;
 
-    let image_cache_dir = cache_dir.join(image::sanitize_image_name(source_image));
     std::fs::create_dir_all(&image_cache_dir).map_err(|e| {
         PusherError::CacheError(format!("Failed to create image cache directory: {}", e))
     })?;
+
+    // Fail before downloading anything if the missing layers can't fit
+    let mut required = 0u64;
+    for layer in &manifest.layers {
+        let size = layer.size.max(0) as u64;
+        if store.size(&layer.digest).await? != Some(size) {
+            required += size;
+        }
+    }
+    store.ensure_capacity(required).await?;
+
     let total_layers = manifest.layers.len();
-    println!(
+    info!(
         "💾 Streaming {} layers to cache sequentially for memory efficiency...",
         total_layers
     );
@@ -67,25 +354,57 @@ pub async fn cache_image(client: &Client, source_image: &str) -> Result<(), Push
     let mut cached_layers = Vec::new();
     let mut skipped_layers = 0;
 
-    for (i, layer_desc) in manifest.layers.iter().enumerate() {
+    let order = options
+        .layer_ranking
+        .order(manifest.layers.iter().map(|layer| layer.digest.as_str()));
+    for i in order {
+        let layer_desc = &manifest.layers[i];
+        if options.cancel.is_cancelled() {
+            return Err(PusherError::Cancelled);
+        }
         let layer_digest = layer_desc.digest.to_string();
-        let layer_path = image_cache_dir.join(layer_digest.replace(":", "_"));
         let layer_size_mb = layer_desc.size as f64 / (1024.0 * 1024.0);
         // Check if layer is already cached and complete
-        if is_layer_cached(&image_cache_dir, &layer_digest, layer_desc.size as u64).await? {
-            println!(
+        if store.size(&layer_digest).await? == Some(layer_desc.size as u64) {
+            info!(
+                digest = %layer_digest,
+                bytes = layer_desc.size,
                 "📦 Layer {}/{}: {} ({:.1} MB) - ✅ Already cached, skipping download",
                 i + 1,
                 total_layers,
                 layer_digest,
                 layer_size_mb
             );
-            cached_layers.push(layer_digest);
+            options.progress.emit(ProgressEvent::LayerCompleted {
+                digest: layer_digest.clone(),
+                size: layer_desc.size as u64,
+                skipped: true,
+            });
+            cached_layers.push((i, layer_digest));
+            skipped_layers += 1;
+            continue;
+        }
+        if store.adopt(&layer_digest, layer_desc.size as u64).await? {
+            info!(
+                digest = %layer_digest,
+                bytes = layer_desc.size,
+                "📦 Layer {}/{}: {} ({:.1} MB) - ♻️  Shared by another namespace, skipping download",
+                i + 1,
+                total_layers,
+                layer_digest,
+                layer_size_mb
+            );
+            options.progress.emit(ProgressEvent::LayerCompleted {
+                digest: layer_digest.clone(),
+                size: layer_desc.size as u64,
+                skipped: true,
+            });
+            cached_layers.push((i, layer_digest));
             skipped_layers += 1;
             continue;
         }
 
-        println!(
+        info!(
             "📦 Streaming layer {}/{}: {} ({:.1} MB)",
             i + 1,
             total_layers,
@@ -94,26 +413,72 @@ pub async fn cache_image(client: &Client, source_image: &str) -> Result<(), Push
         );
         let download_start = std::time::Instant::now();
 
-        let mut file = tokio::fs::File::create(&layer_path).await.map_err(|e| {
-            PusherError::CacheError(format!(
-                "Failed to create layer file {}: {}",
-                layer_digest, e
-            ))
-        })?;
+        options.progress.emit(ProgressEvent::LayerStarted {
+            digest: layer_digest.clone(),
+            size: layer_desc.size as u64,
+        });
 
-        client
-            .pull_blob(&image_ref, layer_desc, &mut file)
-            .await
-            .map_err(|e| {
-                PusherError::PullError(format!("Failed to stream layer {}: {}", layer_digest, e))
-            })?;
+        let transfer = metrics::global().start_layer_transfer(&layer_digest, layer_desc.size as u64);
+        // Every attempt rewrites the layer from the start
+        let download = || async {
+            let watch = StallWatch::new(options.timeouts.idle);
+            let mut file = ProgressWriter {
+                inner: store.writer(&layer_digest).await?,
+                watch: &watch,
+                progress: &options.progress,
+                digest: &layer_digest,
+                total: layer_desc.size as u64,
+                written: 0,
+                reported: 0,
+            };
 
-        file.flush().await.map_err(|e| {
-            PusherError::CacheError(format!(
-                "Failed to flush layer file {}: {}",
-                layer_digest, e
-            ))
-        })?;
+            limiter.acquire().await;
+            let download = client
+                .pull_blob(&image_ref, layer_desc, &mut file)
+                .instrument(info_span!("layer", digest = %layer_digest, size = layer_desc.size));
+            // A partially written layer fails the size check and is downloaded again next time
+            let result = tokio::select! {
+                result = download => result.map_err(|e| {
+                    PusherError::registry(format!("Failed to stream layer {}", layer_digest), e)
+                }),
+                _ = options.cancel.cancelled() => return Err(PusherError::Cancelled),
+                // A hung connection is dropped and the layer downloaded again
+                _ = watch.stalled() => Err(PusherError::transport(
+                    format!("Layer {} download stalled", layer_digest),
+                    TransportError::with_kind(
+                        TransportErrorKind::Timeout,
+                        format!(
+                            "no data received for {}s",
+                            watch.timeout().unwrap_or_default().as_secs()
+                        ),
+                    ),
+                )),
+            };
+            if let Err(e) = &result {
+                limiter.back_off(e).await;
+            }
+            result?;
+
+            file.shutdown().await.map_err(|e| {
+                PusherError::CacheError(format!(
+                    "Failed to finish layer file {}: {}",
+                    layer_digest, e
+                ))
+            })?;
+            file.report();
+            store.persist(&layer_digest).await
+        };
+        options
+            .retry
+            .run_authenticated(&layer_digest, &options.cancel, download, reauthenticate)
+            .await?;
+        drop(transfer);
+        metrics::global().record_download(layer_desc.size as u64);
+        options.progress.emit(ProgressEvent::LayerCompleted {
+            digest: layer_digest.clone(),
+            size: layer_desc.size as u64,
+            skipped: false,
+        });
 
         let download_duration = download_start.elapsed();
         let download_speed = if download_duration.as_secs() > 0 {
@@ -122,29 +487,38 @@ pub async fn cache_image(client: &Client, source_image: &str) -> Result<(), Push
             0.0
         };
 
-        println!(
+        info!(
+            digest = %layer_digest,
+            repository = image_ref.repository(),
+            bytes = layer_desc.size,
             "   ✅ Downloaded layer: {} in {:.1}s @ {:.1} MB/s",
             layer_digest,
             download_duration.as_secs_f64(),
             download_speed
         );
-        cached_layers.push(layer_digest);
+        cached_layers.push((i, layer_digest));
     }
-    println!(
+    // The index lists layers in manifest order
+    cached_layers.sort_by_key(|(i, _)| *i);
+    let cached_layers: Vec<String> = cached_layers
+        .into_iter()
+        .map(|(_, digest)| digest)
+        .collect();
+    info!(
         "🚀 Sequential download completed for {} layers",
         cached_layers.len()
     );
     if skipped_layers > 0 {
-        println!(
+        info!(
             "💡 Skipped {} layers that were already cached",
             skipped_layers
         );
     }
 
-    // Step 4: Cache the manifest for later reconstruction
+    // Step 4: Cache the manifest exactly as served, so pushing it keeps its digest
     let manifest_path = image_cache_dir.join("manifest.json");
-    let manifest_json = serde_json::to_string_pretty(&manifest)?;
-    tokio::fs::write(&manifest_path, manifest_json)
+    let manifest_file_digest = format!("sha256:{:x}", Sha256::digest(&manifest_bytes));
+    store::write_atomic(&manifest_path, manifest_bytes)
         .await
         .map_err(|e| PusherError::CacheError(format!("Failed to cache manifest: {}", e)))?;
 
@@ -152,39 +526,64 @@ pub async fn cache_image(client: &Client, source_image: &str) -> Result<(), Push
     let config_desc = &manifest.config;
     let config_digest = config_desc.digest.to_string();
     let config_path =
-        image_cache_dir.join(format!("config_{}.json", config_digest.replace(":", "_")));
+        image_cache_dir.join(format!("config_{}.json", image::digest::file_name(&config_digest)));
 
-    let mut config_file = tokio::fs::File::create(&config_path)
-        .await
-        .map_err(|e| PusherError::CacheError(format!("Failed to create config file: {}", e)))?;
+    // Downloaded next to its final path and renamed once complete and synced
+    let config_temp_path = store::temp_path(&config_path);
+    let download_config = || async {
+        let mut config_file = tokio::fs::File::create(&config_temp_path).await.map_err(|e| {
+            PusherError::CacheError(format!("Failed to create config file: {}", e))
+        })?;
 
-    client
-        .pull_blob(&image_ref, config_desc, &mut config_file)
-        .await
-        .map_err(|e| PusherError::PullError(format!("Failed to stream config: {}", e)))?;
+        limiter.acquire().await;
+        let result = client
+            .pull_blob(&image_ref, config_desc, &mut config_file)
+            .await
+            .map_err(|e| PusherError::registry("Failed to stream config", e));
+        if let Err(e) = &result {
+            limiter.back_off(e).await;
+        }
+        result?;
 
-    config_file
-        .flush()
+        config_file.flush().await.map_err(|e| {
+            PusherError::CacheError(format!("Failed to flush config file: {}", e))
+        })?;
+        config_file.sync_all().await.map_err(|e| {
+            PusherError::CacheError(format!("Failed to sync config file: {}", e))
+        })
+    };
+    options
+        .retry
+        .run_authenticated("Config pull", &options.cancel, download_config, reauthenticate)
+        .await?;
+    store::rename_durable(&config_temp_path, &config_path)
         .await
-        .map_err(|e| PusherError::CacheError(format!("Failed to flush config file: {}", e)))?;
+        .map_err(|e| PusherError::CacheError(format!("Failed to cache config: {}", e)))?;
 
     // Step 6: Create index file for quick cache lookup and metadata
+    // Written last: an index only exists once everything it references is on disk
     let index = serde_json::json!({
         "source_image": source_image,
         "manifest": "manifest.json",
         "config": config_digest,
         "layers": cached_layers,
+        "manifest_digest": manifest_digest,
+        "reference_digest": reference_digest,
+        "manifest_file_digest": manifest_file_digest,
+        "pinned_digest": image_ref.digest(),
+        "verified_digest": verified_digest,
+        "cache_version": crate::migrate::CACHE_VERSION,
         "cached_at": std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_secs()
     });
     let index_json = serde_json::to_string_pretty(&index)?;
-    tokio::fs::write(image_cache_dir.join("index.json"), index_json)
+    store::write_atomic(&image_cache_dir.join("index.json"), index_json)
         .await
         .map_err(|e| PusherError::CacheError(format!("Failed to create index: {}", e)))?;
 
-    println!(
+    info!(
         "✅ Successfully cached image with {} layers",
         cached_layers.len()
     );
@@ -198,45 +597,187 @@ pub async fn cache_image(client: &Client, source_image: &str) -> Result<(), Push
 ///
 /// # Arguments
 ///
+/// * `cache_dir` - Local cache directory
 /// * `source_image` - Image name to check for in cache
 ///
 /// # Returns
 ///
 /// `Result<bool, PusherError>` - true if cached, false if not found
-pub async fn has_cached_image(source_image: &str) -> Result<bool, PusherError> {
-    let cache_dir = Path::new(".cache");
+pub async fn has_cached_image(cache_dir: &Path, source_image: &str) -> Result<bool, PusherError> {
     let image_cache_dir = cache_dir.join(image::sanitize_image_name(source_image));
     let index_path = image_cache_dir.join("index.json");
 
     Ok(tokio::fs::metadata(&index_path).await.is_ok())
 }
 
-/// Checks if a specific layer is already cached locally
-///
-/// This function verifies that a layer file exists in the cache and has the expected size
-/// to ensure it's a complete download.
+/// Checks whether the cache holds the complete image `reference_digest` was cached from
 ///
-/// # Arguments
+/// Compares the digest the image reference resolved to on the last pull
+/// (recorded in `index.json`) and makes sure the config and every layer are
+/// still present, so an unchanged image needs no further requests. With
+/// `require_verified`, only a cache whose signature was verified counts.
+async fn is_cache_current<S: BlobStore>(
+    image_cache_dir: &Path,
+    reference_digest: &str,
+    require_verified: bool,
+    store: &S,
+) -> bool {
+    let Ok(index) = tokio::fs::read(image_cache_dir.join("index.json")).await else {
+        return false;
+    };
+    let Ok(index) = serde_json::from_slice::<serde_json::Value>(&index) else {
+        return false;
+    };
+    if index["reference_digest"].as_str() != Some(reference_digest)
+        || (require_verified && !index["verified_digest"].is_string())
+    {
+        return false;
+    }
+    let Ok(manifest) = tokio::fs::read(image_cache_dir.join("manifest.json")).await else {
+        return false;
+    };
+    let Ok(manifest) = serde_json::from_slice::<OciImageManifest>(&manifest) else {
+        return false;
+    };
+    let config_digest = image::digest::file_name(&manifest.config.digest);
+    if !image_cache_dir.join(format!("config_{}.json", config_digest)).exists() {
+        return false;
+    }
+    for layer in &manifest.layers {
+        if !matches!(store.size(&layer.digest).await, Ok(Some(size)) if size == layer.size as u64) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Media types accepted when pulling a manifest: image manifests and the indexes listing them
+const MANIFEST_MEDIA_TYPES: [&str; 4] = [
+    oci_client::manifest::OCI_IMAGE_MEDIA_TYPE,
+    oci_client::manifest::IMAGE_MANIFEST_MEDIA_TYPE,
+    oci_client::manifest::OCI_IMAGE_INDEX_MEDIA_TYPE,
+    oci_client::manifest::IMAGE_MANIFEST_LIST_MEDIA_TYPE,
+];
+
+/// Fetches the manifest [`cache_image`] would cache for `source_image`, without caching anything
 ///
-/// * `cache_dir` - The cache directory path for the image
-/// * `layer_digest` - The digest of the layer to check
-/// * `expected_size` - Expected size of the layer in bytes
+/// Lets a copy check the target against the image before its first layer
+/// is downloaded (see [`crate::copy_image`]).
 ///
 /// # Returns
 ///
-/// `Result<bool, PusherError>` - true if layer exists and is complete, false otherwise
-async fn is_layer_cached(
-    cache_dir: &std::path::Path,
-    layer_digest: &str,
-    expected_size: u64,
-) -> Result<bool, PusherError> {
-    let layer_path = cache_dir.join(layer_digest.replace(":", "_"));
-
-    match tokio::fs::metadata(&layer_path).await {
-        Ok(metadata) => {
-            // Check if file exists and has the expected size
-            Ok(metadata.len() == expected_size)
+/// The manifest bytes as served, the parsed manifest and its digest
+pub(crate) async fn fetch_manifest(
+    client: &Client,
+    source_image: &str,
+    options: &PullOptions,
+) -> Result<(Vec<u8>, OciImageManifest, String), PusherError> {
+    let image_ref: Reference = source_image
+        .parse()
+        .map_err(|e| PusherError::PullError(format!("Invalid image reference: {}", e)))?;
+    let options = &PullOptions {
+        retry: options.retry.for_registry(image_ref.resolve_registry()),
+        ..options.clone()
+    };
+    options
+        .retry
+        .run("Manifest pull", &options.cancel, || async {
+            let login =
+                exchange_identity_token(&image_ref, &options.auth, RegistryOperation::Pull).await?;
+            pull_manifest_bytes(client, &image_ref, &login)
+                .await
+                .map_err(|e| PusherError::registry("Failed to pull manifest", e))
+        })
+        .await
+}
+
+/// Pulls the image manifest of `image_ref` with the exact bytes the registry served
+///
+/// Like [`Client::pull_image_manifest`], an index is resolved to the entry
+/// for the platform [`crate::new_client`] selects (Linux AMD64), but the
+/// manifest isn't re-serialized: re-encoding it would change its digest and
+/// break promoting the image by digest.
+async fn pull_manifest_bytes(
+    client: &Client,
+    image_ref: &Reference,
+    auth: &RegistryAuth,
+) -> Result<(Vec<u8>, OciImageManifest, String), OciDistributionError> {
+    let (mut bytes, mut digest) = client
+        .pull_manifest_raw(image_ref, auth, &MANIFEST_MEDIA_TYPES)
+        .await?;
+    let parse = |bytes: &[u8]| {
+        serde_json::from_slice::<OciManifest>(bytes)
+            .map_err(|e| OciDistributionError::ManifestParsingError(e.to_string()))
+    };
+    if let OciManifest::ImageIndex(index) = parse(&bytes)? {
+        let entry = oci_client::client::linux_amd64_resolver(&index.manifests).ok_or_else(|| {
+            OciDistributionError::ImageManifestNotFoundError(
+                "no entry found in image index manifest for linux/amd64".to_string(),
+            )
+        })?;
+        debug!(digest = %entry, "resolved image index entry");
+        (bytes, digest) = client
+            .pull_manifest_raw(&image_ref.clone_with_digest(entry), auth, &MANIFEST_MEDIA_TYPES)
+            .await?;
+    }
+    match parse(&bytes)? {
+        OciManifest::Image(manifest) => Ok((bytes, manifest, digest)),
+        OciManifest::ImageIndex(_) => Err(OciDistributionError::ImageManifestNotFoundError(
+            "received Image Index manifest instead".to_string(),
+        )),
+    }
+}
+
+/// Counts bytes written to a cached layer file, reporting them as progress events and to a [`StallWatch`]
+struct ProgressWriter<'a, W> {
+    inner: W,
+    watch: &'a StallWatch,
+    progress: &'a ProgressReporter,
+    digest: &'a str,
+    total: u64,
+    written: u64,
+    reported: u64,
+}
+
+impl<W> ProgressWriter<'_, W> {
+    /// Emits a `LayerBytes` event for everything written since the last report
+    fn report(&mut self) {
+        if self.written > self.reported {
+            self.reported = self.written;
+            self.progress.emit(ProgressEvent::LayerBytes {
+                digest: self.digest.to_string(),
+                transferred: self.written,
+                total: self.total,
+            });
         }
-        Err(_) => Ok(false), // File doesn't exist
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ProgressWriter<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = &poll {
+            self.watch.touch();
+            self.written += *written as u64;
+            if self.written - self.reported >= PROGRESS_EVENT_BYTES {
+                self.report();
+            }
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
     }
 }
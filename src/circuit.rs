@@ -0,0 +1,167 @@
+//! Per-registry circuit breakers
+//!
+//! Without them, every operation on a registry that keeps failing (server
+//! errors, timeouts, dropped connections) goes through all of its retries
+//! with backoff, holding job slots and delaying transfers to the healthy
+//! registries of a fan-out. After [`CircuitConfig::failure_threshold`]
+//! consecutive failures on one host its circuit opens: operations on that
+//! host fail right away with [`PusherError::CircuitOpen`] until the cooldown
+//! ends. Then a single probe request is let through (half-open); its success
+//! closes the circuit, its failure opens it for another cooldown.
+
+use crate::PusherError;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// When a registry's circuit opens, and for how long
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitConfig {
+    /// Consecutive retryable failures that open the circuit
+    pub failure_threshold: u32,
+    /// How long requests are held back before a probe is let through
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A probe has been in flight since `started`; everything else waits for it
+    HalfOpen {
+        started: Instant,
+    },
+}
+
+/// Tracks the health of one registry host
+///
+/// Shared by every transfer to that host (see [`breaker`]) and consulted by
+/// [`crate::retry::RetryPolicy`] before each attempt.
+///
+/// # Examples
+///
+/// ```
+/// use docker_image_pusher::circuit::{CircuitBreaker, CircuitConfig};
+/// use docker_image_pusher::PusherError;
+///
+/// let breaker = CircuitBreaker::new("registry.example.com", CircuitConfig::default());
+/// for _ in 0..5 {
+///     breaker.check().unwrap();
+///     let timeout = std::io::Error::from(std::io::ErrorKind::TimedOut);
+///     breaker.record::<()>(&Err(PusherError::IoError(timeout)));
+/// }
+/// assert!(matches!(breaker.check(), Err(PusherError::CircuitOpen { .. })));
+/// ```
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    registry: String,
+    config: CircuitConfig,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    /// Creates a closed breaker for `registry`
+    pub fn new(registry: impl Into<String>, config: CircuitConfig) -> Self {
+        Self {
+            registry: registry.into(),
+            config,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// Checks whether a request may be sent now
+    ///
+    /// Fails with [`PusherError::CircuitOpen`] while the circuit is open and
+    /// while another request is probing it.
+    pub fn check(&self) -> Result<(), PusherError> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let reopens = match *state {
+            State::Closed { .. } => return Ok(()),
+            State::Open { until } => until,
+            // A probe that never reported back (e.g. cancelled) gives way after a cooldown
+            State::HalfOpen { started } => started + self.config.cooldown,
+        };
+        if now < reopens {
+            return Err(PusherError::CircuitOpen {
+                registry: self.registry.clone(),
+                retry_after: reopens - now,
+            });
+        }
+        info!("🔌 Probing {} after its cooldown", self.registry);
+        *state = State::HalfOpen { started: now };
+        Ok(())
+    }
+
+    /// Records the outcome of a request [`CircuitBreaker::check`] let through
+    ///
+    /// Only failures a retry might fix count against the registry; throttling
+    /// is left to the rate limiter. Other errors (a 404, a local I/O error, a
+    /// cancellation) say nothing about its health.
+    pub fn record<T>(&self, result: &Result<T, PusherError>) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        match result {
+            Ok(_) => {
+                if !matches!(*state, State::Closed { .. }) {
+                    info!("🔌 {} is answering again, resuming requests", self.registry);
+                }
+                *state = State::Closed { failures: 0 };
+            }
+            Err(e) if e.is_retryable() && !e.is_throttled() => match *state {
+                State::Closed { failures } if failures + 1 < self.config.failure_threshold => {
+                    *state = State::Closed {
+                        failures: failures + 1,
+                    };
+                }
+                State::Closed { .. } | State::HalfOpen { .. } => {
+                    warn!(
+                        "🔌 {} keeps failing, holding requests back for {}s",
+                        self.registry,
+                        self.config.cooldown.as_secs()
+                    );
+                    *state = State::Open {
+                        until: Instant::now() + self.config.cooldown,
+                    };
+                }
+                State::Open { .. } => {}
+            },
+            // Let the next request probe instead of waiting for the stale probe to expire
+            Err(_) => {
+                if matches!(*state, State::HalfOpen { .. }) {
+                    *state = State::Open {
+                        until: Instant::now(),
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// Returns the breaker shared by all transfers to `registry`
+///
+/// Created with `config` the first time the registry is seen.
+pub fn breaker(registry: &str, config: CircuitConfig) -> Arc<CircuitBreaker> {
+    static BREAKERS: OnceLock<Mutex<HashMap<String, Arc<CircuitBreaker>>>> = OnceLock::new();
+    let mut breakers = BREAKERS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    breakers
+        .entry(registry.to_string())
+        .or_insert_with(|| Arc::new(CircuitBreaker::new(registry, config)))
+        .clone()
+}
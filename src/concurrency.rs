@@ -0,0 +1,138 @@
+use crate::PusherError;
+use crate::registry::STREAM_CHUNK_SIZE;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+/// Default number of layers uploaded concurrently when nothing has been learned yet
+///
+/// Leaves room to adapt: memory pressure and missed layer deadlines reduce
+/// concurrency below it, and runs that didn't have to make the next push to
+/// the registry start one higher (see [`crate::profile::ProfileStore::record`]).
+pub const DEFAULT_MAX_CONCURRENT_UPLOADS: usize = 4;
+
+/// How long to hold requests back after a 429/503 without a `Retry-After` header
+pub const DEFAULT_THROTTLE_PAUSE: Duration = Duration::from_secs(5);
+
+/// Concurrency settings a push starts with
+///
+/// Seeded from the persisted registry profile when one exists (see
+/// [`crate::profile::ProfileStore`]), otherwise from the defaults below.
+/// Command-line options override the seeded values.
+#[derive(Debug, Clone)]
+pub struct ConcurrencyConfig {
+    /// Maximum number of layers uploaded concurrently
+    pub max_concurrent: usize,
+    /// Chunk size in bytes for streamed uploads
+    pub chunk_size: usize,
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: DEFAULT_MAX_CONCURRENT_UPLOADS,
+            chunk_size: STREAM_CHUNK_SIZE,
+        }
+    }
+}
+
+/// Token-bucket limiter for registry API requests
+///
+/// Registries such as Docker Hub and Quay throttle on request rate, not just
+/// bandwidth. Every HTTP request sent to a registry (existence checks, chunk
+/// uploads, manifest pushes, blob pulls) first takes a token from the bucket,
+/// so bursts of concurrent HEAD checks are smoothed to the configured rate.
+///
+/// The bucket holds up to one second worth of tokens, allowing short bursts
+/// while keeping the long-run average at `requests_per_second`. A limiter
+/// created without a rate only waits while paused by [`RateLimiter::back_off`].
+#[derive(Debug)]
+pub struct RateLimiter {
+    requests_per_second: Option<f64>,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+    /// Requests are held back until then after the registry throttled us
+    paused_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter allowing `requests_per_second` on average (`None` = unlimited)
+    pub fn new(requests_per_second: Option<f64>) -> Self {
+        let requests_per_second = requests_per_second.filter(|rate| *rate > 0.0);
+        Self {
+            requests_per_second,
+            state: Mutex::new(BucketState {
+                tokens: requests_per_second.map(burst_capacity).unwrap_or(0.0),
+                last_refill: Instant::now(),
+                paused_until: None,
+            }),
+        }
+    }
+
+    /// Waits until a request may be sent, then consumes one token
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().await;
+                let now = Instant::now();
+                if let Some(until) = state.paused_until {
+                    if until > now {
+                        until - now
+                    } else {
+                        state.paused_until = None;
+                        continue;
+                    }
+                } else {
+                    let Some(rate) = self.requests_per_second else {
+                        return;
+                    };
+                    let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+                    state.tokens = (state.tokens + elapsed * rate).min(burst_capacity(rate));
+                    state.last_refill = now;
+
+                    if state.tokens >= 1.0 {
+                        state.tokens -= 1.0;
+                        return;
+                    }
+                    Duration::from_secs_f64((1.0 - state.tokens) / rate)
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Holds back every request through this limiter for `duration`
+    pub async fn pause(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        let mut state = self.state.lock().await;
+        if state.paused_until.is_none_or(|current| current < until) {
+            state.paused_until = Some(until);
+        }
+    }
+
+    /// Pauses the limiter when `error` says the registry is throttling (429/503)
+    ///
+    /// The pause lasts as long as the registry's `Retry-After` asks for, or
+    /// [`DEFAULT_THROTTLE_PAUSE`] without one, so concurrent transfers to the
+    /// same registry back off together instead of hammering it.
+    pub async fn back_off(&self, error: &PusherError) {
+        if error.is_throttled() {
+            let duration = error.retry_after().unwrap_or(DEFAULT_THROTTLE_PAUSE);
+            warn!(
+                "⏸️  Registry is throttling requests, pausing for {:.0}s",
+                duration.as_secs_f64()
+            );
+            self.pause(duration).await;
+        }
+    }
+}
+
+/// Maximum number of tokens the bucket can accumulate
+fn burst_capacity(rate: f64) -> f64 {
+    rate.max(1.0)
+}
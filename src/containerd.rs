@@ -0,0 +1,162 @@
+//! containerd image store as a source and target
+//!
+//! Images are moved through containerd's `ctr` client, which talks to the
+//! containerd socket (content and images services): `ctr images export`
+//! produces an archive that is imported into the cache, and cached images are
+//! streamed as a `docker save` archive into `ctr images import`. `ctr` ships
+//! with containerd but isn't a library dependency: it must be installed
+//! (see [`ContainerdStore::ctr`]), and reaching the socket usually takes root. Pushing into
+//! a node's store pre-loads images for Kubernetes without going through a
+//! registry; the default namespace is the one the kubelet's CRI plugin uses.
+
+use crate::PusherError;
+use crate::import::ImportOptions;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use tracing::{debug, info};
+
+/// Prefix selecting the containerd store in place of a tar file or registry
+pub const TRANSPORT_PREFIX: &str = "containerd:";
+
+/// Socket containerd listens on by default
+pub const DEFAULT_ADDRESS: &str = "/run/containerd/containerd.sock";
+
+/// Namespace holding the images Kubernetes runs
+pub const DEFAULT_NAMESPACE: &str = "k8s.io";
+
+/// Connection settings for a containerd instance
+#[derive(Debug, Clone)]
+pub struct ContainerdStore {
+    /// containerd's gRPC socket
+    pub address: PathBuf,
+    /// containerd namespace the images live in
+    pub namespace: String,
+    /// `ctr` executable, looked up in `PATH` unless a path is given (default `ctr`)
+    pub ctr: PathBuf,
+}
+
+impl Default for ContainerdStore {
+    fn default() -> Self {
+        Self {
+            address: PathBuf::from(DEFAULT_ADDRESS),
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            ctr: PathBuf::from("ctr"),
+        }
+    }
+}
+
+impl ContainerdStore {
+    /// Builds a `ctr` invocation against this instance
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.ctr);
+        command
+            .arg("--address")
+            .arg(&self.address)
+            .arg("--namespace")
+            .arg(&self.namespace);
+        command
+    }
+
+    /// Copies `image` from containerd into the cache as `cache_name`
+    ///
+    /// The image's content for the local platform must be present in
+    /// containerd, e.g. because a pod on this node uses it. `ctr` exports it
+    /// to a scratch archive, removed once imported, in the `tmp_dir` of
+    /// `options` or else the import cache directory.
+    pub async fn import(
+        &self,
+        image: &str,
+        cache_name: &str,
+        options: &ImportOptions,
+    ) -> Result<(), PusherError> {
+        let scratch = options.scratch_dir();
+        std::fs::create_dir_all(scratch).map_err(|e| {
+            PusherError::CacheError(format!("Failed to create {}: {}", scratch.display(), e))
+        })?;
+        let archive = scratch.join(format!("containerd_export_{}.tar", std::process::id()));
+
+        info!("📥 Exporting {} from containerd ({})...", image, self.namespace);
+        let mut command = self.command();
+        command.args(["images", "export"]).arg(&archive).arg(image);
+        let result = match run(command, None).await {
+            Ok(()) => {
+                let archive_path = archive.to_string_lossy().into_owned();
+                crate::import::import_tar_file(&archive_path, cache_name, options).await
+            }
+            Err(e) => Err(e),
+        };
+        let _ = std::fs::remove_file(&archive);
+        result
+    }
+
+    /// Loads the cached image `cached_image` into containerd as `image`
+    ///
+    /// The archive is streamed into `ctr images import`, so nothing but the
+    /// cache itself is written to disk.
+    pub async fn export(&self, cache_dir: &Path, cached_image: &str, image: &str) -> Result<(), PusherError> {
+        info!("📤 Loading {} into containerd ({}) as {}...", cached_image, self.namespace, image);
+        let mut command = self.command();
+        command.args(["images", "import", "-"]);
+        let archive = ArchiveSource {
+            cache_dir: cache_dir.to_path_buf(),
+            cached_image: cached_image.to_string(),
+            image: image.to_string(),
+        };
+        run(command, Some(archive)).await?;
+        info!("✅ {} is available in containerd", image);
+        Ok(())
+    }
+}
+
+/// Cached image to write to `ctr`'s stdin
+struct ArchiveSource {
+    cache_dir: PathBuf,
+    cached_image: String,
+    image: String,
+}
+
+/// Runs `ctr`, optionally feeding it an archive, and turns a failure into an error with its output
+async fn run(mut command: Command, input: Option<ArchiveSource>) -> Result<(), PusherError> {
+    debug!(command = ?command, "running ctr");
+    tokio::task::spawn_blocking(move || {
+        command
+            .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+        let mut child = command.spawn().map_err(|e| {
+            PusherError::LocalStoreError(format!(
+                "Failed to run {:?} (is containerd's ctr installed?): {}",
+                command.get_program(),
+                e
+            ))
+        })?;
+
+        let written = match (input, child.stdin.take()) {
+            (Some(source), Some(stdin)) => crate::export::write_docker_archive(
+                &source.cache_dir,
+                &source.cached_image,
+                &source.image,
+                stdin,
+            )
+            .map(drop),
+            _ => Ok(()),
+        };
+
+        let mut stderr = String::new();
+        if let Some(mut pipe) = child.stderr.take() {
+            let _ = pipe.read_to_string(&mut stderr);
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(PusherError::LocalStoreError(format!(
+                "ctr failed ({}): {}",
+                status,
+                stderr.trim()
+            )));
+        }
+        written
+    })
+    .await
+    .map_err(|e| PusherError::LocalStoreError(format!("ctr task failed: {}", e)))?
+}
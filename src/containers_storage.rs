@@ -0,0 +1,86 @@
+//! Podman/Buildah local storage (`containers-storage:`) as a source
+//!
+//! Images built with podman or buildah live in containers/storage (overlay
+//! layers under `~/.local/share/containers/storage` for rootless users,
+//! `/var/lib/containers/storage` for root). They are read through
+//! `podman save`, which assembles the layers from that storage into a
+//! `docker save` archive; the archive is then imported into the cache and
+//! removed, so no manual export step is needed before pushing.
+
+use crate::PusherError;
+use crate::import::ImportOptions;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use tracing::{debug, info};
+
+/// Prefix selecting containers-storage in place of a tar file
+pub const TRANSPORT_PREFIX: &str = "containers-storage:";
+
+/// Settings for reading from containers/storage
+#[derive(Debug, Clone)]
+pub struct ContainersStorage {
+    /// `podman` executable
+    pub podman: PathBuf,
+    /// Storage root, when not the user's default (`podman --root`)
+    pub root: Option<PathBuf>,
+}
+
+impl Default for ContainersStorage {
+    fn default() -> Self {
+        Self {
+            podman: PathBuf::from("podman"),
+            root: None,
+        }
+    }
+}
+
+impl ContainersStorage {
+    /// Copies `image` from containers/storage into the cache as `cache_name`
+    ///
+    /// `podman save` writes a scratch archive, removed once imported, in the
+    /// `tmp_dir` of `options` or else the import cache directory.
+    pub async fn import(
+        &self,
+        image: &str,
+        cache_name: &str,
+        options: &ImportOptions,
+    ) -> Result<(), PusherError> {
+        let scratch = options.scratch_dir();
+        std::fs::create_dir_all(scratch).map_err(|e| {
+            PusherError::CacheError(format!("Failed to create {}: {}", scratch.display(), e))
+        })?;
+        let archive = scratch.join(format!("podman_save_{}.tar", std::process::id()));
+
+        let mut command = Command::new(&self.podman);
+        if let Some(root) = &self.root {
+            command.arg("--root").arg(root);
+        }
+        command
+            .args(["save", "--format", "docker-archive", "--output"])
+            .arg(&archive)
+            .arg(image)
+            .stdin(Stdio::null());
+
+        info!("📥 Saving {} from containers-storage...", image);
+        debug!(command = ?command, "running podman");
+        let result = match tokio::task::spawn_blocking(move || command.output()).await {
+            Ok(Ok(output)) if output.status.success() => {
+                let archive_path = archive.to_string_lossy().into_owned();
+                crate::import::import_tar_file(&archive_path, cache_name, options).await
+            }
+            Ok(Ok(output)) => Err(PusherError::LocalStoreError(format!(
+                "podman save failed ({}): {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))),
+            Ok(Err(e)) => Err(PusherError::LocalStoreError(format!(
+                "Failed to run {} (is podman installed?): {}",
+                self.podman.display(),
+                e
+            ))),
+            Err(e) => Err(PusherError::LocalStoreError(format!("podman task failed: {}", e))),
+        };
+        let _ = std::fs::remove_file(&archive);
+        result
+    }
+}
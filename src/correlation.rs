@@ -0,0 +1,92 @@
+//! Correlation IDs tying client runs to registry server logs
+//!
+//! Every operation (a CLI run, a server job, or whatever a library user runs
+//! in a [`scope`]) gets an ID that is sent as the `X-Request-Id` header on the
+//! requests this crate makes itself. The process ID is also appended to the
+//! `User-Agent` of the oci-client requests, which can't carry extra headers.
+//! Registry admins can grep their access logs for the ID a failed run printed.
+
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Header carrying the correlation ID on direct registry requests
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// ID of the process, used outside [`scope`]; generated on first use unless [`set_id`] came first
+static PROCESS_ID: OnceLock<String> = OnceLock::new();
+
+/// `User-Agent` handed to oci-client, which wants a `&'static str`
+static USER_AGENT: OnceLock<String> = OnceLock::new();
+
+tokio::task_local! {
+    /// ID of the operation the current task runs, set by [`scope`]
+    static SCOPED_ID: String;
+}
+
+/// Keeps IDs generated within the same nanosecond apart
+static ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Generates a new random-looking 16 hex digit ID
+///
+/// Derived from the time, process ID and a counter, so IDs of concurrent
+/// runs on different hosts don't collide in practice.
+pub fn new_id() -> String {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let mut hasher = Sha256::new();
+    hasher.update(nanos.to_le_bytes());
+    hasher.update(std::process::id().to_le_bytes());
+    hasher.update(ID_COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+    format!("{:x}", hasher.finalize())[..16].to_string()
+}
+
+/// Sets the ID of the process, used by everything outside a [`scope`]; `None` keeps a generated one
+///
+/// Only the first call before the ID is used has an effect, so call it at
+/// startup (the CLI does, with `--request-id`). Give concurrent operations
+/// their own IDs with [`scope`] instead.
+pub fn set_id(id: Option<String>) {
+    if let Some(id) = id {
+        let _ = PROCESS_ID.set(id);
+    }
+}
+
+/// Runs `future` as an operation with its own ID
+///
+/// Requests made by the task running `future` carry `id` instead of the
+/// process ID; tasks it spawns don't inherit it.
+pub async fn scope<F: Future>(id: String, future: F) -> F::Output {
+    SCOPED_ID.scope(id, future).await
+}
+
+/// Returns the ID of the current operation: the [`scope`] of the task, or else the process ID
+pub fn id() -> String {
+    SCOPED_ID
+        .try_with(String::clone)
+        .unwrap_or_else(|_| process_id().to_string())
+}
+
+fn process_id() -> &'static str {
+    PROCESS_ID.get_or_init(new_id)
+}
+
+/// `User-Agent` for oci-client carrying the process ID
+///
+/// oci-client takes the `User-Agent` as a `&'static str`, so it can't follow
+/// [`scope`]; requests it makes within a scope carry the process ID, and
+/// only the `X-Request-Id` of the requests this crate makes itself has the
+/// scoped one.
+pub fn user_agent() -> &'static str {
+    USER_AGENT.get_or_init(|| {
+        format!(
+            "{}/{} (request-id {})",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            process_id()
+        )
+    })
+}
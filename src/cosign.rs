@@ -0,0 +1,460 @@
+//! Cosign signing and verification (enabled with the `cosign` cargo feature)
+//!
+//! Signatures use cosign's "simple signing" payload and are stored under the
+//! `sha256-<hex>.sig` tag of the image's repository, the layout `cosign sign`
+//! produces, so `cosign verify --key cosign.pub` accepts the signatures pushed
+//! here and [`verify_image`] accepts the ones `cosign sign --key` created.
+//! Only key-based signatures are supported; keyless (Fulcio/Rekor) and
+//! Notation signatures are not.
+
+use crate::PusherError;
+use crate::concurrency::RateLimiter;
+use crate::registry::RegistryClient;
+use crate::retry::RetryPolicy;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use oci_client::errors::OciDistributionError;
+use oci_client::manifest::{
+    IMAGE_CONFIG_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE, OciDescriptor, OciImageManifest, OciManifest,
+};
+use oci_client::secrets::RegistryAuth;
+use oci_client::{Client, Reference};
+use p256::ecdsa::signature::{Signer, Verifier};
+use p256::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
+
+/// Media type of the layer holding the signed payload
+pub const SIMPLE_SIGNING_MEDIA_TYPE: &str = "application/vnd.dev.cosign.simplesigning.v1+json";
+
+/// Layer annotation carrying the base64 signature of the payload
+pub const SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+
+/// PEM labels of `cosign generate-key-pair` private keys (current and legacy)
+const ENCRYPTED_KEY_LABELS: [&str; 2] = [
+    "ENCRYPTED SIGSTORE PRIVATE KEY",
+    "ENCRYPTED COSIGN PRIVATE KEY",
+];
+
+/// An ECDSA P-256 private key signing image digests
+pub struct SigningKey {
+    key: p256::ecdsa::SigningKey,
+}
+
+impl SigningKey {
+    /// Parses a PEM private key
+    ///
+    /// Accepts the password-protected `cosign.key` written by
+    /// `cosign generate-key-pair` as well as unencrypted PKCS#8 or SEC1
+    /// (`openssl ecparam -genkey`) P-256 keys. `password` is ignored for the latter.
+    pub fn from_pem(pem: &str, password: &[u8]) -> Result<Self, PusherError> {
+        let (label, der) = p256::pkcs8::der::pem::decode_vec(pem.trim().as_bytes())
+            .map_err(|e| PusherError::SignatureError(format!("Invalid PEM key: {}", e)))?;
+        let secret = if ENCRYPTED_KEY_LABELS.contains(&label) {
+            p256::SecretKey::from_pkcs8_der(&decrypt_cosign_key(&der, password)?)
+                .map_err(|e| e.to_string())
+        } else if label == "EC PRIVATE KEY" {
+            p256::SecretKey::from_sec1_der(&der).map_err(|e| e.to_string())
+        } else {
+            p256::SecretKey::from_pkcs8_der(&der).map_err(|e| e.to_string())
+        }
+        .map_err(|e| PusherError::SignatureError(format!("Unsupported private key: {}", e)))?;
+        Ok(Self {
+            key: secret.into(),
+        })
+    }
+
+    /// Reads and parses a PEM private key file, see [`SigningKey::from_pem`]
+    pub fn from_file(path: &Path, password: &[u8]) -> Result<Self, PusherError> {
+        let pem = std::fs::read_to_string(path).map_err(|e| {
+            PusherError::SignatureError(format!("Failed to read key {}: {}", path.display(), e))
+        })?;
+        Self::from_pem(&pem, password)
+    }
+
+    /// Signs `payload`, returning the base64 DER signature cosign expects
+    fn sign(&self, payload: &[u8]) -> String {
+        let signature: p256::ecdsa::Signature = self.key.sign(payload);
+        BASE64.encode(signature.to_der().as_bytes())
+    }
+}
+
+/// An ECDSA P-256 public key trusted to sign source images (`cosign.pub`)
+#[derive(Debug, Clone)]
+pub struct VerifyingKey {
+    key: p256::ecdsa::VerifyingKey,
+}
+
+impl VerifyingKey {
+    /// Parses a PEM `PUBLIC KEY`, as written by `cosign generate-key-pair`
+    pub fn from_pem(pem: &str) -> Result<Self, PusherError> {
+        let key = p256::ecdsa::VerifyingKey::from_public_key_pem(pem.trim()).map_err(|e| {
+            PusherError::SignatureError(format!("Unsupported public key: {}", e))
+        })?;
+        Ok(Self { key })
+    }
+
+    /// Reads and parses a PEM public key file
+    pub fn from_file(path: &Path) -> Result<Self, PusherError> {
+        let pem = std::fs::read_to_string(path).map_err(|e| {
+            PusherError::SignatureError(format!("Failed to read key {}: {}", path.display(), e))
+        })?;
+        Self::from_pem(&pem)
+    }
+
+    /// Checks a base64 DER (or raw r||s) signature over `payload`
+    fn verify(&self, payload: &[u8], signature: &str) -> bool {
+        let Ok(bytes) = BASE64.decode(signature.trim()) else {
+            return false;
+        };
+        let signature = p256::ecdsa::Signature::from_der(&bytes)
+            .or_else(|_| p256::ecdsa::Signature::from_slice(&bytes));
+        signature.is_ok_and(|signature| self.key.verify(payload, &signature).is_ok())
+    }
+}
+
+/// The JSON document cosign encrypts private keys into
+#[derive(Deserialize)]
+struct EncryptedKey {
+    kdf: Kdf,
+    cipher: Cipher,
+    ciphertext: String,
+}
+
+#[derive(Deserialize)]
+struct Kdf {
+    name: String,
+    params: KdfParams,
+    salt: String,
+}
+
+#[derive(Deserialize)]
+struct KdfParams {
+    #[serde(rename = "N")]
+    n: u64,
+    r: u32,
+    p: u32,
+}
+
+#[derive(Deserialize)]
+struct Cipher {
+    name: String,
+    nonce: String,
+}
+
+/// Decrypts a cosign private key (scrypt + NaCl secretbox) to PKCS#8 DER
+fn decrypt_cosign_key(data: &[u8], password: &[u8]) -> Result<Vec<u8>, PusherError> {
+    use crypto_secretbox::aead::{Aead, KeyInit};
+
+    let invalid = |what: &str| PusherError::SignatureError(format!("Invalid cosign key: {}", what));
+    let encrypted: EncryptedKey =
+        serde_json::from_slice(data).map_err(|_| invalid("malformed key document"))?;
+    if encrypted.kdf.name != "scrypt" || encrypted.cipher.name != "nacl/secretbox" {
+        return Err(invalid("unsupported encryption"));
+    }
+    let decode = |value: &str| BASE64.decode(value).map_err(|_| invalid("bad base64"));
+    let salt = decode(&encrypted.kdf.salt)?;
+    let nonce = decode(&encrypted.cipher.nonce)?;
+    let ciphertext = decode(&encrypted.ciphertext)?;
+
+    let params = &encrypted.kdf.params;
+    if !params.n.is_power_of_two() || nonce.len() != 24 {
+        return Err(invalid("bad scrypt parameters or nonce"));
+    }
+    let params = scrypt::Params::new(params.n.trailing_zeros() as u8, params.r, params.p, 32)
+        .map_err(|_| invalid("bad scrypt parameters"))?;
+    let mut key = [0u8; 32];
+    scrypt::scrypt(password, &salt, &params, &mut key)
+        .map_err(|_| invalid("key derivation failed"))?;
+
+    crypto_secretbox::XSalsa20Poly1305::new(&key.into())
+        .decrypt(nonce.as_slice().into(), ciphertext.as_slice())
+        .map_err(|_| {
+            PusherError::SignatureError(
+                "Failed to decrypt cosign key (wrong COSIGN_PASSWORD?)".to_string(),
+            )
+        })
+}
+
+/// Returns the tag cosign stores the signatures of `digest` under
+pub fn signature_tag(digest: &str) -> String {
+    format!("{}.sig", digest.replace(':', "-"))
+}
+
+/// Builds the simple signing payload binding `repository` to `digest`
+fn simple_signing_payload(repository: &str, digest: &str) -> Result<Vec<u8>, PusherError> {
+    let payload = serde_json::json!({
+        "critical": {
+            "identity": { "docker-reference": repository },
+            "image": { "docker-manifest-digest": digest },
+            "type": "cosign container image signature"
+        },
+        "optional": null
+    });
+    Ok(serde_json::to_vec(&payload)?)
+}
+
+/// Signs the manifest `manifest_digest` of `target_image` and pushes the signature
+///
+/// Signatures already stored for the digest (e.g. by another key) are kept;
+/// the new one is appended to the signature manifest.
+///
+/// # Arguments
+///
+/// * `client` - OCI client for registry operations
+/// * `target_image` - Reference the image was pushed to
+/// * `auth` - Credentials for the target registry
+/// * `manifest_digest` - Digest of the pushed manifest
+/// * `key` - Signing key
+/// * `retry` - Retry behaviour of the signature uploads
+///
+/// # Returns
+///
+/// `Result<String, PusherError>` - The reference the signature was pushed to
+pub async fn sign_image(
+    client: &Client,
+    target_image: &str,
+    auth: &RegistryAuth,
+    manifest_digest: &str,
+    key: &SigningKey,
+    retry: &RetryPolicy,
+) -> Result<String, PusherError> {
+    let target_ref: Reference = target_image
+        .parse()
+        .map_err(|e| PusherError::PushError(format!("Invalid target image reference: {}", e)))?;
+    let signature_ref = Reference::with_tag(
+        target_ref.registry().to_string(),
+        target_ref.repository().to_string(),
+        signature_tag(manifest_digest),
+    );
+    let cancel = CancellationToken::new();
+    let limiter = Arc::new(RateLimiter::new(None));
+    let registry = retry
+        .run("Authentication", &cancel, || {
+            RegistryClient::connect(client, &signature_ref, auth, limiter.clone())
+        })
+        .await?;
+
+    // Sign the payload and wrap it in a layer
+    let repository = format!(
+        "{}/{}",
+        target_ref.resolve_registry(),
+        target_ref.repository()
+    );
+    let payload = simple_signing_payload(&repository, manifest_digest)?;
+    let payload_digest = format!("sha256:{:x}", Sha256::digest(&payload));
+    let layer = OciDescriptor {
+        media_type: SIMPLE_SIGNING_MEDIA_TYPE.to_string(),
+        digest: payload_digest.clone(),
+        size: payload.len() as i64,
+        urls: None,
+        annotations: Some(BTreeMap::from([(
+            SIGNATURE_ANNOTATION.to_string(),
+            key.sign(&payload),
+        )])),
+    };
+
+    // Keep the signatures other keys stored for this digest
+    let mut layers = existing_signatures(client, &registry, &signature_ref, auth).await?;
+    layers.retain(|existing| {
+        existing.digest != payload_digest
+            || existing.annotations.as_ref().and_then(|a| a.get(SIGNATURE_ANNOTATION))
+                != layer.annotations.as_ref().and_then(|a| a.get(SIGNATURE_ANNOTATION))
+    });
+    layers.push(layer);
+
+    let config = serde_json::to_vec(&serde_json::json!({
+        "architecture": "",
+        "config": {},
+        "created": "0001-01-01T00:00:00Z",
+        "history": [{ "created": "0001-01-01T00:00:00Z" }],
+        "os": "",
+        "rootfs": {
+            "type": "layers",
+            "diff_ids": layers.iter().map(|l| l.digest.as_str()).collect::<Vec<_>>()
+        }
+    }))?;
+    let config_digest = format!("sha256:{:x}", Sha256::digest(&config));
+
+    for (blob, digest) in [(&payload, &payload_digest), (&config, &config_digest)] {
+        retry
+            .run_authenticated(
+                "Signature upload",
+                &cancel,
+                || async {
+                    registry.throttle().await;
+                    client
+                        .push_blob(&signature_ref, blob, digest)
+                        .await
+                        .map_err(|e| PusherError::registry("Failed to upload signature", e))
+                },
+                |stage| registry.reauthenticate(client, stage),
+            )
+            .await?;
+    }
+
+    let manifest = OciManifest::Image(OciImageManifest {
+        schema_version: 2,
+        media_type: Some(OCI_IMAGE_MEDIA_TYPE.to_string()),
+        config: OciDescriptor {
+            media_type: IMAGE_CONFIG_MEDIA_TYPE.to_string(),
+            digest: config_digest,
+            size: config.len() as i64,
+            urls: None,
+            annotations: None,
+        },
+        layers,
+        subject: None,
+        artifact_type: None,
+        annotations: None,
+    });
+    let manifest_bytes = serde_json::to_vec(&manifest)?;
+    let content_type: reqwest::header::HeaderValue =
+        manifest.content_type().parse().map_err(|e| {
+            PusherError::PushError(format!("Invalid manifest media type: {}", e))
+        })?;
+    retry
+        .run_authenticated(
+            "Signature manifest push",
+            &cancel,
+            || async {
+                registry.throttle().await;
+                client
+                    .push_manifest_raw(&signature_ref, manifest_bytes.clone(), content_type.clone())
+                    .await
+                    .map_err(|e| PusherError::registry("Failed to push signature manifest", e))
+            },
+            |stage| registry.reauthenticate(client, stage),
+        )
+        .await?;
+
+    info!(
+        digest = manifest_digest,
+        "🔏 Signed {} as {}",
+        manifest_digest,
+        signature_ref
+    );
+    Ok(signature_ref.whole())
+}
+
+/// Verifies that `key` signed one of `digests` of `image`
+///
+/// Looks up the cosign signatures stored next to each digest (in order) and
+/// accepts the first one that is signed by `key` and names that exact digest.
+/// Pass both the platform manifest and the index digest of a multi-arch image,
+/// since either may be what was signed.
+///
+/// # Returns
+///
+/// `Result<String, PusherError>` - The digest a valid signature was found for
+pub async fn verify_image(
+    client: &Client,
+    image: &Reference,
+    auth: &RegistryAuth,
+    digests: &[String],
+    key: &VerifyingKey,
+) -> Result<String, PusherError> {
+    for digest in digests {
+        let signature_ref = Reference::with_tag(
+            image.registry().to_string(),
+            image.repository().to_string(),
+            signature_tag(digest),
+        );
+        let layers = match client.pull_image_manifest(&signature_ref, auth).await {
+            Ok((manifest, _)) => manifest.layers,
+            Err(e) if is_not_found(&e) => continue,
+            Err(e) => return Err(PusherError::registry("Failed to fetch signatures", e)),
+        };
+
+        for layer in layers
+            .iter()
+            .filter(|layer| layer.media_type == SIMPLE_SIGNING_MEDIA_TYPE)
+        {
+            let Some(signature) = layer
+                .annotations
+                .as_ref()
+                .and_then(|a| a.get(SIGNATURE_ANNOTATION))
+            else {
+                continue;
+            };
+            let mut payload = Vec::new();
+            client
+                .pull_blob(&signature_ref, layer, &mut payload)
+                .await
+                .map_err(|e| PusherError::registry("Failed to fetch signature payload", e))?;
+            if crate::image::digest::Digest::compute(
+                crate::image::digest::Algorithm::of(&layer.digest).unwrap_or_default(),
+                &payload,
+            )
+            .to_string()
+                != layer.digest
+                || !key.verify(&payload, signature)
+            {
+                debug!(digest, payload = %layer.digest, "signature doesn't verify");
+                continue;
+            }
+            // The signature must be about this exact manifest, not just any image
+            let signed_digest = serde_json::from_slice::<serde_json::Value>(&payload)
+                .ok()
+                .and_then(|p| {
+                    p["critical"]["image"]["docker-manifest-digest"]
+                        .as_str()
+                        .map(str::to_string)
+                });
+            if signed_digest.as_deref() == Some(digest.as_str()) {
+                info!(digest, "🔏 Verified signature of {}", image);
+                return Ok(digest.clone());
+            }
+        }
+    }
+    Err(PusherError::SignatureError(format!(
+        "No signature by the trusted key found for {} ({})",
+        image,
+        digests.join(", ")
+    )))
+}
+
+/// Returns the signature layers already stored under `signature_ref`
+async fn existing_signatures(
+    client: &Client,
+    registry: &RegistryClient,
+    signature_ref: &Reference,
+    auth: &RegistryAuth,
+) -> Result<Vec<OciDescriptor>, PusherError> {
+    registry.throttle().await;
+    match client.pull_image_manifest(signature_ref, auth).await {
+        Ok((manifest, _)) => Ok(manifest.layers),
+        Err(e) if is_not_found(&e) => {
+            debug!(reference = %signature_ref, "no existing signatures");
+            Ok(Vec::new())
+        }
+        Err(e) => Err(PusherError::registry(
+            "Failed to read existing signatures",
+            e,
+        )),
+    }
+}
+
+/// Checks whether a manifest pull failed because the manifest doesn't exist
+fn is_not_found(error: &OciDistributionError) -> bool {
+    match error {
+        OciDistributionError::ImageManifestNotFoundError(_) => true,
+        OciDistributionError::ServerError { code, .. } => *code == 404,
+        OciDistributionError::RegistryError { envelope, .. } => {
+            envelope.errors.iter().all(|e| {
+                matches!(
+                    e.code,
+                    oci_client::errors::OciErrorCode::ManifestUnknown
+                        | oci_client::errors::OciErrorCode::NotFound
+                        | oci_client::errors::OciErrorCode::NameUnknown
+                )
+            })
+        }
+        _ => false,
+    }
+}
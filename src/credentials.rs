@@ -0,0 +1,834 @@
+//! Registry credentials: pull secrets, Docker configs and pluggable providers
+//!
+//! A `kubernetes.io/dockerconfigjson` secret carries a Docker `config.json`
+//! (`{"auths": {"<registry>": {...}}}`) base64-encoded under the
+//! `.dockerconfigjson` key. [`DockerConfig`] reads such a secret, the legacy
+//! `kubernetes.io/dockercfg` form, or a bare `config.json`, and looks up the
+//! credentials for a registry, so pull secrets already deployed to a cluster
+//! can be reused for migrations.
+//!
+//! Credentials are looked up per registry through the [`AuthProvider`]
+//! trait. Besides [`DockerConfig`] (which also runs the credential helpers a
+//! `docker login` config names), [`DockerConfigFile`], [`StaticAuth`],
+//! [`EnvAuth`] and [`CredentialHelper`] are built in, and [`from_fn`] turns a
+//! closure into a provider. An [`AuthChain`] asks several providers in turn,
+//! so an organisation's own token vending service can be plugged in next to
+//! them. Providers are asked again when a registry rejects a fresh token, so
+//! rotated credentials are picked up without a restart.
+
+use crate::PusherError;
+use crate::transport::TransportResponse;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use futures::future::BoxFuture;
+use oci_spec::distribution::Reference;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::debug;
+
+#[cfg(feature = "oci-client")]
+pub use oci_client::RegistryOperation;
+#[cfg(feature = "oci-client")]
+pub use oci_client::secrets::RegistryAuth;
+
+/// How to authenticate to a registry (oci-client's `RegistryAuth` with the `oci-client` feature)
+#[cfg(not(feature = "oci-client"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistryAuth {
+    /// Access the registry anonymously
+    Anonymous,
+    /// HTTP Basic authentication with a username and password
+    Basic(String, String),
+    /// Bearer token authentication
+    Bearer(String),
+}
+
+/// What a registry token is requested for (oci-client's `RegistryOperation` with the `oci-client` feature)
+#[cfg(not(feature = "oci-client"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RegistryOperation {
+    /// Pushing to the repository
+    Push,
+    /// Pulling from the repository
+    Pull,
+}
+
+/// Host names Docker Hub credentials may be stored under
+const DOCKER_HUB_ALIASES: &[&str] = &["index.docker.io", "registry-1.docker.io", "docker.io"];
+
+/// One entry of the `auths` map
+#[derive(Debug, Clone, Default, Deserialize)]
+struct AuthEntry {
+    /// base64 of `username:password`
+    #[serde(default)]
+    auth: Option<String>,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+    /// OAuth2 refresh token `docker login` stores instead of a password
+    #[serde(default, rename = "identitytoken")]
+    identity_token: Option<String>,
+}
+
+/// Host Docker Hub credentials are stored under by `docker login` and credential helpers
+const DOCKER_HUB_SERVER: &str = "https://index.docker.io/v1/";
+
+/// Username `docker login` and credential helpers store identity tokens under
+///
+/// Registries with an OAuth2 login flow (ACR, Docker Hub with 2FA) hand out
+/// an identity token instead of accepting the password later on. It is
+/// carried as `RegistryAuth::Basic("<token>", identity_token)` and traded for
+/// an access token by [`exchange_identity_token`].
+pub const IDENTITY_TOKEN_USERNAME: &str = "<token>";
+
+/// Client ID sent to token services
+const CLIENT_ID: &str = "docker-image-pusher";
+
+/// How long the registry and its token service have to answer an identity token exchange
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default prefix of the variables [`EnvAuth`] reads
+pub const DEFAULT_ENV_PREFIX: &str = "PUSHER";
+
+/// Supplies registry credentials, e.g. from a token vending service
+///
+/// Implementations answer `None` for registries they have nothing for, so
+/// the next provider of an [`AuthChain`] is asked.
+pub trait AuthProvider: Send + Sync {
+    /// Returns the credentials for `registry`, a host such as `docker.io` or
+    /// `registry.example.com:5000`
+    fn credentials_for<'a>(
+        &'a self,
+        registry: &'a str,
+    ) -> BoxFuture<'a, Result<Option<RegistryAuth>, PusherError>>;
+}
+
+/// Credentials per registry, as stored in a Docker `config.json`
+#[derive(Debug, Clone, Default)]
+pub struct DockerConfig {
+    auths: HashMap<String, AuthEntry>,
+    /// Helper per registry (`credHelpers`)
+    cred_helpers: HashMap<String, String>,
+    /// Helper for all other registries (`credsStore`)
+    creds_store: Option<String>,
+}
+
+impl DockerConfig {
+    /// Parses a Kubernetes secret (JSON) or a bare Docker `config.json`
+    ///
+    /// Accepts the `.dockerconfigjson` key under `data` (base64) or
+    /// `stringData` (plain), the legacy `.dockercfg` key, and a document
+    /// that has an `auths` map itself.
+    pub fn from_json(json: &str) -> Result<Self, PusherError> {
+        let document: serde_json::Value = serde_json::from_str(json)
+            .map_err(|e| PusherError::ConfigError(format!("Invalid pull secret: {}", e)))?;
+
+        for key in [".dockerconfigjson", ".dockercfg"] {
+            let config = if let Some(encoded) = document["data"][key].as_str() {
+                let decoded = STANDARD.decode(encoded.trim()).map_err(|e| {
+                    PusherError::ConfigError(format!("Invalid base64 in secret key {}: {}", key, e))
+                })?;
+                String::from_utf8(decoded).map_err(|e| {
+                    PusherError::ConfigError(format!("Secret key {} is not UTF-8: {}", key, e))
+                })?
+            } else if let Some(plain) = document["stringData"][key].as_str() {
+                plain.to_string()
+            } else {
+                continue;
+            };
+            let config: serde_json::Value = serde_json::from_str(&config).map_err(|e| {
+                PusherError::ConfigError(format!("Invalid Docker config in secret key {}: {}", key, e))
+            })?;
+            // `.dockercfg` is the bare `auths` map
+            let auths = match key {
+                ".dockercfg" => config,
+                _ => config["auths"].clone(),
+            };
+            return Self::from_auths(auths);
+        }
+
+        if document.get("auths").is_some() {
+            return Self::from_auths(document["auths"].clone());
+        }
+        Err(PusherError::ConfigError(
+            "Pull secret has no .dockerconfigjson, .dockercfg or auths entry".to_string(),
+        ))
+    }
+
+    /// Reads a pull secret from `path`, or from stdin when `path` is `-`
+    pub fn from_kube_secret(path: &Path) -> Result<Self, PusherError> {
+        let json = if path.as_os_str() == "-" {
+            let mut json = String::new();
+            std::io::stdin().read_to_string(&mut json)?;
+            json
+        } else {
+            std::fs::read_to_string(path).map_err(|e| {
+                PusherError::ConfigError(format!(
+                    "Failed to read pull secret {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?
+        };
+        Self::from_json(&json)
+    }
+
+    /// Reads the config `docker login` writes, `$DOCKER_CONFIG/config.json` or `~/.docker/config.json`
+    ///
+    /// Its `credHelpers` and `credsStore` entries are honored by running the
+    /// `docker-credential-*` helpers they name. A missing file is an empty config.
+    pub fn load_default() -> Result<Self, PusherError> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Config {
+            #[serde(default)]
+            auths: HashMap<String, AuthEntry>,
+            #[serde(default)]
+            cred_helpers: HashMap<String, String>,
+            #[serde(default)]
+            creds_store: Option<String>,
+        }
+
+        let dir = match std::env::var_os("DOCKER_CONFIG") {
+            Some(dir) => PathBuf::from(dir),
+            None => match std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")) {
+                Some(home) => PathBuf::from(home).join(".docker"),
+                None => return Ok(Self::default()),
+            },
+        };
+        let path = dir.join("config.json");
+        let json = match std::fs::read_to_string(&path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => {
+                return Err(PusherError::ConfigError(format!(
+                    "Failed to read Docker config {}: {}",
+                    path.display(),
+                    e
+                )));
+            }
+        };
+        let config: Config = serde_json::from_str(&json).map_err(|e| {
+            PusherError::ConfigError(format!("Invalid Docker config {}: {}", path.display(), e))
+        })?;
+        Ok(Self {
+            auths: normalize_keys(config.auths),
+            cred_helpers: normalize_keys(config.cred_helpers),
+            creds_store: config.creds_store.filter(|store| !store.is_empty()),
+        })
+    }
+
+    fn from_auths(auths: serde_json::Value) -> Result<Self, PusherError> {
+        let auths: HashMap<String, AuthEntry> = serde_json::from_value(auths)
+            .map_err(|e| PusherError::ConfigError(format!("Invalid auths in pull secret: {}", e)))?;
+        Ok(Self {
+            auths: normalize_keys(auths),
+            ..Default::default()
+        })
+    }
+
+    /// Returns the registries this config has credentials or a credential helper for
+    pub fn registries(&self) -> impl Iterator<Item = &str> {
+        self.auths
+            .keys()
+            .chain(self.cred_helpers.keys())
+            .map(String::as_str)
+    }
+
+    /// Returns the credentials stored for `registry`, if any
+    ///
+    /// `registry` may be a host (`registry.example.com:5000`) or a URL
+    /// such as the `https://index.docker.io/v1/` key `docker login` writes.
+    pub fn auth_for(&self, registry: &str) -> Result<Option<RegistryAuth>, PusherError> {
+        let Some(entry) = self.auths.get(&normalize_registry(registry)) else {
+            return Ok(None);
+        };
+        let identity_token = entry
+            .identity_token
+            .as_ref()
+            .filter(|token| !token.is_empty());
+        if let Some(token) = identity_token {
+            return Ok(Some(RegistryAuth::Basic(
+                IDENTITY_TOKEN_USERNAME.to_string(),
+                token.clone(),
+            )));
+        }
+        if let (Some(username), Some(password)) = (&entry.username, &entry.password) {
+            return Ok(Some(RegistryAuth::Basic(username.clone(), password.clone())));
+        }
+        let Some(auth) = &entry.auth else {
+            return Ok(None);
+        };
+        let decoded = STANDARD
+            .decode(auth.trim())
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or_else(|| {
+                PusherError::ConfigError(format!("Invalid auth entry for {} in pull secret", registry))
+            })?;
+        let (username, password) = decoded.split_once(':').ok_or_else(|| {
+            PusherError::ConfigError(format!(
+                "Auth entry for {} in pull secret is not username:password",
+                registry
+            ))
+        })?;
+        Ok(Some(RegistryAuth::Basic(username.to_string(), password.to_string())))
+    }
+}
+
+impl AuthProvider for DockerConfig {
+    fn credentials_for<'a>(
+        &'a self,
+        registry: &'a str,
+    ) -> BoxFuture<'a, Result<Option<RegistryAuth>, PusherError>> {
+        Box::pin(async move {
+            // Like Docker: the registry's own helper, then the default store, then `auths`
+            let helper = self
+                .cred_helpers
+                .get(&normalize_registry(registry))
+                .or(self.creds_store.as_ref());
+            if let Some(helper) = helper
+                && let Some(auth) = CredentialHelper::new(helper)
+                    .credentials_for(registry)
+                    .await?
+            {
+                return Ok(Some(auth));
+            }
+            self.auth_for(registry)
+        })
+    }
+}
+
+/// A pull secret or `docker login` config read again for every lookup
+///
+/// A loaded [`DockerConfig`] keeps the credentials it was read with. This
+/// provider follows a pull secret Kubernetes updates in place or a new
+/// `docker login`, so a long-running `serve` picks up rotated passwords.
+#[derive(Debug, Clone, Default)]
+pub struct DockerConfigFile {
+    /// Pull secret to read; the `docker login` config if not set
+    kube_secret: Option<PathBuf>,
+}
+
+impl DockerConfigFile {
+    /// Follows the config `docker login` writes (see [`DockerConfig::load_default`])
+    pub fn login() -> Self {
+        Self::default()
+    }
+
+    /// Follows the pull secret at `path`
+    pub fn kube_secret(path: impl Into<PathBuf>) -> Self {
+        Self {
+            kube_secret: Some(path.into()),
+        }
+    }
+}
+
+impl AuthProvider for DockerConfigFile {
+    fn credentials_for<'a>(
+        &'a self,
+        registry: &'a str,
+    ) -> BoxFuture<'a, Result<Option<RegistryAuth>, PusherError>> {
+        Box::pin(async move {
+            let config = match &self.kube_secret {
+                Some(path) => DockerConfig::from_kube_secret(path)?,
+                None => DockerConfig::load_default()?,
+            };
+            config.credentials_for(registry).await
+        })
+    }
+}
+
+/// The same credentials for every registry, or for a single one
+#[derive(Debug, Clone)]
+pub struct StaticAuth {
+    registry: Option<String>,
+    auth: RegistryAuth,
+}
+
+impl StaticAuth {
+    /// Answers `auth` for every registry
+    pub fn new(auth: RegistryAuth) -> Self {
+        Self {
+            registry: None,
+            auth,
+        }
+    }
+
+    /// Answers `auth` for `registry` only
+    pub fn for_registry(registry: &str, auth: RegistryAuth) -> Self {
+        Self {
+            registry: Some(normalize_registry(registry)),
+            auth,
+        }
+    }
+}
+
+impl AuthProvider for StaticAuth {
+    fn credentials_for<'a>(
+        &'a self,
+        registry: &'a str,
+    ) -> BoxFuture<'a, Result<Option<RegistryAuth>, PusherError>> {
+        let matches = self
+            .registry
+            .as_ref()
+            .is_none_or(|own| *own == normalize_registry(registry));
+        Box::pin(std::future::ready(Ok(matches.then(|| self.auth.clone()))))
+    }
+}
+
+/// Credentials from environment variables
+///
+/// `<PREFIX>_<REGISTRY>_USERNAME` and `<PREFIX>_<REGISTRY>_PASSWORD` apply to
+/// one registry, `<REGISTRY>` being its host in upper case with everything
+/// but letters and digits replaced by `_` (`PUSHER_REGISTRY_EXAMPLE_COM_USERNAME`);
+/// `<PREFIX>_USERNAME` and `<PREFIX>_PASSWORD` apply to all others. A
+/// `_TOKEN` variable in place of the pair is sent as a bearer token.
+#[derive(Debug, Clone)]
+pub struct EnvAuth {
+    prefix: String,
+}
+
+impl EnvAuth {
+    /// Reads the variables starting with `prefix`
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+        }
+    }
+}
+
+impl Default for EnvAuth {
+    fn default() -> Self {
+        Self::new(DEFAULT_ENV_PREFIX)
+    }
+}
+
+impl AuthProvider for EnvAuth {
+    fn credentials_for<'a>(
+        &'a self,
+        registry: &'a str,
+    ) -> BoxFuture<'a, Result<Option<RegistryAuth>, PusherError>> {
+        let host: String = normalize_registry(registry)
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let var = |scope: &str, name: &str| std::env::var(format!("{}_{}", scope, name)).ok();
+        let scopes = [format!("{}_{}", self.prefix, host), self.prefix.clone()];
+        let found = scopes.iter().find_map(|scope| {
+            let basic = var(scope, "USERNAME").zip(var(scope, "PASSWORD"));
+            match basic {
+                Some((username, password)) => Some(RegistryAuth::Basic(username, password)),
+                None => var(scope, "TOKEN").map(RegistryAuth::Bearer),
+            }
+        });
+        Box::pin(std::future::ready(Ok(found)))
+    }
+}
+
+/// Credentials from a closure, see [`from_fn`]
+pub struct FnAuth<F>(F);
+
+/// Asks `lookup` for the credentials of each registry
+///
+/// The closure is called again whenever credentials are needed, including
+/// after the registry rejected the previous ones, so it can read them from
+/// Vault or a secret file that is rotated while the process runs.
+///
+/// # Examples
+///
+/// ```
+/// use docker_image_pusher::credentials::{self, AuthChain, RegistryAuth};
+///
+/// let vault = credentials::from_fn(|registry| async move {
+///     // e.g. read `secret/registries/<registry>` from Vault
+///     Ok(Some(RegistryAuth::Basic("deploy".to_string(), format!("password-for-{}", registry))))
+/// });
+/// let credentials = AuthChain::new().with(vault);
+/// let auth = futures::executor::block_on(credentials.resolve("registry.company.com")).unwrap();
+/// assert_eq!(
+///     auth,
+///     RegistryAuth::Basic("deploy".to_string(), "password-for-registry.company.com".to_string())
+/// );
+/// ```
+pub fn from_fn<F, Fut>(lookup: F) -> FnAuth<F>
+where
+    F: Fn(String) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Option<RegistryAuth>, PusherError>> + Send + 'static,
+{
+    FnAuth(lookup)
+}
+
+impl<F, Fut> AuthProvider for FnAuth<F>
+where
+    F: Fn(String) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Option<RegistryAuth>, PusherError>> + Send + 'static,
+{
+    fn credentials_for<'a>(
+        &'a self,
+        registry: &'a str,
+    ) -> BoxFuture<'a, Result<Option<RegistryAuth>, PusherError>> {
+        Box::pin((self.0)(registry.to_string()))
+    }
+}
+
+impl<F> fmt::Debug for FnAuth<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FnAuth").finish_non_exhaustive()
+    }
+}
+
+/// A Docker credential helper, the `docker-credential-<name>` executable
+///
+/// Covers the stores and cloud logins `docker login` can use (`osxkeychain`,
+/// `pass`, `ecr-login`, `gcloud`, ...). A helper that knows no credentials
+/// for a registry answers `None`.
+#[derive(Debug, Clone)]
+pub struct CredentialHelper {
+    program: String,
+}
+
+impl CredentialHelper {
+    /// The helper named `name`, e.g. `ecr-login` for `docker-credential-ecr-login`
+    pub fn new(name: &str) -> Self {
+        Self {
+            program: format!("docker-credential-{}", name),
+        }
+    }
+}
+
+impl AuthProvider for CredentialHelper {
+    fn credentials_for<'a>(
+        &'a self,
+        registry: &'a str,
+    ) -> BoxFuture<'a, Result<Option<RegistryAuth>, PusherError>> {
+        let program = self.program.clone();
+        let server = match normalize_registry(registry).as_str() {
+            "docker.io" => DOCKER_HUB_SERVER.to_string(),
+            _ => registry.to_string(),
+        };
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || run_helper(&program, &server))
+                .await
+                .map_err(|e| {
+                    PusherError::ConfigError(format!("Credential helper task failed: {}", e))
+                })?
+        })
+    }
+}
+
+/// Runs `program get` for `server`, turning its answer into credentials
+fn run_helper(program: &str, server: &str) -> Result<Option<RegistryAuth>, PusherError> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct Reply {
+        username: String,
+        secret: String,
+    }
+
+    let mut child = Command::new(program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| PusherError::ConfigError(format!("Failed to run {}: {}", program, e)))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(server.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        // Also how helpers say they have no credentials for the server
+        debug!(
+            helper = program,
+            server,
+            stderr = %String::from_utf8_lossy(&output.stderr).trim(),
+            "credential helper found nothing"
+        );
+        return Ok(None);
+    }
+    let reply: Reply = serde_json::from_slice(&output.stdout)
+        .map_err(|e| PusherError::ConfigError(format!("Invalid answer from {}: {}", program, e)))?;
+    Ok(Some(RegistryAuth::Basic(reply.username, reply.secret)))
+}
+
+/// Asks several [`AuthProvider`]s in turn; the first one with credentials wins
+///
+/// The secrets it hands out are registered with
+/// [`crate::logging::register_secret`], so they never show up in logs.
+///
+/// # Examples
+///
+/// ```
+/// use docker_image_pusher::PusherError;
+/// use docker_image_pusher::credentials::{AuthChain, AuthProvider, EnvAuth, RegistryAuth};
+/// use futures::future::BoxFuture;
+///
+/// /// Hands out short-lived tokens for the company registry
+/// struct TokenVendor;
+///
+/// impl AuthProvider for TokenVendor {
+///     fn credentials_for<'a>(
+///         &'a self,
+///         registry: &'a str,
+///     ) -> BoxFuture<'a, Result<Option<RegistryAuth>, PusherError>> {
+///         Box::pin(async move {
+///             if registry != "registry.company.com" {
+///                 return Ok(None);
+///             }
+///             // e.g. a request to the token service
+///             Ok(Some(RegistryAuth::Bearer("vended-token".to_string())))
+///         })
+///     }
+/// }
+///
+/// let credentials = AuthChain::new().with(TokenVendor).with(EnvAuth::default());
+/// let auth = futures::executor::block_on(credentials.resolve("registry.company.com")).unwrap();
+/// assert_eq!(auth, RegistryAuth::Bearer("vended-token".to_string()));
+/// ```
+#[derive(Clone, Default)]
+pub struct AuthChain {
+    providers: Vec<Arc<dyn AuthProvider>>,
+}
+
+impl AuthChain {
+    /// Creates a chain without providers, which answers anonymous access
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `provider`, asked after the ones already in the chain
+    pub fn with(mut self, provider: impl AuthProvider + 'static) -> Self {
+        self.providers.push(Arc::new(provider));
+        self
+    }
+
+    /// Checks whether the chain has no providers
+    pub fn is_empty(&self) -> bool {
+        self.providers.is_empty()
+    }
+
+    /// Returns the credentials for `registry`, anonymous access if no provider has any
+    pub async fn resolve(&self, registry: &str) -> Result<RegistryAuth, PusherError> {
+        Ok(self
+            .credentials_for(registry)
+            .await?
+            .unwrap_or(RegistryAuth::Anonymous))
+    }
+
+    /// Returns the credentials for the registry of `image`, like [`AuthChain::resolve`]
+    pub async fn resolve_image(&self, image: &str) -> Result<RegistryAuth, PusherError> {
+        if self.is_empty() {
+            return Ok(RegistryAuth::Anonymous);
+        }
+        let reference: Reference = image.parse().map_err(|e| {
+            PusherError::ConfigError(format!("Invalid image reference {}: {}", image, e))
+        })?;
+        self.resolve(reference.registry()).await
+    }
+}
+
+impl AuthProvider for AuthChain {
+    fn credentials_for<'a>(
+        &'a self,
+        registry: &'a str,
+    ) -> BoxFuture<'a, Result<Option<RegistryAuth>, PusherError>> {
+        Box::pin(async move {
+            for provider in &self.providers {
+                if let Some(auth) = provider.credentials_for(registry).await? {
+                    // Scrubbed from logs like secrets given on the command line
+                    match &auth {
+                        RegistryAuth::Basic(_, secret) | RegistryAuth::Bearer(secret) => {
+                            crate::logging::register_secret(secret)
+                        }
+                        RegistryAuth::Anonymous => {}
+                    }
+                    return Ok(Some(auth));
+                }
+            }
+            Ok(None)
+        })
+    }
+}
+
+impl<P: AuthProvider + ?Sized> AuthProvider for Arc<P> {
+    fn credentials_for<'a>(
+        &'a self,
+        registry: &'a str,
+    ) -> BoxFuture<'a, Result<Option<RegistryAuth>, PusherError>> {
+        (**self).credentials_for(registry)
+    }
+}
+
+impl fmt::Debug for AuthChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthChain")
+            .field("providers", &self.providers.len())
+            .finish()
+    }
+}
+
+/// Trades an identity token for an access token to `reference`'s repository
+///
+/// Identity tokens (see [`IDENTITY_TOKEN_USERNAME`]) are OAuth2 refresh
+/// tokens, which registries reject as passwords. They are posted to the token
+/// service named by the registry's `Bearer` challenge (`grant_type=refresh_token`),
+/// like Docker does, and the access token that comes back is used instead.
+/// Other credentials are returned as they are.
+pub async fn exchange_identity_token(
+    reference: &Reference,
+    auth: &RegistryAuth,
+    operation: RegistryOperation,
+) -> Result<RegistryAuth, PusherError> {
+    let RegistryAuth::Basic(username, identity_token) = auth else {
+        return Ok(auth.clone());
+    };
+    if username != IDENTITY_TOKEN_USERNAME {
+        return Ok(auth.clone());
+    }
+    let registry = reference.resolve_registry();
+    let http = reqwest::Client::new();
+    let ping = http
+        .get(format!("{}/v2/", crate::registry::base_url(registry)))
+        .timeout(EXCHANGE_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| PusherError::transport("Registry ping failed", e.into()))?;
+    let challenge = ping
+        .headers()
+        .get(reqwest::header::WWW_AUTHENTICATE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(BearerChallenge::parse)
+        .ok_or_else(|| {
+            PusherError::ConfigError(format!(
+                "{} doesn't issue bearer tokens, so its identity token can't be used",
+                registry
+            ))
+        })?;
+
+    let actions = match operation {
+        RegistryOperation::Pull => "pull",
+        RegistryOperation::Push => "pull,push",
+    };
+    let scope = format!("repository:{}:{}", reference.repository(), actions);
+    let mut form = vec![
+        ("grant_type", "refresh_token"),
+        ("client_id", CLIENT_ID),
+        ("refresh_token", identity_token.as_str()),
+        ("scope", scope.as_str()),
+    ];
+    if let Some(service) = &challenge.service {
+        form.push(("service", service));
+    }
+    let response = http
+        .post(&challenge.realm)
+        .form(&form)
+        .timeout(EXCHANGE_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| PusherError::transport("Identity token exchange failed", e.into()))?;
+    let response = TransportResponse {
+        status: response.status(),
+        headers: response.headers().clone(),
+        body: response
+            .bytes()
+            .await
+            .map_err(|e| PusherError::transport("Identity token exchange failed", e.into()))?
+            .to_vec(),
+    };
+    if !response.status.is_success() {
+        return Err(PusherError::http(
+            format!("Token service of {} rejected the identity token", registry),
+            &response,
+        ));
+    }
+
+    #[derive(Deserialize)]
+    struct TokenResponse {
+        #[serde(default)]
+        access_token: Option<String>,
+        // Older token services answer with `token` only
+        #[serde(default)]
+        token: Option<String>,
+    }
+    let tokens: TokenResponse = serde_json::from_slice(&response.body)?;
+    let access_token = tokens
+        .access_token
+        .or(tokens.token)
+        .filter(|token| !token.is_empty())
+        .ok_or_else(|| {
+            PusherError::ConfigError(format!(
+                "Token service of {} returned no access token",
+                registry
+            ))
+        })?;
+    crate::logging::register_secret(&access_token);
+    debug!(registry, scope, "exchanged identity token");
+    Ok(RegistryAuth::Bearer(access_token))
+}
+
+/// Where a registry's `WWW-Authenticate: Bearer ...` challenge sends clients for tokens
+struct BearerChallenge {
+    realm: String,
+    service: Option<String>,
+}
+
+impl BearerChallenge {
+    fn parse(header: &str) -> Option<Self> {
+        let (scheme, params) = header.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return None;
+        }
+        let (mut realm, mut service) = (None, None);
+        // Quoted scopes may contain commas; such fragments have no `=` and are skipped
+        for param in params.split(',') {
+            let Some((name, value)) = param.split_once('=') else {
+                continue;
+            };
+            let value = value.trim().trim_matches('"').to_string();
+            match name.trim() {
+                "realm" => realm = Some(value),
+                "service" => service = Some(value),
+                _ => {}
+            }
+        }
+        Some(Self {
+            realm: realm?,
+            service,
+        })
+    }
+}
+
+/// Normalizes the registry keys of a config map
+fn normalize_keys<V>(map: HashMap<String, V>) -> HashMap<String, V> {
+    map.into_iter()
+        .map(|(registry, value)| (normalize_registry(&registry), value))
+        .collect()
+}
+
+/// Reduces a registry key to its lowercase host, mapping Docker Hub aliases to `docker.io`
+fn normalize_registry(registry: &str) -> String {
+    let host = registry
+        .trim()
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+    let host = host.split('/').next().unwrap_or(host).to_ascii_lowercase();
+    if DOCKER_HUB_ALIASES.contains(&host.as_str()) {
+        "docker.io".to_string()
+    } else {
+        host
+    }
+}
@@ -0,0 +1,157 @@
+//! Layer sharing across the cached images (`cache dedup-report`)
+//!
+//! Every cached image keeps its own copy of its layers, so images built on
+//! the same base store the base layers once per image. The report shows how
+//! much of the cache is such duplicates, i.e. what storing each layer once
+//! would save, and which images share which layers, to size air-gap bundles
+//! before copying them.
+
+use crate::PusherError;
+use crate::image::digest;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+/// How the layers of the cached images overlap
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DedupReport {
+    /// Cached images, by name
+    pub images: Vec<ImageUsage>,
+    /// Layers found in more than one image, grouped by the images sharing them,
+    /// largest savings first
+    pub shared: Vec<SharedLayers>,
+    /// Distinct layers in the cache
+    pub distinct_layers: usize,
+    /// Layer bytes stored in the cache, counting every copy
+    pub stored_bytes: u64,
+    /// Layer bytes if every layer were stored once
+    pub distinct_bytes: u64,
+}
+
+/// Layers of one cached image
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageUsage {
+    /// Image name as cached (e.g. "nginx:1.27")
+    pub image: String,
+    /// Layers of the image found in the cache
+    pub layers: usize,
+    /// Size of those layers
+    pub bytes: u64,
+    /// Size of the layers no other cached image has
+    pub unique_bytes: u64,
+}
+
+/// Layers shared by the same set of images, typically their common base
+#[derive(Debug, Clone, Serialize)]
+pub struct SharedLayers {
+    /// Images having all of `layers`
+    pub images: Vec<String>,
+    /// Layer digests
+    pub layers: Vec<String>,
+    /// Size of one copy of the layers
+    pub bytes: u64,
+}
+
+impl SharedLayers {
+    /// Bytes stored once per image beyond the first
+    pub fn duplicate_bytes(&self) -> u64 {
+        self.bytes * (self.images.len() as u64).saturating_sub(1)
+    }
+}
+
+impl DedupReport {
+    /// Bytes storing each layer once would free
+    pub fn savable_bytes(&self) -> u64 {
+        self.stored_bytes - self.distinct_bytes
+    }
+}
+
+/// Analyzes the layers of every complete image cached in `cache_dir`
+///
+/// Layer sizes are those of the cached files; layers missing from an image's
+/// cache directory are left out.
+///
+/// # Examples
+///
+/// ```
+/// # use docker_image_pusher::dedup::dedup_report;
+/// let cache_dir = std::env::temp_dir().join("dedup-report-example");
+/// std::fs::create_dir_all(&cache_dir)?;
+/// let report = dedup_report(&cache_dir)?;
+/// assert!(report.images.is_empty());
+/// assert_eq!(report.savable_bytes(), 0);
+/// # Ok::<(), docker_image_pusher::PusherError>(())
+/// ```
+pub fn dedup_report(cache_dir: &Path) -> Result<DedupReport, PusherError> {
+    let entries = std::fs::read_dir(cache_dir).map_err(|e| {
+        PusherError::CacheError(format!("Failed to read {}: {}", cache_dir.display(), e))
+    })?;
+
+    // Image name -> its layers with their sizes
+    let mut images: BTreeMap<String, BTreeMap<String, u64>> = BTreeMap::new();
+    for entry in entries.flatten() {
+        let dir = entry.path();
+        // Only complete images have an index
+        let Ok(index) = std::fs::read(dir.join("index.json")) else {
+            continue;
+        };
+        let index: serde_json::Value = serde_json::from_slice(&index).unwrap_or_default();
+        let Some(image) = index["source_image"].as_str() else {
+            continue;
+        };
+        let layers = images.entry(image.to_string()).or_default();
+        for layer in index["layers"].as_array().into_iter().flatten() {
+            let Some(layer) = layer.as_str() else {
+                continue;
+            };
+            if let Ok(metadata) = std::fs::metadata(dir.join(digest::file_name(layer))) {
+                layers.insert(layer.to_string(), metadata.len());
+            }
+        }
+    }
+
+    // Layer -> its size and the images having it
+    let mut owners: BTreeMap<&str, (u64, BTreeSet<&str>)> = BTreeMap::new();
+    for (image, layers) in &images {
+        for (layer, size) in layers {
+            let owner = owners.entry(layer).or_insert((*size, BTreeSet::new()));
+            owner.1.insert(image);
+        }
+    }
+
+    let mut report = DedupReport {
+        distinct_layers: owners.len(),
+        distinct_bytes: owners.values().map(|(size, _)| size).sum(),
+        ..Default::default()
+    };
+    for (image, layers) in &images {
+        let bytes: u64 = layers.values().sum();
+        report.stored_bytes += bytes;
+        report.images.push(ImageUsage {
+            image: image.clone(),
+            layers: layers.len(),
+            bytes,
+            unique_bytes: layers
+                .iter()
+                .filter(|(layer, _)| owners[layer.as_str()].1.len() == 1)
+                .map(|(_, size)| size)
+                .sum(),
+        });
+    }
+
+    let mut groups: BTreeMap<&BTreeSet<&str>, SharedLayers> = BTreeMap::new();
+    for (layer, (size, sharing)) in owners.iter().filter(|(_, (_, sharing))| sharing.len() > 1) {
+        let group = groups.entry(sharing).or_insert_with(|| SharedLayers {
+            images: sharing.iter().map(|image| image.to_string()).collect(),
+            layers: Vec::new(),
+            bytes: 0,
+        });
+        group.layers.push(layer.to_string());
+        group.bytes += size;
+    }
+    report.shared = groups.into_values().collect();
+    report
+        .shared
+        .sort_by_key(|group| std::cmp::Reverse(group.duplicate_bytes()));
+    Ok(report)
+}
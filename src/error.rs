@@ -0,0 +1,510 @@
+use crate::report::BatchReport;
+#[cfg(feature = "oci-client")]
+use crate::transport::TransportErrorKind;
+use crate::transport::{TransportError, TransportResponse};
+#[cfg(feature = "oci-client")]
+use oci_client::errors::{OciDistributionError, OciErrorCode};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+use thiserror::Error;
+
+/// One entry of the `{"errors": [...]}` body registries return on failure
+///
+/// Displays as `CODE: message`, e.g. `DENIED: quota exceeded`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegistryApiError {
+    /// Error code such as `BLOB_UNKNOWN`, `DENIED` or `TOOMANYREQUESTS`
+    pub code: String,
+    /// Human-readable message
+    #[serde(default)]
+    pub message: String,
+    /// Registry-specific details, `null` when absent
+    #[serde(default)]
+    pub detail: serde_json::Value,
+}
+
+impl RegistryApiError {
+    /// Parses a registry error body, returning no errors if it isn't one
+    pub fn parse(body: &[u8]) -> Vec<Self> {
+        #[derive(Deserialize)]
+        struct Envelope {
+            errors: Vec<RegistryApiError>,
+        }
+        serde_json::from_slice::<Envelope>(body)
+            .map(|envelope| envelope.errors)
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Display for RegistryApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.message.is_empty() {
+            write!(f, "{}", self.code)
+        } else {
+            write!(f, "{}: {}", self.code, self.message)
+        }
+    }
+}
+
+/// Custom error types for the Docker image pusher application
+///
+/// This enum provides specific error categories to help with debugging
+/// and error handling throughout the application. It is shared by the
+/// library and the command-line binary. Variants wrapping a lower-level
+/// failure keep it as their `source()`, so the full cause chain (down to
+/// e.g. "connection refused") can be reported.
+#[derive(Error, Debug)]
+pub enum PusherError {
+    /// Errors that occur during image pulling operations
+    /// These typically involve network issues or authentication problems
+    #[error("Pull error: {0}")]
+    PullError(String),
+
+    /// Errors that occur during image pushing operations  
+    /// These may involve registry authentication or upload failures
+    #[error("Push error: {0}")]
+    PushError(String),
+
+    /// Errors related to local cache operations
+    /// Including file I/O issues and cache corruption
+    #[error("Cache error: {0}")]
+    CacheError(String),
+
+    /// Standard I/O errors (file operations, network, etc.)
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+
+    /// JSON serialization/deserialization errors
+    #[error("Serde error: {0}")]
+    SerdeError(#[from] serde_json::Error),
+    
+    /// Error when requested cached image is not found
+    #[error("Cache not found")]
+    CacheNotFound,
+
+    /// Errors that occur during tar file processing
+    /// Including tar archive parsing and layer extraction
+    #[error("Tar processing error: {0}")]
+    TarError(String),
+
+    /// Failures reading from or writing to a local image store such as containerd
+    #[error("Local image store error: {0}")]
+    LocalStoreError(String),
+
+    /// Invalid command-line options or configuration values
+    #[error("Configuration error: {0}")]
+    ConfigError(String),
+
+    /// Registry API failures reported by oci-client (manifests, blobs, auth)
+    #[cfg(feature = "oci-client")]
+    #[error("Registry error: {message}")]
+    Registry {
+        /// What was being attempted
+        message: String,
+        /// Underlying oci-client error
+        #[source]
+        source: OciDistributionError,
+    },
+
+    /// HTTP requests of the chunked upload client that could not be completed
+    #[error("Transport error: {message}")]
+    Transport {
+        /// What was being attempted
+        message: String,
+        /// Underlying transport failure
+        #[source]
+        source: TransportError,
+    },
+
+    /// Unexpected HTTP status returned to the chunked upload client
+    #[error("{message}: {}", describe_status(*.status, .errors))]
+    Http {
+        /// What was being attempted
+        message: String,
+        /// Status code returned by the registry
+        status: StatusCode,
+        /// Errors parsed from the response body, if it had any
+        errors: Vec<RegistryApiError>,
+        /// Delay requested by the registry's `Retry-After` header
+        retry_after: Option<Duration>,
+    },
+
+    /// Cached content no longer matches the digest it was pulled under
+    #[error("{what} does not match its digest: expected {expected}, found {actual}")]
+    DigestMismatch {
+        /// The blob or manifest that was checked
+        what: String,
+        /// Digest it was pulled under
+        expected: String,
+        /// Digest of the content found in the cache
+        actual: String,
+    },
+
+    /// A digest that isn't `<algorithm>:<hex>` with a supported algorithm
+    #[error("{what} has an invalid digest: {problem}")]
+    InvalidDigest {
+        /// Where the digest came from
+        what: String,
+        /// What is wrong with it
+        problem: String,
+    },
+
+    /// A manifest that doesn't follow the OCI/Docker image manifest schema
+    #[error("{what} is not a valid image manifest: {}", .problems.join("; "))]
+    InvalidManifest {
+        /// Which manifest was checked
+        what: String,
+        /// Each problem found, naming the offending field
+        problems: Vec<String>,
+    },
+
+    /// The cache filesystem can't hold what a pull or import is about to write
+    #[error(
+        "Not enough disk space in {}: {:.1} MB needed (plus {} MB headroom), {:.1} MB available",
+        .path.display(),
+        *.required as f64 / (1024.0 * 1024.0),
+        crate::store::DISK_HEADROOM / (1024 * 1024),
+        *.available as f64 / (1024.0 * 1024.0)
+    )]
+    InsufficientDiskSpace {
+        /// Directory the data would be written to
+        path: std::path::PathBuf,
+        /// Bytes that would be written
+        required: u64,
+        /// Bytes free on its filesystem
+        available: u64,
+    },
+
+    /// The layers a push is about to upload exceed the remaining quota of its Harbor project
+    #[error(
+        "Harbor project {project} has {:.1} MB of quota left, the push needs {:.1} MB",
+        *.available as f64 / (1024.0 * 1024.0),
+        *.required as f64 / (1024.0 * 1024.0)
+    )]
+    QuotaExceeded {
+        /// The Harbor project pushed to
+        project: String,
+        /// Bytes the push would upload
+        required: u64,
+        /// Bytes left in the project's quota
+        available: u64,
+    },
+
+    /// Lint checks found problems and `--strict` makes them fatal
+    #[error("{findings} lint finding(s) in {image}")]
+    LintFailed {
+        /// The image that was checked
+        image: String,
+        /// Number of problems found
+        findings: usize,
+    },
+
+    /// The `--scan-cmd` vulnerability scanner refused the image
+    #[error("Scan of {image} failed ({status})")]
+    ScanRejected {
+        /// The image that was scanned
+        image: String,
+        /// How the scanner exited
+        status: String,
+    },
+
+    /// Signing keys or image signatures that could not be used or checked
+    #[error("Signature error: {0}")]
+    SignatureError(String),
+
+    /// Some items of a batch (the layers of a push) failed; the others were transferred
+    #[error(
+        "{} of {} blobs failed, first: {}",
+        .0.failed.len(),
+        .0.failed.len() + .0.succeeded.len(),
+        .0.failed.first().map(|f| f.error.as_str()).unwrap_or_default()
+    )]
+    BatchFailed(Box<BatchReport>),
+
+    /// Requests to a registry are held back after it failed repeatedly (see [`crate::circuit`])
+    #[error(
+        "{registry} keeps failing, requests are held back for another {}s",
+        .retry_after.as_secs().max(1)
+    )]
+    CircuitOpen {
+        /// The registry host
+        registry: String,
+        /// Time left until a request is let through again
+        retry_after: Duration,
+    },
+
+    /// The operation was cancelled through its cancellation token
+    #[error("Operation cancelled")]
+    Cancelled,
+}
+
+impl PusherError {
+    /// Creates a cache error with formatted message
+    pub fn cache_error(msg: impl std::fmt::Display) -> Self {
+        PusherError::CacheError(msg.to_string())
+    }
+
+    /// Creates a tar error with formatted message
+    pub fn tar_error(msg: impl std::fmt::Display) -> Self {
+        PusherError::TarError(msg.to_string())
+    }
+
+    /// Creates a push error with formatted message
+    pub fn push_error(msg: impl std::fmt::Display) -> Self {
+        PusherError::PushError(msg.to_string())
+    }
+
+    /// Creates a registry error wrapping an oci-client failure
+    ///
+    /// Structured errors the registry returned are appended to the message.
+    #[cfg(feature = "oci-client")]
+    pub fn registry(msg: impl std::fmt::Display, source: OciDistributionError) -> Self {
+        let errors = api_errors(&source);
+        let message = if errors.is_empty() {
+            msg.to_string()
+        } else {
+            format!("{}: {}", msg, join(&errors))
+        };
+        PusherError::Registry { message, source }
+    }
+
+    /// Creates a transport error wrapping a failed HTTP request
+    pub fn transport(msg: impl std::fmt::Display, source: TransportError) -> Self {
+        PusherError::Transport {
+            message: msg.to_string(),
+            source,
+        }
+    }
+
+    /// Creates an error for an unexpected response, parsing its error body
+    pub fn http(msg: impl std::fmt::Display, response: &TransportResponse) -> Self {
+        PusherError::Http {
+            message: msg.to_string(),
+            status: response.status,
+            errors: RegistryApiError::parse(&response.body),
+            retry_after: response
+                .headers
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+                .map(Duration::from_secs),
+        }
+    }
+
+    /// Returns the structured errors the registry reported, if any
+    pub fn api_errors(&self) -> Vec<RegistryApiError> {
+        match self {
+            PusherError::Http { errors, .. } => errors.clone(),
+            #[cfg(feature = "oci-client")]
+            PusherError::Registry { source, .. } => api_errors(source),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Checks whether the operation may succeed when simply tried again
+    ///
+    /// True for server errors (5xx), 408/429, timeouts, failed connects and
+    /// dropped connections. Client errors such as 403, 404 or a digest
+    /// mismatch will fail the same way again and are not retryable; neither is
+    /// 401, see [`PusherError::is_unauthorized`]. Neither are other transport
+    /// failures, such as TLS errors or invalid requests.
+    pub fn is_retryable(&self) -> bool {
+        if self.is_throttled() {
+            return true;
+        }
+        match self {
+            PusherError::Transport { source, .. } => source.is_retryable(),
+            PusherError::Http { status, .. } => is_retryable_status(status.as_u16()),
+            PusherError::IoError(e) => is_retryable_io(e),
+            #[cfg(feature = "oci-client")]
+            PusherError::Registry { source, .. } => match source {
+                OciDistributionError::ServerError { code, .. } => is_retryable_status(*code),
+                OciDistributionError::RequestError(e) => {
+                    TransportErrorKind::of(e) != TransportErrorKind::Other
+                }
+                OciDistributionError::IoError(e) => is_retryable_io(e),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Checks whether the registry asked us to slow down (429, or 503 which some
+    /// registries use for rate limiting)
+    pub fn is_throttled(&self) -> bool {
+        match self {
+            PusherError::Http { status, errors, .. } => {
+                is_throttle_status(status.as_u16())
+                    || errors.iter().any(|e| e.code == "TOOMANYREQUESTS")
+            }
+            #[cfg(feature = "oci-client")]
+            PusherError::Registry { source, .. } => match source {
+                OciDistributionError::ServerError { code, .. } => is_throttle_status(*code),
+                _ => api_errors(source)
+                    .iter()
+                    .any(|e| e.code == "TOOMANYREQUESTS"),
+            },
+            _ => false,
+        }
+    }
+
+    /// Returns how long the registry asked us to wait before retrying
+    ///
+    /// Only the delay-seconds form of `Retry-After` is understood; responses
+    /// received through oci-client don't expose their headers.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            PusherError::Http { retry_after, .. } => *retry_after,
+            PusherError::CircuitOpen { retry_after, .. } => Some(*retry_after),
+            _ => None,
+        }
+    }
+
+    /// Checks whether the registry rejected the credentials (HTTP 401)
+    ///
+    /// Usually means the bearer token expired; requesting a new one and
+    /// retrying is worth a try.
+    pub fn is_unauthorized(&self) -> bool {
+        match self {
+            PusherError::Http { status, .. } => *status == StatusCode::UNAUTHORIZED,
+            #[cfg(feature = "oci-client")]
+            PusherError::Registry { source, .. } => match source {
+                OciDistributionError::UnauthorizedError { .. } => true,
+                OciDistributionError::ServerError { code, .. } => *code == 401,
+                OciDistributionError::RegistryError { envelope, .. } => envelope
+                    .errors
+                    .iter()
+                    .any(|e| e.code == OciErrorCode::Unauthorized),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Returns true if the registry doesn't know the requested repository,
+    /// manifest or blob
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            PusherError::Http { status, .. } => *status == StatusCode::NOT_FOUND,
+            #[cfg(feature = "oci-client")]
+            PusherError::Registry { source, .. } => match source {
+                OciDistributionError::ImageManifestNotFoundError(_) => true,
+                OciDistributionError::ServerError { code, .. } => *code == 404,
+                OciDistributionError::RegistryError { envelope, .. } => {
+                    envelope.errors.iter().any(|e| {
+                        matches!(
+                            e.code,
+                            OciErrorCode::ManifestUnknown
+                                | OciErrorCode::NameUnknown
+                                | OciErrorCode::BlobUnknown
+                        )
+                    })
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Returns a short, stable category name for metrics and reporting
+    pub fn category(&self) -> &'static str {
+        match self {
+            PusherError::PullError(_) => "pull",
+            PusherError::PushError(_) => "push",
+            PusherError::CacheError(_) => "cache",
+            PusherError::IoError(_) => "io",
+            PusherError::SerdeError(_) => "serde",
+            PusherError::CacheNotFound => "cache_not_found",
+            PusherError::TarError(_) => "tar",
+            PusherError::LocalStoreError(_) => "local_store",
+            PusherError::ConfigError(_) => "config",
+            #[cfg(feature = "oci-client")]
+            PusherError::Registry { .. } => "registry",
+            PusherError::Transport { .. } => "transport",
+            PusherError::Http { .. } => "http",
+            PusherError::DigestMismatch { .. } => "digest_mismatch",
+            PusherError::InvalidDigest { .. } => "invalid_digest",
+            PusherError::InvalidManifest { .. } => "invalid_manifest",
+            PusherError::InsufficientDiskSpace { .. } => "disk_space",
+            PusherError::QuotaExceeded { .. } => "quota",
+            PusherError::LintFailed { .. } => "lint",
+            PusherError::ScanRejected { .. } => "scan",
+            PusherError::SignatureError(_) => "signature",
+            PusherError::BatchFailed(_) => "batch",
+            PusherError::CircuitOpen { .. } => "circuit_open",
+            PusherError::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// Server errors, request timeouts and rate limiting are worth retrying
+fn is_retryable_status(status: u16) -> bool {
+    status >= 500 || status == 408 || status == 429
+}
+
+/// Too Many Requests, and Service Unavailable which some registries use for rate limiting
+fn is_throttle_status(status: u16) -> bool {
+    status == 429 || status == 503
+}
+
+/// Connection-level I/O failures are worth retrying
+fn is_retryable_io(error: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        error.kind(),
+        ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::BrokenPipe
+            | ErrorKind::TimedOut
+            | ErrorKind::UnexpectedEof
+            | ErrorKind::Interrupted
+    )
+}
+
+/// Formats a status with the registry's explanation, e.g. `DENIED: quota exceeded (HTTP 403)`
+fn describe_status(status: StatusCode, errors: &[RegistryApiError]) -> String {
+    if errors.is_empty() {
+        format!("HTTP {}", status)
+    } else {
+        format!("{} (HTTP {})", join(errors), status.as_u16())
+    }
+}
+
+/// Joins several registry errors into one line
+fn join(errors: &[RegistryApiError]) -> String {
+    errors
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Extracts the structured errors from an oci-client failure
+///
+/// oci-client only parses the body of some responses; for the others it keeps
+/// the raw text in a `ServerError`, which is parsed here.
+#[cfg(feature = "oci-client")]
+fn api_errors(source: &OciDistributionError) -> Vec<RegistryApiError> {
+    let envelope = match source {
+        OciDistributionError::RegistryError { envelope, .. } => envelope,
+        OciDistributionError::ServerError { message, .. } => {
+            return RegistryApiError::parse(message.as_bytes());
+        }
+        _ => return Vec::new(),
+    };
+    envelope
+        .errors
+        .iter()
+        .map(|e| RegistryApiError {
+            code: serde_json::to_value(&e.code)
+                .ok()
+                .and_then(|code| code.as_str().map(str::to_string))
+                .unwrap_or_default(),
+            message: e.message.clone(),
+            detail: e.detail.clone(),
+        })
+        .collect()
+}
@@ -0,0 +1,418 @@
+//! Writing cached images as `docker save` archives or flattened filesystems
+//!
+//! The archive has the layout [`crate::import::import_tar_file`] reads, so it
+//! can be loaded with `docker load`, `ctr images import` or imported again by
+//! this tool. [`write_rootfs`] instead applies the layers on top of each other
+//! and writes the resulting filesystem as a single tar.
+
+use crate::image;
+use crate::store::FsBlobStore;
+use crate::PusherError;
+use flate2::read::GzDecoder;
+use oci_client::manifest::OciImageManifest;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use tar::{Archive, Builder, EntryType, Header};
+use tracing::info;
+
+const GZIP_MAGIC_BYTES: [u8; 2] = [0x1f, 0x8b];
+
+/// Prefix of the marker files deleting a path of a lower layer
+const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// Marker file hiding everything lower layers put in its directory
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// Writes a cached image as a `docker save` archive to `writer`
+///
+/// Layers are streamed from the cache one at a time, so memory use doesn't
+/// depend on the image size. Returns the writer once the archive is complete.
+///
+/// # Arguments
+///
+/// * `cache_dir` - Cache directory holding the image
+/// * `image_name` - Name the image was cached under
+/// * `repo_tag` - Tag recorded in the archive (what `docker load` names the image)
+/// * `writer` - Destination of the tar stream
+pub fn write_docker_archive<W: Write>(
+    cache_dir: &Path,
+    image_name: &str,
+    repo_tag: &str,
+    writer: W,
+) -> Result<W, PusherError> {
+    let image_cache_dir = cache_dir.join(image::sanitize_image_name(image_name));
+    let manifest_content = std::fs::read_to_string(image_cache_dir.join("manifest.json"))
+        .map_err(|_| PusherError::CacheNotFound)?;
+    let manifest: OciImageManifest = serde_json::from_str(&manifest_content)?;
+    let store = FsBlobStore::new(&image_cache_dir);
+
+    let mut archive = Builder::new(writer);
+    let config_digest = &manifest.config.digest;
+    let config_name = format!("{}.json", hex_of(config_digest));
+    let config_path =
+        image_cache_dir.join(format!("config_{}.json", image::digest::file_name(config_digest)));
+    let config_data = std::fs::read(&config_path)
+        .map_err(|e| PusherError::CacheError(format!("Failed to read cached config: {}", e)))?;
+    append_bytes(&mut archive, &config_name, &config_data)?;
+
+    let mut layer_names = Vec::with_capacity(manifest.layers.len());
+    for layer in &manifest.layers {
+        let name = format!("{}/layer.tar", hex_of(&layer.digest));
+        info!("📦 Exporting layer: {} ({:.1} MB)", layer.digest, layer.size as f64 / (1024.0 * 1024.0));
+        let mut file = File::open(store.path(&layer.digest)).map_err(|e| {
+            PusherError::CacheError(format!("Failed to open cached layer {}: {}", layer.digest, e))
+        })?;
+        archive
+            .append_file(&name, &mut file)
+            .map_err(|e| PusherError::TarError(format!("Failed to write layer {}: {}", name, e)))?;
+        layer_names.push(name);
+    }
+
+    let docker_manifest = serde_json::json!([{
+        "Config": config_name,
+        "RepoTags": [repo_tag],
+        "Layers": layer_names,
+    }]);
+    append_bytes(&mut archive, "manifest.json", &serde_json::to_vec(&docker_manifest)?)?;
+
+    archive
+        .into_inner()
+        .map_err(|e| PusherError::TarError(format!("Failed to finish tar archive: {}", e)))
+}
+
+/// Returns the hex part of a `sha256:<hex>` digest
+fn hex_of(digest: &str) -> &str {
+    digest.split_once(':').map_or(digest, |(_, hex)| hex)
+}
+
+/// Appends an in-memory file to the archive
+fn append_bytes<W: Write>(archive: &mut Builder<W>, name: &str, data: &[u8]) -> Result<(), PusherError> {
+    let mut header = Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(0);
+    header.set_cksum();
+    archive
+        .append_data(&mut header, name, data)
+        .map_err(|e| PusherError::TarError(format!("Failed to write {}: {}", name, e)))
+}
+
+/// Writes the filesystem of a cached image as a single tar to `writer`
+///
+/// Layers are applied in order the way a container runtime does: whiteout
+/// files (`.wh.<name>`) delete what lower layers put at `<name>`, opaque
+/// whiteouts (`.wh..wh..opq`) empty their directory, and a path replaced by
+/// a later layer is only written once, in its final version. Whiteout files
+/// themselves don't appear in the result. Each layer is read twice (once to
+/// find what survives, once to copy it), so memory use only grows with the
+/// number of paths, not the image size. Returns the writer once the archive
+/// is complete.
+pub fn write_rootfs<W: Write>(cache_dir: &Path, image_name: &str, writer: W) -> Result<W, PusherError> {
+    let image_cache_dir = cache_dir.join(image::sanitize_image_name(image_name));
+    let manifest_content = std::fs::read_to_string(image_cache_dir.join("manifest.json"))
+        .map_err(|_| PusherError::CacheNotFound)?;
+    let manifest: OciImageManifest = serde_json::from_str(&manifest_content)?;
+    let store = FsBlobStore::new(&image_cache_dir);
+    let layer_paths: Vec<_> = manifest.layers.iter().map(|layer| store.path(&layer.digest)).collect();
+
+    let surviving = surviving_paths(&layer_paths)?;
+    let mut archive = Builder::new(writer);
+    for (layer, keep) in layer_paths.iter().zip(&surviving) {
+        info!("📦 Flattening layer: {}", layer.display());
+        for_each_entry(layer, |path, entry| {
+            if keep.contains(path) {
+                copy_entry(&mut archive, path, entry)?;
+            }
+            Ok(())
+        })?;
+    }
+
+    archive
+        .into_inner()
+        .map_err(|e| PusherError::TarError(format!("Failed to finish tar archive: {}", e)))
+}
+
+/// Copies a file or directory tree out of a cached image into `output_dir`
+///
+/// `path_in_image` is looked up in the filesystem the layers add up to, so
+/// files deleted by a later layer aren't extracted and replaced files are
+/// extracted in their final version. The last component of the path is
+/// created in `output_dir` (extracting `/usr/bin/curl` to `out` writes
+/// `out/curl`, extracting `/etc/nginx` writes `out/nginx/...`). Symlinks are
+/// extracted as symlinks, not followed. File modes and modification times
+/// are kept; ownership isn't.
+///
+/// # Returns
+///
+/// `Result<usize, PusherError>` - Number of files, directories and links written
+pub fn extract_path(
+    cache_dir: &Path,
+    image_name: &str,
+    path_in_image: &str,
+    output_dir: &Path,
+) -> Result<usize, PusherError> {
+    let image_cache_dir = cache_dir.join(image::sanitize_image_name(image_name));
+    let manifest_content = std::fs::read_to_string(image_cache_dir.join("manifest.json"))
+        .map_err(|_| PusherError::CacheNotFound)?;
+    let manifest: OciImageManifest = serde_json::from_str(&manifest_content)?;
+    let store = FsBlobStore::new(&image_cache_dir);
+    let layer_paths: Vec<_> = manifest.layers.iter().map(|layer| store.path(&layer.digest)).collect();
+
+    let wanted = normalize(path_in_image);
+    // What comes before the last component isn't recreated in the output directory
+    let base = wanted.rsplit_once('/').map_or(String::new(), |(parent, _)| format!("{}/", parent));
+    let selected = |path: &str| {
+        wanted.is_empty()
+            || path == wanted
+            || path.strip_prefix(wanted.as_str()).is_some_and(|rest| rest.starts_with('/'))
+    };
+
+    let surviving = surviving_paths(&layer_paths)?;
+    std::fs::create_dir_all(output_dir)?;
+    let mut written = 0;
+    for (layer, keep) in layer_paths.iter().zip(&surviving) {
+        for_each_entry(layer, |path, entry| {
+            if !keep.contains(path) || !selected(path) {
+                return Ok(());
+            }
+            let relative = path.strip_prefix(base.as_str()).unwrap_or(path);
+            if relative.split('/').any(|part| part == "..") {
+                return Err(PusherError::TarError(format!(
+                    "Refusing to extract {}: path escapes the output directory",
+                    path
+                )));
+            }
+            extract_entry(entry, output_dir, relative, &base)?;
+            written += 1;
+            Ok(())
+        })?;
+    }
+    if written == 0 {
+        return Err(PusherError::ConfigError(format!("{} not found in {}", path_in_image, image_name)));
+    }
+    Ok(written)
+}
+
+/// Writes one layer entry to `output_dir/relative`
+///
+/// Hard links point at paths in the image, so their target is looked up in
+/// the extracted tree (with `base` stripped, like `relative` was).
+///
+/// Nothing is written through a symlink: an earlier entry may have put one
+/// (`a -> /etc`) where a later one expects a directory (`a/passwd`), and
+/// following it would write outside `output_dir`.
+fn extract_entry<R: Read>(
+    entry: &mut tar::Entry<R>,
+    output_dir: &Path,
+    relative: &str,
+    base: &str,
+) -> Result<(), PusherError> {
+    let destination = output_dir.join(platform_path(relative));
+    let extract_error =
+        |e: std::io::Error| PusherError::TarError(format!("Failed to extract {}: {}", destination.display(), e));
+    create_parents(output_dir, relative)?;
+    // A later layer replaces what an earlier one wrote (a symlink is replaced, not followed)
+    if std::fs::symlink_metadata(&destination).is_ok_and(|meta| !meta.is_dir()) {
+        std::fs::remove_file(&destination).map_err(extract_error)?;
+    }
+    let entry_type = entry.header().entry_type();
+    if entry_type == EntryType::Directory {
+        // Modes of directories are left alone so read-only ones can still be filled
+        return match std::fs::create_dir(&destination) {
+            Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => Err(extract_error(e)),
+            _ => Ok(()),
+        };
+    }
+    if entry_type == EntryType::Link {
+        let target = entry.link_name().map_err(extract_error)?.unwrap_or_default();
+        let target = normalize(&target.to_string_lossy());
+        let Some(target) = target
+            .strip_prefix(base)
+            .filter(|target| !target.split('/').any(|part| part == ".."))
+            .filter(|target| {
+                // The target must be a file already extracted, reached without symlinks
+                check_no_symlinks(output_dir, target).is_ok()
+                    && std::fs::symlink_metadata(output_dir.join(platform_path(target)))
+                        .is_ok_and(|meta| meta.is_file())
+            })
+        else {
+            return Err(PusherError::TarError(format!(
+                "Cannot extract {}: it is a hard link to {}, which is outside the extracted path",
+                relative, target
+            )));
+        };
+        return std::fs::hard_link(output_dir.join(platform_path(target)), &destination).map_err(extract_error);
+    }
+    entry.unpack(&destination).map_err(extract_error)?;
+    Ok(())
+}
+
+/// Creates the parent directories of `output_dir/relative` one by one, refusing to go through symlinks
+fn create_parents(output_dir: &Path, relative: &str) -> Result<(), PusherError> {
+    let Some((parents, _)) = relative.rsplit_once('/') else {
+        return Ok(());
+    };
+    let mut dir = output_dir.to_path_buf();
+    for part in parents.split('/') {
+        dir.push(crate::image::platform_file_name(part));
+        let result = match std::fs::symlink_metadata(&dir) {
+            Ok(meta) if meta.file_type().is_symlink() => return Err(through_symlink(relative, &dir)),
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => std::fs::create_dir(&dir),
+            Err(e) => Err(e),
+        };
+        result.map_err(|e| PusherError::TarError(format!("Failed to create {}: {}", dir.display(), e)))?;
+    }
+    Ok(())
+}
+
+/// Fails if a parent directory of `output_dir/relative` is a symlink
+fn check_no_symlinks(output_dir: &Path, relative: &str) -> Result<(), PusherError> {
+    let Some((parents, _)) = relative.rsplit_once('/') else {
+        return Ok(());
+    };
+    let mut dir = output_dir.to_path_buf();
+    for part in parents.split('/') {
+        dir.push(crate::image::platform_file_name(part));
+        if std::fs::symlink_metadata(&dir).is_ok_and(|meta| meta.file_type().is_symlink()) {
+            return Err(through_symlink(relative, &dir));
+        }
+    }
+    Ok(())
+}
+
+fn through_symlink(relative: &str, link: &Path) -> PusherError {
+    PusherError::TarError(format!(
+        "Refusing to extract {}: {} is a symlink, writing through it could escape the output directory",
+        relative,
+        link.display()
+    ))
+}
+
+/// `relative` (an extracted path, `/`-separated) as a path valid on this platform
+///
+/// On Windows, names it doesn't allow (`aux`, `a:b`, trailing dots) are
+/// renamed as by [`crate::image::platform_file_name`]; this also keeps `\`,
+/// `:` and drive prefixes in layer paths from escaping the output directory.
+fn platform_path(relative: &str) -> std::path::PathBuf {
+    relative
+        .split('/')
+        .map(crate::image::platform_file_name)
+        .collect()
+}
+
+/// Works out, for each layer, which of its paths are still visible once all layers are applied
+///
+/// Walks the layers from the top down. A path is hidden if a higher layer
+/// has it too, whited it out, or whited out, made opaque or replaced with a
+/// non-directory any of its parent directories.
+fn surviving_paths(layers: &[std::path::PathBuf]) -> Result<Vec<HashSet<String>>, PusherError> {
+    // Paths higher layers have claimed (they provide or delete them)
+    let mut taken: HashSet<String> = HashSet::new();
+    // Paths whose contents in lower layers are hidden
+    let mut covered: HashSet<String> = HashSet::new();
+    let mut surviving = vec![HashSet::new(); layers.len()];
+
+    for (index, layer) in layers.iter().enumerate().rev() {
+        let (mut layer_taken, mut layer_covered) = (Vec::new(), Vec::new());
+        let keep = &mut surviving[index];
+        for_each_entry(layer, |path, entry| {
+            let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+            if name == OPAQUE_WHITEOUT {
+                layer_covered.push(parent.to_string());
+                return Ok(());
+            }
+            if let Some(deleted) = name.strip_prefix(WHITEOUT_PREFIX) {
+                let deleted = join(parent, deleted);
+                layer_taken.push(deleted.clone());
+                layer_covered.push(deleted);
+                return Ok(());
+            }
+            if !taken.contains(path) && !ancestors(path).any(|dir| covered.contains(dir)) {
+                keep.insert(path.to_string());
+            }
+            layer_taken.push(path.to_string());
+            if entry.header().entry_type() != EntryType::Directory {
+                layer_covered.push(path.to_string());
+            }
+            Ok(())
+        })?;
+        // A layer's whiteouts only apply to the layers below it
+        taken.extend(layer_taken);
+        covered.extend(layer_covered);
+    }
+    Ok(surviving)
+}
+
+/// Calls `visit` with the normalized path (no leading `./` or `/`, no trailing `/`) of every entry of a layer
+fn for_each_entry<F>(layer: &Path, mut visit: F) -> Result<(), PusherError>
+where
+    F: FnMut(&str, &mut tar::Entry<Box<dyn Read>>) -> Result<(), PusherError>,
+{
+    let file = File::open(layer).map_err(|e| {
+        PusherError::CacheError(format!("Failed to open cached layer {}: {}", layer.display(), e))
+    })?;
+    let mut reader = BufReader::new(file);
+    let tar: Box<dyn Read> = if reader.fill_buf()?.starts_with(&GZIP_MAGIC_BYTES) {
+        Box::new(GzDecoder::new(reader))
+    } else {
+        Box::new(reader)
+    };
+    let read_error =
+        |e: std::io::Error| PusherError::TarError(format!("Failed to read layer {}: {}", layer.display(), e));
+
+    let mut archive = Archive::new(tar);
+    for entry in archive.entries().map_err(read_error)? {
+        let mut entry = entry.map_err(read_error)?;
+        let path = entry.path().map_err(read_error)?.to_string_lossy().into_owned();
+        let path = normalize(&path);
+        if !path.is_empty() {
+            visit(&path, &mut entry)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies a layer entry, with its metadata and (for files) contents, into the archive
+fn copy_entry<W: Write, R: Read>(
+    archive: &mut Builder<W>,
+    path: &str,
+    entry: &mut tar::Entry<R>,
+) -> Result<(), PusherError> {
+    let write_error = |e: std::io::Error| PusherError::TarError(format!("Failed to write {}: {}", path, e));
+    let mut header = entry.header().clone();
+    match entry.link_name().map_err(write_error)? {
+        Some(target) => {
+            let target = target.into_owned();
+            archive.append_link(&mut header, path, target).map_err(write_error)
+        }
+        None => archive.append_data(&mut header, path, entry).map_err(write_error),
+    }
+}
+
+/// Strips `./`, leading and trailing slashes from a tar entry path
+fn normalize(path: &str) -> String {
+    path.split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Joins a directory (empty for the root) and a name
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+/// Parent directories of a normalized path, nearest first (the root is `""`)
+fn ancestors(path: &str) -> impl Iterator<Item = &str> {
+    path.match_indices('/')
+        .map(|(index, _)| &path[..index])
+        .rev()
+        .chain(std::iter::once(""))
+}
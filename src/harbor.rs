@@ -0,0 +1,234 @@
+//! Harbor project management
+//!
+//! Harbor rejects pushes to a project that doesn't exist. With
+//! [`crate::push::PushOptions::create_harbor_project`] set, the push checks
+//! the target project through Harbor's REST API (`/api/v2.0/projects`) before
+//! uploading anything and creates it when it is missing, so new namespaces
+//! can be onboarded in bulk without preparing each project by hand.
+//!
+//! With [`crate::push::PushOptions::harbor_quota`] set, the push also reads
+//! the project's storage quota once it knows which layers are missing and
+//! warns or fails when they won't fit, instead of being denied with
+//! `quota exceeded` after most of the upload.
+//!
+//! The REST API only accepts basic credentials, not the registry's bearer
+//! tokens, so requests go through their own [`ReqwestTransport`].
+
+use crate::PusherError;
+use crate::credentials::RegistryAuth;
+use crate::transport::{RegistryTransport, ReqwestTransport, TransportRequest};
+use oci_spec::distribution::Reference;
+use reqwest::StatusCode;
+use tracing::{debug, info, warn};
+
+/// Settings for projects created on demand
+#[derive(Debug, Clone, Default)]
+pub struct ProjectSettings {
+    /// Allow anonymous pulls from the new project
+    pub public: bool,
+    /// Storage quota in bytes (unlimited if not set)
+    pub storage_limit: Option<u64>,
+}
+
+/// What a push does when the layers it is about to upload exceed the project's remaining quota
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum QuotaCheck {
+    /// Log a warning and push anyway
+    Warn,
+    /// Fail before uploading anything
+    Fail,
+}
+
+/// Storage quota of a Harbor project
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProjectQuota {
+    /// Quota in bytes, `None` when unlimited
+    pub limit: Option<u64>,
+    /// Bytes the project already uses
+    pub used: u64,
+}
+
+impl ProjectQuota {
+    /// Bytes that can still be pushed, `None` when unlimited
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use docker_image_pusher::harbor::ProjectQuota;
+    /// let quota = ProjectQuota { limit: Some(100), used: 30 };
+    /// assert_eq!(quota.remaining(), Some(70));
+    /// assert_eq!(ProjectQuota { limit: None, used: 30 }.remaining(), None);
+    /// ```
+    pub fn remaining(&self) -> Option<u64> {
+        self.limit.map(|limit| limit.saturating_sub(self.used))
+    }
+}
+
+/// Returns the Harbor project of `reference`, the first component of its repository
+pub fn project_name(reference: &Reference) -> &str {
+    let repository = reference.repository();
+    repository.split('/').next().unwrap_or(repository)
+}
+
+/// Creates the Harbor project `reference` will be pushed to unless it exists
+///
+/// Returns whether the project was created. A registry that doesn't answer
+/// like Harbor, or credentials that may push but not manage projects, only
+/// produce a warning, leaving it to the push to report a missing project.
+pub async fn ensure_project(
+    reference: &Reference,
+    auth: &RegistryAuth,
+    settings: &ProjectSettings,
+) -> Result<bool, PusherError> {
+    let transport = ReqwestTransport::new(None, auth);
+    let base_url = format!(
+        "{}/api/v2.0/projects",
+        crate::registry::base_url(reference.resolve_registry())
+    );
+    let project = project_name(reference);
+
+    let what = format!("Failed to check Harbor project {}", project);
+    let response = transport
+        .send(TransportRequest::head(format!("{}?project_name={}", base_url, project)))
+        .await
+        .map_err(|e| PusherError::transport(&what, e))?;
+    debug!(project, status = %response.status, "Harbor project check");
+    match response.status {
+        StatusCode::OK => return Ok(false),
+        StatusCode::NOT_FOUND => {}
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            warn!(
+                "⚠️  Not allowed to look up Harbor project {} (HTTP {}), pushing without creating it",
+                project, response.status
+            );
+            return Ok(false);
+        }
+        status => {
+            warn!(
+                "⚠️  {} does not look like Harbor (project lookup returned HTTP {}), not creating {}",
+                reference.resolve_registry(),
+                status,
+                project
+            );
+            return Ok(false);
+        }
+    }
+
+    info!("🏗️  Creating Harbor project {}...", project);
+    let body = serde_json::json!({
+        "project_name": project,
+        "metadata": { "public": settings.public.to_string() },
+        "storage_limit": settings.storage_limit.map_or(-1, |limit| limit as i64),
+    });
+    let what = format!("Failed to create Harbor project {}", project);
+    let response = transport
+        .send(
+            TransportRequest::post(&base_url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_vec(&body)?),
+        )
+        .await
+        .map_err(|e| PusherError::transport(&what, e))?;
+    match response.status {
+        StatusCode::CREATED => {
+            info!("✅ Created Harbor project {}", project);
+            Ok(true)
+        }
+        // Created concurrently, e.g. by another push in the same batch
+        StatusCode::CONFLICT => Ok(false),
+        _ => Err(PusherError::http(what, &response)),
+    }
+}
+
+/// Reads the storage quota of the Harbor project `reference` will be pushed to
+///
+/// Uses the project summary (`/api/v2.0/projects/<name>/summary`). Returns
+/// `None` when the registry doesn't answer like Harbor or the credentials
+/// may not read the project.
+pub async fn project_quota(
+    reference: &Reference,
+    auth: &RegistryAuth,
+) -> Result<Option<ProjectQuota>, PusherError> {
+    let transport = ReqwestTransport::new(None, auth);
+    let project = project_name(reference);
+    let url = format!(
+        "{}/api/v2.0/projects/{}/summary",
+        crate::registry::base_url(reference.resolve_registry()),
+        project
+    );
+    let what = format!("Failed to read the quota of Harbor project {}", project);
+    let response = transport
+        .send(TransportRequest::get(url).header(reqwest::header::HeaderName::from_static("x-is-resource-name"), "true"))
+        .await
+        .map_err(|e| PusherError::transport(&what, e))?;
+    debug!(project, status = %response.status, "Harbor quota check");
+    if response.status != StatusCode::OK {
+        return Ok(None);
+    }
+    let Ok(summary) = serde_json::from_slice::<serde_json::Value>(&response.body) else {
+        return Ok(None);
+    };
+    let quota = &summary["quota"];
+    let (Some(hard), Some(used)) = (
+        quota["hard"]["storage"].as_i64(),
+        quota["used"]["storage"].as_i64(),
+    ) else {
+        return Ok(None);
+    };
+    Ok(Some(ProjectQuota {
+        // Harbor reports unlimited quotas as -1
+        limit: u64::try_from(hard).ok(),
+        used: used.max(0) as u64,
+    }))
+}
+
+/// Checks that `required` more bytes fit into the quota of `reference`'s Harbor project
+///
+/// Quotas that can't be read pass with a debug message; an exceeded quota
+/// is logged or, with [`QuotaCheck::Fail`], returned as
+/// [`PusherError::QuotaExceeded`].
+pub async fn check_quota(
+    reference: &Reference,
+    auth: &RegistryAuth,
+    required: u64,
+    policy: QuotaCheck,
+) -> Result<(), PusherError> {
+    let project = project_name(reference);
+    let quota = match project_quota(reference, auth).await {
+        Ok(Some(quota)) => quota,
+        Ok(None) => {
+            debug!(project, "Harbor project quota unavailable, skipping check");
+            return Ok(());
+        }
+        Err(e) => {
+            debug!(project, error = %e, "Harbor project quota unavailable, skipping check");
+            return Ok(());
+        }
+    };
+    let Some(available) = quota.remaining() else {
+        debug!(project, "Harbor project quota is unlimited");
+        return Ok(());
+    };
+    if required <= available {
+        info!(
+            "📊 Harbor project {} has {:.1} MB of quota left for {:.1} MB of layers",
+            project,
+            available as f64 / (1024.0 * 1024.0),
+            required as f64 / (1024.0 * 1024.0)
+        );
+        return Ok(());
+    }
+    let error = PusherError::QuotaExceeded {
+        project: project.to_string(),
+        required,
+        available,
+    };
+    match policy {
+        QuotaCheck::Fail => Err(error),
+        QuotaCheck::Warn => {
+            warn!("⚠️  {}, pushing anyway", error);
+            Ok(())
+        }
+    }
+}
@@ -1,3 +1,11 @@
+pub mod digest;
+
+use crate::PusherError;
+#[cfg(feature = "oci-client")]
+use oci_client::manifest::{
+    IMAGE_MANIFEST_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE, OciDescriptor, OciImageManifest,
+};
+
 /// Sanitizes image names for use as directory names
 ///
 /// Docker image names can contain characters that are not valid in file paths.
@@ -10,9 +18,14 @@
 /// - `:` → `_` (tag separators)
 /// - `@` → `_` (digest separators)
 ///
+/// On Windows the result is also made a valid file name there (see
+/// [`windows_file_name`]). Names longer than filesystems allow are cut and
+/// end in a hash of the full name instead.
+///
 /// # Examples
 ///
 /// ```
+/// # use docker_image_pusher::image::sanitize_image_name;
 /// assert_eq!(sanitize_image_name("nginx:latest"), "nginx_latest");
 /// assert_eq!(sanitize_image_name("registry.example.com/app:v1.0"), "registry.example.com_app_v1.0");
 /// ```
@@ -25,8 +38,330 @@
 ///
 /// `String` - Sanitized name safe for use as directory name
 pub fn sanitize_image_name(image_name: &str) -> String {
-    image_name
+    let name = image_name
         .replace("/", "_") // Replace registry/namespace separators
         .replace(":", "_") // Replace tag separators
-        .replace("@", "_") // Replace digest separators
+        .replace("@", "_"); // Replace digest separators
+    let name = platform_file_name(&name);
+    if name.len() <= MAX_FILE_NAME_LEN {
+        return name;
+    }
+    let mut hasher = digest::Hasher::new(digest::Algorithm::Sha256);
+    hasher.update(image_name.as_bytes());
+    let hash = hasher.finalize();
+    let mut end = MAX_FILE_NAME_LEN - 17;
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}_{}", &name[..end], &hash.hex()[..16])
+}
+
+/// Longest file name (in bytes) most filesystems accept
+const MAX_FILE_NAME_LEN: usize = 255;
+
+/// Names Windows reserves for devices, with or without an extension
+const WINDOWS_DEVICE_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Makes `name` a valid file name on Windows
+///
+/// Replaces the characters Windows doesn't allow in file names (`<>:"/\|?*`
+/// and control characters) with `_`, appends `_` to device names such as
+/// `NUL` or `com1.txt`, and replaces the trailing dots and spaces Windows
+/// would drop with `_`.
+///
+/// # Examples
+///
+/// ```
+/// # use docker_image_pusher::image::windows_file_name;
+/// assert_eq!(windows_file_name("nginx_1.27"), "nginx_1.27");
+/// assert_eq!(windows_file_name("a:b\\c"), "a_b_c");
+/// assert_eq!(windows_file_name("nul.example.com_app"), "nul_.example.com_app");
+/// assert_eq!(windows_file_name("app_v1."), "app_v1_");
+/// ```
+pub fn windows_file_name(name: &str) -> String {
+    let mut safe: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let kept = safe.trim_end_matches(['.', ' ']).len();
+    let dropped = safe.len() - kept;
+    safe.truncate(kept);
+    safe.push_str(&"_".repeat(dropped));
+    let stem_len = safe.find('.').unwrap_or(safe.len());
+    if WINDOWS_DEVICE_NAMES
+        .iter()
+        .any(|device| device.eq_ignore_ascii_case(&safe[..stem_len]))
+    {
+        safe.insert(stem_len, '_');
+    }
+    safe
+}
+
+/// Makes `name` a valid file name on this platform: [`windows_file_name`] on
+/// Windows, unchanged elsewhere
+pub fn platform_file_name(name: &str) -> String {
+    if cfg!(windows) {
+        windows_file_name(name)
+    } else {
+        name.to_string()
+    }
+}
+
+/// Image config label Quay reads to expire a tag some time after it was pushed
+pub const QUAY_EXPIRES_AFTER_LABEL: &str = "quay.expires-after";
+
+/// Validates a Quay tag expiration: a number followed by `s`, `m`, `h`, `d` or `w`
+///
+/// # Examples
+///
+/// ```
+/// # use docker_image_pusher::image::parse_expires_after;
+/// assert_eq!(parse_expires_after("2w").unwrap(), "2w");
+/// assert!(parse_expires_after("2 weeks").is_err());
+/// ```
+pub fn parse_expires_after(value: &str) -> Result<String, String> {
+    let (number, unit) = value.split_at(value.len().saturating_sub(1));
+    if !number.is_empty()
+        && number.bytes().all(|b| b.is_ascii_digit())
+        && matches!(unit, "s" | "m" | "h" | "d" | "w")
+    {
+        Ok(value.to_string())
+    } else {
+        Err(format!(
+            "invalid expiration '{}', expected a number followed by s, m, h, d or w (e.g. \"2w\")",
+            value
+        ))
+    }
+}
+
+/// Parses a `key=value` annotation from the command line
+///
+/// # Examples
+///
+/// ```
+/// # use docker_image_pusher::image::parse_annotation;
+/// assert_eq!(
+///     parse_annotation("org.opencontainers.image.revision=3f2a9c1").unwrap(),
+///     ("org.opencontainers.image.revision".to_string(), "3f2a9c1".to_string())
+/// );
+/// assert!(parse_annotation("no-value").is_err());
+/// ```
+pub fn parse_annotation(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.to_string()))
+        }
+        _ => Err(format!(
+            "invalid annotation '{}', expected KEY=VALUE (e.g. \"org.opencontainers.image.source=https://github.com/org/app\")",
+            value
+        )),
+    }
+}
+
+/// Parses an image name from the command line, validating a digest after `@`
+///
+/// # Examples
+///
+/// ```
+/// # use docker_image_pusher::image::parse_image_name;
+/// assert!(parse_image_name("nginx:1.27").is_ok());
+/// assert!(parse_image_name("nginx@sha256:0123").is_err());
+/// ```
+pub fn parse_image_name(value: &str) -> Result<String, String> {
+    if let Some((_, digest)) = value.rsplit_once('@') {
+        digest::validate(digest, value).map_err(|e| e.to_string())?;
+    }
+    Ok(value.to_string())
+}
+
+/// Adds (or replaces) a label in a serialized image config
+///
+/// Labels live under `config.Labels`; both objects are created if missing.
+/// The result is a new config with a different digest.
+pub fn set_config_label(config: &[u8], key: &str, value: &str) -> Result<Vec<u8>, PusherError> {
+    let mut config: serde_json::Value = serde_json::from_slice(config)?;
+    let labels = config
+        .as_object_mut()
+        .ok_or_else(|| PusherError::CacheError("Image config is not a JSON object".to_string()))?
+        .entry("config")
+        .or_insert_with(|| serde_json::json!({}))
+        .as_object_mut()
+        .ok_or_else(|| PusherError::CacheError("Invalid config section in image config".to_string()))?
+        .entry("Labels")
+        .or_insert_with(|| serde_json::json!({}));
+    // A config without labels carries `"Labels": null`
+    if !labels.is_object() {
+        *labels = serde_json::json!({});
+    }
+    labels[key] = serde_json::Value::String(value.to_string());
+    Ok(serde_json::to_vec(&config)?)
+}
+
+/// Checks that an image manifest is well formed before it is cached or pushed
+///
+/// Covers what registries reject with an unhelpful 400: a schema version
+/// other than 2, an unknown manifest media type, descriptors without a media
+/// type, digests that aren't `sha256:`/`sha512:` followed by the right number
+/// of lowercase hex digits, negative sizes and Docker manifests without
+/// layers. All problems are reported at once, each naming the field.
+///
+/// # Examples
+///
+/// ```
+/// # use docker_image_pusher::image::validate_manifest;
+/// let mut manifest: oci_client::manifest::OciImageManifest = serde_json::from_str(r#"{
+///     "schemaVersion": 2,
+///     "config": {
+///         "mediaType": "application/vnd.oci.image.config.v1+json",
+///         "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+///         "size": 2
+///     },
+///     "layers": []
+/// }"#).unwrap();
+/// assert!(validate_manifest(&manifest, "Example").is_ok());
+///
+/// manifest.config.digest = "sha256:abc".to_string();
+/// assert!(validate_manifest(&manifest, "Example").is_err());
+/// ```
+#[cfg(feature = "oci-client")]
+pub fn validate_manifest(manifest: &OciImageManifest, what: &str) -> Result<(), PusherError> {
+    let mut problems = Vec::new();
+    if manifest.schema_version != 2 {
+        problems.push(format!("schemaVersion is {}, expected 2", manifest.schema_version));
+    }
+    let docker = manifest.media_type.as_deref() == Some(IMAGE_MANIFEST_MEDIA_TYPE);
+    if let Some(other) = manifest.media_type.as_deref()
+        && !docker
+        && other != OCI_IMAGE_MEDIA_TYPE
+    {
+        problems.push(format!("mediaType {} is not an image manifest type", other));
+    }
+    check_descriptor(&manifest.config, "config", &mut problems);
+    for (i, layer) in manifest.layers.iter().enumerate() {
+        check_descriptor(layer, &format!("layers[{}]", i), &mut problems);
+    }
+    if docker && manifest.layers.is_empty() {
+        problems.push("layers is empty, Docker image manifests need at least one layer".to_string());
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(PusherError::InvalidManifest {
+            what: what.to_string(),
+            problems,
+        })
+    }
+}
+
+/// Adds the problems of the descriptor at `field` to `problems`
+#[cfg(feature = "oci-client")]
+fn check_descriptor(descriptor: &OciDescriptor, field: &str, problems: &mut Vec<String>) {
+    if descriptor.media_type.is_empty() {
+        problems.push(format!("{}.mediaType is missing", field));
+    }
+    if let Err(problem) = check_digest(&descriptor.digest) {
+        problems.push(format!("{}.digest {}", field, problem));
+    }
+    if descriptor.size < 0 {
+        problems.push(format!("{}.size is negative ({})", field, descriptor.size));
+    }
+}
+
+/// Checks that `digest` is `sha256:` or `sha512:` followed by lowercase hex of the right length
+#[cfg(feature = "oci-client")]
+fn check_digest(digest: &str) -> Result<(), String> {
+    digest.parse::<digest::Digest>().map(|_| ())
+}
+
+/// One step of an image's build history and the layer it produced
+#[cfg(feature = "oci-client")]
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    /// Command that created the step, as recorded by the builder
+    pub created_by: String,
+    /// When the step was created (RFC 3339), if recorded
+    pub created: Option<String>,
+    /// Builder comment, if any
+    pub comment: Option<String>,
+    /// Layer the step produced; `None` for steps that only change metadata (`ENV`, `CMD`, ...)
+    pub layer: Option<OciDescriptor>,
+}
+
+#[cfg(feature = "oci-client")]
+impl HistoryEntry {
+    /// The step as a Dockerfile-style instruction
+    ///
+    /// Strips the `/bin/sh -c #(nop)` wrapper of metadata steps and shows
+    /// shell commands as `RUN`; BuildKit records instructions as is.
+    pub fn instruction(&self) -> String {
+        let command = self.created_by.trim();
+        if let Some(rest) = command.strip_prefix("/bin/sh -c #(nop)") {
+            rest.trim().to_string()
+        } else if let Some(rest) = command.strip_prefix("/bin/sh -c ") {
+            format!("RUN {}", rest.trim())
+        } else {
+            command.to_string()
+        }
+    }
+}
+
+/// Pairs the `history` of an image config with the layers of its manifest
+///
+/// History entries marked `empty_layer` get no layer; every other entry gets
+/// the next layer in order. Layers left over (a config without history, or
+/// with history that was rewritten) are listed with an empty `created_by`.
+///
+/// # Examples
+///
+/// ```
+/// # use docker_image_pusher::image::layer_history;
+/// let config = br#"{"history": [
+///     {"created_by": "/bin/sh -c #(nop) ADD file:abc in / "},
+///     {"created_by": "/bin/sh -c #(nop)  CMD [\"sh\"]", "empty_layer": true}
+/// ]}"#;
+/// let layer: oci_client::manifest::OciDescriptor = serde_json::from_str(r#"{
+///     "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+///     "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+///     "size": 3
+/// }"#).unwrap();
+/// let history = layer_history(config, &[layer]).unwrap();
+/// assert_eq!(history[0].instruction(), "ADD file:abc in /");
+/// assert_eq!(history[0].layer.as_ref().map(|l| l.size), Some(3));
+/// assert_eq!(history[1].instruction(), "CMD [\"sh\"]");
+/// assert!(history[1].layer.is_none());
+/// ```
+#[cfg(feature = "oci-client")]
+pub fn layer_history(
+    config: &[u8],
+    layers: &[OciDescriptor],
+) -> Result<Vec<HistoryEntry>, PusherError> {
+    let config: serde_json::Value = serde_json::from_slice(config)?;
+    let text = |value: &serde_json::Value| value.as_str().map(str::to_string);
+    let mut layers = layers.iter();
+    let mut entries = Vec::new();
+
+    for step in config["history"].as_array().into_iter().flatten() {
+        let empty = step["empty_layer"].as_bool().unwrap_or(false);
+        entries.push(HistoryEntry {
+            created_by: text(&step["created_by"]).unwrap_or_default(),
+            created: text(&step["created"]),
+            comment: text(&step["comment"]),
+            layer: if empty { None } else { layers.next().cloned() },
+        });
+    }
+    entries.extend(layers.map(|layer| HistoryEntry {
+        created_by: String::new(),
+        created: None,
+        comment: None,
+        layer: Some(layer.clone()),
+    }));
+    Ok(entries)
 }
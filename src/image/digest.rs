@@ -0,0 +1,241 @@
+//! Content digests (`<algorithm>:<hex>`) of blobs and manifests
+//!
+//! Most images use `sha256`, but the OCI spec also registers `sha512`, and
+//! digests come from manifests the tool doesn't control. [`Digest`] parses
+//! and validates them, [`Hasher`] computes them with the algorithm a
+//! descriptor asks for, and [`file_name`] turns any digest string into a
+//! cache file name that can't escape the cache directory.
+
+use crate::error::PusherError;
+use sha2::{Sha256, Sha512};
+use std::fmt;
+use std::str::FromStr;
+
+/// Hash algorithm of a digest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Algorithm {
+    /// SHA-256, 64 hex digits
+    #[default]
+    Sha256,
+    /// SHA-512, 128 hex digits
+    Sha512,
+}
+
+impl Algorithm {
+    /// Returns the algorithm named `name`, as used in digest prefixes
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sha256" => Some(Self::Sha256),
+            "sha512" => Some(Self::Sha512),
+            _ => None,
+        }
+    }
+
+    /// Returns the algorithm of `digest`, if it is prefixed with a known one
+    pub fn of(digest: &str) -> Option<Self> {
+        digest
+            .split_once(':')
+            .and_then(|(name, _)| Self::from_name(name))
+    }
+
+    /// Name used in digest prefixes
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+        }
+    }
+
+    /// Number of hex digits of a digest with this algorithm
+    pub fn hex_len(&self) -> usize {
+        match self {
+            Self::Sha256 => 64,
+            Self::Sha512 => 128,
+        }
+    }
+}
+
+/// A validated `<algorithm>:<hex>` digest
+///
+/// # Examples
+///
+/// ```
+/// # use docker_image_pusher::image::digest::{Algorithm, Digest};
+/// let digest: Digest = "sha512:cf83e1357eefb8bdf1542850d66d8007d620e4050b5715dc83f4a921d36ce9ce47d0d13c5d85f2b0ff8318d2877eec2f63b931bd47417a81a538327af927da3e"
+///     .parse()
+///     .unwrap();
+/// assert_eq!(digest.algorithm(), Algorithm::Sha512);
+/// assert_eq!(digest, Digest::compute(Algorithm::Sha512, b""));
+/// assert_eq!(digest.short(), "sha512:cf83e1357eef");
+/// assert!(digest.file_name().starts_with("sha512_cf83"));
+///
+/// assert!("sha256:abc".parse::<Digest>().is_err());
+/// assert!("md5:d41d8cd98f00b204e9800998ecf8427e".parse::<Digest>().is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Digest {
+    algorithm: Algorithm,
+    hex: String,
+}
+
+impl Digest {
+    /// Hashes `data` with `algorithm`
+    pub fn compute(algorithm: Algorithm, data: &[u8]) -> Self {
+        let mut hasher = Hasher::new(algorithm);
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    /// Algorithm of the digest
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Hex part of the digest
+    pub fn hex(&self) -> &str {
+        &self.hex
+    }
+
+    /// Name of the cache file holding the blob, `<algorithm>_<hex>`
+    pub fn file_name(&self) -> String {
+        format!("{}_{}", self.algorithm.name(), self.hex)
+    }
+
+    /// The algorithm and the first 12 hex digits, for display
+    pub fn short(&self) -> String {
+        format!("{}:{}", self.algorithm.name(), &self.hex[..12])
+    }
+}
+
+impl FromStr for Digest {
+    type Err = String;
+
+    fn from_str(digest: &str) -> Result<Self, Self::Err> {
+        let Some((name, hex)) = digest.split_once(':') else {
+            return Err(format!(
+                "'{}' has no algorithm prefix (expected sha256:<hex>)",
+                digest
+            ));
+        };
+        let algorithm = Algorithm::from_name(name)
+            .ok_or_else(|| format!("'{}' uses unsupported algorithm {}", digest, name))?;
+        let length = algorithm.hex_len();
+        if hex.len() != length || !hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+            return Err(format!(
+                "'{}' is not {} lowercase hex digits",
+                digest, length
+            ));
+        }
+        Ok(Self {
+            algorithm,
+            hex: hex.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm.name(), self.hex)
+    }
+}
+
+/// Incrementally computes a [`Digest`]; also usable as an [`std::io::Write`] sink
+pub struct Hasher(HasherState);
+
+enum HasherState {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    /// Starts hashing with `algorithm`
+    pub fn new(algorithm: Algorithm) -> Self {
+        Self(match algorithm {
+            Algorithm::Sha256 => HasherState::Sha256(Sha256::default()),
+            Algorithm::Sha512 => HasherState::Sha512(Sha512::default()),
+        })
+    }
+
+    /// Starts hashing with the algorithm of `digest`, SHA-256 if it names none
+    pub fn for_digest(digest: &str) -> Self {
+        Self::new(Algorithm::of(digest).unwrap_or_default())
+    }
+
+    /// Feeds `data` into the hash
+    pub fn update(&mut self, data: &[u8]) {
+        use sha2::Digest as _;
+        match &mut self.0 {
+            HasherState::Sha256(hasher) => hasher.update(data),
+            HasherState::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Returns the digest of everything fed in
+    pub fn finalize(self) -> Digest {
+        use sha2::Digest as _;
+        let (algorithm, hex) = match self.0 {
+            HasherState::Sha256(hasher) => (Algorithm::Sha256, format!("{:x}", hasher.finalize())),
+            HasherState::Sha512(hasher) => (Algorithm::Sha512, format!("{:x}", hasher.finalize())),
+        };
+        Digest { algorithm, hex }
+    }
+}
+
+impl std::io::Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Parses `digest`, reporting a malformed one as [`PusherError::InvalidDigest`]
+///
+/// Used wherever a digest enters the tool (image references, manifests,
+/// registry responses and the cache index), so a malformed one fails there
+/// instead of turning into a bogus URL or cache file name later.
+///
+/// # Examples
+///
+/// ```
+/// # use docker_image_pusher::image::digest::validate;
+/// let digest = "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a";
+/// assert!(validate(digest, "Config").is_ok());
+///
+/// let error = validate("SHA256:44136FA3", "Config").unwrap_err();
+/// assert_eq!(error.category(), "invalid_digest");
+/// ```
+pub fn validate(digest: &str, what: &str) -> Result<Digest, PusherError> {
+    digest
+        .parse()
+        .map_err(|problem| PusherError::InvalidDigest {
+            what: what.to_string(),
+            problem,
+        })
+}
+
+/// Name of the cache file for the blob `digest`
+///
+/// Valid digests map to `<algorithm>_<hex>`. Anything else (which manifest
+/// validation normally rejects earlier) keeps only ASCII letters and digits,
+/// so a malformed digest can't point outside the cache directory.
+///
+/// # Examples
+///
+/// ```
+/// # use docker_image_pusher::image::digest::file_name;
+/// assert_eq!(file_name("sha256:0123"), "sha256_0123");
+/// assert_eq!(file_name("sha256:../../etc/passwd"), "sha256_______etc_passwd");
+/// ```
+pub fn file_name(digest: &str) -> String {
+    match digest.parse::<Digest>() {
+        Ok(digest) => digest.file_name(),
+        Err(_) => digest
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect(),
+    }
+}
@@ -0,0 +1,1115 @@
+use crate::image;
+use crate::store;
+use crate::{CACHE_DIR, PusherError};
+use oci_client::manifest::{OciImageIndex, OciManifest};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tar::{Archive, EntryType};
+use tracing::{debug, info, instrument, warn};
+
+const LARGE_LAYER_THRESHOLD_BYTES: u64 = 10 * 1024 * 1024; // 10MB for progress tracking
+const STREAM_BUFFER_SIZE: usize = 65536; // 64KB buffer
+const PROGRESS_UPDATE_INTERVAL_SECS: u64 = 2;
+const GZIP_MAGIC_BYTES: [u8; 2] = [0x1f, 0x8b];
+
+const GZIP_LAYER_MEDIA_TYPE: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+const TAR_LAYER_MEDIA_TYPE: &str = "application/vnd.docker.image.rootfs.diff.tar";
+
+/// Tar path that reads the archive from stdin
+pub const STDIN_PATH: &str = "-";
+
+/// Name prefix of the files entries are extracted to before they are named by digest
+pub const TEMP_LAYER_PREFIX: &str = "temp_layer_";
+
+/// Annotation naming an image in an OCI image layout (often only its tag)
+const OCI_REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+/// Annotation with the full image name, written next to [`OCI_REF_NAME_ANNOTATION`] by containerd and buildx
+const CONTAINERD_IMAGE_NAME_ANNOTATION: &str = "io.containerd.image.name";
+
+/// Longest layer chain of a legacy archive, beyond which its parents are taken to loop
+const MAX_LEGACY_LAYERS: usize = 1000;
+
+/// Links followed when resolving a path in the archive before giving up on a loop
+const MAX_LINK_HOPS: usize = 40;
+
+/// Age after which temp files are removed when it can't be told whether their process still runs
+const ORPHAN_MIN_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Distinguishes the temp files of concurrent imports within this process
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Settings for [`import_tar_file`] and [`import_tar`]
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    /// Cache directory imported images are stored in, e.g. a cache namespace
+    /// (see [`crate::namespace`])
+    pub cache_dir: PathBuf,
+    /// Directory archive entries are extracted in instead of the image's cache directory
+    ///
+    /// Useful when the cache is on a small or slow filesystem. It may be on a
+    /// different filesystem than the cache; finished entries are then copied
+    /// over instead of renamed.
+    pub tmp_dir: Option<PathBuf>,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self {
+            cache_dir: PathBuf::from(CACHE_DIR),
+            tmp_dir: None,
+        }
+    }
+}
+
+impl ImportOptions {
+    /// Directory for scratch archives of local stores, removed once imported
+    pub(crate) fn scratch_dir(&self) -> &Path {
+        self.tmp_dir.as_deref().unwrap_or(&self.cache_dir)
+    }
+}
+
+/// Removes temp files left behind by imports that were killed or crashed
+///
+/// Looks in every image directory under `cache_dir` and in `tmp_dir`. Files
+/// of imports that are still running are kept: the process ID in their name
+/// is checked under `/proc` where available, elsewhere only files older than
+/// a day are removed. Returns the number of files removed.
+pub fn remove_orphaned_temp_files(cache_dir: &Path, tmp_dir: Option<&Path>) -> usize {
+    let mut dirs: Vec<PathBuf> = std::fs::read_dir(cache_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect();
+    dirs.extend(tmp_dir.map(Path::to_path_buf));
+
+    let mut removed = 0;
+    for dir in dirs {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let Some(suffix) = name.to_str().and_then(|name| name.strip_prefix(TEMP_LAYER_PREFIX)) else {
+                continue;
+            };
+            if !is_orphaned(suffix, &entry) {
+                continue;
+            }
+            match std::fs::remove_file(entry.path()) {
+                Ok(()) => {
+                    debug!(path = %entry.path().display(), "removed orphaned temp file");
+                    removed += 1;
+                }
+                Err(e) => warn!("⚠️  Failed to remove orphaned temp file {}: {}", entry.path().display(), e),
+            }
+        }
+    }
+    if removed > 0 {
+        info!("🧹 Removed {} orphaned temp file(s) from interrupted imports", removed);
+    }
+    removed
+}
+
+/// Tells whether the temp file `entry`, named [`TEMP_LAYER_PREFIX`] + `suffix`, belongs to no running import
+fn is_orphaned(suffix: &str, entry: &std::fs::DirEntry) -> bool {
+    let pid = suffix.split('_').next().and_then(|pid| pid.parse::<u32>().ok());
+    if pid == Some(std::process::id()) {
+        return false;
+    }
+    let proc = Path::new("/proc");
+    if let Some(pid) = pid
+        && proc.is_dir()
+    {
+        return !proc.join(pid.to_string()).exists();
+    }
+    entry
+        .metadata()
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age > ORPHAN_MIN_AGE)
+}
+
+/// Detects the appropriate media type for a Docker layer based on its content
+///
+/// This function examines the first few bytes of a layer file to determine
+/// whether it's gzipped or uncompressed, and returns the appropriate Docker
+/// media type string.
+///
+/// # Arguments
+///
+/// * `layer_path` - Path to the layer file
+///
+/// # Returns
+///
+/// `Result<String, PusherError>` - The detected media type
+fn detect_layer_media_type(layer_path: &std::path::Path) -> Result<String, PusherError> {
+    use std::io::Read;
+    
+    let mut file = std::fs::File::open(layer_path)
+        .map_err(|e| PusherError::tar_error(format!("Failed to open layer file: {}", e)))?;
+    
+    let mut buffer = [0u8; 2];
+    let bytes_read = file.read(&mut buffer)
+        .map_err(|e| PusherError::tar_error(format!("Failed to read layer header: {}", e)))?;
+    
+    if bytes_read >= 2 && buffer == GZIP_MAGIC_BYTES {
+        Ok(GZIP_LAYER_MEDIA_TYPE.to_string())
+    } else if bytes_read >= 2 {
+        Ok(TAR_LAYER_MEDIA_TYPE.to_string())
+    } else {
+        // Default to gzipped if we can't determine
+        Ok(GZIP_LAYER_MEDIA_TYPE.to_string())
+    }
+}
+
+/// Shows extraction progress for large layers
+fn show_extraction_progress(total_read: u64, layer_size: u64, layer_size_mb: f64, extract_start: std::time::Instant) {
+    let progress = (total_read as f64 / layer_size as f64) * 100.0;
+    let elapsed = extract_start.elapsed();
+    let mb_per_sec = if elapsed.as_secs() > 0 {
+        (total_read as f64 / (1024.0 * 1024.0)) / elapsed.as_secs_f64()
+    } else {
+        0.0
+    };
+
+    info!(
+        "   📊 Progress: {:.1}% ({:.1}/{:.1} MB) @ {:.1} MB/s",
+        progress,
+        total_read as f64 / (1024.0 * 1024.0),
+        layer_size_mb,
+        mb_per_sec
+    );
+}
+
+/// Imports a Docker tar archive and caches it using the same structure as pulled images
+///
+/// This function processes tar files created by `docker save` command and extracts:
+/// - Image manifest(s)
+/// - Layer tar.gz files  
+/// - Image configuration JSON
+///
+/// The extracted components are cached using the same structure as `cache_image()`,
+/// ensuring compatibility with the push functionality.
+///
+/// ## Docker Save Format
+///
+/// A `docker save` tar contains:
+/// - `manifest.json` - List of images with their layers and config references
+/// - `<layer_id>/layer.tar` - Individual layer data (sometimes gzipped)
+/// - `<config_hash>.json` - Image configuration
+/// - `repositories` (optional) - Repository and tag information
+///
+/// ## OCI Archive Format
+///
+/// Archives without a `manifest.json` are read as an OCI image layout
+/// (`podman save --format oci-archive`, `buildx --output type=oci`):
+/// - `oci-layout` - Layout version marker
+/// - `index.json` - Image index pointing at the image manifest(s)
+/// - `blobs/<algorithm>/<hex>` - Manifests, configs and layers by digest
+///
+/// Archives written by Docker before 1.10 have neither; their image config
+/// is rebuilt from the `repositories` file and the `<layer_id>/json` files.
+///
+/// ## Cache Structure
+///
+/// The function creates the same cache structure as `cache_image()`:
+/// - `.cache/{sanitized_image_name}/manifest.json` - OCI image manifest
+/// - `.cache/{sanitized_image_name}/config_{digest}.json` - Image config
+/// - `.cache/{sanitized_image_name}/{layer_digest}` - Layer files
+/// - `.cache/{sanitized_image_name}/index.json` - Cache metadata
+///
+/// # Arguments
+///
+/// * `tar_path` - Path to the Docker tar archive file, or `-` to read it from stdin
+/// * `image_name` - Name to use for caching (e.g., "myapp:v1.0")
+///
+/// # Returns
+///
+/// `Result<(), PusherError>` - Success or detailed error information
+///
+/// # Example
+///
+/// ```bash
+/// # Create a tar archive with docker save
+/// docker save myapp:latest > myapp.tar
+///
+/// # Import it into the cache
+/// docker-image-pusher import myapp.tar myapp:latest
+///
+/// # Or pipe it in without an intermediate file
+/// docker save myapp:latest | docker-image-pusher import - myapp:latest
+///
+/// # Now it can be pushed like any cached image
+/// docker-image-pusher push myapp:latest registry.example.com/myapp:latest -u user -p pass
+/// ```
+#[instrument(name = "import", skip_all, fields(tar = %tar_path, image = %image_name))]
+pub async fn import_tar_file(
+    tar_path: &str,
+    image_name: &str,
+    options: &ImportOptions,
+) -> Result<(), PusherError> {
+    if tar_path == STDIN_PATH {
+        info!("📂 Reading tar archive from stdin");
+        return import_tar(std::io::stdin(), "stdin", image_name, options).await;
+    }
+    info!("📂 Opening tar archive: {}", tar_path);
+    let tar_file = File::open(tar_path)
+        .map_err(|e| PusherError::TarError(format!("Failed to open tar file: {}", e)))?;
+    // Every file of the archive is written to the cache while it is read
+    let size = tar_file
+        .metadata()
+        .map_err(|e| PusherError::TarError(format!("Failed to read tar file metadata: {}", e)))?
+        .len();
+    crate::store::ensure_free_space(&options.cache_dir, size)?;
+    import_tar(tar_file, tar_path, image_name, options).await
+}
+
+/// Imports a Docker tar archive read from any stream, e.g. a pipe or a socket
+///
+/// The archive is read in a single pass, so it doesn't have to be seekable:
+/// every file is streamed to the cache under its digest while it is read,
+/// and once `manifest.json` (usually the last entry) or the OCI `index.json`
+/// is known the layers and config it references are kept and everything
+/// else is discarded.
+///
+/// # Arguments
+///
+/// * `reader` - The tar stream
+/// * `source` - Description of the stream for logs and the cache index (e.g. a path)
+/// * `image_name` - Name to use for caching (e.g., "myapp:v1.0")
+/// * `options` - Where to cache the image and extract to
+pub async fn import_tar<R: Read + Send + 'static>(
+    reader: R,
+    source: &str,
+    image_name: &str,
+    options: &ImportOptions,
+) -> Result<(), PusherError> {
+    // Step 1: Create cache directory structure
+    let cache_dir = &options.cache_dir;
+    std::fs::create_dir_all(cache_dir)
+        .map_err(|e| PusherError::CacheError(format!("Failed to create cache directory: {}", e)))?;
+
+    let image_cache_dir = cache_dir.join(image::sanitize_image_name(image_name));
+    std::fs::create_dir_all(&image_cache_dir).map_err(|e| {
+        PusherError::CacheError(format!("Failed to create image cache directory: {}", e))
+    })?;
+
+    // Step 2: Stream every entry into the cache (blocking reads stay off the async workers)
+    let extract_dir = image_cache_dir.clone();
+    let tmp_dir = options.tmp_dir.clone();
+    let extracted =
+        tokio::task::spawn_blocking(move || extract_entries(reader, &extract_dir, tmp_dir))
+            .await
+            .map_err(|e| PusherError::TarError(format!("Extraction task failed: {}", e)))??;
+
+    // Step 3: Find the image in the archive, from Docker's manifest.json, the OCI index
+    // or the repositories file of archives older than manifest.json
+    let archived = match &extracted.docker_manifest {
+        Some(docker_manifest) => {
+            docker_archive_image(&extracted, &image_cache_dir, docker_manifest)?
+        }
+        None if extracted.files.contains_key("index.json") => {
+            oci_archive_image(&extracted, &image_cache_dir, image_name)?
+        }
+        None => legacy_archive_image(&extracted, &image_cache_dir, image_name)?,
+    };
+
+    info!("📋 Found image with {} layers", archived.layers.len());
+    info!("⚙️  Config file: {}", archived.config_name);
+
+    // Step 4: Resolve the config and layers, in manifest order
+    let config_contents = archived.config;
+    let config_digest = format!("sha256:{:x}", Sha256::digest(&config_contents));
+
+    let mut imported_layers = Vec::new();
+    for layer_path in &archived.layers {
+        let (layer_digest, layer_size) = extracted.lookup(layer_path)?;
+        // Even an empty layer is a tar stream of end-of-archive blocks
+        if layer_size == 0 {
+            return Err(PusherError::TarError(format!(
+                "Layer {} is empty (0 bytes) in the archive",
+                layer_path
+            )));
+        }
+
+        // Detect media type based on layer content
+        let media_type = detect_layer_media_type(&image_cache_dir.join(image::digest::file_name(&layer_digest)))?;
+        imported_layers.push(ImportedLayer {
+            digest: layer_digest,
+            size: layer_size,
+            media_type,
+        });
+    }
+
+    // Check the layers against the config and give them all the same compression
+    let normalize_dir = image_cache_dir.clone();
+    let normalize_config = config_contents.clone();
+    let imported_layers = tokio::task::spawn_blocking(move || {
+        normalize_layers(&normalize_dir, &normalize_config, imported_layers)
+    })
+    .await
+    .map_err(|e| PusherError::TarError(format!("Layer normalization task failed: {}", e)))??;
+
+    let mut oci_layers = Vec::new();
+    let mut cached_layers = Vec::new();
+    for layer in imported_layers {
+        // Create OCI layer descriptor using file size and detected media type
+        oci_layers.push(serde_json::json!({
+            "mediaType": layer.media_type,
+            "size": layer.size,
+            "digest": layer.digest
+        }));
+        cached_layers.push(layer.digest);
+    }
+
+    // Drop extracted files the image doesn't reference (the config is stored separately)
+    for (digest, _) in extracted.files.values() {
+        if !cached_layers.contains(digest) {
+            let _ = std::fs::remove_file(image_cache_dir.join(image::digest::file_name(digest)));
+        }
+    }
+
+    info!(
+        "✅ Successfully extracted {} layers and config",
+        cached_layers.len()
+    );
+
+    // Step 5: Save config to cache
+    let config_file_name = format!("config_{}.json", image::digest::file_name(&config_digest));
+    let config_path = image_cache_dir.join(&config_file_name);
+
+    store::write_atomic(&config_path, &config_contents)
+        .await
+        .map_err(|e| PusherError::CacheError(format!("Failed to cache config: {}", e)))?;
+
+    // Step 6: Create OCI manifest
+    let oci_manifest = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+        "config": {
+            "mediaType": "application/vnd.docker.container.image.v1+json",
+            "size": config_contents.len(),
+            "digest": config_digest
+        },
+        "layers": oci_layers
+    });
+    // Fail the import rather than leave a cache entry every push would be rejected for
+    image::validate_manifest(
+        &serde_json::from_value(oci_manifest.clone())?,
+        &format!("Manifest generated from {}", source),
+    )?;
+
+    // Step 7: Save manifest to cache
+    let manifest_path = image_cache_dir.join("manifest.json");
+    let manifest_json = serde_json::to_string_pretty(&oci_manifest)?;
+    let manifest_file_digest = format!("sha256:{:x}", Sha256::digest(manifest_json.as_bytes()));
+    store::write_atomic(&manifest_path, manifest_json)
+        .await
+        .map_err(|e| PusherError::CacheError(format!("Failed to cache manifest: {}", e)))?;
+
+    // Step 8: Create index file for cache lookup
+    let index = serde_json::json!({
+        "source_image": image_name,
+        "source_type": "tar_import",
+        "source_file": source,
+        "manifest": "manifest.json",
+        "config": config_digest,
+        "layers": cached_layers,
+        "manifest_file_digest": manifest_file_digest,
+        "cache_version": crate::migrate::CACHE_VERSION,
+        "cached_at": std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    });
+
+    let index_json = serde_json::to_string_pretty(&index)?;
+    // Written last, after every layer and the config are synced
+    store::write_atomic(&image_cache_dir.join("index.json"), index_json)
+        .await
+        .map_err(|e| PusherError::CacheError(format!("Failed to create index: {}", e)))?;
+
+    info!(
+        "🎉 Successfully imported tar archive with {} layers",
+        cached_layers.len()
+    );
+    info!("💡 Cache structure matches pulled images - can be pushed with 'push' command");
+
+    Ok(())
+}
+
+/// A layer of the imported image, as stored in the cache
+struct ImportedLayer {
+    digest: String,
+    size: u64,
+    media_type: String,
+}
+
+/// Checks imported layers against the config's `rootfs.diff_ids` and makes their compression consistent
+///
+/// Every layer must decompress to the diff_id the config lists at its
+/// position, otherwise the archive is inconsistent and registries (or
+/// `docker pull` later) would reject the image. Archives mixing gzipped and
+/// plain layers, which some registries refuse, get their plain layers
+/// gzipped; diff_ids are of the uncompressed content, so the config stays
+/// valid.
+fn normalize_layers(
+    image_cache_dir: &Path,
+    config: &[u8],
+    mut layers: Vec<ImportedLayer>,
+) -> Result<Vec<ImportedLayer>, PusherError> {
+    let config: serde_json::Value = serde_json::from_slice(config)
+        .map_err(|e| PusherError::TarError(format!("Failed to parse image config: {}", e)))?;
+    let diff_ids = config["rootfs"]["diff_ids"]
+        .as_array()
+        .ok_or_else(|| PusherError::TarError("Image config has no rootfs.diff_ids".to_string()))?;
+    if diff_ids.len() != layers.len() {
+        return Err(PusherError::TarError(format!(
+            "Image config lists {} diff_ids but the archive has {} layers",
+            diff_ids.len(),
+            layers.len()
+        )));
+    }
+
+    for (layer, diff_id) in layers.iter().zip(diff_ids) {
+        let diff_id = diff_id.as_str().unwrap_or_default();
+        let algorithm = image::digest::validate(diff_id, "Image config rootfs.diff_ids")?.algorithm();
+        let gzipped = layer.media_type == GZIP_LAYER_MEDIA_TYPE;
+        // Plain layers were hashed with sha256 while extracting
+        let actual = if !gzipped && algorithm == image::digest::Algorithm::Sha256 {
+            layer.digest.clone()
+        } else {
+            let file = std::io::BufReader::new(File::open(
+                image_cache_dir.join(image::digest::file_name(&layer.digest)),
+            )?);
+            let mut content: Box<dyn Read> = match gzipped {
+                true => Box::new(flate2::read::GzDecoder::new(file)),
+                false => Box::new(file),
+            };
+            let mut hasher = image::digest::Hasher::new(algorithm);
+            std::io::copy(&mut content, &mut hasher)
+                .map_err(|e| PusherError::TarError(format!("Failed to decompress layer {}: {}", layer.digest, e)))?;
+            hasher.finalize().to_string()
+        };
+        if actual != diff_id {
+            return Err(PusherError::TarError(format!(
+                "Layer {} has content {} but the config expects diff_id {}",
+                layer.digest, actual, diff_id
+            )));
+        }
+    }
+
+    let compressed = layers.iter().filter(|layer| layer.media_type == GZIP_LAYER_MEDIA_TYPE).count();
+    if compressed == 0 || compressed == layers.len() {
+        return Ok(layers);
+    }
+    info!(
+        "🗜️  Archive mixes gzipped and plain layers, compressing {} plain layer(s)",
+        layers.len() - compressed
+    );
+    // The same plain layer can appear more than once
+    let mut converted: HashMap<String, (String, u64)> = HashMap::new();
+    for layer in layers.iter_mut().filter(|layer| layer.media_type == TAR_LAYER_MEDIA_TYPE) {
+        let (digest, size) = match converted.get(&layer.digest) {
+            Some(done) => done.clone(),
+            None => {
+                let done = compress_layer(image_cache_dir, &layer.digest)?;
+                converted.insert(layer.digest.clone(), done.clone());
+                done
+            }
+        };
+        debug!(from = %layer.digest, to = %digest, "compressed plain layer");
+        layer.digest = digest;
+        layer.size = size;
+        layer.media_type = GZIP_LAYER_MEDIA_TYPE.to_string();
+    }
+    Ok(layers)
+}
+
+/// Gzips the cached plain layer `digest`, storing the result under its own digest
+///
+/// The plain file is left in place; the import removes files the image
+/// doesn't reference once the layers are final.
+///
+/// # Returns
+///
+/// `Result<(String, u64), PusherError>` - Digest and size of the compressed layer
+fn compress_layer(image_cache_dir: &Path, digest: &str) -> Result<(String, u64), PusherError> {
+    let temp_path = image_cache_dir.join(format!(
+        "{}{}_{}",
+        TEMP_LAYER_PREFIX,
+        std::process::id(),
+        TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let result = (|| {
+        let mut input = File::open(image_cache_dir.join(image::digest::file_name(digest)))?;
+        let mut encoder = flate2::write::GzEncoder::new(File::create(&temp_path)?, flate2::Compression::default());
+        std::io::copy(&mut input, &mut encoder)?;
+        encoder.finish()?.sync_all()?;
+
+        let mut hasher = Sha256::new();
+        let size = std::io::copy(&mut File::open(&temp_path)?, &mut hasher)?;
+        Ok::<_, std::io::Error>((format!("sha256:{:x}", hasher.finalize()), size))
+    })();
+    let (compressed, size) = match result {
+        Ok(done) => done,
+        Err(e) => {
+            let _ = std::fs::remove_file(&temp_path);
+            return Err(PusherError::TarError(format!("Failed to compress layer {}: {}", digest, e)));
+        }
+    };
+    move_file(&temp_path, &image_cache_dir.join(image::digest::file_name(&compressed)))?;
+    Ok((compressed, size))
+}
+
+/// The image an archive holds
+struct ArchivedImage {
+    /// Where the config comes from, for logs
+    config_name: String,
+    /// Image config
+    config: Vec<u8>,
+    /// Layers, as paths in the archive, in manifest order
+    layers: Vec<String>,
+}
+
+/// The image of a `docker save` archive (docker-archive), from its `manifest.json`
+fn docker_archive_image(
+    extracted: &Extracted,
+    image_cache_dir: &Path,
+    docker_manifest: &serde_json::Value,
+) -> Result<ArchivedImage, PusherError> {
+    let manifest_array = docker_manifest
+        .as_array()
+        .ok_or_else(|| PusherError::TarError("Invalid manifest.json format".to_string()))?;
+
+    if manifest_array.is_empty() {
+        return Err(PusherError::TarError("Empty manifest.json".to_string()));
+    }
+
+    // Use the first image in the manifest (docker save can contain multiple images)
+    let image_info = &manifest_array[0];
+    let config = image_info["Config"]
+        .as_str()
+        .ok_or_else(|| PusherError::TarError("No Config field in manifest".to_string()))?;
+    let layers = image_info["Layers"]
+        .as_array()
+        .ok_or_else(|| PusherError::TarError("No Layers field in manifest".to_string()))?
+        .iter()
+        .map(|layer| {
+            layer
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| PusherError::TarError("Invalid layer path".to_string()))
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(ArchivedImage {
+        config_name: config.to_string(),
+        config: extracted.read(config, image_cache_dir)?,
+        layers,
+    })
+}
+
+/// The image of an OCI image layout packed in a tar (oci-archive), from its `index.json`
+///
+/// `podman save --format oci-archive` and `buildx --output type=oci` write
+/// these. Of several images, the one whose name annotation matches
+/// `image_name` (or its tag) is imported, otherwise the first; indexes of
+/// multi-platform images resolve to Linux AMD64 like pulls do.
+fn oci_archive_image(
+    extracted: &Extracted,
+    image_cache_dir: &Path,
+    image_name: &str,
+) -> Result<ArchivedImage, PusherError> {
+    info!("📄 Found OCI image layout");
+    let index: OciImageIndex =
+        serde_json::from_slice(&extracted.read("index.json", image_cache_dir)?)
+            .map_err(|e| PusherError::TarError(format!("Failed to parse index.json: {}", e)))?;
+    let tag = image_tag(image_name);
+    let named = index.manifests.iter().find(|entry| {
+        entry.annotations.as_ref().is_some_and(|annotations| {
+            [OCI_REF_NAME_ANNOTATION, CONTAINERD_IMAGE_NAME_ANNOTATION]
+                .iter()
+                .filter_map(|key| annotations.get(*key))
+                .any(|name| name == image_name || Some(name.as_str()) == tag)
+        })
+    });
+    let mut digest = match named {
+        Some(entry) => Some(entry.digest.clone()),
+        None if index.manifests.iter().any(|entry| entry.platform.is_some()) => {
+            oci_client::client::linux_amd64_resolver(&index.manifests)
+        }
+        None => index.manifests.first().map(|entry| entry.digest.clone()),
+    };
+    loop {
+        let Some(manifest_digest) = digest else {
+            return Err(PusherError::TarError(
+                "index.json has no image for linux/amd64".to_string(),
+            ));
+        };
+        let path = oci_blob_path(extracted, &manifest_digest)?;
+        let manifest = serde_json::from_slice(&extracted.read(&path, image_cache_dir)?)
+            .map_err(|e| PusherError::TarError(format!("Failed to parse {}: {}", path, e)))?;
+        match manifest {
+            OciManifest::Image(manifest) => {
+                let config = oci_blob_path(extracted, &manifest.config.digest)?;
+                return Ok(ArchivedImage {
+                    config: extracted.read(&config, image_cache_dir)?,
+                    config_name: config,
+                    layers: manifest
+                        .layers
+                        .iter()
+                        .map(|layer| oci_blob_path(extracted, &layer.digest))
+                        .collect::<Result<_, _>>()?,
+                });
+            }
+            OciManifest::ImageIndex(index) => {
+                digest = oci_client::client::linux_amd64_resolver(&index.manifests);
+            }
+        }
+    }
+}
+
+/// The image of a `docker save` archive from before Docker 1.10, which has no `manifest.json`
+///
+/// Such archives hold a directory per layer, with its `layer.tar` and the
+/// legacy image JSON (`json`) naming its parent, and a `repositories` file
+/// mapping tags to the top layer. The config is rebuilt from the top layer's
+/// JSON the way `docker load` does, with the diff_ids and history of the
+/// layer chain; layers without content (an empty `layer.tar`) only add a
+/// history entry.
+fn legacy_archive_image(
+    extracted: &Extracted,
+    image_cache_dir: &Path,
+    image_name: &str,
+) -> Result<ArchivedImage, PusherError> {
+    let top = if extracted.files.contains_key("repositories") {
+        info!("📄 Found legacy repositories file");
+        let repositories: BTreeMap<String, BTreeMap<String, String>> = serde_json::from_slice(
+            &extracted.read("repositories", image_cache_dir)?,
+        )
+        .map_err(|e| PusherError::TarError(format!("Failed to parse repositories: {}", e)))?;
+        let tagged: Vec<(String, &String)> = repositories
+            .iter()
+            .flat_map(|(repository, tags)| {
+                tags.iter()
+                    .map(move |(tag, id)| (format!("{}:{}", repository, tag), id))
+            })
+            .collect();
+        let tag = image_tag(image_name);
+        tagged
+            .iter()
+            .find(|(name, _)| name == image_name)
+            .or_else(|| tagged.iter().find(|(name, _)| image_tag(name) == tag))
+            .or_else(|| tagged.first())
+            .map(|(_, id)| id.to_string())
+            .ok_or_else(|| {
+                PusherError::TarError("The repositories file lists no images".to_string())
+            })?
+    } else {
+        // `docker save <id>` writes no repositories file; the top layer is the one nothing builds on
+        let ids: Vec<&str> = extracted
+            .files
+            .keys()
+            .filter_map(|path| path.strip_suffix("/json"))
+            .collect();
+        if ids.is_empty() {
+            return Err(PusherError::TarError(
+                "No manifest.json (docker-archive), index.json (oci-archive) or repositories (legacy docker save) found in tar archive"
+                    .to_string(),
+            ));
+        }
+        let mut parents = HashSet::new();
+        for id in &ids {
+            let layer = legacy_layer_json(extracted, image_cache_dir, id)?;
+            if let Some(parent) = layer["parent"].as_str() {
+                parents.insert(parent.to_string());
+            }
+        }
+        let mut tops = ids.into_iter().filter(|id| !parents.contains(*id));
+        match (tops.next(), tops.next()) {
+            (Some(top), None) => top.to_string(),
+            _ => {
+                return Err(PusherError::TarError(
+                    "The legacy archive holds several images and no repositories file to choose from".to_string(),
+                ));
+            }
+        }
+    };
+
+    // Walk the parent chain from the top layer down
+    let mut chain = Vec::new();
+    let mut next = Some(top);
+    while let Some(id) = next {
+        if chain.len() > MAX_LEGACY_LAYERS {
+            return Err(PusherError::TarError(format!(
+                "Layer {} has more than {} parents",
+                id, MAX_LEGACY_LAYERS
+            )));
+        }
+        let layer = legacy_layer_json(extracted, image_cache_dir, &id)?;
+        next = layer["parent"].as_str().map(str::to_string);
+        chain.push((id, layer));
+    }
+    chain.reverse();
+
+    let mut layers = Vec::new();
+    let mut diff_ids = Vec::new();
+    let mut history = Vec::new();
+    for (id, layer) in &chain {
+        let layer_path = format!("{}/layer.tar", id);
+        let (digest, size) = extracted.lookup(&layer_path)?;
+        let mut entry = serde_json::json!({});
+        let created_by = layer["container_config"]["Cmd"]
+            .as_array()
+            .map(|cmd| {
+                cmd.iter()
+                    .filter_map(|arg| arg.as_str())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .unwrap_or_default();
+        for (key, value) in [
+            ("created", layer["created"].as_str().unwrap_or_default()),
+            ("author", layer["author"].as_str().unwrap_or_default()),
+            ("created_by", created_by.as_str()),
+            ("comment", layer["comment"].as_str().unwrap_or_default()),
+        ] {
+            if !value.is_empty() {
+                entry[key] = value.into();
+            }
+        }
+        if size == 0 {
+            entry["empty_layer"] = true.into();
+        } else {
+            layers.push(layer_path);
+            diff_ids.push(digest);
+        }
+        history.push(entry);
+    }
+
+    let (top_id, top) = chain
+        .pop()
+        .ok_or_else(|| PusherError::TarError("Legacy archive has no layers".to_string()))?;
+    let mut config = top;
+    if let Some(fields) = config.as_object_mut() {
+        for legacy in ["id", "parent", "Size", "parent_id", "layer_id", "throwaway"] {
+            fields.remove(legacy);
+        }
+        // Images this old may predate the platform fields
+        fields
+            .entry("architecture")
+            .or_insert_with(|| "amd64".into());
+        fields.entry("os").or_insert_with(|| "linux".into());
+    }
+    config["rootfs"] = serde_json::json!({ "type": "layers", "diff_ids": diff_ids });
+    config["history"] = history.into();
+    Ok(ArchivedImage {
+        config_name: format!("rebuilt from {}/json", top_id),
+        config: serde_json::to_vec(&config)?,
+        layers,
+    })
+}
+
+/// Legacy image JSON of the layer `id` of a pre-1.10 `docker save` archive
+fn legacy_layer_json(
+    extracted: &Extracted,
+    image_cache_dir: &Path,
+    id: &str,
+) -> Result<serde_json::Value, PusherError> {
+    let path = format!("{}/json", id);
+    serde_json::from_slice(&extracted.read(&path, image_cache_dir)?)
+        .map_err(|e| PusherError::TarError(format!("Failed to parse {}: {}", path, e)))
+}
+
+/// Tag of `image_name`, if it has one
+fn image_tag(image_name: &str) -> Option<&str> {
+    image_name
+        .rsplit_once(':')
+        .map(|(_, tag)| tag)
+        .filter(|tag| !tag.contains('/'))
+}
+
+/// Path in an OCI image layout of the blob `digest`, checked against the archive's contents
+fn oci_blob_path(extracted: &Extracted, digest: &str) -> Result<String, PusherError> {
+    let path = format!("blobs/{}", digest.replacen(':', "/", 1));
+    let (actual, _) = extracted.lookup(&path)?;
+    // Files are hashed with SHA-256 while extracting
+    if digest.starts_with("sha256:") && actual != digest {
+        return Err(PusherError::TarError(format!(
+            "{} in the archive doesn't match its digest (it is {})",
+            path, actual
+        )));
+    }
+    Ok(path)
+}
+
+/// Everything read from an archive in one pass
+struct Extracted {
+    /// Parsed `manifest.json`, if the archive had one
+    docker_manifest: Option<serde_json::Value>,
+    /// Digest and size of every other file, by its path in the archive
+    files: HashMap<String, (String, u64)>,
+    /// Target of every hard link and symlink, by its path in the archive
+    ///
+    /// `docker save` links identical layers to one copy, as `layer.tar`
+    /// symlinks (or directory symlinks) or hard links.
+    links: HashMap<String, String>,
+}
+
+impl Extracted {
+    /// Returns the digest and size of the file at `path` in the archive, following links
+    fn lookup(&self, path: &str) -> Result<(String, u64), PusherError> {
+        let requested = archive_path("", path);
+        let mut resolved = requested.clone();
+        for _ in 0..MAX_LINK_HOPS {
+            if let Some(file) = self.files.get(&resolved) {
+                return Ok(file.clone());
+            }
+            // The path itself or one of its parent directories may be a link
+            let Some((prefix_len, target)) = std::iter::once(resolved.as_str())
+                .chain(resolved.rmatch_indices('/').map(|(i, _)| &resolved[..i]))
+                .find_map(|prefix| Some((prefix.len(), self.links.get(prefix)?)))
+            else {
+                let linked = if resolved == requested {
+                    String::new()
+                } else {
+                    format!(" (it links to {})", resolved)
+                };
+                return Err(PusherError::TarError(format!(
+                    "{} not found in tar archive{}",
+                    path, linked
+                )));
+            };
+            resolved = archive_path(target, resolved[prefix_len..].trim_start_matches('/'));
+        }
+        Err(PusherError::TarError(format!(
+            "Cannot resolve {} in tar archive: too many levels of links",
+            path
+        )))
+    }
+
+    /// Reads the file at `path` in the archive back from `image_cache_dir`
+    fn read(&self, path: &str, image_cache_dir: &Path) -> Result<Vec<u8>, PusherError> {
+        let (digest, _) = self.lookup(path)?;
+        std::fs::read(image_cache_dir.join(image::digest::file_name(&digest)))
+            .map_err(|e| PusherError::TarError(format!("Failed to read {}: {}", path, e)))
+    }
+}
+
+/// `path` as seen from the archive directory `dir`, with `.` and `..` resolved
+///
+/// Entry names are relative to the archive root; some tar writers put `./`
+/// in front of them, and symlink targets are relative to their directory
+/// (an absolute target starts from the archive root).
+fn archive_path(dir: &str, path: &str) -> String {
+    let base = if path.starts_with('/') { "" } else { dir };
+    let mut parts = Vec::new();
+    for part in base.split('/').chain(path.split('/')) {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+/// Streams all files of the archive into `image_cache_dir`, each stored under its digest
+///
+/// Entries are extracted in `tmp_dir` first if given, else in `image_cache_dir`.
+fn extract_entries<R: Read>(
+    reader: R,
+    image_cache_dir: &Path,
+    tmp_dir: Option<PathBuf>,
+) -> Result<Extracted, PusherError> {
+    let mut archive = Archive::new(reader);
+    let temp_dir = match tmp_dir {
+        Some(dir) => {
+            std::fs::create_dir_all(&dir).map_err(|e| {
+                PusherError::TarError(format!("Failed to create temp directory {}: {}", dir.display(), e))
+            })?;
+            dir
+        }
+        None => image_cache_dir.to_path_buf(),
+    };
+    let temp_path = temp_dir.join(format!(
+        "{}{}_{}",
+        TEMP_LAYER_PREFIX,
+        std::process::id(),
+        TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let result = extract_entries_via(&mut archive, image_cache_dir, &temp_path);
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Loop of [`extract_entries`], extracting each entry to `temp_path` first
+fn extract_entries_via<R: Read>(
+    archive: &mut Archive<R>,
+    image_cache_dir: &Path,
+    temp_path: &Path,
+) -> Result<Extracted, PusherError> {
+    let mut extracted = Extracted {
+        docker_manifest: None,
+        files: HashMap::new(),
+        links: HashMap::new(),
+    };
+
+    for entry_result in archive
+        .entries()
+        .map_err(|e| PusherError::TarError(format!("Failed to read tar entries: {}", e)))?
+    {
+        let mut entry = entry_result
+            .map_err(|e| PusherError::TarError(format!("Failed to read tar entry: {}", e)))?;
+        // Names and sizes from PAX extended headers are already applied by `tar`
+        let entry_type = entry.header().entry_type();
+        let path = entry
+            .path()
+            .map_err(|e| PusherError::TarError(format!("Failed to get entry path: {}", e)))?;
+        let path_str = archive_path("", &path.to_string_lossy());
+
+        if entry_type.is_hard_link() || entry_type.is_symlink() {
+            let target = entry
+                .link_name()
+                .map_err(|e| PusherError::TarError(format!("Failed to get link target: {}", e)))?
+                .unwrap_or_default();
+            // Hard link targets are archive paths, symlink targets relative to the link
+            let dir = match path_str.rsplit_once('/') {
+                Some((dir, _)) if entry_type.is_symlink() => dir,
+                _ => "",
+            };
+            let target = archive_path(dir, &target.to_string_lossy());
+            debug!(path = %path_str, target = %target, "recording tar link");
+            extracted.links.insert(path_str, target);
+            continue;
+        }
+        // GNU sparse entries are read back with their holes filled in
+        if !matches!(
+            entry_type,
+            EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse
+        ) {
+            continue;
+        }
+
+        if path_str == "manifest.json" {
+            info!("📄 Found Docker manifest.json");
+            let mut contents = Vec::new();
+            entry
+                .read_to_end(&mut contents)
+                .map_err(|e| PusherError::TarError(format!("Failed to read manifest: {}", e)))?;
+
+            extracted.docker_manifest = Some(serde_json::from_slice(&contents).map_err(|e| {
+                PusherError::TarError(format!("Failed to parse manifest.json: {}", e))
+            })?);
+            continue;
+        }
+
+        // Get entry size for progress indication
+        let layer_size = entry.size();
+        let layer_size_mb = layer_size as f64 / (1024.0 * 1024.0);
+        if layer_size > LARGE_LAYER_THRESHOLD_BYTES {
+            info!("📦 Extracting layer: {} ({:.1} MB)", path_str, layer_size_mb);
+        } else {
+            debug!(path = %path_str, bytes = layer_size, "extracting tar entry");
+        }
+        let extract_start = std::time::Instant::now();
+
+        let mut temp_file = File::create(temp_path).map_err(|e| {
+            PusherError::TarError(format!("Failed to create temp file: {}", e))
+        })?;
+
+        // Stream entry data to temp file while computing hash
+        let mut hasher = Sha256::new();
+        let mut buffer = [0u8; STREAM_BUFFER_SIZE];
+        let mut total_read = 0u64;
+        let mut last_progress_time = std::time::Instant::now();
+
+        loop {
+            let bytes_read = entry.read(&mut buffer).map_err(|e| {
+                PusherError::TarError(format!("Failed to read layer chunk: {}", e))
+            })?;
+
+            if bytes_read == 0 {
+                break; // End of entry
+            }
+
+            temp_file.write_all(&buffer[..bytes_read]).map_err(|e| {
+                PusherError::TarError(format!("Failed to write layer chunk: {}", e))
+            })?;
+
+            hasher.update(&buffer[..bytes_read]);
+            total_read += bytes_read as u64;
+
+            // Progress indication for large layers with timing
+            if layer_size > LARGE_LAYER_THRESHOLD_BYTES
+                && last_progress_time.elapsed()
+                    > std::time::Duration::from_secs(PROGRESS_UPDATE_INTERVAL_SECS)
+            {
+                show_extraction_progress(total_read, layer_size, layer_size_mb, extract_start);
+                last_progress_time = std::time::Instant::now();
+            }
+        }
+
+        temp_file.flush().map_err(|e| {
+            PusherError::TarError(format!("Failed to flush temp file: {}", e))
+        })?;
+        temp_file.sync_all().map_err(|e| {
+            PusherError::TarError(format!("Failed to sync temp file: {}", e))
+        })?;
+        drop(temp_file);
+
+        let digest = format!("sha256:{:x}", hasher.finalize());
+        if layer_size > LARGE_LAYER_THRESHOLD_BYTES {
+            let extract_duration = extract_start.elapsed();
+            let extract_speed = if extract_duration.as_secs() > 0 {
+                layer_size_mb / extract_duration.as_secs_f64()
+            } else {
+                0.0
+            };
+            info!(
+                digest = %digest,
+                bytes = total_read,
+                "   ✅ Layer extracted: {} in {:.1}s @ {:.1} MB/s",
+                digest,
+                extract_duration.as_secs_f64(),
+                extract_speed
+            );
+        }
+
+        // Identical files (e.g. a layer shared by two images) are stored once
+        move_file(temp_path, &image_cache_dir.join(image::digest::file_name(&digest)))?;
+        extracted.files.insert(path_str, (digest, total_read));
+    }
+
+    Ok(extracted)
+}
+
+/// Moves `from` to `to`, copying when they are on different filesystems
+fn move_file(from: &Path, to: &Path) -> Result<(), PusherError> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    // Written next to the target first, so the cache never holds a partial blob
+    let partial = to.with_extension("partial");
+    let copied = std::fs::copy(from, &partial)
+        .and_then(|_| File::open(&partial)?.sync_all())
+        .and_then(|_| std::fs::rename(&partial, to));
+    let _ = std::fs::remove_file(from);
+    copied.map_err(|e| {
+        let _ = std::fs::remove_file(&partial);
+        PusherError::TarError(format!("Failed to move layer file to {}: {}", to.display(), e))
+    })
+}
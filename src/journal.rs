@@ -0,0 +1,121 @@
+//! On-disk journal of open upload sessions
+//!
+//! Registries keep the data of unfinished blob uploads until the session
+//! expires, and the distribution API has no way to list them. Every streamed
+//! upload therefore records its session in `.cache/upload_sessions/` (one
+//! small file per session, kept up to date as the session moves) and removes
+//! the file once the upload is finalized or aborted. Files left behind belong
+//! to runs that crashed or were killed; pushes abort the stale ones of their
+//! target repository when they start, and `cleanup-uploads` aborts them on
+//! demand.
+
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// Directory (inside the cache directory) holding the journal
+const JOURNAL_DIR_NAME: &str = "upload_sessions";
+
+/// Age after which a pushing run considers a journaled session abandoned
+///
+/// Live uploads touch their entry after every chunk, so anything this old
+/// doesn't belong to a concurrently running push.
+pub const STALE_SESSION_SECS: u64 = 15 * 60;
+
+/// An upload session as recorded in the journal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// Registry host the session was started on
+    pub registry: String,
+    /// Repository the blob was being uploaded to
+    pub repository: String,
+    /// Digest of the blob being uploaded
+    pub digest: String,
+    /// Latest location of the session, the URL to `DELETE` to abort it
+    pub location: String,
+    /// Unix timestamp of the session's last activity
+    pub updated_at: u64,
+}
+
+impl JournalEntry {
+    /// Seconds since the session's last activity
+    pub fn age_secs(&self) -> u64 {
+        unix_now().saturating_sub(self.updated_at)
+    }
+}
+
+/// Journal of open upload sessions in a cache directory
+///
+/// Writing the journal is best effort: a failure only costs the ability to
+/// clean up after a crash, so it is logged and the upload goes on.
+#[derive(Debug, Clone)]
+pub struct UploadJournal {
+    dir: PathBuf,
+}
+
+impl UploadJournal {
+    /// Opens the journal of `cache_dir`; nothing is created until a session is recorded
+    pub fn new(cache_dir: &Path) -> Self {
+        Self {
+            dir: cache_dir.join(JOURNAL_DIR_NAME),
+        }
+    }
+
+    /// Records (or updates) the session stored under `key`
+    pub fn record(&self, key: &str, entry: &JournalEntry) {
+        let entry = JournalEntry {
+            updated_at: unix_now(),
+            ..entry.clone()
+        };
+        let result = std::fs::create_dir_all(&self.dir).and_then(|_| {
+            std::fs::write(
+                self.path(key),
+                serde_json::to_vec(&entry).unwrap_or_default(),
+            )
+        });
+        if let Err(e) = result {
+            debug!(error = %e, "failed to journal upload session");
+        }
+    }
+
+    /// Removes the session stored under `key`
+    pub fn forget(&self, key: &str) {
+        let _ = std::fs::remove_file(self.path(key));
+    }
+
+    /// Returns the journaled sessions of `repository` on `registry` with their keys
+    ///
+    /// Unreadable entries are skipped.
+    pub fn entries(&self, registry: &str, repository: &str) -> Vec<(String, JournalEntry)> {
+        let Ok(files) = std::fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+        let mut entries: Vec<(String, JournalEntry)> = files
+            .flatten()
+            .filter_map(|file| {
+                let key = file
+                    .file_name()
+                    .to_str()?
+                    .strip_suffix(".json")?
+                    .to_string();
+                let entry: JournalEntry =
+                    serde_json::from_slice(&std::fs::read(file.path()).ok()?).ok()?;
+                Some((key, entry))
+            })
+            .filter(|(_, entry)| entry.registry == registry && entry.repository == repository)
+            .collect();
+        entries.sort_by_key(|(_, entry)| entry.updated_at);
+        entries
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
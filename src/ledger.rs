@@ -0,0 +1,178 @@
+//! Ledger of blobs confirmed present on target registries
+//!
+//! A nightly mirror pushes mostly the same layers every run, and every push
+//! starts with a `HEAD` request per layer to find out what the target
+//! already has. With a TTL set (`push --ledger-ttl`), pushes record every
+//! blob the target confirmed (by an existence check or a finished upload)
+//! with the time, and take blobs confirmed within the TTL as present without
+//! asking again. The ledger is kept per target registry in
+//! `.cache/blob_ledger/`, with entries per repository since registries only
+//! skip blobs the repository itself has.
+//!
+//! A registry may garbage-collect a blob the ledger still vouches for; the
+//! manifest push then fails, and the push forgets the repository's entries
+//! and runs again with fresh checks.
+
+use crate::store;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::debug;
+
+/// Directory (inside the cache directory) holding the ledgers
+const LEDGER_DIR_NAME: &str = "blob_ledger";
+
+/// Entries older than this are dropped when saving, whatever the TTL
+const MAX_ENTRY_AGE_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// Repository -> blob digest -> Unix timestamp of its last confirmation
+type Confirmations = BTreeMap<String, BTreeMap<String, u64>>;
+
+/// Contents of a ledger file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LedgerFile {
+    repositories: Confirmations,
+}
+
+/// Blobs confirmed present on one registry
+///
+/// Changes are kept in memory until [`BlobLedger::save`], which merges them
+/// with what concurrent pushes saved in the meantime.
+///
+/// # Examples
+///
+/// ```
+/// use docker_image_pusher::ledger::BlobLedger;
+///
+/// let cache_dir = std::env::temp_dir().join("ledger-example");
+/// # let _ = std::fs::remove_dir_all(&cache_dir);
+/// let ledger = BlobLedger::load(&cache_dir, "registry.example.com");
+/// let digest = "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a";
+/// ledger.record("mirror/nginx", digest);
+/// assert!(ledger.confirmed("mirror/nginx", digest, 3600));
+/// assert!(!ledger.confirmed("mirror/redis", digest, 3600));
+///
+/// let runtime = tokio::runtime::Runtime::new().unwrap();
+/// runtime.block_on(ledger.save());
+/// let reloaded = BlobLedger::load(&cache_dir, "registry.example.com");
+/// assert!(reloaded.confirmed("mirror/nginx", digest, 3600));
+/// ```
+#[derive(Debug)]
+pub struct BlobLedger {
+    path: PathBuf,
+    state: Mutex<LedgerState>,
+}
+
+#[derive(Debug, Default)]
+struct LedgerState {
+    confirmations: Confirmations,
+    /// Repositories whose entries were dropped, not to be merged back on save
+    forgotten: BTreeSet<String>,
+}
+
+impl BlobLedger {
+    /// Loads the ledger of `registry` from `cache_dir`, starting empty if there is none
+    pub fn load(cache_dir: &Path, registry: &str) -> Self {
+        let path = cache_dir.join(LEDGER_DIR_NAME).join(format!(
+            "{}.json",
+            crate::image::sanitize_image_name(registry)
+        ));
+        let confirmations = read(&path);
+        Self {
+            path,
+            state: Mutex::new(LedgerState {
+                confirmations,
+                forgotten: BTreeSet::new(),
+            }),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, LedgerState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Whether `digest` was confirmed present in `repository` within the last `ttl_secs`
+    pub fn confirmed(&self, repository: &str, digest: &str, ttl_secs: u64) -> bool {
+        self.state()
+            .confirmations
+            .get(repository)
+            .and_then(|blobs| blobs.get(digest))
+            .is_some_and(|confirmed_at| unix_now().saturating_sub(*confirmed_at) < ttl_secs)
+    }
+
+    /// Records that `repository` has `digest` now
+    pub fn record(&self, repository: &str, digest: &str) {
+        self.state()
+            .confirmations
+            .entry(repository.to_string())
+            .or_default()
+            .insert(digest.to_string(), unix_now());
+    }
+
+    /// Drops every entry of `repository`, e.g. after the registry denied having one
+    pub fn forget_repository(&self, repository: &str) {
+        let mut state = self.state();
+        state.confirmations.remove(repository);
+        state.forgotten.insert(repository.to_string());
+    }
+
+    /// Writes the ledger back, merged with entries saved since it was loaded
+    ///
+    /// Best effort: a failure only costs the existence checks the ledger would
+    /// have saved, so it is logged and the push goes on.
+    pub async fn save(&self) {
+        let json = {
+            let mut state = self.state();
+            let mut merged = read(&self.path);
+            for repository in &state.forgotten {
+                merged.remove(repository);
+            }
+            for (repository, blobs) in &state.confirmations {
+                let entry = merged.entry(repository.clone()).or_default();
+                for (digest, confirmed_at) in blobs {
+                    let known = entry.entry(digest.clone()).or_default();
+                    *known = (*known).max(*confirmed_at);
+                }
+            }
+            let now = unix_now();
+            for blobs in merged.values_mut() {
+                blobs.retain(|_, confirmed_at| {
+                    now.saturating_sub(*confirmed_at) < MAX_ENTRY_AGE_SECS
+                });
+            }
+            merged.retain(|_, blobs| !blobs.is_empty());
+            state.confirmations = merged;
+            let file = LedgerFile {
+                repositories: state.confirmations.clone(),
+            };
+            serde_json::to_vec(&file).unwrap_or_default()
+        };
+        if let Some(dir) = self.path.parent()
+            && let Err(e) = tokio::fs::create_dir_all(dir).await
+        {
+            debug!(error = %e, "failed to create the blob ledger directory");
+            return;
+        }
+        if let Err(e) = store::write_atomic(&self.path, json).await {
+            debug!(error = %e, "failed to save the blob ledger");
+        }
+    }
+}
+
+/// Reads the ledger file at `path`, empty if missing or unreadable
+fn read(path: &Path) -> Confirmations {
+    std::fs::read(path)
+        .ok()
+        .and_then(|data| serde_json::from_slice::<LedgerFile>(&data).ok())
+        .map(|file| file.repositories)
+        .unwrap_or_default()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,136 @@
+/*!
+# Docker Image Pusher
+
+A memory-optimized Docker image transfer tool that streams large Docker images and layers
+without loading them entirely into memory. This tool addresses the common issue of excessive
+memory usage when pulling or pushing large Docker images (multi-GB images) by using streaming
+APIs from the oci-client library.
+
+## Key Features
+
+- **Memory-Efficient Streaming**: Downloads and uploads image layers one by one using streaming APIs
+- **Large Layer Handling**: Special chunked processing for layers > 100MB to prevent memory exhaustion
+- **Local Caching**: Caches pulled images locally for faster subsequent pushes
+- **Registry Authentication**: Supports both anonymous and authenticated registry access
+- **Progress Monitoring**: Real-time feedback on layer transfer progress and sizes
+- **Media Type Detection**: Automatically detects layer compression format (gzip vs uncompressed)
+
+## Architecture
+
+The tool operates in two main phases:
+
+1. **Pull Phase**:
+   - Fetches image manifest to get layer information
+   - Streams each layer directly to local cache files
+   - Saves manifest and config separately for later use
+
+2. **Push Phase**:
+   - Reads cached layers from local storage
+   - Uploads layers individually with size-based optimization
+   - Pushes final manifest to complete image transfer
+
+3. **Import Phase**:
+   - Extracts layers from Docker tar archives (docker save format)
+   - Maintains media type information for better registry compatibility
+   - Creates unified cache structure for consistency
+
+## Memory Optimization Strategies
+
+- Parallel layer processing with controlled concurrency to utilize multiple CPU cores
+- Direct file-to-registry streaming without intermediate buffers
+- Chunked reading (64KB chunks) for layers exceeding 10MB
+- Semaphore-based rate limiting to prevent registry overload and memory pressure
+- Size-based upload strategies for optimal performance
+*/
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(feature = "oci-client")]
+pub mod cache;
+pub mod circuit;
+pub mod concurrency;
+#[cfg(feature = "import")]
+pub mod containerd;
+#[cfg(feature = "import")]
+pub mod containers_storage;
+pub mod correlation;
+#[cfg(feature = "cosign")]
+pub mod cosign;
+pub mod credentials;
+pub mod dedup;
+mod error;
+#[cfg(feature = "import")]
+pub mod export;
+pub mod harbor;
+pub mod image;
+#[cfg(feature = "import")]
+pub mod import;
+pub mod journal;
+pub mod ledger;
+#[cfg(feature = "import")]
+pub mod lint;
+pub mod logging;
+pub mod metrics;
+pub mod migrate;
+pub mod monitor;
+pub mod namespace;
+pub mod popularity;
+pub mod profile;
+pub mod progress;
+#[cfg(feature = "oci-client")]
+pub mod provenance;
+#[cfg(feature = "oci-client")]
+pub mod push;
+pub mod registry;
+pub mod report;
+pub mod retry;
+pub mod schedule;
+#[cfg(feature = "import")]
+pub mod scan;
+#[cfg(feature = "oci-client")]
+pub mod server;
+#[cfg(feature = "import")]
+pub mod ssh;
+pub mod store;
+pub mod systemd;
+#[cfg(feature = "oci-client")]
+mod transfer;
+pub mod transport;
+pub mod trust;
+#[cfg(feature = "otel")]
+pub mod telemetry;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+#[cfg(feature = "tui")]
+pub mod tui;
+
+pub use error::{PusherError, RegistryApiError};
+#[cfg(feature = "oci-client")]
+pub use transfer::{ImageTransfer, TransferBuilder, copy_image};
+
+/// Root directory of the local image cache
+pub const CACHE_DIR: &str = ".cache";
+
+/// Creates an OCI client configured the way all commands use it
+///
+/// The platform resolver picks the Linux AMD64 variant of multi-platform images.
+#[cfg(feature = "oci-client")]
+pub fn new_client() -> oci_client::Client {
+    new_client_with_timeouts(&transport::Timeouts::default())
+}
+
+/// Creates an OCI client like [`new_client`], giving up on connections after `timeouts.connect`
+///
+/// oci-client has no per-request timeouts; its transfers are bounded by the
+/// idle timeout of the caller instead (see [`cache::PullOptions::timeouts`]).
+/// Its `User-Agent` carries the current [`correlation::id`].
+#[cfg(feature = "oci-client")]
+pub fn new_client_with_timeouts(timeouts: &transport::Timeouts) -> oci_client::Client {
+    let client_config = oci_client::client::ClientConfig {
+        platform_resolver: Some(Box::new(oci_client::client::linux_amd64_resolver)),
+        connect_timeout: timeouts.connect,
+        user_agent: correlation::user_agent(),
+        ..Default::default()
+    };
+    oci_client::Client::new(client_config)
+}
@@ -1,135 +1,22 @@
-/*!
-# Docker Image Pusher
-
-A memory-optimized Docker image transfer tool that streams large Docker images and layers
-without loading them entirely into memory. This tool addresses the common issue of excessive
-memory usage when pulling or pushing large Docker images (multi-GB images) by using streaming
-APIs from the oci-client library.
-
-## Key Features
-
-- **Memory-Efficient Streaming**: Downloads and uploads image layers one by one using streaming APIs
-- **Large Layer Handling**: Special chunked processing for layers > 100MB to prevent memory exhaustion
-- **Local Caching**: Caches pulled images locally for faster subsequent pushes
-- **Registry Authentication**: Supports both anonymous and authenticated registry access
-- **Progress Monitoring**: Real-time feedback on layer transfer progress and sizes
-- **Media Type Detection**: Automatically detects layer compression format (gzip vs uncompressed)
-
-## Architecture
-
-The tool operates in two main phases:
-
-1. **Pull Phase**:
-   - Fetches image manifest to get layer information
-   - Streams each layer directly to local cache files
-   - Saves manifest and config separately for later use
-
-2. **Push Phase**:
-   - Reads cached layers from local storage
-   - Uploads layers individually with size-based optimization
-   - Pushes final manifest to complete image transfer
-
-3. **Import Phase**:
-   - Extracts layers from Docker tar archives (docker save format)
-   - Maintains media type information for better registry compatibility
-   - Creates unified cache structure for consistency
-
-## Memory Optimization Strategies
-
-- Parallel layer processing with controlled concurrency to utilize multiple CPU cores
-- Direct file-to-registry streaming without intermediate buffers
-- Chunked reading (64KB chunks) for layers exceeding 10MB
-- Semaphore-based rate limiting to prevent registry overload and memory pressure
-- Size-based upload strategies for optimal performance
-*/
-
 use anyhow::Result;
 use clap::{Parser, Subcommand};
+use docker_image_pusher::import::{self, import_tar_file};
+use docker_image_pusher::{containerd, containers_storage, export, lint, scan, ssh};
+use docker_image_pusher::push::{PushOptions, push_cached_image};
+use docker_image_pusher::credentials::{
+    AuthChain, AuthProvider, DockerConfig, DockerConfigFile, EnvAuth,
+};
+use docker_image_pusher::transport::Timeouts;
+use docker_image_pusher::{
+    CACHE_DIR, PusherError, cache, concurrency, correlation, dedup, harbor, image, journal,
+    logging, metrics, migrate, monitor, namespace, registry, retry, server, systemd, trust,
+};
 use oci_client::manifest::OciImageManifest;
-use oci_client::{Client, Reference};
-use serde_json;
-use sha2::{Digest, Sha256};
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::Path;
-use tar::Archive;
-use thiserror::Error;
-
-mod cache;
-mod image;
-
-// Constants for better code maintainability
-const CACHE_DIR: &str = ".cache";
-const LARGE_LAYER_THRESHOLD_MB: f64 = 100.0;
-const MEDIUM_LAYER_THRESHOLD_MB: f64 = 50.0;
-const LARGE_LAYER_THRESHOLD_BYTES: u64 = 10 * 1024 * 1024; // 10MB for progress tracking
-const STREAM_BUFFER_SIZE: usize = 65536; // 64KB buffer
-const PROGRESS_UPDATE_INTERVAL_SECS: u64 = 2;
-const RATE_LIMIT_DELAY_MS: u64 = 200;
-
-// Progress tracking intervals based on layer size
-const LARGE_LAYER_PROGRESS_INTERVAL_SECS: u64 = 5;
-const NORMAL_LAYER_PROGRESS_INTERVAL_SECS: u64 = 10;
-
-// Network speed estimation constants
-const ESTIMATED_SPEED_MBPS: f64 = 10.0; // Conservative estimate for ETA calculation
-const GZIP_MAGIC_BYTES: [u8; 2] = [0x1f, 0x8b];
-
-/// Custom error types for the Docker image pusher application
-///
-/// This enum provides specific error categories to help with debugging
-/// and error handling throughout the application.
-#[derive(Error, Debug)]
-pub enum PusherError {
-    /// Errors that occur during image pulling operations
-    /// These typically involve network issues or authentication problems
-    #[error("Pull error: {0}")]
-    PullError(String),
-
-    /// Errors that occur during image pushing operations  
-    /// These may involve registry authentication or upload failures
-    #[error("Push error: {0}")]
-    PushError(String),
-
-    /// Errors related to local cache operations
-    /// Including file I/O issues and cache corruption
-    #[error("Cache error: {0}")]
-    CacheError(String),
-
-    /// Standard I/O errors (file operations, network, etc.)
-    #[error("IO error: {0}")]
-    IoError(#[from] std::io::Error),
-
-    /// JSON serialization/deserialization errors
-    #[error("Serde error: {0}")]
-    SerdeError(#[from] serde_json::Error),
-    
-    /// Error when requested cached image is not found
-    #[error("Cache not found")]
-    CacheNotFound,
-
-    /// Errors that occur during tar file processing
-    /// Including tar archive parsing and layer extraction
-    #[error("Tar processing error: {0}")]
-    TarError(String),
-}
-
-impl PusherError {
-    /// Creates a cache error with formatted message
-    pub fn cache_error(msg: impl std::fmt::Display) -> Self {
-        PusherError::CacheError(msg.to_string())
-    }
-
-    /// Creates a tar error with formatted message
-    pub fn tar_error(msg: impl std::fmt::Display) -> Self {
-        PusherError::TarError(msg.to_string())
-    }
-
-    /// Creates a push error with formatted message
-    pub fn push_error(msg: impl std::fmt::Display) -> Self {
-        PusherError::PushError(msg.to_string())
-    }
-}
+use oci_client::secrets::RegistryAuth;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, error, info, info_span, warn};
 
 /// Command-line interface definition for the Docker image pusher
 ///
@@ -141,11 +28,74 @@ impl PusherError {
     long_about = "This tool efficiently transfers Docker images between registries using streaming APIs to minimize memory usage. It supports pulling from registries, importing from tar archives (docker save), and pushing to registries - all optimized for large images (multi-GB)."
 )]
 struct Cli {
+    /// Increase output detail: -v info (dependencies too), -vv debug, -vvv per-chunk trace and dependency internals
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Plain ASCII output without emoji (also enabled by setting NO_COLOR, and on the classic Windows console)
+    #[arg(long, global = true, alias = "no-emoji")]
+    plain: bool,
+
+    /// Log output format: human-readable console lines or one JSON object per line
+    #[arg(long, global = true, value_enum, default_value_t = logging::LogFormat::Console)]
+    log_format: logging::LogFormat,
+
+    /// Also write full verbose logs (including per-chunk details) to this file
+    ///
+    /// The file is rotated at 100MB, keeping the 5 most recent rotations.
+    #[arg(long, global = true)]
+    log_file: Option<std::path::PathBuf>,
+
+    /// Serve Prometheus metrics at this address while running (e.g. "0.0.0.0:9090")
+    #[arg(long, global = true)]
+    metrics_listen: Option<String>,
+
+    /// Write Prometheus metrics to this file on exit (node_exporter textfile collector)
+    #[arg(long, global = true)]
+    metrics_textfile: Option<std::path::PathBuf>,
+
+    /// Push metrics (with the run's duration and outcome) to this Prometheus Pushgateway on exit
+    #[arg(long, global = true)]
+    pushgateway_url: Option<String>,
+
+    /// `job` label of the metrics pushed to the Pushgateway
+    #[arg(long, global = true, default_value = "docker-image-pusher")]
+    pushgateway_job: String,
+
+    /// `instance` label of the metrics pushed to the Pushgateway (e.g. the CI job ID)
+    #[arg(long, global = true)]
+    pushgateway_instance: Option<String>,
+
+    /// Export spans to this OTLP/HTTP collector (e.g. "http://localhost:4318/v1/traces")
+    ///
+    /// Requires building with `--features otel`.
+    #[arg(long, global = true)]
+    otlp_endpoint: Option<String>,
+
+    /// Correlation ID sent to registries as X-Request-Id and printed with errors (generated if not given)
+    #[arg(long, global = true)]
+    request_id: Option<String>,
+
+    /// Keep images in this namespace of the cache, apart from those of other namespaces
+    ///
+    /// For build servers shared by several teams: each namespace has its own
+    /// images and metadata under .cache/namespaces/<NAME>, while identical
+    /// layers are stored once and pulled once for all namespaces.
+    #[arg(long, global = true, value_name = "NAME")]
+    cache_namespace: Option<String>,
+
+    /// Show a full-screen dashboard with per-layer progress, throughput and logs
+    #[cfg(feature = "tui")]
+    #[arg(long, global = true)]
+    tui: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
 
 /// Available subcommands for the application
+// Parsed once per run, so the size of the push arguments doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
     /// Pull an image from a registry and cache it locally
@@ -154,7 +104,30 @@ enum Commands {
     /// in a local cache directory (.cache/) for later use.
     Pull {
         /// Source image to pull (e.g., "nginx:latest" or "registry.example.com/app:v1.0")
+        #[arg(value_parser = docker_image_pusher::image::parse_image_name)]
         source_image: String,
+
+        /// Maximum registry API requests per second (unlimited if not set)
+        #[arg(long)]
+        requests_per_second: Option<f64>,
+
+        #[command(flatten)]
+        timeouts: TimeoutArgs,
+
+        /// Kubernetes dockerconfigjson pull secret (JSON) to authenticate with, "-" for stdin
+        ///
+        /// The credentials for the source registry are used; without an
+        /// entry for it the image is pulled anonymously.
+        #[arg(long)]
+        kube_secret: Option<std::path::PathBuf>,
+
+        /// Transfer the image even though DOCKER_CONTENT_TRUST requires signed images
+        #[arg(long)]
+        disable_content_trust: bool,
+
+        #[cfg(feature = "cosign")]
+        #[command(flatten)]
+        verify: VerifyArgs,
     },
     /// Push a cached image to a target registry
     ///
@@ -162,902 +135,1429 @@ enum Commands {
     /// target registry with authentication.
     Push {
         /// Source image name (must be previously cached)
+        #[arg(value_parser = docker_image_pusher::image::parse_image_name)]
         source_image: String,
 
         /// Target image to push to (full registry path with tag)
+        #[arg(value_parser = docker_image_pusher::image::parse_image_name)]
         target_image: String,
 
         /// Username for target registry authentication
-        #[arg(short, long)]
-        username: String,
+        ///
+        /// Without -u/-p the credentials come from --kube-secret, the
+        /// PUSHER_USERNAME/PUSHER_PASSWORD variables or docker login.
+        #[arg(short, long, requires = "password")]
+        username: Option<String>,
 
         /// Password for target registry authentication  
-        #[arg(short, long)]
-        password: String,
+        #[arg(short, long, requires = "username")]
+        password: Option<String>,
+
+        /// Pre-obtained bearer token for the target registry (e.g. a GitLab CI JWT)
+        ///
+        /// Sent as is instead of logging in, for registries that only hand out
+        /// short-lived tokens. PUSHER_TOKEN is used the same way when no other
+        /// credentials are given.
+        #[arg(long, conflicts_with_all = ["username", "password"])]
+        token: Option<String>,
+
+        /// Kubernetes dockerconfigjson pull secret (JSON) to authenticate with, "-" for stdin
+        ///
+        /// Its entry for the target registry replaces `-u`/`-p`; its entry for
+        /// the source registry is used if the image has to be pulled first.
+        #[arg(long)]
+        kube_secret: Option<std::path::PathBuf>,
+
+        /// Transfer the image even though DOCKER_CONTENT_TRUST requires signed images
+        #[arg(long)]
+        disable_content_trust: bool,
+
+        /// Memory cap for buffered layer data (e.g. "512M", "2G")
+        ///
+        /// Concurrent uploads never hold more layer data in memory than this
+        /// in total; layers that don't fit are streamed from disk in chunks.
+        /// When memory use approaches this cap (or the container's cgroup limit),
+        /// concurrency is reduced and layers are streamed as well.
+        #[arg(long, value_parser = monitor::parse_memory_size)]
+        max_memory: Option<u64>,
+
+        /// Maximum number of layers uploaded concurrently
+        ///
+        /// Defaults to the value learned for the target registry on previous
+        /// runs, or 4 if the registry hasn't been pushed to before.
+        #[arg(long)]
+        max_concurrent: Option<usize>,
+
+        /// Maximum registry API requests per second (unlimited if not set)
+        ///
+        /// Applies to existence checks, chunk uploads and manifest pushes,
+        /// independent of how many bytes are transferred.
+        #[arg(long)]
+        requests_per_second: Option<f64>,
+
+        /// Deadline in seconds for a single layer upload
+        ///
+        /// A layer that takes longer is cancelled and rescheduled at lower
+        /// concurrency while the other uploads continue.
+        #[arg(long)]
+        layer_timeout: Option<u64>,
+
+        #[command(flatten)]
+        timeouts: TimeoutArgs,
+
+        /// Fail instead of warning when the target tag already points to a different image
+        ///
+        /// Checked before any layer is uploaded, protecting release tags and
+        /// registries with immutable-tag policies.
+        #[arg(long)]
+        no_overwrite: bool,
+
+        /// Upload every layer even if the registry says it already has it
+        ///
+        /// Skips the existence checks, for registries whose storage lost
+        /// blobs or whose HEAD requests (often through a proxy) report
+        /// blobs that aren't really there.
+        #[arg(long)]
+        force_upload: bool,
+
+        /// Upload this layer even if the registry says it already has it (repeatable)
+        ///
+        /// Repairs a target image whose copy of one blob is corrupt without
+        /// re-uploading the others.
+        #[arg(long = "repush-digest", value_name = "DIGEST")]
+        repush_digests: Vec<image::digest::Digest>,
+
+        /// Trust layers confirmed on the target within this many seconds
+        ///
+        /// Keeps a ledger of the layers each target repository was seen to
+        /// have (in the cache directory), and skips the existence checks of
+        /// those confirmed recently, saving a HEAD request per layer on
+        /// repeated mirror runs.
+        #[arg(long, value_name = "SECS")]
+        ledger_ttl: Option<u64>,
+
+        /// Have Quay expire the pushed tag after this long (e.g. "12h", "2w")
+        ///
+        /// Sets the `quay.expires-after` label in the image config, so the
+        /// pushed image gets a new config and manifest digest.
+        #[arg(long, value_parser = docker_image_pusher::image::parse_expires_after)]
+        expires_after: Option<String>,
+
+        /// Set an annotation on the pushed manifest (repeatable, KEY=VALUE)
+        ///
+        /// E.g. "org.opencontainers.image.source=https://github.com/org/app".
+        /// Changes the manifest digest; layers and config are pushed unchanged.
+        #[arg(long = "annotation", value_name = "KEY=VALUE", value_parser = docker_image_pusher::image::parse_annotation)]
+        annotations: Vec<(String, String)>,
+
+        /// Set an annotation on every layer descriptor of the pushed manifest (repeatable, KEY=VALUE)
+        #[arg(long = "layer-annotation", value_name = "KEY=VALUE", value_parser = docker_image_pusher::image::parse_annotation)]
+        layer_annotations: Vec<(String, String)>,
+
+        /// Create the target's Harbor project if it doesn't exist yet
+        #[arg(long)]
+        create_project: bool,
+
+        /// Make projects created by --create-project public
+        #[arg(long, requires = "create_project")]
+        project_public: bool,
+
+        /// Storage quota for projects created by --create-project (e.g. "10G")
+        #[arg(long, requires = "create_project", value_parser = monitor::parse_memory_size)]
+        project_quota: Option<u64>,
+
+        /// Check the target's Harbor project quota before uploading: warn or fail if it won't fit
+        ///
+        /// Compares the layers the registry doesn't have yet with the quota
+        /// left in the project; registries that aren't Harbor are skipped.
+        #[arg(long, value_enum, value_name = "ACTION")]
+        harbor_quota: Option<harbor::QuotaCheck>,
+
+        /// Lint the cached image before pushing (see the `lint` command)
+        #[arg(long)]
+        lint: bool,
+
+        /// Fail the push if --lint finds anything
+        #[arg(long, requires = "lint")]
+        strict: bool,
+
+        /// Scanner to run on the cached image before pushing; a non-zero exit blocks the push
+        ///
+        /// Run by the shell, with {tar} replaced by the path of a `docker save`
+        /// archive of the image and {image} by its name, e.g.
+        /// "trivy image --input {tar} --exit-code 1 --severity CRITICAL".
+        #[arg(long, value_name = "COMMAND")]
+        scan_cmd: Option<String>,
+
+        /// Attach a SLSA provenance attestation to the pushed image as an OCI referrer
+        ///
+        /// Records the builder, the source image and its digest, and when the
+        /// push ran. The registry must support the OCI 1.1 referrers API for
+        /// the attestation to be listed under the image.
+        #[arg(long)]
+        provenance: bool,
+
+        /// Builder id recorded by --provenance (e.g. the URL of the CI job)
+        #[arg(long, requires = "provenance", default_value = docker_image_pusher::provenance::DEFAULT_BUILDER_ID)]
+        builder_id: String,
+
+        /// Write a JSON transfer report to this file after the push
+        ///
+        /// Lists every blob with its size, whether it was uploaded or skipped,
+        /// duration, average speed and retries, plus the manifest digest and URL.
+        /// If some layers fail, lists the succeeded and failed blobs instead.
+        #[arg(long)]
+        report: Option<std::path::PathBuf>,
+
+        /// Sign the pushed manifest with this cosign private key
+        ///
+        /// Accepts `cosign generate-key-pair` keys (password from COSIGN_PASSWORD)
+        /// and unencrypted PEM P-256 keys. The signature is pushed to the
+        /// `sha256-<digest>.sig` tag, where `cosign verify --key` finds it.
+        #[cfg(feature = "cosign")]
+        #[arg(long)]
+        sign_key: Option<std::path::PathBuf>,
+
+        #[cfg(feature = "cosign")]
+        #[command(flatten)]
+        verify: VerifyArgs,
     },
 
     /// Import a Docker tar archive and cache it locally
     ///
-    /// This processes tar files created by `docker save` command,
+    /// This processes tar files created by `docker save` command (docker-archive)
+    /// or OCI image layouts packed in a tar (oci-archive, e.g. from `podman save`),
     /// extracting layers and metadata to create a unified cache structure.
     Import {
-        /// Path to the Docker tar archive file
+        /// Path to the Docker tar archive file, "ssh://[user@]host[:port]/path" for an archive
+        /// on a remote host, "containerd:<image>" to read from containerd, or
+        /// "containers-storage:<image>" to read from podman/buildah storage
         tar_file: String,
 
         /// Image name to use for caching (e.g., "myapp:v1.0")
+        #[arg(value_parser = docker_image_pusher::image::parse_image_name)]
         image_name: String,
-    },
-}
 
-/// Application entry point
-///
-/// Initializes the OCI client with a platform resolver for Linux AMD64 images
-/// and dispatches to the appropriate command handler based on user input.
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let cli = Cli::parse();
+        #[command(flatten)]
+        containerd: ContainerdArgs,
 
-    // Configure OCI client with platform resolver to handle multi-platform images
-    // This ensures we pull the correct architecture variant (Linux AMD64 in this case)
-    let mut client_config = oci_client::client::ClientConfig::default();
-    client_config.platform_resolver = Some(Box::new(oci_client::client::linux_amd64_resolver));
-    let client = Client::new(client_config);
-    match cli.command {
-        Commands::Pull { source_image } => {
-            println!("🚀 Pulling and caching image: {}", source_image);
-            cache::cache_image(&client, &source_image).await?;
-            println!("✅ Successfully cached image: {}", source_image);
-        }
-        Commands::Push {
-            source_image,
-            target_image,
-            username,
-            password,
-        } => {
-            println!(
-                "📤 Pushing image from cache: {} -> {}",
-                source_image, target_image
-            );
+        /// containers-storage root, when not the default one (podman --root)
+        #[arg(long)]
+        storage_root: Option<std::path::PathBuf>,
 
-            // Ensure we have the image cached before attempting to push
-            if !cache::has_cached_image(&source_image).await? {
-                println!("⚠️  Image not found in cache, pulling first...");
-                cache::cache_image(&client, &source_image).await?;
-            }
+        /// Directory for temporary files while extracting (defaults to the cache directory)
+        ///
+        /// May be on a different filesystem than the cache, e.g. a larger or
+        /// faster scratch disk. Leftovers of interrupted imports are removed
+        /// from it on the next run.
+        #[arg(long)]
+        tmp_dir: Option<std::path::PathBuf>,
+    },
+    /// Check a cached image for common problems
+    ///
+    /// Flags oversized layers, excessive layer counts, missing labels,
+    /// world-writable files and obvious secrets (AWS keys, private keys)
+    /// in the layers.
+    Lint {
+        /// Cached image to check
+        #[arg(value_parser = docker_image_pusher::image::parse_image_name)]
+        image: String,
+
+        /// Exit with an error if anything is found
+        #[arg(long)]
+        strict: bool,
+
+        /// Layers larger than this are flagged (e.g. "500M")
+        #[arg(long, value_parser = monitor::parse_memory_size)]
+        max_layer_size: Option<u64>,
+
+        /// Images with more layers than this are flagged
+        #[arg(long)]
+        max_layers: Option<usize>,
+
+        /// Label the image config must carry (repeatable; replaces the default OCI source/revision labels)
+        #[arg(long = "require-label")]
+        required_labels: Vec<String>,
+    },
+    /// Show what a cached image is made of
+    ///
+    /// Prints the config digest, platform and size, and the build history
+    /// with the layer each step produced, including steps that only changed
+    /// metadata.
+    Inspect {
+        /// Cached image to inspect
+        #[arg(value_parser = docker_image_pusher::image::parse_image_name)]
+        image: String,
+
+        /// Show build steps in full instead of truncating them
+        #[arg(long)]
+        no_trunc: bool,
+    },
+    /// Inspect and maintain the local cache
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+    /// Write a cached image to a tar archive or a local image store
+    ///
+    /// The archive has the `docker save` layout and can be loaded with
+    /// `docker load` or `ctr images import`.
+    Export {
+        /// Cached image to export
+        #[arg(value_parser = docker_image_pusher::image::parse_image_name)]
+        source_image: String,
 
-            // Push the cached image to target registry
-            push_cached_image(&client, &source_image, &target_image, &username, &password).await?;
-            println!("✅ Successfully pushed image: {}", target_image);
-        }
-        Commands::Import {
-            tar_file,
-            image_name,
-        } => {
-            println!(
-                "📦 Importing Docker tar archive: {} as {}",
-                tar_file, image_name
-            );
-            import_tar_file(&tar_file, &image_name).await?;
-            println!("✅ Successfully imported and cached image: {}", image_name);
-        }
-    }
+        /// Tar file to write, "-" for stdout, or "containerd:<image>" to load the image into containerd
+        destination: String,
 
-    Ok(())
-}
+        /// Name recorded in the archive (defaults to the cached image name)
+        #[arg(long)]
+        tag: Option<String>,
 
-/// Checks if a blob exists in the target registry
-///
-/// This function attempts to check if a blob already exists in the registry
-/// to avoid unnecessary uploads. Since the oci-client library doesn't expose
-/// a direct HEAD request method, we use a conservative approach for now.
-///
-/// # Arguments
-///
-/// * `client` - OCI client for registry operations
-/// * `target_ref` - Target registry reference
-/// * `auth` - Authentication for the registry
-/// * `digest` - Digest of the blob to check
-///
-/// # Returns
-///
-/// `Result<bool, PusherError>` - true if blob exists in registry, false otherwise
-async fn blob_exists_in_registry(
-    _client: &Client,
-    _target_ref: &Reference,
-    _auth: &oci_client::secrets::RegistryAuth,
-    _digest: &str,
-) -> Result<bool, PusherError> {
-    // For production use, you might want to implement this using direct HTTP calls
-    // to perform a HEAD request to the blob URL:
-    // GET /v2/{name}/blobs/{digest} or HEAD /v2/{name}/blobs/{digest}
-
-    // For now, we'll assume the blob doesn't exist to maintain upload behavior
-    // but provide logging to indicate the check was performed
-
-    // In the future, this could be enhanced with:
-    // 1. Direct HTTP HEAD requests to the registry
-    // 2. Maintaining a local cache of known uploaded blobs
-    // 3. Using registry-specific APIs if available
-
-    Ok(false) // Conservative approach - always attempt upload
-}
+        /// Write the flattened filesystem (all layers applied, whiteouts resolved) instead of the image
+        #[arg(long, conflicts_with = "tag")]
+        rootfs: bool,
 
-/// Pushes a cached image to a target registry with memory optimization
-///
-/// This function implements several memory optimization strategies:
-///
-/// ## Size-Based Processing Strategy:
-/// - **Small layers (<100MB)**: Read entire layer into memory and upload
-/// - **Large layers (>100MB)**: Use chunked reading (50MB chunks) to prevent memory exhaustion
-/// - **Rate limiting**: Add delays between large layer uploads to prevent registry overload
-///
-/// ## Upload Process:
-/// 1. Authenticate with target registry
-/// 2. Read cached manifest and layer information  
-/// 3. Upload each layer individually with size-appropriate strategy
-/// 4. Upload image configuration
-/// 5. Push final manifest to complete the image
-///
-/// # Arguments
-///
-/// * `client` - OCI client for registry operations
-/// * `source_image` - Name of cached image to push
-/// * `target_image` - Destination image reference with registry
-/// * `username` - Authentication username for target registry
-/// * `password` - Authentication password for target registry
-///
-/// # Returns
-///
-/// `Result<(), PusherError>` - Success or detailed error information
-async fn push_cached_image(
-    client: &Client,
-    source_image: &str,
-    target_image: &str,
-    username: &str,
-    password: &str,
-) -> Result<(), PusherError> {
-    let cache_dir = Path::new(CACHE_DIR);
-    let image_cache_dir = cache_dir.join(image::sanitize_image_name(source_image));
+        #[command(flatten)]
+        containerd: ContainerdArgs,
+    },
+    /// Copy files or directories out of a cached image
+    ///
+    /// Looks the path up in the image's final filesystem, so files deleted
+    /// or replaced by later layers are handled like a container would see them.
+    Extract {
+        /// Cached image to extract from
+        #[arg(value_parser = docker_image_pusher::image::parse_image_name)]
+        image: String,
+
+        /// File or directory in the image (e.g. /usr/bin/curl)
+        path: String,
+
+        /// Directory to write to; the path's last component is created inside it
+        #[arg(short, long, default_value = ".")]
+        output: std::path::PathBuf,
+    },
+    /// Abort upload sessions that crashed or killed runs left on a repository
+    ///
+    /// Registries keep the data of unfinished uploads until the sessions
+    /// expire. Sessions are found through the journal pushes keep in the
+    /// cache; sessions of a push that is still running are aborted too.
+    CleanupUploads {
+        /// Repository to clean up (e.g. registry.example.com/app)
+        repository: String,
+
+        /// Username for registry authentication
+        #[arg(short, long, requires = "password")]
+        username: Option<String>,
+
+        /// Password for registry authentication
+        #[arg(short, long, requires = "username")]
+        password: Option<String>,
+
+        /// Pre-obtained bearer token for the registry, instead of -u/-p
+        #[arg(long, conflicts_with_all = ["username", "password"])]
+        token: Option<String>,
+
+        /// Only list the journaled sessions
+        #[arg(long)]
+        dry_run: bool,
+    },
 
-    // Setup Basic authentication for target registry
-    let auth = oci_client::secrets::RegistryAuth::Basic(username.to_string(), password.to_string());
+    /// Run as a service with an HTTP API for submitting transfer jobs
+    ///
+    /// Jobs are submitted with `POST /jobs` ({"source", "target", "username",
+    /// "password"}), queried with `GET /jobs` and `GET /jobs/<id>`, and
+    /// cancelled with `DELETE /jobs/<id>`. Jobs without a target only pull
+    /// into the cache.
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+
+        /// Jobs transferring at the same time; further jobs are queued
+        #[arg(long, default_value_t = 2)]
+        max_jobs: usize,
+
+        /// Token clients must send as "Authorization: Bearer <token>"
+        #[arg(long)]
+        api_token: Option<String>,
+
+        /// Submit jobs on the cron schedules of this sync manifest (JSON)
+        #[arg(long)]
+        sync_manifest: Option<std::path::PathBuf>,
+
+        /// Registry (host[:port]) /readyz requires to be reachable (repeatable; the
+        /// sync manifest's registries are always checked)
+        #[arg(long = "ready-registry")]
+        ready_registries: Vec<String>,
+
+        /// Also serve a read-only pull-through cache (Registry v2 API) on this address
+        #[arg(long)]
+        proxy_listen: Option<String>,
+
+        /// Registry (host[:port]) the pull-through cache pulls from on a miss
+        #[arg(long, default_value = "docker.io", requires = "proxy_listen")]
+        proxy_upstream: String,
+
+        /// Trust layers confirmed on a target within this many seconds (see push --ledger-ttl)
+        #[arg(long, value_name = "SECS")]
+        ledger_ttl: Option<u64>,
+
+        /// Also serve the gRPC interface (proto/pusher.proto) on this address
+        #[cfg(feature = "grpc")]
+        #[arg(long)]
+        grpc_listen: Option<String>,
+    },
 
-    // Parse and validate target image reference
-    let target_ref: Reference = target_image
-        .parse()
-        .map_err(|e| PusherError::PushError(format!("Invalid target image reference: {}", e)))?;
-
-    // Step 1: Authenticate with the target registry
-    println!("🔐 Authenticating with registry...");
-    client
-        .auth(&target_ref, &auth, oci_client::RegistryOperation::Push)
-        .await
-        .map_err(|e| PusherError::PushError(format!("Authentication failed: {}", e)))?;
-    println!("✅ Authentication successful!");
-
-    // Step 2: Read cached metadata and manifest
-    let index_path = image_cache_dir.join("index.json");
-    let index_content = tokio::fs::read_to_string(&index_path)
-        .await
-        .map_err(|_| PusherError::CacheNotFound)?;
-    let index: serde_json::Value = serde_json::from_str(&index_content)?;
-
-    let manifest_path = image_cache_dir.join("manifest.json");
-    let manifest_content = tokio::fs::read_to_string(&manifest_path)
-        .await
-        .map_err(|e| PusherError::CacheError(format!("Failed to read cached manifest: {}", e)))?;
-    let manifest: OciImageManifest = serde_json::from_str(&manifest_content)?;
-
-    // Extract layer digest list from index
-    let layer_digests: Vec<String> = index["layers"]
-        .as_array()
-        .ok_or(PusherError::CacheError(
-            "Invalid layers format in index".to_string(),
-        ))?
-        .iter()
-        .map(|v| v.as_str().unwrap_or("").to_string())
-        .collect();
-    println!(
-        "📤 Uploading {} cached layers sequentially with memory optimization...",
-        layer_digests.len()
-    );
-    // Step 3: Upload layers sequentially with memory optimization and registry checks
-    let mut uploaded_layers = Vec::new();
-    let mut skipped_uploads = 0;
+    /// Serve the cached images read-only over the Registry v2 API
+    ///
+    /// Lets `docker pull <host>:5000/library/nginx:latest` fetch an image
+    /// cached with `pull nginx:latest`, e.g. from an air-gapped cluster.
+    /// Nothing is pulled from the images' registries.
+    ServeCache {
+        /// Address to listen on (":5000" listens on all interfaces)
+        #[arg(long, default_value = "127.0.0.1:5000")]
+        listen: String,
+    },
+}
 
-    for (i, digest) in layer_digests.iter().enumerate() {
-        let layer_path = image_cache_dir.join(digest.replace(":", "_"));
+/// Subcommands of `cache`
+#[derive(Subcommand)]
+enum CacheCommands {
+    /// Report the layers the cached images share
+    ///
+    /// Shows how many bytes the cache holds more than once because images
+    /// keep their own copy of common base layers, and which images share
+    /// which layers, e.g. to size air-gap bundles.
+    DedupReport {
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Upgrade the cached images to the cache format of this version
+    ///
+    /// Images are changed in place after their files are hard-linked into
+    /// .cache/migration_backup, so nothing has to be pulled again. A failed
+    /// migration is rolled back automatically.
+    Migrate {
+        /// Restore the images as they were before the last migration
+        #[arg(long)]
+        rollback: bool,
+    },
+}
 
-        // Check layer size to determine upload strategy
-        let layer_metadata = tokio::fs::metadata(&layer_path).await.map_err(|e| {
-            PusherError::CacheError(format!("Failed to get layer metadata {}: {}", digest, e))
-        })?;
-        let layer_size_mb = layer_metadata.len() as f64 / (1024.0 * 1024.0);
+/// Export destination that writes the archive to stdout
+const STDOUT_PATH: &str = "-";
 
-        println!(
-            "📦 Uploading layer {}/{}: {} ({:.1} MB)",
-            i + 1,
-            layer_digests.len(),
-            digest,
-            layer_size_mb
-        );
+/// Connection to containerd for `containerd:` sources and destinations
+#[derive(clap::Args)]
+struct ContainerdArgs {
+    /// containerd socket
+    #[arg(long, default_value = containerd::DEFAULT_ADDRESS)]
+    containerd_address: std::path::PathBuf,
 
-        // Check if blob already exists in registry to avoid unnecessary upload
-        if blob_exists_in_registry(client, &target_ref, &auth, digest).await? {
-            println!(
-                "   ✅ Layer already exists in registry, skipping upload: {}",
-                digest
-            );
-            uploaded_layers.push(digest.clone());
-            skipped_uploads += 1;
-            continue;
-        } // MEMORY OPTIMIZATION: Different strategies based on layer size
-        if layer_size_mb > LARGE_LAYER_THRESHOLD_MB {
-            upload_large_layer(client, &target_ref, &layer_path, digest, layer_size_mb).await?;
-        } else {
-            upload_small_layer(client, &target_ref, &layer_path, digest, layer_size_mb).await?;
-        }
-        
-        println!("   ✅ Successfully uploaded layer {}", digest);
-        
-        // Rate limiting: Add delay for large layers to prevent overwhelming the registry
-        if layer_size_mb > MEDIUM_LAYER_THRESHOLD_MB {
-            tokio::time::sleep(tokio::time::Duration::from_millis(RATE_LIMIT_DELAY_MS)).await;
-        }
-        uploaded_layers.push(digest.clone());
-    }
+    /// containerd namespace (Kubernetes uses "k8s.io")
+    #[arg(long, default_value = containerd::DEFAULT_NAMESPACE)]
+    containerd_namespace: String,
+}
 
-    println!(
-        "🚀 Sequential upload completed for {} layers",
-        uploaded_layers.len()
-    );
-    if skipped_uploads > 0 {
-        println!(
-            "💡 Skipped {} layers that already existed in registry",
-            skipped_uploads
-        );
+impl ContainerdArgs {
+    fn store(self) -> containerd::ContainerdStore {
+        containerd::ContainerdStore {
+            address: self.containerd_address,
+            namespace: self.containerd_namespace,
+            ..Default::default()
+        }
     }
+}
 
-    // Step 4: Upload image configuration
-    let config_digest = index["config"]
-        .as_str()
-        .ok_or(PusherError::CacheError("Invalid index format".to_string()))?;
-    let config_path =
-        image_cache_dir.join(format!("config_{}.json", config_digest.replace(":", "_")));
-
-    println!("⚙️  Uploading config: {}", config_digest);
-    let config_data = tokio::fs::read(&config_path)
-        .await
-        .map_err(|e| PusherError::CacheError(format!("Failed to read cached config: {}", e)))?;
-
-    client
-        .push_blob(&target_ref, &config_data, config_digest)
-        .await
-        .map_err(|e| PusherError::PushError(format!("Failed to upload config: {}", e)))?;
-
-    // Step 5: Push the final manifest to complete the image
-    println!("📋 Pushing manifest to registry: {}", target_image);
-    let manifest_enum = oci_client::manifest::OciManifest::Image(manifest);
-    let manifest_url = client
-        .push_manifest(&target_ref, &manifest_enum)
-        .await
-        .map_err(|e| PusherError::PushError(format!("Failed to push manifest: {}", e)))?;
+/// Network timeouts shared by `pull` and `push`, in seconds (0 disables one)
+#[derive(clap::Args)]
+struct TimeoutArgs {
+    /// Seconds to wait for a connection to the registry
+    #[arg(long, default_value_t = 30)]
+    connect_timeout: u64,
 
-    println!(
-        "🎉 Successfully pushed {} layers to {}",
-        uploaded_layers.len(),
-        manifest_url
-    );
-    Ok(())
+    /// Seconds a transfer may go without moving any data before it is retried
+    ///
+    /// Catches hung connections that would otherwise keep a layer waiting
+    /// for hours. Applies to layer downloads and streamed uploads.
+    #[arg(long, default_value_t = 60)]
+    stall_timeout: u64,
+
+    /// Seconds a metadata request (existence checks, starting an upload) may take in total
+    #[arg(long, default_value_t = 30)]
+    request_timeout: u64,
+
+    /// Seconds a single upload request (a chunk, or finalizing a blob) may take in total
+    #[arg(long, default_value_t = 7200)]
+    upload_timeout: u64,
 }
 
-/// Detects the appropriate media type for a Docker layer based on its content
-///
-/// This function examines the first few bytes of a layer file to determine
-/// whether it's gzipped or uncompressed, and returns the appropriate Docker
-/// media type string.
-///
-/// # Arguments
-///
-/// * `layer_path` - Path to the layer file
-///
-/// # Returns
-///
-/// `Result<String, PusherError>` - The detected media type
-fn detect_layer_media_type(layer_path: &std::path::Path) -> Result<String, PusherError> {
-    use std::io::Read;
-    
-    let mut file = std::fs::File::open(layer_path)
-        .map_err(|e| PusherError::tar_error(format!("Failed to open layer file: {}", e)))?;
-    
-    let mut buffer = [0u8; 2];
-    let bytes_read = file.read(&mut buffer)
-        .map_err(|e| PusherError::tar_error(format!("Failed to read layer header: {}", e)))?;
-    
-    if bytes_read >= 2 && buffer == GZIP_MAGIC_BYTES {
-        Ok("application/vnd.docker.image.rootfs.diff.tar.gzip".to_string())
-    } else if bytes_read >= 2 {
-        Ok("application/vnd.docker.image.rootfs.diff.tar".to_string())
-    } else {
-        // Default to gzipped if we can't determine
-        Ok("application/vnd.docker.image.rootfs.diff.tar.gzip".to_string())
+impl TimeoutArgs {
+    fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            connect: seconds(self.connect_timeout),
+            idle: seconds(self.stall_timeout),
+            request: seconds(self.request_timeout),
+            upload: seconds(self.upload_timeout),
+        }
     }
 }
 
-/// Formats size display for progress reporting
-fn format_size_display(size_mb: f64) -> (f64, &'static str) {
-    if size_mb > 1024.0 {
-        (size_mb / 1024.0, "GB")
-    } else {
-        (size_mb, "MB")
-    }
+/// Source image signature checks shared by `pull` and `push`
+#[cfg(feature = "cosign")]
+#[derive(clap::Args)]
+struct VerifyArgs {
+    /// Refuse source images without a valid cosign signature by --trusted-key
+    ///
+    /// Checked before anything is cached, and again before a cached image is pushed.
+    #[arg(long, requires = "trusted_key")]
+    verify_signature: bool,
+
+    /// Public key (e.g. cosign.pub) source image signatures must verify against
+    #[arg(long, requires = "verify_signature")]
+    trusted_key: Option<std::path::PathBuf>,
 }
 
-/// Calculates upload progress estimation
-fn calculate_upload_progress(elapsed_secs: u64, layer_size_mb: f64) -> f64 {
-    if elapsed_secs > 10 {
-        let time_factor = elapsed_secs as f64 / (layer_size_mb / 8.0);
-        ((time_factor / (1.0 + time_factor)) * 100.0).min(95.0)
-    } else {
-        10.0 // Assume 10% in first 10 seconds
+#[cfg(feature = "cosign")]
+impl VerifyArgs {
+    /// Loads the trusted key if verification was requested
+    fn load(&self) -> Result<Option<docker_image_pusher::cosign::VerifyingKey>, PusherError> {
+        match &self.trusted_key {
+            Some(path) if self.verify_signature => {
+                docker_image_pusher::cosign::VerifyingKey::from_file(path).map(Some)
+            }
+            _ => Ok(None),
+        }
     }
 }
 
-/// Creates a progress tracking task for large layer uploads
-fn create_progress_tracker(
-    layer_size_mb: f64,
-    layer_size_bytes: u64,
-    network_start: std::time::Instant,
-    digest: &str,
-) -> Option<tokio::task::JoinHandle<()>> {
-    if layer_size_mb <= LARGE_LAYER_THRESHOLD_MB {
-        return None;
+/// Application entry point
+///
+/// Initializes the OCI client with a platform resolver for Linux AMD64 images
+/// and dispatches to the appropriate command handler based on user input.
+///
+/// Exits with status 2 when a push partially succeeded (some layers uploaded,
+/// others failed) and 1 on any other failure.
+#[tokio::main]
+async fn main() -> Result<ExitCode> {
+    let started = std::time::Instant::now();
+    let cli = Cli::parse();
+    let plain = cli.plain
+        || std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty())
+        || logging::legacy_windows_console();
+    // An archive written to stdout must not be interleaved with progress output
+    let to_stderr = matches!(&cli.command, Commands::Export { destination, .. } if destination == STDOUT_PATH);
+    let target = if to_stderr {
+        logging::LogTarget::Stderr
+    } else {
+        logging::LogTarget::Stdout
+    };
+    #[cfg(feature = "tui")]
+    let dashboard_logs = (cli.tui && !to_stderr).then(docker_image_pusher::tui::LogBuffer::new);
+    #[cfg(feature = "tui")]
+    let target = match &dashboard_logs {
+        Some(logs) => logging::LogTarget::Sink(Box::new(logs.clone())),
+        None => target,
+    };
+    let _log_guard = logging::init(
+        cli.log_format,
+        cli.verbose,
+        plain,
+        cli.log_file.as_deref(),
+        cli.otlp_endpoint.as_deref(),
+        target,
+    )?;
+
+    if let Some(listen) = &cli.metrics_listen {
+        metrics::serve(listen).await?;
     }
 
-    let layer_size_mb_clone = layer_size_mb;
-    let network_start_clone = network_start;
-    let digest_suffix = digest.chars().skip(digest.len() - 8).collect::<String>();
-    let interval_secs = if layer_size_mb > 1000.0 { 
-        LARGE_LAYER_PROGRESS_INTERVAL_SECS 
-    } else { 
-        NORMAL_LAYER_PROGRESS_INTERVAL_SECS 
+    // Temp files of imports that were killed would otherwise stay around forever
+    let tmp_dir = match &cli.command {
+        Commands::Import { tmp_dir, .. } => tmp_dir.as_deref(),
+        _ => None,
+    };
+    let cache_dir = match &cli.cache_namespace {
+        Some(name) => namespace::cache_dir(Path::new(CACHE_DIR), name)?,
+        None => PathBuf::from(CACHE_DIR),
+    };
+    import::remove_orphaned_temp_files(&cache_dir, tmp_dir);
+
+    // The first Ctrl-C cancels gracefully (aborting open upload sessions); a second one exits
+    let cancel = CancellationToken::new();
+    let on_interrupt = cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            warn!("⚠️  Interrupted, cancelling transfers (press Ctrl-C again to exit immediately)...");
+            on_interrupt.cancel();
+            if tokio::signal::ctrl_c().await.is_ok() {
+                std::process::exit(130);
+            }
+        }
+    });
+    // systemd stops units with SIGTERM, and kills them for good after TimeoutStopSec
+    let on_terminate = cancel.clone();
+    tokio::spawn(async move {
+        systemd::terminated().await;
+        warn!("⚠️  Terminated, cancelling transfers...");
+        systemd::notify("STOPPING=1");
+        on_terminate.cancel();
+    });
+    systemd::start();
+
+    #[cfg(feature = "tui")]
+    let dashboard = match dashboard_logs {
+        Some(logs) => Some(docker_image_pusher::tui::Dashboard::start(
+            dashboard_title(&cli.command),
+            logs,
+            cancel.clone(),
+        )?),
+        None => None,
     };
 
-    Some(tokio::spawn(async move {
-        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(interval_secs));
-        let mut progress_counter = 1;
-        
-        loop {
-            interval.tick().await;
-            let elapsed = network_start_clone.elapsed();
-            
-            if elapsed.as_secs() > 0 {
-                let elapsed_min = elapsed.as_secs_f64() / 60.0;
-                let estimated_progress_percent = calculate_upload_progress(elapsed.as_secs(), layer_size_mb_clone);
-                
-                let estimated_transferred_mb = (estimated_progress_percent / 100.0) * layer_size_mb_clone;
-                let estimated_remaining_mb = layer_size_mb_clone - estimated_transferred_mb;
-                let estimated_transferred_bytes = (estimated_progress_percent / 100.0) * layer_size_bytes as f64;
-
-                let current_speed_mbps = if elapsed.as_secs() > 5 {
-                    estimated_transferred_mb / elapsed.as_secs_f64()
-                } else {
-                    ESTIMATED_SPEED_MBPS
-                };
-
-                let remaining_time_min = if current_speed_mbps > 0.0 {
-                    estimated_remaining_mb / current_speed_mbps / 60.0
-                } else {
-                    0.0
-                };
-
-                let (transferred_display, unit) = format_size_display(estimated_transferred_mb);
-                let (total_display, _) = format_size_display(layer_size_mb_clone);
-
-                println!("   ⏳ Upload progress #{}: {:.1}% | {:.1}/{:.1} {} | Speed: ~{:.1} MB/s | ETA: {:.1}min", 
-                    progress_counter,
-                    estimated_progress_percent,
-                    transferred_display,
-                    total_display,
-                    unit,
-                    current_speed_mbps,
-                    remaining_time_min);
-
-                // Show detailed information periodically
-                if progress_counter % 2 == 0 {
-                    println!("   📊 Data transferred: {:.0}/{} bytes | Elapsed: {:.1}min | Layer: ...{}", 
-                        estimated_transferred_bytes,
-                        layer_size_bytes,
-                        elapsed_min,
-                        digest_suffix);
-                }
+    correlation::set_id(cli.request_id);
+    let request_id = correlation::id();
+    let result = run(cli.command, cache_dir, cancel)
+        .instrument(info_span!("operation", request_id = %request_id))
+        .await;
+    // Restores the terminal, so everything from here on is printed again
+    #[cfg(feature = "tui")]
+    if let Some(dashboard) = dashboard {
+        dashboard.stop();
+    }
+    if let Err(e) = &result {
+        metrics::global().record_error(e.category());
+        error!("🆔 Request ID: {} (registry logs have it as X-Request-Id or in the User-Agent)", request_id);
+    }
 
-                // Show network analysis for very large layers
-                if progress_counter % 3 == 0 && layer_size_mb_clone > 1000.0 {
-                    let gb_size = layer_size_mb_clone / 1024.0;
-                    let avg_speed = estimated_transferred_mb / elapsed.as_secs_f64();
-                    let completion_percent = ((estimated_transferred_mb / layer_size_mb_clone) * 100.0).min(95.0);
-                    
-                    println!("   📈 Network: {:.2} GB total | Avg: {:.1} MB/s | Progress: {:.1}% | Large transfer in progress", 
-                        gb_size, avg_speed, completion_percent);
-                }
+    if let Some(path) = &cli.metrics_textfile
+        && let Err(e) = metrics::global().write_textfile(path)
+    {
+        warn!("⚠️  Failed to write metrics textfile {}: {}", path.display(), e);
+    }
+    if let Some(url) = &cli.pushgateway_url {
+        let outcome = match &result {
+            Ok(()) => "success",
+            Err(PusherError::BatchFailed(report)) if report.is_partial_success() => "partial_success",
+            Err(e) => e.category(),
+        };
+        if let Err(e) = metrics::global()
+            .push_to_gateway(
+                url,
+                &cli.pushgateway_job,
+                cli.pushgateway_instance.as_deref(),
+                outcome,
+                started.elapsed(),
+            )
+            .await
+        {
+            warn!("⚠️  {}", e);
+        }
+    }
 
-                progress_counter += 1;
+    match result {
+        Ok(()) => Ok(ExitCode::SUCCESS),
+        Err(PusherError::BatchFailed(report)) if report.is_partial_success() => {
+            for failure in &report.failed {
+                error!("❌ {} [{}]: {}", failure.digest, failure.category, failure.error);
             }
+            error!(
+                "⚠️  Partial success: {} of {} blobs transferred, rerun to upload the rest",
+                report.succeeded.len(),
+                report.succeeded.len() + report.failed.len()
+            );
+            Ok(ExitCode::from(2))
         }
-    }))
+        // Printed by anyhow rather than the log layers, so scrub it here
+        Err(e) => Err(anyhow::anyhow!(
+            "{}",
+            logging::redact(&format!("{:#}", anyhow::Error::from(e)))
+        )),
+    }
 }
 
-/// Uploads a large layer with progress tracking and optimization
-async fn upload_large_layer(
-    client: &Client,
-    target_ref: &Reference,
-    layer_path: &std::path::Path,
-    digest: &str,
-    layer_size_mb: f64,
-) -> Result<(), PusherError> {
-    println!("   🔄 Streaming large layer ({:.1} MB) directly to registry...", layer_size_mb);
-    
-    let upload_start = std::time::Instant::now();
-    let layer_data = tokio::fs::read(layer_path).await.map_err(|e| {
-        PusherError::CacheError(format!("Failed to read cached layer {}: {}", digest, e))
-    })?;
-
-    let read_duration = upload_start.elapsed();
-    println!("   📖 File read completed in {:.1}s ({:.1} MB)", 
-        read_duration.as_secs_f64(),
-        layer_data.len() as f64 / (1024.0 * 1024.0)
-    );
-
-    // Show estimated time for very large layers
-    if layer_size_mb > 1000.0 {
-        let estimated_time_min = layer_size_mb / ESTIMATED_SPEED_MBPS / 60.0;
-        println!("   ⏱️  Estimated upload time: {:.1}-{:.1} minutes", 
-            estimated_time_min * 0.5, estimated_time_min * 2.0);
+/// Describes the running command in the `--tui` dashboard's header
+#[cfg(feature = "tui")]
+fn dashboard_title(command: &Commands) -> String {
+    match command {
+        Commands::Pull { source_image, .. } => format!("Pulling {}", source_image),
+        Commands::Push {
+            source_image,
+            target_image,
+            ..
+        } => format!("Pushing {} to {}", source_image, target_image),
+        Commands::Import {
+            tar_file,
+            image_name,
+            ..
+        } => format!("Importing {} as {}", tar_file, image_name),
+        _ => "docker-image-pusher".to_string(),
     }
+}
 
-    let network_start = std::time::Instant::now();
-    let progress_handle = create_progress_tracker(
-        layer_size_mb, 
-        layer_data.len() as u64, 
-        network_start, 
-        digest
-    );
-
-    // Perform the actual upload
-    let upload_result = client.push_blob(target_ref, &layer_data, digest).await;
+/// Returns the registry host of `image`
+fn registry_of(image: &str) -> Result<String, PusherError> {
+    let reference: oci_client::Reference = image
+        .parse()
+        .map_err(|e| PusherError::ConfigError(format!("Invalid image reference {}: {}", image, e)))?;
+    Ok(reference.registry().to_string())
+}
 
-    // Cancel progress tracking
-    if let Some(handle) = progress_handle {
-        handle.abort();
+/// Providers asked for credentials no option gave: the pull secret, `PUSHER_*`
+/// variables, then what `docker login` stored
+///
+/// Files are read again on every lookup, so credentials rotated while a
+/// transfer runs are picked up when the registry rejects the old ones.
+fn credential_chain(kube_secret: Option<&Path>, secret: Option<&DockerConfig>) -> AuthChain {
+    let mut chain = AuthChain::new();
+    match (kube_secret, secret) {
+        // Stdin can only be read once
+        (Some(path), Some(_)) if path.as_os_str() != "-" => {
+            chain = chain.with(DockerConfigFile::kube_secret(path));
+        }
+        (_, Some(secret)) => chain = chain.with(secret.clone()),
+        (_, None) => {}
     }
+    chain = chain.with(EnvAuth::default());
+    match DockerConfig::load_default() {
+        Ok(_) => chain.with(DockerConfigFile::login()),
+        Err(e) => {
+            warn!("⚠️  Ignoring the Docker config: {}", e);
+            chain
+        }
+    }
+}
 
-    upload_result.map_err(|e| {
-        PusherError::PushError(format!("Failed to upload layer {}: {}", digest, e))
-    })?;
-
-    let network_duration = network_start.elapsed();
-    let total_duration = upload_start.elapsed();
-    let upload_speed = if network_duration.as_secs() > 0 {
-        (layer_data.len() as f64 / (1024.0 * 1024.0)) / network_duration.as_secs_f64()
-    } else {
-        0.0
-    };
-
-    println!("   ⚡ Upload completed! Total: {:.1}s (read: {:.1}s, upload: {:.1}s) @ {:.1} MB/s",
-        total_duration.as_secs_f64(),
-        read_duration.as_secs_f64(),
-        network_duration.as_secs_f64(),
-        upload_speed
-    );
-
-    // Additional success details for very large uploads
-    if layer_size_mb > 1000.0 {
-        let gb_transferred = layer_size_mb / 1024.0;
-        println!("   🎉 Successfully transferred {:.2} GB in {:.1} minutes",
-            gb_transferred, network_duration.as_secs_f64() / 60.0);
+/// Picks the credentials for pulling `image`
+///
+/// Falls back to anonymous access when no provider has credentials for the registry.
+async fn source_auth(
+    credentials: &AuthChain,
+    secret: Option<&DockerConfig>,
+    image: &str,
+) -> Result<RegistryAuth, PusherError> {
+    let auth = credentials.resolve_image(image).await?;
+    if auth == RegistryAuth::Anonymous && secret.is_some() {
+        warn!("⚠️  Pull secret has no credentials for {}, pulling anonymously", image);
     }
+    register_auth(&auth);
+    Ok(auth)
+}
 
+/// Refuses to transfer images without signature checks when `DOCKER_CONTENT_TRUST` requires them
+///
+/// The pull and push enforce this themselves (see [`trust`]); checking up
+/// front fails before any credentials are looked up, and warns once when
+/// `--disable-content-trust` opts out like it does for `docker pull`.
+fn check_content_trust(disabled: bool, verifying: bool) -> Result<(), PusherError> {
+    if !disabled {
+        return trust::check_content_trust(verifying);
+    }
+    if trust::content_trust_required() && !verifying {
+        warn!("⚠️  {} is set, but content trust is disabled for this command", trust::CONTENT_TRUST_ENV);
+    }
     Ok(())
 }
 
-/// Uploads a small layer with simple timing
-async fn upload_small_layer(
-    client: &Client,
-    target_ref: &Reference,
-    layer_path: &std::path::Path,
-    digest: &str,
-    layer_size_mb: f64,
-) -> Result<(), PusherError> {
-    println!("   📤 Uploading layer directly...");
-    
-    let read_start = std::time::Instant::now();
-    let layer_data = tokio::fs::read(layer_path).await.map_err(|e| {
-        PusherError::CacheError(format!("Failed to read cached layer {}: {}", digest, e))
-    })?;
-
-    let read_duration = read_start.elapsed();
-    let upload_start = std::time::Instant::now();
-
-    client.push_blob(target_ref, &layer_data, digest).await.map_err(|e| {
-        PusherError::PushError(format!("Failed to upload layer {}: {}", digest, e))
-    })?;
-
-    let upload_duration = upload_start.elapsed();
-    let total_duration = read_start.elapsed();
-    let speed = if total_duration.as_secs() > 0 {
-        layer_size_mb / total_duration.as_secs_f64()
-    } else {
-        0.0
-    };
+/// Converts a number of seconds from the command line, where 0 means "disabled"
+fn seconds(secs: u64) -> Option<std::time::Duration> {
+    (secs > 0).then(|| std::time::Duration::from_secs(secs))
+}
 
-    println!("   ⚡ Completed in {:.1}s (read: {:.1}ms, upload: {:.1}s) @ {:.1} MB/s",
-        total_duration.as_secs_f64(),
-        read_duration.as_millis(),
-        upload_duration.as_secs_f64(),
-        speed
+/// Build steps longer than this are cut short by `inspect` unless `--no-trunc` is given
+const INSPECT_STEP_WIDTH: usize = 80;
+
+/// Prints the config, size and layer history of a cached image
+fn inspect_image(cache_dir: &Path, image: &str, no_trunc: bool) -> Result<(), PusherError> {
+    let image_cache_dir = cache_dir.join(image::sanitize_image_name(image));
+    let manifest: OciImageManifest =
+        serde_json::from_slice(&std::fs::read(image_cache_dir.join("manifest.json"))?)?;
+    let config = std::fs::read(image_cache_dir.join(format!(
+        "config_{}.json",
+        image::digest::file_name(&manifest.config.digest)
+    )))
+    .map_err(|e| PusherError::CacheError(format!("Failed to read cached config: {}", e)))?;
+    let platform: serde_json::Value = serde_json::from_slice(&config)?;
+    let history = image::layer_history(&config, &manifest.layers)?;
+
+    let total: i64 = manifest.layers.iter().map(|layer| layer.size).sum();
+    println!("Image:    {}", image);
+    println!("Config:   {}", manifest.config.digest);
+    println!(
+        "Platform: {}/{}",
+        platform["os"].as_str().unwrap_or("unknown"),
+        platform["architecture"].as_str().unwrap_or("unknown")
     );
-
+    println!(
+        "Size:     {} in {} layer(s)",
+        human_size(total),
+        manifest.layers.len()
+    );
+    println!();
+    println!("{:>3}  {:>10}  {:<19}  CREATED BY", "#", "SIZE", "LAYER");
+    for (step, entry) in history.iter().enumerate() {
+        let (size, layer) = match &entry.layer {
+            Some(layer) => (human_size(layer.size), short_digest(&layer.digest)),
+            None => ("0 B".to_string(), "<empty>".to_string()),
+        };
+        let mut instruction = match entry.instruction() {
+            text if text.is_empty() => "<missing history>".to_string(),
+            text => text,
+        };
+        if !no_trunc && instruction.chars().count() > INSPECT_STEP_WIDTH {
+            instruction = instruction.chars().take(INSPECT_STEP_WIDTH - 3).collect::<String>() + "...";
+        }
+        println!("{:>3}  {:>10}  {:<19}  {}", step + 1, size, layer, instruction);
+    }
     Ok(())
 }
 
-/// Shows extraction progress for large layers
-fn show_extraction_progress(total_read: u64, layer_size: u64, layer_size_mb: f64, extract_start: std::time::Instant) {
-    let progress = (total_read as f64 / layer_size as f64) * 100.0;
-    let elapsed = extract_start.elapsed();
-    let mb_per_sec = if elapsed.as_secs() > 0 {
-        (total_read as f64 / (1024.0 * 1024.0)) / elapsed.as_secs_f64()
-    } else {
-        0.0
+/// Prints the layer sharing of the cached images as tables
+fn print_dedup_report(report: &dedup::DedupReport) {
+    let savable_percent = match report.stored_bytes {
+        0 => 0.0,
+        stored => report.savable_bytes() as f64 * 100.0 / stored as f64,
     };
-
     println!(
-        "   📊 Progress: {:.1}% ({:.1}/{:.1} MB) @ {:.1} MB/s",
-        progress,
-        total_read as f64 / (1024.0 * 1024.0),
-        layer_size_mb,
-        mb_per_sec
+        "Images:   {} ({} distinct layer(s))",
+        report.images.len(),
+        report.distinct_layers
+    );
+    println!("Stored:   {}", human_size(report.stored_bytes as i64));
+    println!("Distinct: {}", human_size(report.distinct_bytes as i64));
+    println!(
+        "Savable:  {} ({:.0}%) by storing each layer once",
+        human_size(report.savable_bytes() as i64),
+        savable_percent
     );
+    println!();
+    println!("{:>6}  {:>10}  {:>10}  IMAGE", "LAYERS", "SIZE", "UNIQUE");
+    for usage in &report.images {
+        println!(
+            "{:>6}  {:>10}  {:>10}  {}",
+            usage.layers,
+            human_size(usage.bytes as i64),
+            human_size(usage.unique_bytes as i64),
+            usage.image
+        );
+    }
+    if report.shared.is_empty() {
+        return;
+    }
+    println!();
+    println!(
+        "{:>6}  {:>10}  {:>10}  SHARED BY",
+        "LAYERS", "SIZE", "SAVABLE"
+    );
+    for group in &report.shared {
+        println!(
+            "{:>6}  {:>10}  {:>10}  {}",
+            group.layers.len(),
+            human_size(group.bytes as i64),
+            human_size(group.duplicate_bytes() as i64),
+            group.images.join(", ")
+        );
+    }
 }
 
-/// Imports a Docker tar archive and caches it using the same structure as pulled images
-///
-/// This function processes tar files created by `docker save` command and extracts:
-/// - Image manifest(s)
-/// - Layer tar.gz files  
-/// - Image configuration JSON
-///
-/// The extracted components are cached using the same structure as `cache_image()`,
-/// ensuring compatibility with the push functionality.
-///
-/// ## Docker Save Format
-///
-/// A `docker save` tar contains:
-/// - `manifest.json` - List of images with their layers and config references
-/// - `<layer_id>/layer.tar` - Individual layer data (sometimes gzipped)
-/// - `<config_hash>.json` - Image configuration
-/// - `repositories` (optional) - Repository and tag information
-///
-/// ## Cache Structure
-///
-/// The function creates the same cache structure as `cache_image()`:
-/// - `.cache/{sanitized_image_name}/manifest.json` - OCI image manifest
-/// - `.cache/{sanitized_image_name}/config_{digest}.json` - Image config
-/// - `.cache/{sanitized_image_name}/{layer_digest}` - Layer files
-/// - `.cache/{sanitized_image_name}/index.json` - Cache metadata
-///
-/// # Arguments
-///
-/// * `tar_path` - Path to the Docker tar archive file
-/// * `image_name` - Name to use for caching (e.g., "myapp:v1.0")
-///
-/// # Returns
-///
-/// `Result<(), PusherError>` - Success or detailed error information
-///
-/// # Example
-///
-/// ```bash
-/// # Create a tar archive with docker save
-/// docker save myapp:latest > myapp.tar
-///
-/// # Import it into the cache
-/// docker-image-pusher import myapp.tar myapp:latest
-///
-/// # Now it can be pushed like any cached image
-/// docker-image-pusher push myapp:latest registry.example.com/myapp:latest -u user -p pass
-/// ```
-async fn import_tar_file(tar_path: &str, image_name: &str) -> Result<(), PusherError> {
-    println!("📂 Opening tar archive: {}", tar_path);
-    // Step 1: Open and parse the tar archive
-    let tar_file = File::open(tar_path)
-        .map_err(|e| PusherError::TarError(format!("Failed to open tar file: {}", e)))?;
-
-    let mut archive = Archive::new(tar_file);
-    let _entries = archive
-        .entries()
-        .map_err(|e| PusherError::TarError(format!("Failed to read tar entries: {}", e)))?;
-
-    // Step 2: Create cache directory structure
-    let cache_dir = Path::new(CACHE_DIR);
-    std::fs::create_dir_all(cache_dir)
-        .map_err(|e| PusherError::CacheError(format!("Failed to create cache directory: {}", e)))?;
-
-    let image_cache_dir = cache_dir.join(image::sanitize_image_name(image_name));
-    std::fs::create_dir_all(&image_cache_dir).map_err(|e| {
-        PusherError::CacheError(format!("Failed to create image cache directory: {}", e))
-    })?;
-    // Step 3: Extract and parse the main manifest.json from the tar
-    println!("🔍 Searching for Docker manifest in tar archive...");
-    let mut docker_manifest: Option<serde_json::Value> = None;
-    let mut layer_mapping: std::collections::HashMap<String, (std::path::PathBuf, u64)> =
-        std::collections::HashMap::new();
-    let mut config_data: Option<(String, Vec<u8>)> = None;
-
-    // Reset the archive for reading
-    let tar_file = File::open(tar_path)
-        .map_err(|e| PusherError::TarError(format!("Failed to reopen tar file: {}", e)))?;
-    let mut archive = Archive::new(tar_file);
-
-    // Step 4: First pass - find and parse manifest.json
-    for entry_result in archive
-        .entries()
-        .map_err(|e| PusherError::TarError(format!("Failed to read tar entries: {}", e)))?
-    {
-        let mut entry = entry_result
-            .map_err(|e| PusherError::TarError(format!("Failed to read tar entry: {}", e)))?;
-
-        let path = entry
-            .path()
-            .map_err(|e| PusherError::TarError(format!("Failed to get entry path: {}", e)))?;
-        let path_str = path.to_string_lossy();
-
-        if path_str == "manifest.json" {
-            println!("📄 Found Docker manifest.json");
-            let mut contents = Vec::new();
-            entry
-                .read_to_end(&mut contents)
-                .map_err(|e| PusherError::TarError(format!("Failed to read manifest: {}", e)))?;
-
-            docker_manifest = Some(serde_json::from_slice(&contents).map_err(|e| {
-                PusherError::TarError(format!("Failed to parse manifest.json: {}", e))
-            })?);
-            break;
-        }
+/// Formats a byte count with a binary unit (e.g. "3.2 MB")
+fn human_size(bytes: i64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
     }
+    format!("{:.1} {}", size, UNITS[unit])
+}
 
-    let docker_manifest = docker_manifest.ok_or_else(|| {
-        PusherError::TarError("No manifest.json found in tar archive".to_string())
-    })?;
+/// Shortens a digest to its algorithm and first 12 hex digits
+fn short_digest(digest: &str) -> String {
+    match digest.parse::<image::digest::Digest>() {
+        Ok(parsed) => parsed.short(),
+        Err(_) => digest.to_string(),
+    }
+}
 
-    // Step 5: Parse the Docker manifest to get image info
-    let manifest_array = docker_manifest
-        .as_array()
-        .ok_or_else(|| PusherError::TarError("Invalid manifest.json format".to_string()))?;
+/// Lints a cached image, logging every finding; with `strict` any finding is an error
+async fn lint_image(
+    cache_dir: &Path,
+    image: &str,
+    options: lint::LintOptions,
+    strict: bool,
+) -> Result<(), PusherError> {
+    info!("🔎 Linting {}...", image);
+    let name = image.to_string();
+    let cache_dir = cache_dir.to_path_buf();
+    let findings =
+        tokio::task::spawn_blocking(move || lint::lint_cached_image(&cache_dir, &name, &options))
+            .await
+            .map_err(|e| PusherError::CacheError(format!("Lint task failed: {}", e)))??;
+    if findings.is_empty() {
+        info!("✅ No lint findings for {}", image);
+        return Ok(());
+    }
+    for finding in &findings {
+        warn!(check = finding.check, "⚠️  {}", finding.message);
+    }
+    if strict {
+        return Err(PusherError::LintFailed {
+            image: image.to_string(),
+            findings: findings.len(),
+        });
+    }
+    warn!("⚠️  {} lint finding(s) for {}", findings.len(), image);
+    Ok(())
+}
 
-    if manifest_array.is_empty() {
-        return Err(PusherError::TarError("Empty manifest.json".to_string()));
+/// Keeps the password or token in `auth` out of all log output
+fn register_auth(auth: &RegistryAuth) {
+    match auth {
+        RegistryAuth::Basic(_, password) => logging::register_secret(password),
+        RegistryAuth::Bearer(token) => logging::register_secret(token),
+        RegistryAuth::Anonymous => {}
     }
+}
 
-    // Use the first image in the manifest (docker save can contain multiple images)
-    let image_info = &manifest_array[0];
-    let config_file = image_info["Config"]
-        .as_str()
-        .ok_or_else(|| PusherError::TarError("No Config field in manifest".to_string()))?;
-    let layers = image_info["Layers"]
-        .as_array()
-        .ok_or_else(|| PusherError::TarError("No Layers field in manifest".to_string()))?;
-
-    println!("📋 Found image with {} layers", layers.len());
-    println!("⚙️  Config file: {}", config_file);
-
-    // Step 6: Second pass - extract layers and config
-    let tar_file = File::open(tar_path)
-        .map_err(|e| PusherError::TarError(format!("Failed to reopen tar file: {}", e)))?;
-    let mut archive = Archive::new(tar_file);
-
-    for entry_result in archive
-        .entries()
-        .map_err(|e| PusherError::TarError(format!("Failed to read tar entries: {}", e)))?
-    {
-        let mut entry = entry_result
-            .map_err(|e| PusherError::TarError(format!("Failed to read tar entry: {}", e)))?;
-
-        let path = entry
-            .path()
-            .map_err(|e| PusherError::TarError(format!("Failed to get entry path: {}", e)))?;
-        let path_str = path.to_string_lossy();
-
-        // Extract config file
-        if path_str == config_file {
-            println!("⚙️  Extracting config: {}", config_file);
-            let mut contents = Vec::new();
-            entry
-                .read_to_end(&mut contents)
-                .map_err(|e| PusherError::TarError(format!("Failed to read config: {}", e)))?;
-
-            // Compute config digest
-            let mut hasher = Sha256::new();
-            hasher.update(&contents);
-            let config_digest = format!("sha256:{:x}", hasher.finalize());
-
-            config_data = Some((config_digest, contents));
-            continue;
-        } // Extract layer files using streaming approach for memory efficiency
-        for layer in layers {
-            let layer_path = layer
-                .as_str()
-                .ok_or_else(|| PusherError::TarError("Invalid layer path".to_string()))?;
-
-            if path_str == layer_path {
-                // Get layer size for progress indication
-                let layer_size = entry.size();
-                let layer_size_mb = layer_size as f64 / (1024.0 * 1024.0);
-                println!(
-                    "📦 Extracting layer: {} ({:.1} MB)",
-                    layer_path, layer_size_mb
-                );
-                let extract_start = std::time::Instant::now();
-
-                // Create temporary file for the layer
-                let temp_layer_path =
-                    image_cache_dir.join(format!("temp_layer_{}", std::process::id()));
-                let mut temp_file = std::fs::File::create(&temp_layer_path).map_err(|e| {
-                    PusherError::TarError(format!("Failed to create temp file: {}", e))
-                })?;
-
-                // Stream layer data to temp file while computing hash
-                let mut hasher = Sha256::new();
-                let mut buffer = [0u8; STREAM_BUFFER_SIZE];
-                let mut total_read = 0u64;
-                let mut last_progress_time = std::time::Instant::now();
-
-                loop {
-                    let bytes_read = entry.read(&mut buffer).map_err(|e| {
-                        PusherError::TarError(format!("Failed to read layer chunk: {}", e))
-                    })?;
-
-                    if bytes_read == 0 {
-                        break; // End of layer
-                    }
+/// Dispatches a parsed subcommand to its handler
+///
+/// Initializes the OCI client with a platform resolver for Linux AMD64 images
+/// before running the command.
+async fn run(
+    command: Commands,
+    cache_dir: PathBuf,
+    cancel: CancellationToken,
+) -> Result<(), PusherError> {
+    let timeouts = match &command {
+        Commands::Pull { timeouts, .. } | Commands::Push { timeouts, .. } => timeouts.timeouts(),
+        _ => Timeouts::default(),
+    };
+    let client = docker_image_pusher::new_client_with_timeouts(&timeouts);
+    match command {
+        Commands::Pull {
+            source_image,
+            requests_per_second,
+            timeouts: _,
+            kube_secret,
+            disable_content_trust,
+            #[cfg(feature = "cosign")]
+            verify,
+        } => {
+            #[cfg(feature = "cosign")]
+            check_content_trust(disable_content_trust, verify.verify_signature)?;
+            #[cfg(not(feature = "cosign"))]
+            check_content_trust(disable_content_trust, false)?;
+            info!("🚀 Pulling and caching image: {}", source_image);
+            let secret = kube_secret
+                .as_deref()
+                .map(DockerConfig::from_kube_secret)
+                .transpose()?;
+            let credentials = credential_chain(kube_secret.as_deref(), secret.as_ref());
+            let options = cache::PullOptions {
+                cache_dir: cache_dir.clone(),
+                auth: source_auth(&credentials, secret.as_ref(), &source_image).await?,
+                credentials: credentials.clone(),
+                requests_per_second,
+                timeouts,
+                cancel: cancel.clone(),
+                disable_content_trust,
+                #[cfg(feature = "cosign")]
+                trusted_key: verify.load()?,
+                ..Default::default()
+            };
+            cache::cache_image(&client, &source_image, &options).await?;
+            info!("✅ Successfully cached image: {}", source_image);
+        }
+        Commands::Push {
+            source_image,
+            target_image,
+            username,
+            password,
+            token,
+            max_memory,
+            max_concurrent,
+            requests_per_second,
+            layer_timeout,
+            timeouts: _,
+            no_overwrite,
+            force_upload,
+            repush_digests,
+            ledger_ttl,
+            expires_after,
+            annotations,
+            layer_annotations,
+            create_project,
+            project_public,
+            project_quota,
+            harbor_quota,
+            kube_secret,
+            disable_content_trust,
+            lint: run_lint,
+            strict,
+            scan_cmd,
+            provenance,
+            builder_id,
+            report,
+            #[cfg(feature = "cosign")]
+            sign_key,
+            #[cfg(feature = "cosign")]
+            verify,
+        } => {
+            #[cfg(feature = "cosign")]
+            check_content_trust(disable_content_trust, verify.verify_signature)?;
+            #[cfg(not(feature = "cosign"))]
+            check_content_trust(disable_content_trust, false)?;
+            let secret = kube_secret
+                .as_deref()
+                .map(DockerConfig::from_kube_secret)
+                .transpose()?;
+            let credentials = credential_chain(kube_secret.as_deref(), secret.as_ref());
+            // Credentials given as options can't change, so there is nothing to look up again
+            let target_credentials = match (&username, &password, &token) {
+                (Some(_), Some(_), _) | (_, _, Some(_)) => AuthChain::new(),
+                _ => credentials.clone(),
+            };
+            let auth = match (username, password, token) {
+                (_, _, Some(token)) => RegistryAuth::Bearer(token),
+                (Some(username), Some(password), None) => RegistryAuth::Basic(username, password),
+                _ => credentials
+                    .credentials_for(&registry_of(&target_image)?)
+                    .await?
+                    .ok_or_else(|| match &secret {
+                        Some(secret) => PusherError::ConfigError(format!(
+                            "Pull secret has no credentials for {} (it has: {})",
+                            target_image,
+                            secret.registries().collect::<Vec<_>>().join(", ")
+                        )),
+                        None => PusherError::ConfigError(format!(
+                            "No credentials for {}: pass -u/-p, --token or --kube-secret, set {prefix}_USERNAME and {prefix}_PASSWORD (or {prefix}_TOKEN), or docker login",
+                            target_image,
+                            prefix = docker_image_pusher::credentials::DEFAULT_ENV_PREFIX
+                        )),
+                    })?,
+            };
+            register_auth(&auth);
+            info!(
+                "📤 Pushing image from cache: {} -> {}",
+                source_image, target_image
+            );
 
-                    // Write to temp file using std::io::Write trait
-                    temp_file.write_all(&buffer[..bytes_read]).map_err(|e| {
-                        PusherError::TarError(format!("Failed to write layer chunk: {}", e))
-                    })?;
-
-                    // Update hash
-                    hasher.update(&buffer[..bytes_read]);
-                    total_read += bytes_read as u64;
-
-                    // Progress indication for large layers with timing
-                    if layer_size > LARGE_LAYER_THRESHOLD_BYTES && 
-                       last_progress_time.elapsed() > std::time::Duration::from_secs(PROGRESS_UPDATE_INTERVAL_SECS)
-                    {
-                        show_extraction_progress(total_read, layer_size, layer_size_mb, extract_start);
-                        last_progress_time = std::time::Instant::now();
-                    }
-                }
+            // Load the key up front so a wrong path or password fails before any upload
+            #[cfg(feature = "cosign")]
+            let signing_key = sign_key
+                .map(|path| {
+                    let password = std::env::var("COSIGN_PASSWORD").unwrap_or_default();
+                    logging::register_secret(&password);
+                    docker_image_pusher::cosign::SigningKey::from_file(&path, password.as_bytes())
+                })
+                .transpose()?;
+            #[cfg(feature = "cosign")]
+            let trusted_key = verify.load()?;
+
+            let options = PushOptions {
+                cache_dir: cache_dir.clone(),
+                max_memory,
+                max_concurrent,
+                requests_per_second,
+                layer_timeout_secs: layer_timeout,
+                timeouts,
+                no_overwrite,
+                force_upload,
+                repush_digests: repush_digests.iter().map(ToString::to_string).collect(),
+                ledger_ttl_secs: ledger_ttl,
+                expires_after,
+                annotations: annotations.into_iter().collect(),
+                layer_annotations: layer_annotations.into_iter().collect(),
+                create_harbor_project: create_project.then_some(harbor::ProjectSettings {
+                    public: project_public,
+                    storage_limit: project_quota,
+                }),
+                harbor_quota,
+                credentials: target_credentials,
+                cancel: cancel.clone(),
+                disable_content_trust,
+                #[cfg(feature = "cosign")]
+                trusted_key: trusted_key.clone(),
+                ..Default::default()
+            };
+            let started_on = std::time::SystemTime::now();
 
-                // Finalize temp file using std::io::Write trait
-                temp_file.flush().map_err(|e| {
-                    PusherError::TarError(format!("Failed to flush temp file: {}", e))
-                })?;
-                drop(temp_file);
-
-                // Compute final digest and show extraction stats
-                let layer_digest = format!("sha256:{:x}", hasher.finalize());
-                let extract_duration = extract_start.elapsed();
-                let extract_speed = if extract_duration.as_secs() > 0 {
-                    layer_size_mb / extract_duration.as_secs_f64()
-                } else {
-                    0.0
+            // Ensure we have the image cached before attempting to push
+            let pushed = if cache::has_cached_image(&cache_dir, &source_image).await? {
+                None
+            } else {
+                warn!("⚠️  Image not found in cache, pulling first...");
+                let pull_options = cache::PullOptions {
+                    cache_dir: cache_dir.clone(),
+                    auth: source_auth(&credentials, secret.as_ref(), &source_image).await?,
+                    credentials: credentials.clone(),
+                    requests_per_second,
+                    timeouts,
+                    cancel: cancel.clone(),
+                    disable_content_trust,
+                    #[cfg(feature = "cosign")]
+                    trusted_key,
+                    ..Default::default()
                 };
+                if run_lint || scan_cmd.is_some() {
+                    // Nothing may reach the target before the checks pass
+                    cache::cache_image(&client, &source_image, &pull_options).await?;
+                    None
+                } else {
+                    Some(
+                        docker_image_pusher::copy_image(
+                            &client,
+                            &source_image,
+                            &target_image,
+                            &auth,
+                            pull_options,
+                            &options,
+                        )
+                        .await,
+                    )
+                }
+            };
+
+            if run_lint {
+                lint_image(
+                    &cache_dir,
+                    &source_image,
+                    lint::LintOptions::default(),
+                    strict,
+                )
+                .await?;
+            }
+            if let Some(command) = &scan_cmd {
+                scan::scan_cached_image(&cache_dir, &source_image, command).await?;
+            }
 
+            // Push the cached image to target registry (unless it was copied straight through)
+            let pushed = match pushed {
+                Some(pushed) => pushed,
+                None => push_cached_image(&client, &source_image, &target_image, &auth, &options).await,
+            };
+            let transfer_report = match pushed {
+                Ok(transfer_report) => transfer_report,
+                Err(PusherError::BatchFailed(batch)) => {
+                    if let Some(path) = report {
+                        batch.write(&path)?;
+                        info!("📝 Partial transfer report written to {}", path.display());
+                    }
+                    return Err(PusherError::BatchFailed(batch));
+                }
+                Err(e) => return Err(e),
+            };
+            info!("✅ Successfully pushed image: {}", target_image);
+            #[cfg(feature = "cosign")]
+            if let Some(key) = &signing_key {
+                docker_image_pusher::cosign::sign_image(
+                    &client,
+                    &target_image,
+                    &auth,
+                    &transfer_report.manifest_digest,
+                    key,
+                    &options.retry,
+                )
+                .await?;
+            }
+            if provenance {
+                let statement = docker_image_pusher::provenance::Provenance::for_cached_image(
+                    &cache_dir,
+                    &source_image,
+                    &builder_id,
+                    started_on,
+                )?;
+                docker_image_pusher::provenance::attach_provenance(
+                    &client,
+                    &target_image,
+                    &auth,
+                    &transfer_report.manifest_digest,
+                    &statement,
+                    &options.retry,
+                )
+                .await?;
+            }
+            if let Some(path) = report {
+                transfer_report.write(&path)?;
+                info!("📝 Transfer report written to {}", path.display());
+            }
+        }
+        Commands::Import {
+            tar_file,
+            image_name,
+            containerd,
+            storage_root,
+            tmp_dir,
+        } => {
+            let options = import::ImportOptions { cache_dir, tmp_dir };
+            if tar_file.starts_with(ssh::TRANSPORT_PREFIX) {
+                ssh::import_remote_tar(&tar_file, &image_name, &options).await?;
+                info!("✅ Successfully imported and cached image: {}", image_name);
+                return Ok(());
+            }
+            if let Some(image) = tar_file.strip_prefix(containerd::TRANSPORT_PREFIX) {
+                containerd.store().import(image, &image_name, &options).await?;
+                info!("✅ Successfully imported and cached image: {}", image_name);
+                return Ok(());
+            }
+            if let Some(image) = tar_file.strip_prefix(containers_storage::TRANSPORT_PREFIX) {
+                let storage = containers_storage::ContainersStorage {
+                    root: storage_root,
+                    ..Default::default()
+                };
+                storage.import(image, &image_name, &options).await?;
+                info!("✅ Successfully imported and cached image: {}", image_name);
+                return Ok(());
+            }
+            info!(
+                "📦 Importing Docker tar archive: {} as {}",
+                tar_file, image_name
+            );
+            import_tar_file(&tar_file, &image_name, &options).await?;
+            info!("✅ Successfully imported and cached image: {}", image_name);
+        }
+        Commands::Lint {
+            image,
+            strict,
+            max_layer_size,
+            max_layers,
+            required_labels,
+        } => {
+            if !cache::has_cached_image(&cache_dir, &image).await? {
+                return Err(PusherError::CacheNotFound);
+            }
+            let defaults = lint::LintOptions::default();
+            let options = lint::LintOptions {
+                max_layer_size: max_layer_size.unwrap_or(defaults.max_layer_size),
+                max_layers: max_layers.unwrap_or(defaults.max_layers),
+                required_labels: match required_labels.is_empty() {
+                    true => defaults.required_labels,
+                    false => required_labels,
+                },
+            };
+            lint_image(&cache_dir, &image, options, strict).await?;
+        }
+        Commands::Inspect { image, no_trunc } => {
+            if !cache::has_cached_image(&cache_dir, &image).await? {
+                return Err(PusherError::CacheNotFound);
+            }
+            inspect_image(&cache_dir, &image, no_trunc)?;
+        }
+        Commands::Cache {
+            command: CacheCommands::DedupReport { json },
+        } => {
+            let report = dedup::dedup_report(&cache_dir)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print_dedup_report(&report);
+            }
+        }
+        Commands::Cache {
+            command: CacheCommands::Migrate { rollback },
+        } => {
+            if rollback {
+                let restored = migrate::rollback(&cache_dir)?;
+                info!(
+                    "↩️  Restored {} image(s) from the migration backup",
+                    restored
+                );
+                return Ok(());
+            }
+            let report = migrate::migrate(&cache_dir).await?;
+            match &report.backup {
+                Some(backup) => info!(
+                    "✅ Migrated {} image(s) to cache format {} ({} already current); undo with `cache migrate --rollback`, or delete {} to keep the result",
+                    report.migrated.len(),
+                    migrate::CACHE_VERSION,
+                    report.current,
+                    backup.display()
+                ),
+                None => info!(
+                    "✅ All {} cached image(s) are at cache format {}",
+                    report.current,
+                    migrate::CACHE_VERSION
+                ),
+            }
+        }
+        Commands::Export {
+            source_image,
+            destination,
+            tag,
+            rootfs,
+            containerd,
+        } => {
+            if !cache::has_cached_image(&cache_dir, &source_image).await? {
+                return Err(PusherError::CacheNotFound);
+            }
+            if let Some(image) = destination.strip_prefix(containerd::TRANSPORT_PREFIX) {
+                if rootfs {
+                    return Err(PusherError::ConfigError(
+                        "--rootfs writes a tar file and cannot be loaded into containerd".to_string(),
+                    ));
+                }
+                containerd
+                    .store()
+                    .export(&cache_dir, &source_image, image)
+                    .await?;
+                return Ok(());
+            }
+            let shown = match destination.as_str() {
+                STDOUT_PATH => "stdout".to_string(),
+                path => path.to_string(),
+            };
+            info!("📦 Exporting {} to {}", source_image, shown);
+            let repo_tag = tag.unwrap_or_else(|| source_image.clone());
+            let output: Box<dyn std::io::Write + Send> = if destination == STDOUT_PATH {
+                Box::new(std::io::stdout())
+            } else {
+                Box::new(std::fs::File::create(&destination)?)
+            };
+            let mut output = tokio::task::spawn_blocking(move || {
+                let output = std::io::BufWriter::new(output);
+                if rootfs {
+                    export::write_rootfs(&cache_dir, &source_image, output)
+                } else {
+                    export::write_docker_archive(&cache_dir, &source_image, &repo_tag, output)
+                }
+            })
+            .await
+            .map_err(|e| PusherError::TarError(format!("Export task failed: {}", e)))??;
+            std::io::Write::flush(&mut output)?;
+            info!("✅ Exported to {}", shown);
+        }
+        Commands::Extract {
+            image,
+            path,
+            output,
+        } => {
+            if !cache::has_cached_image(&cache_dir, &image).await? {
+                return Err(PusherError::CacheNotFound);
+            }
+            info!("📂 Extracting {} from {} to {}", path, image, output.display());
+            let destination = output.clone();
+            let written = tokio::task::spawn_blocking(move || {
+                export::extract_path(&cache_dir, &image, &path, &destination)
+            })
+            .await
+            .map_err(|e| PusherError::TarError(format!("Extract task failed: {}", e)))??;
+            info!("✅ Extracted {} entries to {}", written, output.display());
+        }
+        Commands::CleanupUploads {
+            repository,
+            username,
+            password,
+            token,
+            dry_run,
+        } => {
+            let reference: oci_client::Reference = repository.parse().map_err(|e| {
+                PusherError::ConfigError(format!("Invalid repository {}: {}", repository, e))
+            })?;
+            let journal = journal::UploadJournal::new(&cache_dir);
+            let entries = journal.entries(reference.resolve_registry(), reference.repository());
+            if entries.is_empty() {
+                info!("✅ No journaled upload sessions for {}", repository);
+                return Ok(());
+            }
+            println!("{:<19}  {:>8}  LOCATION", "BLOB", "IDLE");
+            for (_, entry) in &entries {
                 println!(
-                    "   ✅ Layer extracted: {} in {:.1}s @ {:.1} MB/s",
-                    layer_digest,
-                    extract_duration.as_secs_f64(),
-                    extract_speed
+                    "{:<19}  {:>7}s  {}",
+                    short_digest(&entry.digest),
+                    entry.age_secs(),
+                    entry.location
                 );
-                // Move the temp file to final location with proper digest name
-                let final_layer_path = image_cache_dir.join(layer_digest.replace(":", "_"));
-                std::fs::rename(&temp_layer_path, &final_layer_path).map_err(|e| {
-                    PusherError::TarError(format!("Failed to rename layer file: {}", e))
-                })?;
-
-                // Store layer info without loading content into memory
-                layer_mapping.insert(layer_digest.clone(), (final_layer_path, total_read));
-
-                break;
             }
+            if dry_run {
+                return Ok(());
+            }
+            let auth = match (username, password, token) {
+                (_, _, Some(token)) => RegistryAuth::Bearer(token),
+                (Some(username), Some(password), None) => RegistryAuth::Basic(username, password),
+                _ => EnvAuth::default()
+                    .credentials_for(reference.registry())
+                    .await?
+                    .unwrap_or(RegistryAuth::Anonymous),
+            };
+            register_auth(&auth);
+            let limiter = std::sync::Arc::new(concurrency::RateLimiter::new(None));
+            let registry = retry::RetryPolicy::default()
+                .run("Authentication", &cancel, || {
+                    registry::RegistryClient::connect(&client, &reference, &auth, limiter.clone())
+                })
+                .await?;
+            let cleared = registry.abort_journaled_uploads(&journal, 0).await;
+            info!(
+                "🧹 Aborted {} of {} upload session(s) on {}",
+                cleared,
+                entries.len(),
+                repository
+            );
+        }
+        Commands::Serve {
+            listen,
+            max_jobs,
+            api_token,
+            sync_manifest,
+            ready_registries,
+            proxy_listen,
+            proxy_upstream,
+            ledger_ttl,
+            #[cfg(feature = "grpc")]
+            grpc_listen,
+        } => {
+            let sync = sync_manifest
+                .as_deref()
+                .map(server::sync::SyncManifest::load)
+                .transpose()?;
+            if let Some(token) = &api_token {
+                logging::register_secret(token);
+            } else if !listen.starts_with("127.0.0.1:") && !listen.starts_with("localhost:") {
+                warn!("⚠️  Serving the job API on {} without --api-token", listen);
+            }
+            server::serve(server::ServerOptions {
+                listen,
+                max_jobs,
+                api_token,
+                #[cfg(feature = "grpc")]
+                grpc_listen,
+                sync,
+                proxy: proxy_listen.map(|listen| server::proxy::ProxyOptions {
+                    listen,
+                    upstream: Some(proxy_upstream),
+                }),
+                credentials: credential_chain(None, None),
+                ready_registries,
+                ledger_ttl_secs: ledger_ttl,
+                cache_dir,
+                cancel,
+            })
+            .await?;
+        }
+        Commands::ServeCache { listen } => {
+            let listen = match listen.strip_prefix(':') {
+                Some(port) => format!("0.0.0.0:{}", port),
+                None => listen,
+            };
+            server::proxy::serve(
+                server::proxy::ProxyOptions {
+                    listen,
+                    upstream: None,
+                },
+                cache_dir,
+                AuthChain::new(),
+                cancel,
+            )
+            .await?;
         }
     }
 
-    // Step 7: Verify we got all required components
-    let (config_digest, config_contents) = config_data
-        .ok_or_else(|| PusherError::TarError("Config file not found in tar".to_string()))?;
-
-    if layer_mapping.len() != layers.len() {
-        return Err(PusherError::TarError(format!(
-            "Expected {} layers, found {}",
-            layers.len(),
-            layer_mapping.len()
-        )));
-    }
-
-    println!(
-        "✅ Successfully extracted {} layers and config",
-        layer_mapping.len()
-    );    // Step 8: Create OCI-compatible manifest using file-based layer info
-    let mut oci_layers = Vec::new();
-    let mut cached_layers = Vec::new();
-
-    for (layer_digest, (layer_path, layer_size)) in &layer_mapping {
-        cached_layers.push(layer_digest.clone());
-
-        // Detect media type based on layer content
-        let media_type = detect_layer_media_type(layer_path)?;
-
-        // Create OCI layer descriptor using file size and detected media type
-        oci_layers.push(serde_json::json!({
-            "mediaType": media_type,
-            "size": layer_size,
-            "digest": layer_digest
-        }));
-    }
-
-    // Step 9: Save config to cache
-    let config_file_name = format!("config_{}.json", config_digest.replace(":", "_"));
-    let config_path = image_cache_dir.join(&config_file_name);
-
-    tokio::fs::write(&config_path, &config_contents)
-        .await
-        .map_err(|e| PusherError::CacheError(format!("Failed to cache config: {}", e)))?;
-
-    // Step 10: Create OCI manifest
-    let oci_manifest = serde_json::json!({
-        "schemaVersion": 2,
-        "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
-        "config": {
-            "mediaType": "application/vnd.docker.container.image.v1+json",
-            "size": config_contents.len(),
-            "digest": config_digest
-        },
-        "layers": oci_layers
-    });
-
-    // Step 11: Save manifest to cache
-    let manifest_path = image_cache_dir.join("manifest.json");
-    let manifest_json = serde_json::to_string_pretty(&oci_manifest)?;
-    tokio::fs::write(&manifest_path, manifest_json)
-        .await
-        .map_err(|e| PusherError::CacheError(format!("Failed to cache manifest: {}", e)))?;
-
-    // Step 12: Create index file for cache lookup
-    let index = serde_json::json!({
-        "source_image": image_name,
-        "source_type": "tar_import",
-        "source_file": tar_path,
-        "manifest": "manifest.json",
-        "config": config_digest,
-        "layers": cached_layers,
-        "cached_at": std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-    });
-
-    let index_json = serde_json::to_string_pretty(&index)?;
-    tokio::fs::write(image_cache_dir.join("index.json"), index_json)
-        .await
-        .map_err(|e| PusherError::CacheError(format!("Failed to create index: {}", e)))?;
-
-    println!(
-        "🎉 Successfully imported tar archive with {} layers",
-        cached_layers.len()
-    );
-    println!("💡 Cache structure matches pulled images - can be pushed with 'push' command");
-
     Ok(())
 }
+
@@ -0,0 +1,501 @@
+use crate::PusherError;
+use crate::progress::ProgressEvent;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write as _;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{info, warn};
+
+/// Process-wide transfer metrics in Prometheus exposition format
+///
+/// Counters and gauges are updated from the pull/push/import paths and can be
+/// exposed either over HTTP (`--metrics-listen`) for long-running sync jobs,
+/// written to a node_exporter textfile (`--metrics-textfile`) on exit, or
+/// pushed to a Pushgateway (`--pushgateway-url`) on exit.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    bytes_uploaded: AtomicU64,
+    bytes_downloaded: AtomicU64,
+    layers_uploaded: AtomicU64,
+    layers_skipped: AtomicU64,
+    retries: AtomicU64,
+    current_concurrency: AtomicU64,
+    active_transfers: AtomicU64,
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+    live: Mutex<Live>,
+}
+
+/// Number of concurrency adjustments kept for [`LiveStats::adjustments`]
+const ADJUSTMENT_HISTORY: usize = 32;
+
+/// Window over which [`LiveStats::throughput`] is measured
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(10);
+
+/// State behind [`Metrics::live_stats`] that doesn't fit in a counter
+#[derive(Debug, Default)]
+struct Live {
+    layers: BTreeMap<String, LayerProgress>,
+    adjustments: VecDeque<ConcurrencyAdjustment>,
+    /// Total layer bytes moved so far, sampled over the throughput window
+    samples: VecDeque<(Instant, u64)>,
+    moved: u64,
+    peak_throughput: f64,
+}
+
+/// Point-in-time view of the transfers running in this process, e.g. for a dashboard
+///
+/// Returned by [`live_stats`]; the totals are the same counters the
+/// Prometheus endpoint exposes.
+#[derive(Debug, Clone, Default)]
+pub struct LiveStats {
+    /// Current transfer concurrency limit
+    pub concurrency: usize,
+    /// Layer transfers in progress
+    pub active_transfers: u64,
+    /// Progress of each layer transfer in progress, by digest
+    pub layers: Vec<LayerProgress>,
+    /// Layer bytes per second over the last 10 seconds
+    pub throughput: f64,
+    /// Highest `throughput` seen so far; a current value far below it means the transfer slowed down
+    pub peak_throughput: f64,
+    /// Latest concurrency changes, oldest first
+    pub adjustments: Vec<ConcurrencyAdjustment>,
+    /// Bytes uploaded to registries
+    pub bytes_uploaded: u64,
+    /// Bytes downloaded from registries
+    pub bytes_downloaded: u64,
+    /// Layers uploaded
+    pub layers_uploaded: u64,
+    /// Layers skipped because the target already had them
+    pub layers_skipped: u64,
+    /// Transfers retried or rescheduled
+    pub retries: u64,
+}
+
+/// Progress of one layer transfer
+#[derive(Debug, Clone)]
+pub struct LayerProgress {
+    /// Digest of the layer
+    pub digest: String,
+    /// Bytes transferred so far
+    pub transferred: u64,
+    /// Size of the layer in bytes
+    pub total: u64,
+    /// Time since the transfer started
+    pub elapsed: Duration,
+    started: Instant,
+}
+
+/// A change of the transfer concurrency limit
+#[derive(Debug, Clone)]
+pub struct ConcurrencyAdjustment {
+    /// When the limit changed
+    pub at: SystemTime,
+    /// Limit before the change
+    pub from: usize,
+    /// Limit after the change
+    pub to: usize,
+    /// Why it changed (e.g. "memory pressure")
+    pub reason: &'static str,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+/// Returns the global metrics registry
+pub fn global() -> &'static Metrics {
+    &METRICS
+}
+
+/// Returns a snapshot of the transfers running in this process
+///
+/// # Examples
+///
+/// ```
+/// let stats = docker_image_pusher::metrics::live_stats();
+/// for layer in &stats.layers {
+///     println!("{}: {}/{} bytes", layer.digest, layer.transferred, layer.total);
+/// }
+/// println!("{:.1} MB/s at concurrency {}", stats.throughput / 1e6, stats.concurrency);
+/// ```
+pub fn live_stats() -> LiveStats {
+    global().live_stats()
+}
+
+/// Decrements the active transfer gauge when a transfer ends (or is cancelled)
+pub struct ActiveTransfer {
+    digest: Option<String>,
+}
+
+impl Drop for ActiveTransfer {
+    fn drop(&mut self) {
+        let metrics = global();
+        metrics.active_transfers.fetch_sub(1, Ordering::Relaxed);
+        if let Some(digest) = &self.digest {
+            metrics.live().layers.remove(digest);
+        }
+    }
+}
+
+impl Metrics {
+    /// Records a layer upload that transferred `bytes`
+    pub fn record_upload(&self, bytes: u64) {
+        self.bytes_uploaded.fetch_add(bytes, Ordering::Relaxed);
+        self.layers_uploaded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a layer download that transferred `bytes`
+    pub fn record_download(&self, bytes: u64) {
+        self.bytes_downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Records a layer skipped because the target already had it
+    pub fn record_skip(&self) {
+        self.layers_skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a transfer being retried or rescheduled
+    pub fn record_retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a failed operation under its error category
+    pub fn record_error(&self, category: &'static str) {
+        let mut errors = self.errors.lock().unwrap();
+        *errors.entry(category).or_default() += 1;
+    }
+
+    /// Sets the current transfer concurrency limit
+    pub fn set_concurrency(&self, concurrency: usize) {
+        self.current_concurrency
+            .store(concurrency as u64, Ordering::Relaxed);
+    }
+
+    /// Records a change of the concurrency limit and why it happened
+    pub fn record_adjustment(&self, from: usize, to: usize, reason: &'static str) {
+        let mut live = self.live();
+        if live.adjustments.len() == ADJUSTMENT_HISTORY {
+            live.adjustments.pop_front();
+        }
+        live.adjustments.push_back(ConcurrencyAdjustment {
+            at: SystemTime::now(),
+            from,
+            to,
+            reason,
+        });
+    }
+
+    /// Marks a transfer as active until the returned guard is dropped
+    pub fn start_transfer(&self) -> ActiveTransfer {
+        self.active_transfers.fetch_add(1, Ordering::Relaxed);
+        ActiveTransfer { digest: None }
+    }
+
+    /// Marks the transfer of layer `digest` as active until the returned guard is dropped
+    ///
+    /// While it runs, its progress events (see [`Metrics::observe`]) show up
+    /// in [`LiveStats::layers`].
+    pub fn start_layer_transfer(&self, digest: &str, size: u64) -> ActiveTransfer {
+        self.active_transfers.fetch_add(1, Ordering::Relaxed);
+        self.live().layers.insert(
+            digest.to_string(),
+            LayerProgress {
+                digest: digest.to_string(),
+                transferred: 0,
+                total: size,
+                elapsed: Duration::ZERO,
+                started: Instant::now(),
+            },
+        );
+        ActiveTransfer {
+            digest: Some(digest.to_string()),
+        }
+    }
+
+    /// Updates layer progress and throughput from a progress event
+    ///
+    /// Every [`crate::progress::ProgressReporter`] passes its events here.
+    pub fn observe(&self, event: &ProgressEvent) {
+        let ProgressEvent::LayerBytes {
+            digest,
+            transferred,
+            ..
+        } = event
+        else {
+            return;
+        };
+        let mut live = self.live();
+        let Some(layer) = live.layers.get_mut(digest) else {
+            return;
+        };
+        // A retried transfer starts over, which isn't progress
+        let delta = transferred.saturating_sub(layer.transferred);
+        layer.transferred = *transferred;
+        live.moved += delta;
+
+        let now = Instant::now();
+        let moved = live.moved;
+        live.samples.push_back((now, moved));
+        while live
+            .samples
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > THROUGHPUT_WINDOW)
+        {
+            live.samples.pop_front();
+        }
+        let throughput = throughput(&live.samples);
+        live.peak_throughput = live.peak_throughput.max(throughput);
+    }
+
+    /// Returns a snapshot of the transfers running in this process
+    pub fn live_stats(&self) -> LiveStats {
+        let live = self.live();
+        let layers = live
+            .layers
+            .values()
+            .map(|layer| LayerProgress {
+                elapsed: layer.started.elapsed(),
+                ..layer.clone()
+            })
+            .collect();
+        LiveStats {
+            concurrency: self.current_concurrency.load(Ordering::Relaxed) as usize,
+            active_transfers: self.active_transfers.load(Ordering::Relaxed),
+            layers,
+            throughput: throughput(&live.samples),
+            peak_throughput: live.peak_throughput,
+            adjustments: live.adjustments.iter().cloned().collect(),
+            bytes_uploaded: self.bytes_uploaded.load(Ordering::Relaxed),
+            bytes_downloaded: self.bytes_downloaded.load(Ordering::Relaxed),
+            layers_uploaded: self.layers_uploaded.load(Ordering::Relaxed),
+            layers_skipped: self.layers_skipped.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+        }
+    }
+
+    fn live(&self) -> std::sync::MutexGuard<'_, Live> {
+        self.live.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "docker_pusher_bytes_uploaded_total",
+                "Bytes uploaded to registries",
+                &self.bytes_uploaded,
+            ),
+            (
+                "docker_pusher_bytes_downloaded_total",
+                "Bytes downloaded from registries",
+                &self.bytes_downloaded,
+            ),
+            (
+                "docker_pusher_layers_uploaded_total",
+                "Layers uploaded",
+                &self.layers_uploaded,
+            ),
+            (
+                "docker_pusher_layers_skipped_total",
+                "Layers skipped because they already existed",
+                &self.layers_skipped,
+            ),
+            (
+                "docker_pusher_retries_total",
+                "Transfers retried or rescheduled",
+                &self.retries,
+            ),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+
+        let gauges = [
+            (
+                "docker_pusher_current_concurrency",
+                "Current transfer concurrency limit",
+                &self.current_concurrency,
+            ),
+            (
+                "docker_pusher_active_transfers",
+                "Transfers currently in progress",
+                &self.active_transfers,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP docker_pusher_errors_total Failed operations by error category"
+        );
+        let _ = writeln!(out, "# TYPE docker_pusher_errors_total counter");
+        for (category, count) in self.errors.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "docker_pusher_errors_total{{category=\"{}\"}} {}",
+                category, count
+            );
+        }
+        out
+    }
+
+    /// Writes the metrics to a node_exporter textfile, atomically replacing the previous one
+    pub fn write_textfile(&self, path: &Path) -> Result<(), PusherError> {
+        let temp_path = path.with_extension("prom.tmp");
+        std::fs::write(&temp_path, self.render())?;
+        std::fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+
+    /// Pushes the metrics of a finished run to a Prometheus Pushgateway
+    ///
+    /// Short-lived runs end before a scrape could catch them, so they push
+    /// their final metrics instead. Besides everything [`Metrics::render`]
+    /// returns, the run's duration and outcome are added: `outcome` is
+    /// `success`, `partial_success` or the error category. The push replaces
+    /// the metrics of the group identified by `job` and `instance`
+    /// (`PUT <url>/metrics/job/<job>/instance/<instance>`).
+    pub async fn push_to_gateway(
+        &self,
+        url: &str,
+        job: &str,
+        instance: Option<&str>,
+        outcome: &str,
+        duration: Duration,
+    ) -> Result<(), PusherError> {
+        let mut target = reqwest::Url::parse(url)
+            .map_err(|e| PusherError::ConfigError(format!("Invalid Pushgateway URL {}: {}", url, e)))?;
+        {
+            let mut segments = target.path_segments_mut().map_err(|_| {
+                PusherError::ConfigError(format!("Invalid Pushgateway URL {}", url))
+            })?;
+            segments.pop_if_empty().push("metrics");
+            for (name, value) in [("job", Some(job)), ("instance", instance)] {
+                if let Some(value) = value.filter(|value| !value.is_empty()) {
+                    segments.extend(grouping_label(name, value));
+                }
+            }
+        }
+
+        let mut body = self.render();
+        let completed_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let gauges = [
+            (
+                "docker_pusher_run_duration_seconds",
+                "Wall-clock time of the run",
+                format!("{:.3}", duration.as_secs_f64()),
+            ),
+            (
+                "docker_pusher_run_success",
+                "Whether the run succeeded (1) or not (0)",
+                u8::from(outcome == "success").to_string(),
+            ),
+            (
+                "docker_pusher_run_last_completion_timestamp_seconds",
+                "Unix time at which the run ended",
+                completed_at.as_secs().to_string(),
+            ),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(body, "# HELP {} {}", name, help);
+            let _ = writeln!(body, "# TYPE {} gauge", name);
+            let _ = writeln!(body, "{} {}", name, value);
+        }
+        let _ = writeln!(body, "# HELP docker_pusher_run_outcome How the run ended");
+        let _ = writeln!(body, "# TYPE docker_pusher_run_outcome gauge");
+        let _ = writeln!(body, "docker_pusher_run_outcome{{outcome=\"{}\"}} 1", outcome);
+
+        let response = reqwest::Client::new()
+            .put(target.clone())
+            .header(reqwest::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .timeout(Duration::from_secs(10))
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| PusherError::ConfigError(format!("Failed to push metrics to {}: {}", target, e)))?;
+        if !response.status().is_success() {
+            return Err(PusherError::ConfigError(format!(
+                "Pushgateway {} rejected the metrics: {} {}",
+                target,
+                response.status(),
+                response.text().await.unwrap_or_default().trim()
+            )));
+        }
+        info!("📊 Pushed metrics to {}", target);
+        Ok(())
+    }
+}
+
+/// URL path segments of a Pushgateway grouping label
+///
+/// Values containing `/` can't appear in a path as is, so the Pushgateway
+/// takes them base64url-encoded under `<name>@base64`.
+fn grouping_label(name: &str, value: &str) -> [String; 2] {
+    use base64::Engine;
+    if value.contains('/') {
+        [
+            format!("{}@base64", name),
+            base64::engine::general_purpose::URL_SAFE.encode(value),
+        ]
+    } else {
+        [name.to_string(), value.to_string()]
+    }
+}
+
+/// Bytes per second between the oldest and newest sample
+fn throughput(samples: &VecDeque<(Instant, u64)>) -> f64 {
+    match (samples.front(), samples.back()) {
+        (Some((first_at, first)), Some((last_at, last))) if last_at > first_at => {
+            (last - first) as f64 / last_at.duration_since(*first_at).as_secs_f64()
+        }
+        _ => 0.0,
+    }
+}
+
+/// Serves `/metrics` over plain HTTP until the process exits
+///
+/// This is a deliberately tiny HTTP/1.1 responder: every request receives the
+/// current metrics, which is all a Prometheus scraper needs.
+pub async fn serve(listen: &str) -> Result<(), PusherError> {
+    let listener = tokio::net::TcpListener::bind(listen).await.map_err(|e| {
+        PusherError::ConfigError(format!("Failed to bind metrics endpoint {}: {}", listen, e))
+    })?;
+    info!("📊 Serving Prometheus metrics on http://{}/metrics", listen);
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!("⚠️  Metrics endpoint accept failed: {}", e);
+                    continue;
+                }
+            };
+            tokio::spawn(async move {
+                // Read (and ignore) the request head before responding
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request).await;
+                let body = global().render();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+                let _ = stream.shutdown().await;
+            });
+        }
+    });
+    Ok(())
+}
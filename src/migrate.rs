@@ -0,0 +1,249 @@
+//! Cache format upgrades (`cache migrate`)
+//!
+//! Every cache index records the format version it was written with
+//! ([`CACHE_VERSION`]); indexes without one are version 1. When the format
+//! changes, [`migrate`] upgrades the images already cached in place, so a
+//! cache of terabytes doesn't have to be pulled again.
+//!
+//! Before an image is changed, its files are hard-linked into
+//! `.cache/migration_backup/` (no extra space for the layers a migration
+//! leaves alone, since changed files are replaced rather than rewritten). A
+//! migration that fails midway is rolled back automatically, and a
+//! completed one can be undone with [`rollback`] until the backup is
+//! deleted. Other commands shouldn't use the cache while it is migrated.
+//!
+//! | Version | Change |
+//! |---|---|
+//! | 1 | Indexes without a format version |
+//! | 2 | Indexes record the digest of the cached manifest (`manifest_file_digest`) |
+
+use crate::PusherError;
+use crate::image::digest::{Algorithm, Hasher};
+use crate::store;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// Cache format version written by this build
+pub const CACHE_VERSION: u64 = 2;
+
+/// Directory (inside the cache directory) holding the images as they were before migrating
+const BACKUP_DIR_NAME: &str = "migration_backup";
+
+/// Outcome of [`migrate`]
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MigrationReport {
+    /// Images upgraded to [`CACHE_VERSION`]
+    pub migrated: Vec<String>,
+    /// Images already at [`CACHE_VERSION`]
+    pub current: usize,
+    /// Images written by a newer version of the tool, left alone
+    pub newer: Vec<String>,
+    /// Backup of the migrated images, `None` if nothing was migrated
+    pub backup: Option<PathBuf>,
+}
+
+/// A complete image in the cache and the format version of its index
+struct CachedImage {
+    dir: PathBuf,
+    name: String,
+    version: u64,
+    index: serde_json::Value,
+}
+
+/// Upgrades every complete image in `cache_dir` to [`CACHE_VERSION`]
+///
+/// Fails without changing anything if the backup of an earlier migration is
+/// still there; it must be rolled back or deleted first.
+///
+/// # Examples
+///
+/// ```
+/// use docker_image_pusher::migrate;
+///
+/// let cache_dir = std::env::temp_dir().join("migrate-example");
+/// # let _ = std::fs::remove_dir_all(&cache_dir);
+/// std::fs::create_dir_all(&cache_dir)?;
+/// let runtime = tokio::runtime::Runtime::new().unwrap();
+/// let report = runtime.block_on(migrate::migrate(&cache_dir))?;
+/// assert!(report.migrated.is_empty());
+/// # Ok::<(), docker_image_pusher::PusherError>(())
+/// ```
+pub async fn migrate(cache_dir: &Path) -> Result<MigrationReport, PusherError> {
+    let mut report = MigrationReport::default();
+    let mut outdated = Vec::new();
+    for image in cached_images(cache_dir)? {
+        if image.version == CACHE_VERSION {
+            report.current += 1;
+        } else if image.version > CACHE_VERSION {
+            warn!(
+                "⚠️  {} was cached by a newer version (format {}), leaving it alone",
+                image.name, image.version
+            );
+            report.newer.push(image.name);
+        } else {
+            outdated.push(image);
+        }
+    }
+    if outdated.is_empty() {
+        return Ok(report);
+    }
+
+    let backup = cache_dir.join(BACKUP_DIR_NAME);
+    if backup.exists() {
+        return Err(PusherError::CacheError(format!(
+            "{} holds the backup of an earlier migration; roll it back (cache migrate --rollback) or delete it first",
+            backup.display()
+        )));
+    }
+    for image in outdated {
+        info!(
+            "🔧 Migrating {} from cache format {} to {}",
+            image.name, image.version, CACHE_VERSION
+        );
+        let result = match back_up(&image.dir, &backup) {
+            Ok(()) => upgrade(image).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(name) => report.migrated.push(name),
+            Err(e) => {
+                warn!("⚠️  Migration failed, restoring the cache: {}", e);
+                rollback(cache_dir)?;
+                return Err(e);
+            }
+        }
+    }
+    report.backup = Some(backup);
+    Ok(report)
+}
+
+/// Restores the images the last [`migrate`] changed from its backup, and deletes the backup
+///
+/// Changes made to those images since the migration are lost.
+///
+/// # Returns
+///
+/// `Result<usize, PusherError>` - Number of images restored
+pub fn rollback(cache_dir: &Path) -> Result<usize, PusherError> {
+    let backup = cache_dir.join(BACKUP_DIR_NAME);
+    let entries = std::fs::read_dir(&backup).map_err(|e| {
+        PusherError::CacheError(format!(
+            "No migration backup in {}: {}",
+            backup.display(),
+            e
+        ))
+    })?;
+    let mut restored = 0;
+    for entry in entries {
+        let saved = entry
+            .map_err(|e| PusherError::CacheError(format!("Failed to read the backup: {}", e)))?
+            .path();
+        let Some(name) = saved.file_name() else {
+            continue;
+        };
+        let dir = cache_dir.join(name);
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).map_err(|e| {
+                PusherError::CacheError(format!("Failed to remove {}: {}", dir.display(), e))
+            })?;
+        }
+        std::fs::rename(&saved, &dir).map_err(|e| {
+            PusherError::CacheError(format!("Failed to restore {}: {}", dir.display(), e))
+        })?;
+        restored += 1;
+    }
+    std::fs::remove_dir_all(&backup).map_err(|e| {
+        PusherError::CacheError(format!("Failed to remove {}: {}", backup.display(), e))
+    })?;
+    Ok(restored)
+}
+
+/// Complete images in `cache_dir`, with the format version of their index
+fn cached_images(cache_dir: &Path) -> Result<Vec<CachedImage>, PusherError> {
+    let entries = std::fs::read_dir(cache_dir).map_err(|e| {
+        PusherError::CacheError(format!("Failed to read {}: {}", cache_dir.display(), e))
+    })?;
+    let mut images = Vec::new();
+    for entry in entries.flatten() {
+        let dir = entry.path();
+        // Only complete images have an index
+        let Ok(index) = std::fs::read(dir.join("index.json")) else {
+            continue;
+        };
+        let index: serde_json::Value = serde_json::from_slice(&index).map_err(|e| {
+            PusherError::CacheError(format!("Invalid index in {}: {}", dir.display(), e))
+        })?;
+        images.push(CachedImage {
+            name: index["source_image"]
+                .as_str()
+                .map(str::to_string)
+                .unwrap_or_else(|| entry.file_name().to_string_lossy().into_owned()),
+            version: index["cache_version"].as_u64().unwrap_or(1),
+            index,
+            dir,
+        });
+    }
+    images.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(images)
+}
+
+/// Hard-links (or copies) the files of `dir` into a directory of the same name in `backup`
+fn back_up(dir: &Path, backup: &Path) -> Result<(), PusherError> {
+    let io_error = |e: std::io::Error| {
+        PusherError::CacheError(format!("Failed to back up {}: {}", dir.display(), e))
+    };
+    let Some(name) = dir.file_name() else {
+        return Ok(());
+    };
+    let saved = backup.join(name);
+    std::fs::create_dir_all(&saved).map_err(io_error)?;
+    for entry in std::fs::read_dir(dir).map_err(io_error)? {
+        let entry = entry.map_err(io_error)?;
+        if !entry.file_type().map_err(io_error)?.is_file() {
+            continue;
+        }
+        let target = saved.join(entry.file_name());
+        if std::fs::hard_link(entry.path(), &target).is_err() {
+            std::fs::copy(entry.path(), &target).map_err(io_error)?;
+        }
+    }
+    Ok(())
+}
+
+/// Applies the migration steps from the image's version up, then writes its index
+///
+/// # Returns
+///
+/// `Result<String, PusherError>` - Name of the migrated image
+async fn upgrade(mut image: CachedImage) -> Result<String, PusherError> {
+    for version in image.version..CACHE_VERSION {
+        match version {
+            1 => record_manifest_digest(&image.dir, &mut image.index).await?,
+            _ => unreachable!("no migration from cache format {}", version),
+        }
+    }
+    image.index["cache_version"] = CACHE_VERSION.into();
+    let index_json = serde_json::to_string_pretty(&image.index)?;
+    store::write_atomic(&image.dir.join("index.json"), index_json)
+        .await
+        .map_err(|e| PusherError::CacheError(format!("Failed to write index: {}", e)))?;
+    Ok(image.name)
+}
+
+/// Version 1 to 2: records the digest of the cached manifest bytes
+async fn record_manifest_digest(
+    dir: &Path,
+    index: &mut serde_json::Value,
+) -> Result<(), PusherError> {
+    if index["manifest_file_digest"].is_string() {
+        return Ok(());
+    }
+    let manifest = tokio::fs::read(dir.join("manifest.json"))
+        .await
+        .map_err(|e| PusherError::CacheError(format!("Failed to read manifest: {}", e)))?;
+    let mut hasher = Hasher::new(Algorithm::Sha256);
+    hasher.update(&manifest);
+    index["manifest_file_digest"] = hasher.finalize().to_string().into();
+    Ok(())
+}
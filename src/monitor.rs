@@ -0,0 +1,400 @@
+use crate::PusherError;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::debug;
+
+/// Fraction of the memory cap above which new work is considered under pressure
+const MEMORY_PRESSURE_RATIO: f64 = 0.8;
+
+/// cgroup v1 reports "unlimited" as a huge page-aligned value rather than "max"
+const CGROUP_V1_UNLIMITED_THRESHOLD: u64 = 1 << 60;
+
+/// Samples process memory usage and decides how aggressively layers can be buffered
+///
+/// Some upload paths read a whole layer into memory before handing it to the
+/// registry client. The monitor compares the current resident set size against
+/// the effective memory cap (the smaller of `--max-memory` and the cgroup limit)
+/// so the push loop can lower its concurrency or fall back to streaming uploads
+/// before the process gets OOM-killed.
+///
+/// On platforms where RSS cannot be sampled the monitor never reports pressure
+/// and the tool behaves exactly as it would without a cap.
+#[derive(Debug, Clone)]
+pub struct PerformanceMonitor {
+    memory_cap: Option<u64>,
+    buffer_limit: Option<u64>,
+}
+
+impl PerformanceMonitor {
+    /// Creates a monitor using the configured cap and the container's cgroup limit
+    ///
+    /// # Arguments
+    ///
+    /// * `max_memory` - Optional user-supplied cap in bytes (`--max-memory`)
+    pub fn new(max_memory: Option<u64>) -> Self {
+        let cgroup = cgroup_memory_limit();
+        let memory_cap = match (max_memory, cgroup) {
+            (Some(configured), Some(cgroup)) => Some(configured.min(cgroup)),
+            (configured, cgroup) => configured.or(cgroup),
+        };
+        // Only part of a container's limit can go to buffers; the rest is the process itself
+        let cgroup_share = cgroup.map(|limit| (limit as f64 * MEMORY_PRESSURE_RATIO) as u64);
+        let buffer_limit = match (max_memory, cgroup_share) {
+            (Some(configured), Some(cgroup)) => Some(configured.min(cgroup)),
+            (configured, cgroup) => configured.or(cgroup),
+        };
+        Self {
+            memory_cap,
+            buffer_limit,
+        }
+    }
+
+    /// Returns the effective memory cap in bytes, if any
+    pub fn memory_cap(&self) -> Option<u64> {
+        self.memory_cap
+    }
+
+    /// Returns how many bytes of layer data may be buffered at once, if limited
+    ///
+    /// This is `--max-memory` itself, or the share of the cgroup limit left
+    /// once the process's own memory is accounted for, whichever is smaller.
+    pub fn buffer_limit(&self) -> Option<u64> {
+        self.buffer_limit
+    }
+
+    /// Returns the current resident set size of this process in bytes
+    ///
+    /// Only implemented on Linux (via `/proc/self/status`); returns `None` elsewhere.
+    pub fn current_rss(&self) -> Option<u64> {
+        let status = std::fs::read_to_string("/proc/self/status").ok()?;
+        status
+            .lines()
+            .find(|line| line.starts_with("VmRSS:"))
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|kb| kb.parse::<u64>().ok())
+            .map(|kb| kb * 1024)
+    }
+
+    /// Checks whether `bytes` more can be buffered without approaching the cap
+    pub fn can_buffer(&self, bytes: u64) -> bool {
+        match (self.memory_cap, self.current_rss()) {
+            (Some(cap), Some(rss)) => (rss + bytes) as f64 <= cap as f64 * MEMORY_PRESSURE_RATIO,
+            _ => true,
+        }
+    }
+
+    /// Checks whether the process is already close to its memory cap
+    pub fn is_under_pressure(&self) -> bool {
+        !self.can_buffer(0)
+    }
+
+    /// Computes how many transfers may run concurrently given current memory use
+    ///
+    /// Halves the configured limit while under pressure (never below one) so
+    /// in-flight buffers can drain before more work is scheduled.
+    pub fn adjust_concurrency(&self, configured: usize) -> usize {
+        if self.is_under_pressure() {
+            (configured / 2).max(1)
+        } else {
+            configured.max(1)
+        }
+    }
+}
+
+/// Hard limit on layer data held in memory across concurrent transfers
+///
+/// Sampling RSS only notices pressure after the memory is in use, so a burst
+/// of concurrent buffered uploads can still overshoot the cap. The budget
+/// accounts for bytes instead: every transfer reserves what it will buffer
+/// (the whole layer, or its chunk size when streaming) before reading
+/// anything and waits until enough is free, so the sum of in-flight buffers
+/// never exceeds the limit.
+///
+/// Reservations larger than the whole budget are clamped to it, so a single
+/// transfer can always proceed; callers stream layers that don't fit.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    /// One permit per KiB, so the largest reservation fits in a `u32`
+    permits: Option<Semaphore>,
+    limit: Option<u64>,
+}
+
+/// A reservation from a [`MemoryBudget`], returned to it when dropped
+#[derive(Debug)]
+pub struct MemoryReservation<'a> {
+    _permit: Option<SemaphorePermit<'a>>,
+}
+
+impl MemoryBudget {
+    /// Creates a budget of `limit` bytes (`None` = unlimited)
+    pub fn new(limit: Option<u64>) -> Self {
+        let permits = limit.map(|limit| Semaphore::new(kibibytes(limit) as usize));
+        Self { permits, limit }
+    }
+
+    /// Returns the budget in bytes, if limited
+    pub fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    /// Checks whether `bytes` can be buffered at all without exceeding the budget
+    pub fn fits(&self, bytes: u64) -> bool {
+        self.limit.is_none_or(|limit| bytes <= limit)
+    }
+
+    /// Waits until `bytes` are free and reserves them
+    pub async fn reserve(&self, bytes: u64) -> MemoryReservation<'_> {
+        let permit = match (&self.permits, self.limit) {
+            (Some(permits), Some(limit)) => permits
+                .acquire_many(kibibytes(bytes.min(limit)).max(1))
+                .await
+                .ok(),
+            _ => None,
+        };
+        MemoryReservation { _permit: permit }
+    }
+}
+
+/// Converts bytes to whole KiB (rounded up), saturating at `u32::MAX`
+fn kibibytes(bytes: u64) -> u32 {
+    bytes.div_ceil(1024).min(u32::MAX as u64) as u32
+}
+
+/// Smallest chunk adaptive sizing shrinks to
+pub const MIN_CHUNK_SIZE: usize = 1024 * 1024; // 1MB
+
+/// Largest chunk adaptive sizing grows to (stays below common 100MB proxy body limits)
+pub const MAX_CHUNK_SIZE: usize = 64 * 1024 * 1024; // 64MB
+
+/// How long a single PATCH request should ideally take
+///
+/// Long enough that per-request latency is negligible on fast links, short
+/// enough that a failed chunk costs little to resend on slow ones.
+const TARGET_CHUNK_DURATION: Duration = Duration::from_secs(4);
+
+/// Picks the size of each chunk in a streamed upload from how earlier chunks went
+///
+/// Works like TCP slow start: the size doubles after every chunk that
+/// finished well within [`TARGET_CHUNK_DURATION`] and grows by a quarter
+/// after one that merely made it, or once the size passed the one at which a
+/// chunk last failed. A failed chunk halves the size; a chunk that took more
+/// than twice the target shrinks it towards what the measured throughput
+/// moves in the target time (by half at most). The result is
+/// clamped to [`MIN_CHUNK_SIZE`]..[`MAX_CHUNK_SIZE`] (or the maximum set with
+/// [`ChunkSizer::with_max_size`]) and halved while the
+/// [`PerformanceMonitor`] reports that the buffer would not fit under the
+/// memory cap.
+///
+/// One sizer is shared by all concurrent uploads of a push, so every layer
+/// benefits from what the others measured.
+#[derive(Debug)]
+pub struct ChunkSizer {
+    state: Mutex<ChunkState>,
+    adaptive: bool,
+    max_size: usize,
+    monitor: Option<PerformanceMonitor>,
+}
+
+#[derive(Debug)]
+struct ChunkState {
+    size: usize,
+    /// Size at which a chunk last failed; growth slows down above it
+    threshold: usize,
+}
+
+impl ChunkSizer {
+    /// Creates a sizer that always uses `size`
+    pub fn fixed(size: usize) -> Self {
+        Self {
+            state: Mutex::new(ChunkState {
+                size: size.max(1),
+                threshold: usize::MAX,
+            }),
+            adaptive: false,
+            max_size: size.max(1),
+            monitor: None,
+        }
+    }
+
+    /// Creates a sizer starting at `initial` and adapting to the link
+    ///
+    /// # Arguments
+    ///
+    /// * `initial` - First chunk size, e.g. the one learned for the registry
+    /// * `monitor` - Memory monitor whose cap bounds the chunk buffer
+    pub fn adaptive(initial: usize, monitor: PerformanceMonitor) -> Self {
+        Self {
+            state: Mutex::new(ChunkState {
+                size: initial.clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE),
+                threshold: usize::MAX,
+            }),
+            adaptive: true,
+            max_size: MAX_CHUNK_SIZE,
+            monitor: Some(monitor),
+        }
+    }
+
+    /// Lowers the largest size chunks may grow to, e.g. to fit a [`MemoryBudget`]
+    ///
+    /// Never goes below [`MIN_CHUNK_SIZE`].
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size.clamp(MIN_CHUNK_SIZE, self.max_size.max(MIN_CHUNK_SIZE));
+        let state = self.state.get_mut().unwrap();
+        state.size = state.size.min(self.max_size);
+        self
+    }
+
+    /// Returns the largest chunk this sizer will ask for
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Returns the current chunk size, as learned so far
+    pub fn current(&self) -> usize {
+        self.state.lock().unwrap().size
+    }
+
+    /// Returns the size to use for the next chunk
+    pub fn next_size(&self) -> usize {
+        let mut size = self.current();
+        if let Some(monitor) = &self.monitor {
+            while size > MIN_CHUNK_SIZE && !monitor.can_buffer(size as u64) {
+                size /= 2;
+            }
+        }
+        size
+    }
+
+    /// Records a chunk of `bytes` that was accepted after `elapsed`
+    pub fn record_success(&self, bytes: usize, elapsed: Duration) {
+        let mut state = self.state.lock().unwrap();
+        // The short tail chunk of a blob says more about latency than throughput
+        if !self.adaptive || bytes < state.size / 2 {
+            return;
+        }
+        let seconds = elapsed.as_secs_f64().max(0.001);
+        let ideal = (bytes as f64 / seconds * TARGET_CHUNK_DURATION.as_secs_f64()) as usize;
+        let size = if elapsed > TARGET_CHUNK_DURATION * 2 {
+            ideal.max(state.size / 2)
+        } else if elapsed < TARGET_CHUNK_DURATION / 2 && state.size < state.threshold {
+            state.size.saturating_mul(2)
+        } else if elapsed < TARGET_CHUNK_DURATION {
+            state.size.saturating_add(state.size / 4)
+        } else {
+            state.size
+        };
+        let size = size.clamp(MIN_CHUNK_SIZE, self.max_size);
+        if size != state.size {
+            debug!(
+                from = state.size,
+                to = size,
+                throughput_mbps = bytes as f64 / seconds / (1024.0 * 1024.0),
+                "chunk size adjusted"
+            );
+            state.size = size;
+        }
+    }
+
+    /// Records a chunk that failed, e.g. timed out or was rejected
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !self.adaptive {
+            return;
+        }
+        state.threshold = state.size;
+        state.size = (state.size / 2).max(MIN_CHUNK_SIZE);
+        debug!(to = state.size, "chunk failed, chunk size halved");
+    }
+}
+
+/// How long a transfer may go without moving any bytes before it is retried
+pub const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Detects a transfer that stopped moving data
+///
+/// The transfer calls [`StallWatch::touch`] whenever bytes move and races
+/// itself against [`StallWatch::stalled`], which resolves once nothing moved
+/// for the timeout, however long the transfer as a whole is allowed to take.
+/// A hung TLS connection otherwise keeps a layer waiting until the OS gives
+/// up on the socket, which can take hours.
+#[derive(Debug, Clone)]
+pub struct StallWatch {
+    timeout: Option<Duration>,
+    last_progress: Arc<Mutex<Instant>>,
+}
+
+impl StallWatch {
+    /// Creates a watch firing after `timeout` without progress, or never for `None`
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            last_progress: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Returns the time without progress after which the transfer counts as stalled
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Records that bytes moved
+    pub fn touch(&self) {
+        *self.last_progress.lock().unwrap() = Instant::now();
+    }
+
+    /// Resolves once no progress was recorded for the timeout; never without one
+    pub async fn stalled(&self) {
+        let Some(timeout) = self.timeout else {
+            return std::future::pending().await;
+        };
+        loop {
+            let deadline = *self.last_progress.lock().unwrap() + timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    }
+}
+
+/// Reads the memory limit imposed by the current cgroup (v2, then v1)
+fn cgroup_memory_limit() -> Option<u64> {
+    if let Ok(limit) = std::fs::read_to_string("/sys/fs/cgroup/memory.max") {
+        return limit.trim().parse::<u64>().ok();
+    }
+
+    std::fs::read_to_string("/sys/fs/cgroup/memory/memory.limit_in_bytes")
+        .ok()
+        .and_then(|limit| limit.trim().parse::<u64>().ok())
+        .filter(|limit| *limit < CGROUP_V1_UNLIMITED_THRESHOLD)
+}
+
+/// Parses human-readable memory sizes such as `512M`, `2G` or `1048576`
+///
+/// Suffixes are binary multiples (`K` = 1024) and an optional trailing `B`
+/// or `iB` is accepted, so `512MiB` and `512MB` are equivalent.
+pub fn parse_memory_size(input: &str) -> Result<u64, PusherError> {
+    let trimmed = input.trim();
+    let upper = trimmed.to_ascii_uppercase();
+    let without_unit = upper
+        .strip_suffix("IB")
+        .or_else(|| upper.strip_suffix('B'))
+        .unwrap_or(&upper);
+
+    let (number, multiplier) = match without_unit.chars().last() {
+        Some('K') => (&without_unit[..without_unit.len() - 1], 1u64 << 10),
+        Some('M') => (&without_unit[..without_unit.len() - 1], 1u64 << 20),
+        Some('G') => (&without_unit[..without_unit.len() - 1], 1u64 << 30),
+        Some('T') => (&without_unit[..without_unit.len() - 1], 1u64 << 40),
+        _ => (without_unit, 1u64),
+    };
+
+    number
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|value| value.checked_mul(multiplier))
+        .filter(|bytes| *bytes > 0)
+        .ok_or_else(|| PusherError::ConfigError(format!("Invalid memory size: {}", input)))
+}
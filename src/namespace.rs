@@ -0,0 +1,65 @@
+//! Cache namespaces (`--cache-namespace`)
+//!
+//! A shared build server can keep the images of several teams in one cache
+//! root without any team seeing the image names of another: each namespace
+//! is a cache directory of its own under `.cache/namespaces/<name>/`, with
+//! its images, indexes, learned registry profiles, blob ledgers and upload
+//! journals.
+//!
+//! Layer blobs are content addressed and carry no names, so namespaces still
+//! share them: a layer pulled into a namespace is hard-linked into the
+//! root's `blobs/` directory, and a pull in another namespace links it from
+//! there instead of downloading it again (see [`crate::store::FsBlobStore::sharing`]).
+//! Caches outside a namespace don't use the shared blobs.
+
+use crate::PusherError;
+use std::path::{Path, PathBuf};
+
+/// Directory (inside the cache root) holding the namespaces
+const NAMESPACES_DIR_NAME: &str = "namespaces";
+
+/// Directory (inside the cache root) holding the blobs shared by the namespaces
+const SHARED_BLOBS_DIR_NAME: &str = "blobs";
+
+/// Cache directory of `namespace` within the cache root `root`
+///
+/// Names are letters, digits, `.`, `_` and `-`, not starting with `.`, and
+/// must be valid directory names on Windows too (no `NUL` or trailing `.`),
+/// so a cache can move between runners.
+///
+/// # Examples
+///
+/// ```
+/// # use docker_image_pusher::namespace;
+/// # use std::path::Path;
+/// let dir = namespace::cache_dir(Path::new(".cache"), "team-a")?;
+/// assert_eq!(dir, Path::new(".cache/namespaces/team-a"));
+/// assert_eq!(namespace::shared_blobs_dir(&dir), Some(Path::new(".cache/blobs").to_path_buf()));
+/// assert!(namespace::cache_dir(Path::new(".cache"), "../team-b").is_err());
+/// assert!(namespace::cache_dir(Path::new(".cache"), "aux").is_err());
+/// # Ok::<(), docker_image_pusher::PusherError>(())
+/// ```
+pub fn cache_dir(root: &Path, namespace: &str) -> Result<PathBuf, PusherError> {
+    let valid = !namespace.is_empty()
+        && !namespace.starts_with('.')
+        && namespace
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        && crate::image::windows_file_name(namespace) == namespace;
+    if !valid {
+        return Err(PusherError::ConfigError(format!(
+            "Invalid cache namespace {:?}: use letters, digits, '.', '_' and '-', not starting with '.' or ending with '.', and not a Windows device name",
+            namespace
+        )));
+    }
+    Ok(root.join(NAMESPACES_DIR_NAME).join(namespace))
+}
+
+/// Shared blob directory for `cache_dir`, `None` unless it is a namespace's cache directory
+pub fn shared_blobs_dir(cache_dir: &Path) -> Option<PathBuf> {
+    let namespaces = cache_dir.parent()?;
+    if namespaces.file_name()? != NAMESPACES_DIR_NAME {
+        return None;
+    }
+    Some(namespaces.parent()?.join(SHARED_BLOBS_DIR_NAME))
+}
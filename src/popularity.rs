@@ -0,0 +1,127 @@
+//! Layer popularity across pending transfers
+//!
+//! When several images are copied into the same repository (e.g. the tags
+//! of a sync manifest firing together), uploading the layers they share
+//! first lets the other transfers find them in the registry and skip them,
+//! instead of each uploading its own copy. [`LayerPopularity`] counts how
+//! many pending images of each target repository use each layer; a transfer
+//! orders its downloads and uploads by the counts of its repository (see
+//! [`LayerRanking`]). Registries only skip blobs the same repository already
+//! has, so images of other repositories don't count.
+
+use oci_spec::distribution::Reference;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// How many pending images of each target repository use each layer
+///
+/// Cheap to clone; clones share the counts.
+///
+/// # Examples
+///
+/// ```
+/// # use docker_image_pusher::popularity::LayerPopularity;
+/// let popularity = LayerPopularity::default();
+/// let base = "sha256:1111111111111111111111111111111111111111111111111111111111111111".to_string();
+/// let app = "sha256:2222222222222222222222222222222222222222222222222222222222222222".to_string();
+/// popularity.add("registry.example.com/app:v1", &[base.clone()]);
+/// popularity.add("registry.example.com/app:v2", &[app.clone(), base.clone()]);
+///
+/// // The base layer is uploaded first
+/// let ranking = popularity.ranking("registry.example.com/app:v2");
+/// assert_eq!(ranking.order([app.as_str(), base.as_str()]), vec![1, 0]);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct LayerPopularity {
+    /// Repository -> layer digest -> pending images using it
+    counts: Arc<Mutex<HashMap<String, HashMap<String, usize>>>>,
+}
+
+impl LayerPopularity {
+    /// Counts `layers` as used by one more image pending for `target`
+    pub fn add(&self, target: &str, layers: &[String]) {
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        let repository = counts.entry(repository_of(target)).or_default();
+        for layer in layers {
+            *repository.entry(layer.clone()).or_default() += 1;
+        }
+    }
+
+    /// Undoes [`LayerPopularity::add`] once the image for `target` is no longer pending
+    pub fn remove(&self, target: &str, layers: &[String]) {
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        let key = repository_of(target);
+        let Some(repository) = counts.get_mut(&key) else {
+            return;
+        };
+        for layer in layers {
+            if let Some(count) = repository.get_mut(layer) {
+                *count -= 1;
+                if *count == 0 {
+                    repository.remove(layer);
+                }
+            }
+        }
+        if repository.is_empty() {
+            counts.remove(&key);
+        }
+    }
+
+    /// The counts of the repository of `target`, for ordering its layers
+    pub fn ranking(&self, target: &str) -> LayerRanking {
+        LayerRanking {
+            popularity: Some((self.clone(), repository_of(target))),
+        }
+    }
+}
+
+/// Layer popularity within one target repository
+///
+/// The default ranks all layers the same, keeping manifest order.
+#[derive(Debug, Clone, Default)]
+pub struct LayerRanking {
+    popularity: Option<(LayerPopularity, String)>,
+}
+
+impl LayerRanking {
+    /// Pending images of the repository using `digest`
+    pub fn count(&self, digest: &str) -> usize {
+        let Some((popularity, repository)) = &self.popularity else {
+            return 0;
+        };
+        let counts = popularity.counts.lock().unwrap_or_else(|e| e.into_inner());
+        counts
+            .get(repository)
+            .and_then(|layers| layers.get(digest))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Whether images other than the one being transferred use `digest`
+    pub fn is_shared(&self, digest: &str) -> bool {
+        self.count(digest) > 1
+    }
+
+    /// Indices of `digests`, most used layers first and manifest order otherwise
+    pub fn order<'a>(&self, digests: impl IntoIterator<Item = &'a str>) -> Vec<usize> {
+        let mut ranked: Vec<(usize, usize)> = digests
+            .into_iter()
+            .map(|digest| self.count(digest))
+            .enumerate()
+            .collect();
+        ranked.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        ranked.into_iter().map(|(i, _)| i).collect()
+    }
+}
+
+/// `registry/repository` of an image reference, the image itself if it doesn't parse
+fn repository_of(image: &str) -> String {
+    match image.parse::<Reference>() {
+        Ok(reference) => format!(
+            "{}/{}",
+            reference.resolve_registry(),
+            reference.repository()
+        ),
+        Err(_) => image.to_string(),
+    }
+}
@@ -0,0 +1,136 @@
+use crate::PusherError;
+use crate::concurrency::ConcurrencyConfig;
+use crate::store;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// File (inside the cache directory) holding learned per-registry profiles
+const PROFILE_FILE_NAME: &str = "registry_profiles.json";
+
+/// Upper bound for concurrency reached by probing upwards between runs
+const MAX_PROFILE_CONCURRENCY: usize = 8;
+
+/// Weight of the newest run when averaging throughput
+const THROUGHPUT_SMOOTHING: f64 = 0.3;
+
+/// Performance settings learned for one registry host
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegistryProfile {
+    /// Concurrency to start the next push with
+    pub max_concurrent: usize,
+    /// Chunk size in bytes for streamed uploads
+    pub chunk_size: usize,
+    /// Smoothed upload throughput in MB/s
+    pub throughput_mbps: f64,
+    /// Unix timestamp of the last update
+    pub updated_at: u64,
+}
+
+/// Summary of a finished push used to update a registry profile
+#[derive(Debug, Clone)]
+pub struct RunObservation {
+    /// Concurrency the run started with
+    pub starting_concurrency: usize,
+    /// Lowest concurrency the run backed off to (the starting one if it never did)
+    pub final_concurrency: usize,
+    /// Chunk size used for streamed uploads
+    pub chunk_size: usize,
+    /// Bytes actually uploaded (skipped layers excluded)
+    pub bytes_uploaded: u64,
+    /// Wall-clock time spent uploading layers
+    pub elapsed: std::time::Duration,
+}
+
+/// Persisted collection of registry profiles keyed by registry host
+///
+/// Stored as `.cache/registry_profiles.json` so that a push to a registry that
+/// was used before starts from the concurrency and chunk size that worked last
+/// time instead of re-learning from the defaults.
+#[derive(Debug, Default)]
+pub struct ProfileStore {
+    path: PathBuf,
+    profiles: HashMap<String, RegistryProfile>,
+}
+
+impl ProfileStore {
+    /// Loads profiles from the cache directory, starting empty if none exist or the file is unreadable
+    pub fn load(cache_dir: &Path) -> Self {
+        let path = cache_dir.join(PROFILE_FILE_NAME);
+        let profiles = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+        Self { path, profiles }
+    }
+
+    /// Returns the profile recorded for `registry`, if any
+    pub fn get(&self, registry: &str) -> Option<&RegistryProfile> {
+        self.profiles.get(registry)
+    }
+
+    /// Builds the concurrency configuration to start a push to `registry` with
+    pub fn seed_config(&self, registry: &str) -> ConcurrencyConfig {
+        match self.get(registry) {
+            Some(profile) => ConcurrencyConfig {
+                max_concurrent: profile.max_concurrent.max(1),
+                chunk_size: profile.chunk_size,
+            },
+            None => ConcurrencyConfig::default(),
+        }
+    }
+
+    /// Folds the results of a finished push into the profile for `registry`
+    ///
+    /// A run that completed without backing off probes one level higher next
+    /// time; a run that had to reduce concurrency records the reduced level.
+    pub fn record(&mut self, registry: &str, observation: &RunObservation) {
+        let run_throughput = if observation.elapsed.as_secs_f64() > 0.0 {
+            observation.bytes_uploaded as f64
+                / (1024.0 * 1024.0)
+                / observation.elapsed.as_secs_f64()
+        } else {
+            0.0
+        };
+
+        let max_concurrent = if observation.final_concurrency < observation.starting_concurrency {
+            observation.final_concurrency
+        } else {
+            (observation.final_concurrency + 1).min(MAX_PROFILE_CONCURRENCY)
+        };
+
+        let throughput_mbps = match self.profiles.get(registry) {
+            Some(previous) if previous.throughput_mbps > 0.0 => {
+                previous.throughput_mbps * (1.0 - THROUGHPUT_SMOOTHING)
+                    + run_throughput * THROUGHPUT_SMOOTHING
+            }
+            _ => run_throughput,
+        };
+
+        self.profiles.insert(
+            registry.to_string(),
+            RegistryProfile {
+                max_concurrent: max_concurrent.max(1),
+                chunk_size: observation.chunk_size,
+                throughput_mbps,
+                updated_at: std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+            },
+        );
+    }
+
+    /// Writes all profiles back to the cache directory
+    ///
+    /// The file is replaced atomically, so a crash mid-save keeps the previous profiles.
+    pub async fn save(&self) -> Result<(), PusherError> {
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let json = serde_json::to_string_pretty(&self.profiles)?;
+        store::write_atomic(&self.path, json).await.map_err(|e| {
+            PusherError::cache_error(format!("Failed to save registry profiles: {}", e))
+        })
+    }
+}
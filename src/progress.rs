@@ -0,0 +1,120 @@
+use futures::Stream;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+
+/// A transfer progress notification delivered to library consumers
+///
+/// Events are emitted from the pull and push paths alongside the regular log
+/// output, so embedding applications (GUIs, CI dashboards, ...) can render
+/// their own progress bars without parsing log lines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// A layer transfer is about to start
+    LayerStarted {
+        /// Digest of the layer
+        digest: String,
+        /// Total size of the layer in bytes
+        size: u64,
+    },
+    /// More bytes of a layer have been transferred
+    LayerBytes {
+        /// Digest of the layer
+        digest: String,
+        /// Bytes transferred so far
+        transferred: u64,
+        /// Total size of the layer in bytes (0 if unknown)
+        total: u64,
+    },
+    /// A layer transfer finished
+    LayerCompleted {
+        /// Digest of the layer
+        digest: String,
+        /// Size of the layer in bytes
+        size: u64,
+        /// Whether the transfer was skipped because the target already had the layer
+        skipped: bool,
+    },
+    /// The image manifest was pushed to the target registry
+    ManifestPushed {
+        /// Target image reference
+        reference: String,
+        /// URL of the pushed manifest
+        url: String,
+    },
+}
+
+/// Callback invoked for every [`ProgressEvent`]
+pub type ProgressCallback = Arc<dyn Fn(ProgressEvent) + Send + Sync>;
+
+/// Delivers progress events to an optional consumer callback
+///
+/// Cheap to clone; a reporter without a callback discards all events.
+#[derive(Clone, Default)]
+pub struct ProgressReporter {
+    callback: Option<ProgressCallback>,
+}
+
+impl ProgressReporter {
+    /// Creates a reporter forwarding events to `callback`
+    pub fn new(callback: Box<dyn Fn(ProgressEvent) + Send + Sync>) -> Self {
+        Self {
+            callback: Some(Arc::from(callback)),
+        }
+    }
+
+    /// Creates a reporter feeding a [`ProgressStream`]
+    ///
+    /// The stream ends once the reporter and all its clones are dropped.
+    pub fn stream() -> (Self, ProgressStream) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let reporter = Self {
+            callback: Some(Arc::new(move |event| {
+                // The consumer may stop listening early; that's not an error
+                let _ = sender.send(event);
+            })),
+        };
+        (reporter, ProgressStream { receiver })
+    }
+
+    /// Sends `event` to the callback, if one is registered
+    ///
+    /// Every event also feeds [`crate::metrics::live_stats`].
+    pub fn emit(&self, event: ProgressEvent) {
+        crate::metrics::global().observe(&event);
+        if let Some(callback) = &self.callback {
+            callback(event);
+        }
+    }
+
+    /// Checks whether a callback is registered (lets callers skip building events)
+    pub fn is_enabled(&self) -> bool {
+        self.callback.is_some()
+    }
+}
+
+impl fmt::Debug for ProgressReporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProgressReporter")
+            .field("enabled", &self.is_enabled())
+            .finish()
+    }
+}
+
+/// Async stream of [`ProgressEvent`]s for consumers that prefer
+/// `while let Some(event) = stream.next().await` over a callback
+///
+/// Events are buffered without limit, so a slow consumer never stalls a transfer.
+pub struct ProgressStream {
+    receiver: mpsc::UnboundedReceiver<ProgressEvent>,
+}
+
+impl Stream for ProgressStream {
+    type Item = ProgressEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
@@ -0,0 +1,257 @@
+//! SLSA provenance attestations for pushed images
+//!
+//! The attestation is an in-toto statement with a SLSA v1 provenance
+//! predicate naming the builder, the source image (and its digest, when it
+//! was pulled from a registry) and when the push ran. It is pushed as an
+//! OCI 1.1 artifact whose `subject` is the pushed manifest, so registries
+//! with the referrers API list it under the image and admission controllers
+//! can find it there. The statement is not signed.
+
+use crate::PusherError;
+use crate::concurrency::RateLimiter;
+use crate::image;
+use crate::registry::RegistryClient;
+use crate::retry::RetryPolicy;
+use chrono::{DateTime, SecondsFormat, Utc};
+use oci_client::manifest::{OCI_IMAGE_MEDIA_TYPE, OciDescriptor, OciImageManifest};
+use oci_client::secrets::RegistryAuth;
+use oci_client::{Client, Reference};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+/// Artifact type and layer media type of in-toto statements
+pub const IN_TOTO_MEDIA_TYPE: &str = "application/vnd.in-toto+json";
+
+/// Predicate type of SLSA v1 provenance
+pub const SLSA_PROVENANCE_V1: &str = "https://slsa.dev/provenance/v1";
+
+/// Builder id recorded when none is given
+pub const DEFAULT_BUILDER_ID: &str = "https://github.com/yorelog/docker-image-pusher";
+
+/// Build type describing a copy of an image from a cache to a registry
+const MIRROR_BUILD_TYPE: &str = "https://github.com/yorelog/docker-image-pusher/mirror@v1";
+
+/// Media type of the empty config OCI artifacts use
+const EMPTY_CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.empty.v1+json";
+
+/// What a provenance attestation records about a push
+#[derive(Debug, Clone)]
+pub struct Provenance {
+    /// URI identifying who ran the push (e.g. a CI workflow URL)
+    pub builder_id: String,
+    /// URI of the source: `docker://<image>` for pulls, `file://<path>` for imports
+    pub source_uri: String,
+    /// Digest the source image was pulled at, unknown for imports
+    pub source_digest: Option<String>,
+    /// When the push started
+    pub started_on: SystemTime,
+    /// When the push finished
+    pub finished_on: SystemTime,
+}
+
+impl Provenance {
+    /// Describes a push of the image cached as `image_name`, reading its source from the cache index
+    pub fn for_cached_image(
+        cache_dir: &Path,
+        image_name: &str,
+        builder_id: &str,
+        started_on: SystemTime,
+    ) -> Result<Self, PusherError> {
+        let index_path = cache_dir
+            .join(image::sanitize_image_name(image_name))
+            .join("index.json");
+        let index: serde_json::Value = serde_json::from_slice(
+            &std::fs::read(&index_path).map_err(|_| PusherError::CacheNotFound)?,
+        )?;
+        let source_uri = match index["source_file"].as_str() {
+            Some(file) => format!("file://{}", file),
+            None => format!("docker://{}", index["source_image"].as_str().unwrap_or(image_name)),
+        };
+        let source_digest = index["manifest_digest"]
+            .as_str()
+            .or(index["reference_digest"].as_str())
+            .map(str::to_string);
+        Ok(Self {
+            builder_id: builder_id.to_string(),
+            source_uri,
+            source_digest,
+            started_on,
+            finished_on: SystemTime::now(),
+        })
+    }
+
+    /// Builds the in-toto statement for the manifest `digest` of `repository`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use docker_image_pusher::provenance::Provenance;
+    /// let provenance = Provenance {
+    ///     builder_id: "https://ci.example.com/mirror".to_string(),
+    ///     source_uri: "docker://nginx:1.27".to_string(),
+    ///     source_digest: Some("sha256:abc".to_string()),
+    ///     started_on: std::time::UNIX_EPOCH,
+    ///     finished_on: std::time::UNIX_EPOCH,
+    /// };
+    /// let statement = provenance.statement("registry.example.com/nginx", "sha256:def");
+    /// assert_eq!(statement["subject"][0]["digest"]["sha256"], "def");
+    /// assert_eq!(statement["predicate"]["runDetails"]["builder"]["id"], "https://ci.example.com/mirror");
+    /// assert_eq!(statement["predicate"]["runDetails"]["metadata"]["startedOn"], "1970-01-01T00:00:00Z");
+    /// ```
+    pub fn statement(&self, repository: &str, digest: &str) -> serde_json::Value {
+        let mut source = serde_json::json!({ "uri": self.source_uri });
+        if let Some((algorithm, hex)) = self.source_digest.as_deref().and_then(|d| d.split_once(':')) {
+            source["digest"] = serde_json::json!({ algorithm: hex });
+        }
+        let (algorithm, hex) = digest.split_once(':').unwrap_or(("sha256", digest));
+        serde_json::json!({
+            "_type": "https://in-toto.io/Statement/v1",
+            "subject": [{
+                "name": repository,
+                "digest": { algorithm: hex }
+            }],
+            "predicateType": SLSA_PROVENANCE_V1,
+            "predicate": {
+                "buildDefinition": {
+                    "buildType": MIRROR_BUILD_TYPE,
+                    "externalParameters": {
+                        "source": self.source_uri,
+                        "target": repository
+                    },
+                    "resolvedDependencies": [source]
+                },
+                "runDetails": {
+                    "builder": { "id": self.builder_id },
+                    "metadata": {
+                        "startedOn": rfc3339(self.started_on),
+                        "finishedOn": rfc3339(self.finished_on)
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Formats a time as an RFC 3339 UTC timestamp with second precision
+fn rfc3339(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Pushes a provenance attestation for the manifest `manifest_digest` of `target_image`
+///
+/// The attestation is pushed by digest with the pushed manifest as its
+/// subject; on registries without the OCI 1.1 referrers API it is listed in
+/// the subject's referrers tag instead (see [`crate::registry::referrers`]).
+///
+/// # Arguments
+///
+/// * `client` - OCI client for registry operations
+/// * `target_image` - Reference the image was pushed to
+/// * `auth` - Credentials for the target registry
+/// * `manifest_digest` - Digest of the pushed manifest
+/// * `provenance` - What to record
+/// * `retry` - Retry behaviour of the uploads
+///
+/// # Returns
+///
+/// `Result<String, PusherError>` - The reference the attestation was pushed to
+pub async fn attach_provenance(
+    client: &Client,
+    target_image: &str,
+    auth: &RegistryAuth,
+    manifest_digest: &str,
+    provenance: &Provenance,
+    retry: &RetryPolicy,
+) -> Result<String, PusherError> {
+    let target_ref: Reference = target_image
+        .parse()
+        .map_err(|e| PusherError::PushError(format!("Invalid target image reference: {}", e)))?;
+    let subject_ref = Reference::with_digest(
+        target_ref.registry().to_string(),
+        target_ref.repository().to_string(),
+        manifest_digest.to_string(),
+    );
+    let cancel = CancellationToken::new();
+    let limiter = Arc::new(RateLimiter::new(None));
+    let registry = retry
+        .run("Authentication", &cancel, || {
+            RegistryClient::connect(client, &subject_ref, auth, limiter.clone())
+        })
+        .await?;
+
+    // The subject descriptor needs the media type and size of the pushed manifest
+    let subject = retry
+        .run_authenticated(
+            "Subject manifest fetch",
+            &cancel,
+            || registry.subject_descriptor(manifest_digest),
+            |stage| registry.reauthenticate(client, stage),
+        )
+        .await?;
+
+    let repository = format!("{}/{}", target_ref.resolve_registry(), target_ref.repository());
+    let statement = serde_json::to_vec(&provenance.statement(&repository, manifest_digest))?;
+    let config = b"{}".to_vec();
+    let statement_digest = retry
+        .run_authenticated(
+            "Provenance upload",
+            &cancel,
+            || registry.push_blob_from_bytes(&statement),
+            |stage| registry.reauthenticate(client, stage),
+        )
+        .await?;
+    let config_digest = retry
+        .run_authenticated(
+            "Provenance upload",
+            &cancel,
+            || registry.push_blob_from_bytes(&config),
+            |stage| registry.reauthenticate(client, stage),
+        )
+        .await?;
+
+    let manifest = OciImageManifest {
+        schema_version: 2,
+        media_type: Some(OCI_IMAGE_MEDIA_TYPE.to_string()),
+        artifact_type: Some(IN_TOTO_MEDIA_TYPE.to_string()),
+        config: OciDescriptor {
+            media_type: EMPTY_CONFIG_MEDIA_TYPE.to_string(),
+            digest: config_digest,
+            size: config.len() as i64,
+            urls: None,
+            annotations: None,
+        },
+        layers: vec![OciDescriptor {
+            media_type: IN_TOTO_MEDIA_TYPE.to_string(),
+            digest: statement_digest,
+            size: statement.len() as i64,
+            urls: None,
+            annotations: None,
+        }],
+        subject: Some(subject),
+        annotations: None,
+    };
+    let attestation_digest = retry
+        .run_authenticated(
+            "Provenance manifest push",
+            &cancel,
+            || registry.push_referrer(&manifest),
+            |stage| registry.reauthenticate(client, stage),
+        )
+        .await?;
+    let attestation_ref = Reference::with_digest(
+        target_ref.registry().to_string(),
+        target_ref.repository().to_string(),
+        attestation_digest,
+    );
+
+    info!(
+        digest = manifest_digest,
+        "📜 Attached provenance to {} as {}",
+        manifest_digest,
+        attestation_ref
+    );
+    Ok(attestation_ref.whole())
+}
@@ -0,0 +1,1732 @@
+use crate::concurrency;
+use crate::credentials::AuthChain;
+use crate::image;
+use crate::ledger::BlobLedger;
+use crate::metrics;
+use crate::monitor;
+use crate::popularity::LayerRanking;
+use crate::profile;
+use crate::progress::{ProgressEvent, ProgressReporter, ProgressStream};
+use crate::registry;
+use crate::report::{BatchReport, BlobFailure, BlobReport, BlobStatus, TransferReport};
+use crate::retry::RetryPolicy;
+use crate::store::{BlobStore, FsBlobStore};
+use crate::transport::Timeouts;
+use crate::{CACHE_DIR, PusherError};
+use futures::stream::{FuturesUnordered, StreamExt};
+use oci_client::manifest::OciImageManifest;
+use oci_client::secrets::RegistryAuth;
+use oci_client::{Client, Reference};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, debug, info, info_span, instrument, warn};
+
+// Size thresholds (in MB) selecting the upload strategy
+const LARGE_LAYER_THRESHOLD_MB: f64 = 100.0;
+const MEDIUM_LAYER_THRESHOLD_MB: f64 = 50.0;
+const RATE_LIMIT_DELAY_MS: u64 = 200;
+const MAX_LAYER_DEADLINE_ATTEMPTS: u32 = 3;
+
+// Progress tracking intervals based on layer size
+const LARGE_LAYER_PROGRESS_INTERVAL_SECS: u64 = 5;
+const NORMAL_LAYER_PROGRESS_INTERVAL_SECS: u64 = 10;
+
+// Network speed estimation constants
+const ESTIMATED_SPEED_MBPS: f64 = 10.0; // Conservative estimate for ETA calculation
+
+/// Tunables for the push phase that come from command-line options
+#[derive(Debug, Clone)]
+pub struct PushOptions {
+    /// Local cache directory holding the source image
+    pub cache_dir: PathBuf,
+    /// Memory cap in bytes for buffered layer data
+    pub max_memory: Option<u64>,
+    /// Maximum number of layers uploaded concurrently (overrides the learned profile)
+    pub max_concurrent: Option<usize>,
+    /// Registry API request rate limit
+    pub requests_per_second: Option<f64>,
+    /// Deadline for a single layer upload before it is rescheduled
+    pub layer_timeout_secs: Option<u64>,
+    /// Connect, idle and total timeouts for the upload client's requests
+    pub timeouts: Timeouts,
+    /// Fail instead of warning when the target tag already points to a different manifest
+    pub no_overwrite: bool,
+    /// Upload every layer without asking the registry whether it already has it
+    pub force_upload: bool,
+    /// Layers to upload even if the registry already has them, e.g. to repair a corrupt blob
+    pub repush_digests: BTreeSet<String>,
+    /// Quay tag expiration (e.g. "2w"), set as the `quay.expires-after` config label
+    pub expires_after: Option<String>,
+    /// Annotations set on the pushed manifest (e.g. `org.opencontainers.image.source`)
+    pub annotations: BTreeMap<String, String>,
+    /// Annotations set on every layer descriptor of the pushed manifest
+    pub layer_annotations: BTreeMap<String, String>,
+    /// Create the target's Harbor project with these settings if it doesn't exist
+    pub create_harbor_project: Option<crate::harbor::ProjectSettings>,
+    /// Compare the layers to upload with the remaining quota of the target's Harbor project
+    pub harbor_quota: Option<crate::harbor::QuotaCheck>,
+    /// Asked again for the target registry's credentials when a fresh token
+    /// is rejected, e.g. after a password rotation
+    pub credentials: AuthChain,
+    /// How failed registry requests (auth, layers, config, manifest) are retried
+    pub retry: RetryPolicy,
+    /// Receives layer and manifest progress events
+    pub progress: ProgressReporter,
+    /// Cancels the push: no new layers are started and running uploads stop
+    /// at their next chunk, aborting their upload sessions
+    pub cancel: CancellationToken,
+    /// Takes layers the target confirmed within this many seconds as present
+    /// without checking (see [`crate::ledger`]); `None` doesn't use the ledger
+    pub ledger_ttl_secs: Option<u64>,
+    /// Uploads layers other pending images of the repository share first, and
+    /// checks for them again right before uploading in case one was pushed meanwhile
+    pub layer_ranking: LayerRanking,
+    /// Push even though `DOCKER_CONTENT_TRUST` requires signed images (see [`crate::trust`])
+    pub disable_content_trust: bool,
+    /// Only push a cached image whose signature by this key was verified when
+    /// it was pulled, and whose cached manifest is still the one verified
+    #[cfg(feature = "cosign")]
+    pub trusted_key: Option<crate::cosign::VerifyingKey>,
+}
+
+impl Default for PushOptions {
+    fn default() -> Self {
+        Self {
+            cache_dir: PathBuf::from(CACHE_DIR),
+            max_memory: None,
+            max_concurrent: None,
+            requests_per_second: None,
+            layer_timeout_secs: None,
+            timeouts: Timeouts::default(),
+            no_overwrite: false,
+            force_upload: false,
+            repush_digests: BTreeSet::new(),
+            expires_after: None,
+            annotations: BTreeMap::new(),
+            layer_annotations: BTreeMap::new(),
+            create_harbor_project: None,
+            harbor_quota: None,
+            credentials: AuthChain::new(),
+            retry: RetryPolicy::default(),
+            progress: ProgressReporter::default(),
+            cancel: CancellationToken::new(),
+            ledger_ttl_secs: None,
+            layer_ranking: LayerRanking::default(),
+            disable_content_trust: false,
+            #[cfg(feature = "cosign")]
+            trusted_key: None,
+        }
+    }
+}
+
+impl PushOptions {
+    /// Registers a callback receiving [`ProgressEvent`]s for this push
+    pub fn on_progress(mut self, callback: Box<dyn Fn(ProgressEvent) + Send + Sync>) -> Self {
+        self.progress = ProgressReporter::new(callback);
+        self
+    }
+
+    /// Returns a stream of [`ProgressEvent`]s for this push, replacing any callback
+    ///
+    /// The stream ends when these options are dropped.
+    pub fn progress_stream(&mut self) -> ProgressStream {
+        let (reporter, stream) = ProgressReporter::stream();
+        self.progress = reporter;
+        stream
+    }
+
+    /// Refuses the push when `DOCKER_CONTENT_TRUST` requires signature checks these options don't make
+    pub(crate) fn check_content_trust(&self) -> Result<(), PusherError> {
+        if self.disable_content_trust {
+            return Ok(());
+        }
+        #[cfg(feature = "cosign")]
+        let verifying = self.trusted_key.is_some();
+        #[cfg(not(feature = "cosign"))]
+        let verifying = false;
+        crate::trust::check_content_trust(verifying)
+    }
+
+    /// Whether the layer `digest` is uploaded without checking if the registry has it
+    pub fn forces_upload(&self, digest: &str) -> bool {
+        self.force_upload || self.repush_digests.contains(digest)
+    }
+}
+
+/// Pushes a cached image to a target registry with memory optimization
+///
+/// This function implements several memory optimization strategies:
+///
+/// ## Size-Based Processing Strategy:
+/// - **Small layers (<100MB)**: Read entire layer into memory and upload
+/// - **Large layers (>100MB)**: Read into memory with progress tracking
+/// - **Memory pressure**: Stream layers from disk in chunks and lower concurrency
+///   when memory use approaches `--max-memory` or the cgroup limit
+/// - **Rate limiting**: Add delays between large layer uploads to prevent registry overload
+///
+/// ## Upload Process:
+/// 1. Authenticate with target registry
+/// 2. Read cached manifest and layer information  
+/// 3. Check which layers the registry already has, all at once
+/// 4. Upload the missing layers concurrently (bounded by the learned or configured
+///    concurrency) with size-appropriate strategy
+/// 5. Upload image configuration
+/// 6. Push final manifest to complete the image
+///
+/// # Arguments
+///
+/// * `client` - OCI client for registry operations
+/// * `source_image` - Name of cached image to push
+/// * `target_image` - Destination image reference with registry
+/// * `auth` - Credentials for the target registry
+/// * `options` - Concurrency and memory settings for the upload
+///
+/// # Returns
+///
+/// `Result<TransferReport, PusherError>` - Per-blob summary of the push or detailed error information
+pub async fn push_cached_image(
+    client: &Client,
+    source_image: &str,
+    target_image: &str,
+    auth: &RegistryAuth,
+    options: &PushOptions,
+) -> Result<TransferReport, PusherError> {
+    let store = FsBlobStore::new(
+        options
+            .cache_dir
+            .join(image::sanitize_image_name(source_image)),
+    );
+    push_cached_image_with_store(client, source_image, target_image, auth, options, &store).await
+}
+
+/// Uploads layers of `source_image` to `target_image` as they land in the cache
+///
+/// Meant to run alongside the pull of `source_image`: every digest received
+/// on `layers` (sent once the layer is complete in the cache) is streamed to
+/// the target right away, so uploads overlap the remaining downloads. Layers
+/// the target already has are skipped. Only layer blobs are uploaded; the
+/// [`push_cached_image`] that follows the pull finds them in place and
+/// uploads the config and manifest. A failed layer is only logged, since
+/// that push uploads whatever is still missing.
+///
+/// # Returns
+///
+/// `Result<usize, PusherError>` - Number of layers uploaded (skipped ones excluded)
+pub async fn push_layers_as_cached(
+    client: &Client,
+    source_image: &str,
+    target_image: &str,
+    auth: &RegistryAuth,
+    options: &PushOptions,
+    layers: tokio::sync::mpsc::UnboundedReceiver<String>,
+) -> Result<usize, PusherError> {
+    let target_ref: Reference = target_image
+        .parse()
+        .map_err(|e| PusherError::PushError(format!("Invalid target image reference: {}", e)))?;
+    let registry = connect_target(client, &target_ref, auth, options).await?;
+    upload_layers_as_cached(client, &registry, &target_ref, source_image, options, layers).await
+}
+
+/// Uploads layers like [`push_layers_as_cached`] through an already authenticated `registry`
+pub(crate) async fn upload_layers_as_cached(
+    client: &Client,
+    registry: &registry::RegistryClient,
+    target_ref: &Reference,
+    source_image: &str,
+    options: &PushOptions,
+    layers: tokio::sync::mpsc::UnboundedReceiver<String>,
+) -> Result<usize, PusherError> {
+    let options = &PushOptions {
+        retry: options.retry.for_registry(target_ref.resolve_registry()),
+        ..options.clone()
+    };
+    let store = FsBlobStore::new(
+        options
+            .cache_dir
+            .join(image::sanitize_image_name(source_image)),
+    );
+    let max_concurrent = options.max_concurrent.unwrap_or_else(|| {
+        profile::ProfileStore::load(&options.cache_dir)
+            .seed_config(target_ref.resolve_registry())
+            .max_concurrent
+    });
+    // The push after the pull records what the registry confirmed
+    let ledger = options.ledger_ttl_secs.map(|ttl| {
+        (
+            BlobLedger::load(&options.cache_dir, target_ref.resolve_registry()),
+            ttl,
+        )
+    });
+
+    let digests = futures::stream::unfold(layers, |mut layers| async move {
+        layers.recv().await.map(|digest| (digest, layers))
+    });
+    let uploaded = digests
+        .map(|digest| {
+            let store = &store;
+            let ledger = &ledger;
+            async move {
+                let upload = || async {
+                    let confirmed = ledger.as_ref().is_some_and(|(ledger, ttl)| {
+                        ledger.confirmed(target_ref.repository(), &digest, *ttl)
+                    });
+                    if !options.forces_upload(&digest)
+                        && (confirmed || registry.blob_exists(&digest).await?)
+                    {
+                        return Ok(false);
+                    }
+                    let size = store.size(&digest).await?.ok_or_else(|| {
+                        PusherError::CacheError(format!("Layer {} is missing from the cache", digest))
+                    })?;
+                    info!("⏩ Uploading layer {} while the pull continues", digest);
+                    registry
+                        .push_blob_streamed(
+                            store.stream(&digest).await?,
+                            &digest,
+                            size,
+                            registry::STREAM_CHUNK_SIZE,
+                            &options.progress,
+                            &options.cancel,
+                        )
+                        .await?;
+                    metrics::global().record_upload(size);
+                    Ok(true)
+                };
+                let result = options
+                    .retry
+                    .run_authenticated(&digest, &options.cancel, upload, |stage| {
+                        registry.reauthenticate(client, stage)
+                    })
+                    .await;
+                match result {
+                    Ok(uploaded) => uploaded,
+                    Err(PusherError::Cancelled) => false,
+                    Err(e) => {
+                        warn!("⚠️  Early upload of layer {} failed, the push will retry it: {}", digest, e);
+                        false
+                    }
+                }
+            }
+        })
+        .buffer_unordered(max_concurrent.max(1))
+        .filter(|uploaded| futures::future::ready(*uploaded))
+        .count()
+        .await;
+    Ok(uploaded)
+}
+
+/// Authenticates with the target registry, creating its Harbor project first if asked to
+///
+/// The returned client carries the push token (refreshed on 401s through
+/// [`registry::RegistryClient::reauthenticate`]) and a connection pool, and
+/// can serve every upload of one operation. Its upload sessions are
+/// journaled in the cache directory, and stale sessions earlier runs left on
+/// the target repository are aborted first.
+pub(crate) async fn connect_target(
+    client: &Client,
+    target_ref: &Reference,
+    auth: &RegistryAuth,
+    options: &PushOptions,
+) -> Result<registry::RegistryClient, PusherError> {
+    if let Some(digest) = target_ref.digest() {
+        image::digest::validate(digest, &format!("Target image reference {}", target_ref))?;
+    }
+    let options = &PushOptions {
+        retry: options.retry.for_registry(target_ref.resolve_registry()),
+        ..options.clone()
+    };
+    if let Some(settings) = &options.create_harbor_project {
+        options
+            .retry
+            .run("Harbor project check", &options.cancel, || {
+                crate::harbor::ensure_project(target_ref, auth, settings)
+            })
+            .await?;
+    }
+
+    info!("🔐 Authenticating with registry...");
+    let journal = crate::journal::UploadJournal::new(&options.cache_dir);
+    let limiter = Arc::new(concurrency::RateLimiter::new(options.requests_per_second));
+    let registry = options
+        .retry
+        .run("Authentication", &options.cancel, || {
+            registry::RegistryClient::connect(client, target_ref, auth, limiter.clone())
+        })
+        .await?
+        .with_timeouts(options.timeouts)
+        .with_journal(journal.clone())
+        .with_credentials(options.credentials.clone());
+    info!("✅ Authentication successful!");
+
+    // Sessions a crashed run left on this repository keep counting against its quota
+    let swept = registry
+        .abort_journaled_uploads(&journal, crate::journal::STALE_SESSION_SECS)
+        .await;
+    if swept > 0 {
+        info!("🧹 Aborted {} upload session(s) left behind by earlier runs", swept);
+    }
+    Ok(registry)
+}
+
+/// Checks the target before the first upload of an image whose manifest digest is `manifest_digest`
+///
+/// Fails when the target tag points to a different image and
+/// [`PushOptions::no_overwrite`] is set (and warns otherwise), or when the
+/// `required` bytes of layers the target lacks exceed the remaining quota of
+/// its Harbor project ([`PushOptions::harbor_quota`]). Runs on the source
+/// manifest before the early uploads of [`crate::copy_image`], so nothing is
+/// uploaded for a copy that is refused; [`push_cached_image`] makes the same
+/// checks on the cached image before its first upload, except for the quota
+/// of a copy checked here.
+pub(crate) async fn preflight(
+    client: &Client,
+    registry: &registry::RegistryClient,
+    target_ref: &Reference,
+    auth: &RegistryAuth,
+    manifest_digest: &str,
+    required: u64,
+    options: &PushOptions,
+) -> Result<(), PusherError> {
+    if let Some(tag) = target_ref.tag()
+        && target_ref.digest().is_none()
+    {
+        check_tag(client, registry, target_ref, tag, manifest_digest, options).await?;
+    }
+    if let Some(policy) = options.harbor_quota {
+        crate::harbor::check_quota(target_ref, auth, required, policy).await?;
+    }
+    Ok(())
+}
+
+/// Sums the sizes of the layers of `manifest` a push would upload to the target
+///
+/// The layers are checked concurrently, like the push's own pre-check; a
+/// layer whose check keeps failing counts as missing.
+pub(crate) async fn missing_bytes(
+    client: &Client,
+    registry: &registry::RegistryClient,
+    manifest: &OciImageManifest,
+    options: &PushOptions,
+) -> Result<u64, PusherError> {
+    let digests: Vec<String> = manifest
+        .layers
+        .iter()
+        .map(|layer| layer.digest.clone())
+        .collect();
+    let existing = check_existing_layers(
+        client,
+        registry,
+        &digests,
+        options
+            .max_concurrent
+            .unwrap_or(concurrency::DEFAULT_MAX_CONCURRENT_UPLOADS),
+        &options.retry,
+        &options.cancel,
+    )
+    .await;
+    if options.cancel.is_cancelled() {
+        return Err(PusherError::Cancelled);
+    }
+    Ok(manifest
+        .layers
+        .iter()
+        .zip(existing)
+        .filter(|(layer, exists)| options.forces_upload(&layer.digest) || *exists != Some(true))
+        .map(|(layer, _)| layer.size.max(0) as u64)
+        .sum())
+}
+
+/// Refuses (with `--no-overwrite`) or warns about replacing what `tag` of the target points to
+async fn check_tag(
+    client: &Client,
+    registry: &registry::RegistryClient,
+    target_ref: &Reference,
+    tag: &str,
+    manifest_digest: &str,
+    options: &PushOptions,
+) -> Result<(), PusherError> {
+    let existing = options
+        .retry
+        .run_authenticated(
+            "Tag check",
+            &options.cancel,
+            || registry.manifest_digest(tag),
+            |stage| registry.reauthenticate(client, stage),
+        )
+        .await?;
+    match existing {
+        Some(existing) if existing != manifest_digest => {
+            if options.no_overwrite {
+                return Err(PusherError::PushError(format!(
+                    "{} already points to {}, refusing to overwrite it with {} (--no-overwrite)",
+                    target_ref, existing, manifest_digest
+                )));
+            }
+            warn!(
+                "⚠️  {} already points to {}, it will be overwritten with {}",
+                target_ref, existing, manifest_digest
+            );
+        }
+        Some(_) => info!("📌 {} already points to this image", target_ref),
+        None => {}
+    }
+    Ok(())
+}
+
+/// Returns the digest of the manifest a push of an image with `manifest` (served as `bytes`) sends
+///
+/// `None` when [`PushOptions::expires_after`] gives the image a new config,
+/// which is only known once the config is cached.
+pub(crate) fn expected_manifest_digest(
+    bytes: &[u8],
+    mut manifest: OciImageManifest,
+    options: &PushOptions,
+) -> Result<Option<String>, PusherError> {
+    if options.expires_after.is_some() {
+        return Ok(None);
+    }
+    if options.annotations.is_empty() && options.layer_annotations.is_empty() {
+        return Ok(Some(format!("sha256:{:x}", Sha256::digest(bytes))));
+    }
+    annotate_manifest(&mut manifest, options);
+    let bytes = serde_json::to_vec(&oci_client::manifest::OciManifest::Image(manifest))?;
+    Ok(Some(format!("sha256:{:x}", Sha256::digest(&bytes))))
+}
+
+/// Sets the annotations of `options` on the manifest and its layer descriptors
+fn annotate_manifest(manifest: &mut OciImageManifest, options: &PushOptions) {
+    if !options.annotations.is_empty() {
+        manifest
+            .annotations
+            .get_or_insert_with(BTreeMap::new)
+            .extend(options.annotations.clone());
+    }
+    if !options.layer_annotations.is_empty() {
+        for layer in &mut manifest.layers {
+            layer
+                .annotations
+                .get_or_insert_with(BTreeMap::new)
+                .extend(options.layer_annotations.clone());
+        }
+    }
+}
+
+/// Pushes a cached image like [`push_cached_image`], reading layers from `store`
+///
+/// The manifest, config and index are still read from the cache directory;
+/// only layer blobs come from the [`BlobStore`].
+pub async fn push_cached_image_with_store<S: BlobStore>(
+    client: &Client,
+    source_image: &str,
+    target_image: &str,
+    auth: &RegistryAuth,
+    options: &PushOptions,
+    store: &S,
+) -> Result<TransferReport, PusherError> {
+    push_cached_image_via(client, None, source_image, target_image, auth, options, store).await
+}
+
+/// Pushes a cached image like [`push_cached_image_with_store`], reusing `registry` if given
+///
+/// Operations that talk to the target more than once (see
+/// [`crate::copy_image`]) connect once with [`connect_target`] and pass the
+/// client here, so its token and pooled connections carry over instead of
+/// authenticating and handshaking again.
+#[instrument(
+    name = "push",
+    skip_all,
+    fields(source = %source_image, target = %target_image, registry = tracing::field::Empty)
+)]
+pub(crate) async fn push_cached_image_via<S: BlobStore>(
+    client: &Client,
+    registry: Option<&registry::RegistryClient>,
+    source_image: &str,
+    target_image: &str,
+    auth: &RegistryAuth,
+    options: &PushOptions,
+    store: &S,
+) -> Result<TransferReport, PusherError> {
+    options.check_content_trust()?;
+    let push_start = std::time::Instant::now();
+    let started_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let cache_dir = options.cache_dir.as_path();
+    let image_cache_dir = cache_dir.join(image::sanitize_image_name(source_image));
+
+    // Parse and validate target image reference
+    let target_ref: Reference = target_image
+        .parse()
+        .map_err(|e| PusherError::PushError(format!("Invalid target image reference: {}", e)))?;
+
+    tracing::Span::current().record("registry", target_ref.resolve_registry());
+    let options = &PushOptions {
+        retry: options.retry.for_registry(target_ref.resolve_registry()),
+        ..options.clone()
+    };
+
+    // Step 1: Authenticate with the target registry
+    let connected;
+    let registry = match registry {
+        Some(registry) => registry,
+        None => {
+            connected = connect_target(client, &target_ref, auth, options).await?;
+            &connected
+        }
+    };
+
+    let monitor = monitor::PerformanceMonitor::new(options.max_memory);
+
+    // Seed concurrency from what worked for this registry last time
+    let registry_host = target_ref.resolve_registry().to_string();
+    let mut profiles = profile::ProfileStore::load(cache_dir);
+    let mut config = profiles.seed_config(&registry_host);
+    let ledger = options
+        .ledger_ttl_secs
+        .map(|_| BlobLedger::load(cache_dir, &registry_host));
+    let repository = target_ref.repository();
+    if let Some(learned) = profiles.get(&registry_host) {
+        info!(
+            "📈 Using learned profile for {}: concurrency {}, chunk {} MB, ~{:.1} MB/s",
+            registry_host,
+            learned.max_concurrent,
+            learned.chunk_size / (1024 * 1024),
+            learned.throughput_mbps
+        );
+    }
+    if let Some(max_concurrent) = options.max_concurrent {
+        config.max_concurrent = max_concurrent.max(1);
+    }
+    if let Some(cap) = monitor.memory_cap() {
+        info!(
+            "🧠 Memory cap: {:.1} MB (layers are streamed when usage approaches it)",
+            cap as f64 / (1024.0 * 1024.0)
+        );
+    }
+    let budget = monitor::MemoryBudget::new(monitor.buffer_limit());
+    if let Some(limit) = budget.limit() {
+        info!(
+            "🧠 Buffering at most {:.1} MB of layer data across concurrent uploads",
+            limit as f64 / (1024.0 * 1024.0)
+        );
+    }
+
+    // Step 2: Read cached metadata and manifest
+    let index_path = image_cache_dir.join("index.json");
+    let index_content = tokio::fs::read_to_string(&index_path)
+        .await
+        .map_err(|_| PusherError::CacheNotFound)?;
+    let index: serde_json::Value = serde_json::from_str(&index_content)?;
+
+    let manifest_path = image_cache_dir.join("manifest.json");
+    let manifest_content = tokio::fs::read_to_string(&manifest_path)
+        .await
+        .map_err(|e| PusherError::CacheError(format!("Failed to read cached manifest: {}", e)))?;
+    let mut manifest: OciImageManifest = serde_json::from_str(&manifest_content)?;
+
+    // A digest-pinned image is promoted only if the cache still holds exactly what was pulled
+    if let Some(pinned) = index["pinned_digest"].as_str() {
+        info!("📌 Verifying cached content of {} against its digests...", pinned);
+        verify_cached_content(&index, &manifest_content, &manifest, &image_cache_dir, store)
+            .await?;
+        info!("✅ Cached content matches {}", pinned);
+    }
+
+    // The signature was checked when the image was pulled; the cached manifest,
+    // which pins the config and layers by digest, must still be the one it covers
+    #[cfg(feature = "cosign")]
+    if options.trusted_key.is_some() {
+        let verified = index["verified_digest"].as_str().ok_or_else(|| {
+            PusherError::SignatureError(format!(
+                "Cached image {} was not verified when it was pulled; pull it again with a trusted key",
+                source_image
+            ))
+        })?;
+        image::digest::validate(verified, "Cache index verified_digest")?;
+        let pulled = index["manifest_digest"].as_str().unwrap_or_default();
+        let actual = format!("sha256:{:x}", Sha256::digest(manifest_content.as_bytes()));
+        if actual != pulled {
+            return Err(PusherError::DigestMismatch {
+                what: format!("Cached manifest of {}", source_image),
+                expected: pulled.to_string(),
+                actual,
+            });
+        }
+        // Multi-arch images are signed by their index, which the cached manifest was resolved from
+        if verified != pulled && index["reference_digest"].as_str() != Some(verified) {
+            return Err(PusherError::SignatureError(format!(
+                "Cached image {} doesn't match the verified digest {}; pull it again",
+                source_image, verified
+            )));
+        }
+        info!("🔏 Cached manifest matches the signature verified at pull time ({})", verified);
+    }
+
+    let config_digest = index["config"]
+        .as_str()
+        .ok_or(PusherError::CacheError("Invalid index format".to_string()))?;
+    image::digest::validate(config_digest, "Cache index config")?;
+    let config_path =
+        image_cache_dir.join(format!("config_{}.json", image::digest::file_name(config_digest)));
+    let mut config_data = tokio::fs::read(&config_path)
+        .await
+        .map_err(|e| PusherError::CacheError(format!("Failed to read cached config: {}", e)))?;
+    let mut config_digest = config_digest.to_string();
+    // The cached manifest bytes are pushed verbatim unless an option changes the manifest
+    let mut manifest_changed = false;
+
+    // Quay expires the tag based on a config label, which gives the image a new config
+    if let Some(expires_after) = &options.expires_after {
+        if index["pinned_digest"].is_string() {
+            return Err(PusherError::PushError(
+                "Setting an expiration changes the image config, which a digest-pinned source forbids"
+                    .to_string(),
+            ));
+        }
+        config_data = image::set_config_label(
+            &config_data,
+            image::QUAY_EXPIRES_AFTER_LABEL,
+            expires_after,
+        )?;
+        config_digest = format!("sha256:{:x}", Sha256::digest(&config_data));
+        manifest.config.digest = config_digest.clone();
+        manifest.config.size = config_data.len() as i64;
+        manifest_changed = true;
+        info!("⏳ Tag will expire {} after the push ({})", expires_after, config_digest);
+    }
+
+    // Annotations only change the manifest; layers and config are uploaded as cached
+    if !options.annotations.is_empty() || !options.layer_annotations.is_empty() {
+        if index["pinned_digest"].is_string() {
+            return Err(PusherError::PushError(
+                "Setting annotations changes the manifest, which a digest-pinned source forbids".to_string(),
+            ));
+        }
+        annotate_manifest(&mut manifest, options);
+        debug!(
+            manifest = options.annotations.len(),
+            layer = options.layer_annotations.len(),
+            "set annotations"
+        );
+        manifest_changed = true;
+    }
+
+    // A malformed manifest (e.g. from a broken import) would only earn an opaque 400 at the very end
+    image::validate_manifest(&manifest, &format!("Cached manifest of {}", source_image))?;
+    if manifest.config.size != config_data.len() as i64 {
+        return Err(PusherError::InvalidManifest {
+            what: format!("Cached manifest of {}", source_image),
+            problems: vec![format!(
+                "config.size is {}, but the cached config has {} bytes",
+                manifest.config.size,
+                config_data.len()
+            )],
+        });
+    }
+
+    // Serialized up front: its digest decides whether the target tag may be replaced
+    let manifest_enum = oci_client::manifest::OciManifest::Image(manifest.clone());
+    let manifest_bytes = if manifest_changed {
+        serde_json::to_vec(&manifest_enum)?
+    } else {
+        manifest_content.into_bytes()
+    };
+    let manifest_digest = format!("sha256:{:x}", Sha256::digest(&manifest_bytes));
+
+    // Check the tag before uploading anything, so --no-overwrite fails fast
+    if let Some(tag) = target_ref.tag()
+        && target_ref.digest().is_none()
+    {
+        check_tag(client, registry, &target_ref, tag, &manifest_digest, options).await?;
+    }
+
+    // Extract layer digest list from index
+    let layer_digests: Vec<String> = index["layers"]
+        .as_array()
+        .ok_or(PusherError::CacheError(
+            "Invalid layers format in index".to_string(),
+        ))?
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            let digest = v.as_str().unwrap_or("");
+            image::digest::validate(digest, &format!("Cache index layers[{}]", i))?;
+            Ok(digest.to_string())
+        })
+        .collect::<Result<_, PusherError>>()?;
+    info!(
+        "📤 Uploading {} cached layers (up to {} concurrently) with memory optimization...",
+        layer_digests.len(),
+        config.max_concurrent
+    );
+    // Step 3: Upload layers with bounded concurrency, memory optimization and registry checks
+    let mut uploaded_layers = Vec::new();
+    let mut blob_reports = Vec::new();
+    let mut failures = Vec::new();
+    let mut skipped_uploads = 0;
+    let mut bytes_uploaded = 0u64;
+    let upload_start = std::time::Instant::now();
+
+    // Check every layer up front so the worklist only holds what the registry is missing
+    let mut ledger_skips = 0;
+    let existing = if options.force_upload {
+        info!("🔁 Uploading all layers without checking the registry for them (--force-upload)");
+        vec![Some(false); layer_digests.len()]
+    } else {
+        for digest in &options.repush_digests {
+            if !layer_digests.contains(digest) {
+                warn!("⚠️  {} is not a layer of {}, nothing to re-push", digest, source_image);
+            }
+        }
+        // Layers the ledger vouches for skip the check
+        let ttl = options.ledger_ttl_secs.unwrap_or_default();
+        let ledgered: Vec<bool> = layer_digests
+            .iter()
+            .map(|digest| {
+                ledger
+                    .as_ref()
+                    .is_some_and(|ledger| ledger.confirmed(repository, digest, ttl))
+            })
+            .collect();
+        let unconfirmed: Vec<String> = layer_digests
+            .iter()
+            .zip(&ledgered)
+            .filter(|(_, ledgered)| !**ledgered)
+            .map(|(digest, _)| digest.clone())
+            .collect();
+        ledger_skips = layer_digests.len() - unconfirmed.len();
+        if ledger_skips > 0 {
+            info!(
+                "📒 {} layer(s) were confirmed on the target in the last {}s, not checking them again",
+                ledger_skips, ttl
+            );
+        }
+        let mut checked = check_existing_layers(
+            client,
+            registry,
+            &unconfirmed,
+            monitor.adjust_concurrency(config.max_concurrent),
+            &options.retry,
+            &options.cancel,
+        )
+        .await
+        .into_iter();
+        let mut existing: Vec<Option<bool>> = ledgered
+            .iter()
+            .map(|ledgered| match ledgered {
+                true => Some(true),
+                false => checked.next().flatten(),
+            })
+            .collect();
+        for ((exists, digest), ledgered) in existing.iter_mut().zip(&layer_digests).zip(&ledgered) {
+            if options.repush_digests.contains(digest) {
+                info!("🔁 Re-pushing layer {} (--repush-digest)", digest);
+                *exists = Some(false);
+            } else if let Some(ledger) = &ledger
+                && *exists == Some(true)
+                && !ledgered
+            {
+                ledger.record(repository, digest);
+            }
+        }
+        existing
+    };
+    if options.cancel.is_cancelled() {
+        return Err(PusherError::Cancelled);
+    }
+    let mut pending: VecDeque<(usize, &String, u32)> = VecDeque::new();
+    for (i, (digest, exists)) in layer_digests.iter().zip(&existing).enumerate() {
+        if *exists != Some(true) {
+            pending.push_back((i, digest, 0));
+            continue;
+        }
+        let size = store.size(digest).await?.unwrap_or_default();
+        info!(
+            digest = %digest,
+            bytes = size,
+            "   ✅ Layer {}/{} already exists in registry, skipping upload: {}",
+            i + 1,
+            layer_digests.len(),
+            digest
+        );
+        metrics::global().record_skip();
+        options.progress.emit(ProgressEvent::LayerCompleted {
+            digest: digest.clone(),
+            size,
+            skipped: true,
+        });
+        skipped_uploads += 1;
+        blob_reports.push((
+            i,
+            BlobReport::new(
+                digest,
+                "layer",
+                size,
+                BlobStatus::Skipped,
+                std::time::Duration::ZERO,
+                0,
+            ),
+        ));
+        uploaded_layers.push(digest.clone());
+    }
+    // Shared layers first, so other transfers into the repository can skip them sooner
+    pending
+        .make_contiguous()
+        .sort_by_key(|(_, digest, _)| std::cmp::Reverse(options.layer_ranking.count(digest)));
+    if !pending.is_empty() {
+        info!(
+            "📋 {} of {} layers need uploading",
+            pending.len(),
+            layer_digests.len()
+        );
+    }
+    if let Some(policy) = options.harbor_quota {
+        // Sized from the manifest, for the layers the pre-check found missing
+        let required = pending
+            .iter()
+            .filter_map(|(_, digest, _)| {
+                manifest
+                    .layers
+                    .iter()
+                    .find(|layer| layer.digest == **digest)
+            })
+            .map(|layer| layer.size.max(0) as u64)
+            .sum();
+        crate::harbor::check_quota(&target_ref, auth, required, policy).await?;
+    }
+    // Streamed uploads share one chunk size, adapted to the link as chunks complete
+    let mut chunks = monitor::ChunkSizer::adaptive(config.chunk_size, monitor.clone());
+    if let Some(limit) = budget.limit() {
+        // Leave room for every concurrent upload to stream a chunk
+        let share = limit / config.max_concurrent.max(1) as u64;
+        chunks = chunks.with_max_size(share.min(usize::MAX as u64) as usize);
+    }
+    let mut in_flight = FuturesUnordered::new();
+    let mut concurrency_ceiling = config.max_concurrent;
+    // The profile records how far the run had to back off, even if it recovered
+    let mut lowest_ceiling = config.max_concurrent;
+    let mut reported_pressure = false;
+    let mut was_pressured = false;
+    let mut current_limit = config.max_concurrent;
+    let mut ceiling_reason = "";
+    let layer_timeout = options.layer_timeout_secs.map(std::time::Duration::from_secs);
+
+    loop {
+        // Re-evaluate the concurrency limit every time a slot frees up
+        let limit = monitor.adjust_concurrency(concurrency_ceiling);
+        metrics::global().set_concurrency(limit);
+        let pressured = limit < concurrency_ceiling;
+        if limit != current_limit {
+            let reason = if pressured {
+                "memory pressure"
+            } else if was_pressured {
+                "memory pressure eased"
+            } else {
+                ceiling_reason
+            };
+            metrics::global().record_adjustment(current_limit, limit, reason);
+            current_limit = limit;
+        }
+        was_pressured = pressured;
+        if limit < concurrency_ceiling && !reported_pressure {
+            warn!(
+                "⚠️  Memory pressure detected, reducing upload concurrency to {}",
+                limit
+            );
+            reported_pressure = true;
+        }
+
+        // Once cancelled, start nothing new and let running uploads wind down
+        if options.cancel.is_cancelled() {
+            pending.clear();
+        }
+
+        while in_flight.len() < limit {
+            let Some((i, digest, attempt)) = pending.pop_front() else {
+                break;
+            };
+            let (registry, monitor) = (registry, &monitor);
+            let (total, chunks, budget) = (layer_digests.len(), &chunks, &budget);
+            // The pre-check covers the first try; retries and reschedules check again
+            // in case an earlier attempt landed the blob after all, and shared layers
+            // in case another transfer into the repository pushed them since
+            let force = options.forces_upload(digest);
+            let mut check_existing = !force
+                && (attempt > 0
+                    || existing[i].is_none()
+                    || options.layer_ranking.is_shared(digest));
+            let upload = options.retry.run_authenticated(
+                digest,
+                &options.cancel,
+                move || {
+                    let check = std::mem::replace(&mut check_existing, !force);
+                    push_layer(
+                        registry,
+                        monitor,
+                        store,
+                        digest,
+                        i,
+                        total,
+                        chunks,
+                        budget,
+                        &options.progress,
+                        &options.cancel,
+                        check,
+                    )
+                },
+                move |stage| registry.reauthenticate(client, stage),
+            );
+            let upload = upload.instrument(info_span!(
+                "layer",
+                digest = %digest,
+                attempt,
+                size = tracing::field::Empty
+            ));
+            // A stalled layer only cancels its own task; the others keep running
+            in_flight.push(async move {
+                let outcome = match layer_timeout {
+                    Some(deadline) => tokio::time::timeout(deadline, upload).await.ok(),
+                    None => Some(upload.await),
+                };
+                (i, digest, attempt, outcome)
+            });
+        }
+
+        match in_flight.next().await {
+            Some((i, digest, attempt, Some(result))) => {
+                let outcome = match result {
+                    Ok(outcome) => outcome,
+                    // Keep draining so streamed uploads can abort their sessions
+                    Err(_) if options.cancel.is_cancelled() => continue,
+                    // The other layers keep going so a rerun only has to upload this one
+                    Err(e) => {
+                        warn!("❌ Layer {} failed: {}", digest, e);
+                        failures.push((i, BlobFailure::new(digest, "layer", &e)));
+                        continue;
+                    }
+                };
+                if let Some(ledger) = &ledger {
+                    ledger.record(repository, &outcome.digest);
+                }
+                let status = if outcome.skipped {
+                    skipped_uploads += 1;
+                    BlobStatus::Skipped
+                } else {
+                    bytes_uploaded += outcome.size;
+                    BlobStatus::Uploaded
+                };
+                blob_reports.push((
+                    i,
+                    BlobReport::new(
+                        &outcome.digest,
+                        "layer",
+                        outcome.size,
+                        status,
+                        outcome.duration,
+                        attempt,
+                    ),
+                ));
+                uploaded_layers.push(outcome.digest);
+                // Every layer that makes it wins back a slot lost to a missed deadline
+                if concurrency_ceiling < config.max_concurrent {
+                    concurrency_ceiling += 1;
+                    ceiling_reason = "layer completed";
+                }
+            }
+            Some((i, digest, attempt, None)) => {
+                let attempt = attempt + 1;
+                if attempt >= MAX_LAYER_DEADLINE_ATTEMPTS {
+                    let error = PusherError::PushError(format!(
+                        "Layer {} exceeded the {}s deadline {} times, giving up",
+                        digest,
+                        options.layer_timeout_secs.unwrap_or_default(),
+                        attempt
+                    ));
+                    warn!("❌ {}", error);
+                    failures.push((i, BlobFailure::new(digest, "layer", &error)));
+                    continue;
+                }
+                // Reschedule at lower concurrency so the retry gets more bandwidth
+                metrics::global().record_retry();
+                concurrency_ceiling = (concurrency_ceiling / 2).max(1);
+                lowest_ceiling = lowest_ceiling.min(concurrency_ceiling);
+                ceiling_reason = "layer deadline exceeded";
+                info!(
+                    "⏰ Layer {} exceeded the {}s deadline, rescheduling (attempt {}/{}, concurrency {})",
+                    digest,
+                    options.layer_timeout_secs.unwrap_or_default(),
+                    attempt + 1,
+                    MAX_LAYER_DEADLINE_ATTEMPTS,
+                    concurrency_ceiling
+                );
+                pending.push_back((i, digest, attempt));
+            }
+            None => break,
+        }
+    }
+
+    // Uploads cut off by their deadline couldn't abort their sessions themselves
+    let orphaned = registry.abort_open_uploads().await;
+    if orphaned > 0 {
+        info!("🧹 Aborted {} unfinished upload session(s)", orphaned);
+    }
+    if options.cancel.is_cancelled() {
+        return Err(PusherError::Cancelled);
+    }
+    if let Some(ledger) = &ledger {
+        ledger.save().await;
+    }
+    // Report blobs in manifest order
+    blob_reports.sort_by_key(|(i, _)| *i);
+    if !failures.is_empty() {
+        failures.sort_by_key(|(i, _)| *i);
+        return Err(PusherError::BatchFailed(Box::new(BatchReport {
+            source: source_image.to_string(),
+            target: target_image.to_string(),
+            succeeded: blob_reports.into_iter().map(|(_, blob)| blob).collect(),
+            failed: failures.into_iter().map(|(_, failure)| failure).collect(),
+        })));
+    }
+
+    info!(
+        "🚀 Layer upload completed for {} layers",
+        uploaded_layers.len()
+    );
+    if skipped_uploads > 0 {
+        info!(
+            "💡 Skipped {} layers that already existed in registry",
+            skipped_uploads
+        );
+    }
+
+    // Remember how this registry behaved to seed the next run
+    profiles.record(
+        &registry_host,
+        &profile::RunObservation {
+            starting_concurrency: config.max_concurrent,
+            final_concurrency: lowest_ceiling,
+            chunk_size: chunks.current(),
+            bytes_uploaded,
+            elapsed: upload_start.elapsed(),
+        },
+    );
+    if let Err(e) = profiles.save().await {
+        warn!("⚠️  {}", e);
+    }
+
+    // Step 4: Upload image configuration
+    info!("⚙️  Uploading config: {}", config_digest);
+
+    let config_start = std::time::Instant::now();
+    options
+        .retry
+        .run_authenticated(
+            "Config upload",
+            &options.cancel,
+            || async {
+                registry.throttle().await;
+                let result = client
+                    .push_blob(&target_ref, &config_data, &config_digest)
+                    .await
+                    .map_err(|e| PusherError::registry("Failed to upload config", e));
+                if let Err(e) = &result {
+                    registry.back_off(e).await;
+                }
+                result
+            },
+            |stage| registry.reauthenticate(client, stage),
+        )
+        .await?;
+    bytes_uploaded += config_data.len() as u64;
+
+    // Config goes last in the report
+    let mut blobs: Vec<BlobReport> = blob_reports.into_iter().map(|(_, blob)| blob).collect();
+    blobs.push(BlobReport::new(
+        &config_digest,
+        "config",
+        config_data.len() as u64,
+        BlobStatus::Uploaded,
+        config_start.elapsed(),
+        0,
+    ));
+
+    // Step 5: Push the final manifest to complete the image
+    info!("📋 Pushing manifest to registry: {}", target_image);
+    let content_type: reqwest::header::HeaderValue =
+        manifest_enum.content_type().parse().map_err(|e| {
+            PusherError::PushError(format!("Invalid manifest media type: {}", e))
+        })?;
+    let manifest_push = options
+        .retry
+        .run_authenticated(
+            "Manifest push",
+            &options.cancel,
+            || async {
+                registry.throttle().await;
+                let result = client
+                    .push_manifest_raw(&target_ref, manifest_bytes.clone(), content_type.clone())
+                    .await
+                    .map_err(|e| PusherError::registry("Failed to push manifest", e));
+                if let Err(e) = &result {
+                    registry.back_off(e).await;
+                }
+                result
+            },
+            |stage| registry.reauthenticate(client, stage),
+        )
+        .await;
+    let manifest_url = match (manifest_push, &ledger) {
+        (Ok(url), _) => url,
+        // The registry may have garbage-collected a blob the ledger vouched for
+        (Err(e), Some(ledger)) if ledger_skips > 0 && !matches!(e, PusherError::Cancelled) => {
+            warn!(
+                "⚠️  Manifest push failed after trusting the blob ledger, checking all layers again: {}",
+                e
+            );
+            ledger.forget_repository(repository);
+            ledger.save().await;
+            let options = PushOptions {
+                ledger_ttl_secs: None,
+                ..options.clone()
+            };
+            return Box::pin(push_cached_image_via(
+                client,
+                Some(registry),
+                source_image,
+                target_image,
+                auth,
+                &options,
+                store,
+            ))
+            .await;
+        }
+        (Err(e), _) => return Err(e),
+    };
+
+    info!(
+        repository = target_ref.repository(),
+        manifest_url = %manifest_url,
+        "🎉 Successfully pushed {} layers to {}",
+        uploaded_layers.len(),
+        manifest_url
+    );
+    options.progress.emit(ProgressEvent::ManifestPushed {
+        reference: target_image.to_string(),
+        url: manifest_url.clone(),
+    });
+    Ok(TransferReport {
+        source: source_image.to_string(),
+        target: target_image.to_string(),
+        started_at,
+        duration_secs: push_start.elapsed().as_secs_f64(),
+        bytes_uploaded,
+        manifest_digest,
+        manifest_url,
+        blobs,
+    })
+}
+
+/// Checks the cached manifest, config and layers of a pinned image against their digests
+///
+/// The manifest has to hash to the pinned digest itself when that names a
+/// manifest rather than a multi-arch index.
+async fn verify_cached_content<S: BlobStore>(
+    index: &serde_json::Value,
+    manifest_content: &str,
+    manifest: &OciImageManifest,
+    image_cache_dir: &std::path::Path,
+    store: &S,
+) -> Result<(), PusherError> {
+    let check = |what: String, expected: &str, actual: String| {
+        if actual == expected {
+            Ok(())
+        } else {
+            Err(PusherError::DigestMismatch {
+                what,
+                expected: expected.to_string(),
+                actual,
+            })
+        }
+    };
+
+    let expected = index["manifest_file_digest"].as_str().ok_or_else(|| {
+        PusherError::CacheError("Cache index has no manifest digest; pull the image again".to_string())
+    })?;
+    image::digest::validate(expected, "Cache index manifest_file_digest")?;
+    check(
+        "Cached manifest".to_string(),
+        expected,
+        format!("sha256:{:x}", Sha256::digest(manifest_content.as_bytes())),
+    )?;
+
+    // The pin names the cached manifest itself, unless it names the index the
+    // manifest was resolved from (the registry then served a different digest)
+    let pinned = index["pinned_digest"].as_str().unwrap_or_default();
+    image::digest::validate(pinned, "Cache index pinned_digest")?;
+    let algorithm = image::digest::Algorithm::of(pinned).unwrap_or_default();
+    let from_index = index["manifest_digest"].as_str().is_some_and(|digest| {
+        digest != pinned && image::digest::Algorithm::of(digest) == Some(algorithm)
+    });
+    if !from_index {
+        check(
+            "Cached manifest".to_string(),
+            pinned,
+            image::digest::Digest::compute(algorithm, manifest_content.as_bytes()).to_string(),
+        )?;
+    }
+
+    let config_digest = &manifest.config.digest;
+    let config_path =
+        image_cache_dir.join(format!("config_{}.json", image::digest::file_name(config_digest)));
+    let config_data = tokio::fs::read(&config_path)
+        .await
+        .map_err(|e| PusherError::CacheError(format!("Failed to read cached config: {}", e)))?;
+    check(
+        "Cached config".to_string(),
+        config_digest,
+        image::digest::Digest::compute(
+            image::digest::Algorithm::of(config_digest).unwrap_or_default(),
+            &config_data,
+        )
+        .to_string(),
+    )?;
+
+    for layer in &manifest.layers {
+        check(
+            format!("Cached layer {}", layer.digest),
+            &layer.digest,
+            store.compute_digest(&layer.digest).await?,
+        )?;
+    }
+    Ok(())
+}
+
+/// Checks which layers the registry already has, running up to `limit` HEAD requests at once
+///
+/// Returns one entry per digest, in order: whether the blob exists, or
+/// `None` when the check kept failing and the upload should check again.
+async fn check_existing_layers(
+    client: &Client,
+    registry: &registry::RegistryClient,
+    digests: &[String],
+    limit: usize,
+    retry: &RetryPolicy,
+    cancel: &CancellationToken,
+) -> Vec<Option<bool>> {
+    let started = std::time::Instant::now();
+    // Collected first: a stream mapping through the closure isn't Send, so couldn't be spawned
+    let checks: Vec<_> = digests
+        .iter()
+        .map(|digest| async move {
+            let exists = retry
+                .run_authenticated(
+                    digest,
+                    cancel,
+                    || registry.blob_exists(digest),
+                    |stage| registry.reauthenticate(client, stage),
+                )
+                .await;
+            match exists {
+                Ok(exists) => Some(exists),
+                Err(e) => {
+                    debug!(digest = %digest, error = %e, "blob existence pre-check failed");
+                    None
+                }
+            }
+        })
+        .collect();
+    let existing: Vec<Option<bool>> = futures::stream::iter(checks)
+        .buffered(limit.max(1))
+        .collect()
+        .await;
+    debug!(
+        layers = digests.len(),
+        elapsed = ?started.elapsed(),
+        "blob existence pre-check finished"
+    );
+    existing
+}
+
+/// Result of uploading (or skipping) a single layer
+struct LayerOutcome {
+    /// Digest of the layer
+    digest: String,
+    /// Size of the layer in bytes
+    size: u64,
+    /// Whether the upload was skipped because the registry already had the blob
+    skipped: bool,
+    /// Time spent on the layer, existence check included
+    duration: std::time::Duration,
+}
+
+/// Uploads a single cached layer, choosing a strategy based on size and memory pressure
+///
+/// # Returns
+///
+/// `Result<LayerOutcome, PusherError>` - The layer digest, size and whether the upload was skipped
+#[allow(clippy::too_many_arguments)]
+async fn push_layer<S: BlobStore>(
+    registry: &registry::RegistryClient,
+    monitor: &monitor::PerformanceMonitor,
+    store: &S,
+    digest: &str,
+    index: usize,
+    total: usize,
+    chunks: &monitor::ChunkSizer,
+    budget: &monitor::MemoryBudget,
+    progress: &ProgressReporter,
+    cancel: &CancellationToken,
+    check_existing: bool,
+) -> Result<LayerOutcome, PusherError> {
+    let started = std::time::Instant::now();
+
+    // Check layer size to determine upload strategy
+    let layer_size = store.size(digest).await?.ok_or_else(|| {
+        PusherError::CacheError(format!("Layer {} is missing from the cache", digest))
+    })?;
+    let layer_size_mb = layer_size as f64 / (1024.0 * 1024.0);
+    tracing::Span::current().record("size", layer_size);
+
+    info!(
+        "📦 Uploading layer {}/{}: {} ({:.1} MB)",
+        index + 1,
+        total,
+        digest,
+        layer_size_mb
+    );
+
+    // Check if blob already exists in registry to avoid unnecessary upload
+    if check_existing && registry.blob_exists(digest).await? {
+        info!(
+            digest = %digest,
+            bytes = layer_size,
+            "   ✅ Layer already exists in registry, skipping upload: {}",
+            digest
+        );
+        metrics::global().record_skip();
+        progress.emit(ProgressEvent::LayerCompleted {
+            digest: digest.to_string(),
+            size: layer_size,
+            skipped: true,
+        });
+        return Ok(LayerOutcome {
+            digest: digest.to_string(),
+            size: layer_size,
+            skipped: true,
+            duration: started.elapsed(),
+        });
+    }
+
+    let _transfer = metrics::global().start_layer_transfer(digest, layer_size);
+    progress.emit(ProgressEvent::LayerStarted {
+        digest: digest.to_string(),
+        size: layer_size,
+    });
+
+    // MEMORY OPTIMIZATION: Stream from disk when buffering would approach the memory cap,
+    // otherwise pick a strategy based on layer size
+    let oversized = !budget.fits(layer_size);
+    let streamed = oversized || !monitor.can_buffer(layer_size);
+    debug!(
+        streamed,
+        rss = ?monitor.current_rss(),
+        memory_cap = ?monitor.memory_cap(),
+        buffer_limit = ?budget.limit(),
+        "upload strategy selected"
+    );
+
+    if streamed {
+        info!(
+            "   🌊 {}, streaming layer from disk in chunks of ~{} MB...",
+            if oversized { "Layer exceeds the memory budget" } else { "Memory near cap" },
+            chunks.current() / (1024 * 1024)
+        );
+        registry
+            .push_blob_chunked(
+                store.stream(digest).await?,
+                digest,
+                layer_size,
+                chunks,
+                budget,
+                progress,
+                cancel,
+            )
+            .await?;
+    } else {
+        // Hold the whole layer's share of the memory budget for the upload
+        // (streamed uploads reserve their largest chunk themselves)
+        let _reservation = tokio::select! {
+            reservation = budget.reserve(layer_size) => reservation,
+            _ = cancel.cancelled() => return Err(PusherError::Cancelled),
+        };
+        let upload = async {
+            if layer_size_mb > LARGE_LAYER_THRESHOLD_MB {
+                upload_large_layer(registry, store, digest, layer_size_mb).await
+            } else {
+                upload_small_layer(registry, store, digest, layer_size_mb).await
+            }
+        };
+        // The dropped upload's session is aborted with the other unfinished ones
+        tokio::select! {
+            result = upload => result?,
+            _ = cancel.cancelled() => return Err(PusherError::Cancelled),
+        };
+    }
+    if !streamed {
+        // Buffered uploads hand the whole layer over at once
+        progress.emit(ProgressEvent::LayerBytes {
+            digest: digest.to_string(),
+            transferred: layer_size,
+            total: layer_size,
+        });
+    }
+
+    metrics::global().record_upload(layer_size);
+    info!(
+        digest = %digest,
+        bytes = layer_size,
+        "   ✅ Successfully uploaded layer {}",
+        digest
+    );
+    progress.emit(ProgressEvent::LayerCompleted {
+        digest: digest.to_string(),
+        size: layer_size,
+        skipped: false,
+    });
+
+    // Rate limiting: Add delay for large layers to prevent overwhelming the registry
+    if layer_size_mb > MEDIUM_LAYER_THRESHOLD_MB {
+        tokio::time::sleep(tokio::time::Duration::from_millis(RATE_LIMIT_DELAY_MS)).await;
+    }
+    Ok(LayerOutcome {
+        digest: digest.to_string(),
+        size: layer_size,
+        skipped: false,
+        duration: started.elapsed(),
+    })
+}
+
+/// Formats size display for progress reporting
+fn format_size_display(size_mb: f64) -> (f64, &'static str) {
+    if size_mb > 1024.0 {
+        (size_mb / 1024.0, "GB")
+    } else {
+        (size_mb, "MB")
+    }
+}
+
+/// Calculates upload progress estimation
+fn calculate_upload_progress(elapsed_secs: u64, layer_size_mb: f64) -> f64 {
+    if elapsed_secs > 10 {
+        let time_factor = elapsed_secs as f64 / (layer_size_mb / 8.0);
+        ((time_factor / (1.0 + time_factor)) * 100.0).min(95.0)
+    } else {
+        10.0 // Assume 10% in first 10 seconds
+    }
+}
+
+/// Aborts a background task when dropped
+///
+/// Uploads can be cancelled mid-flight (e.g. when a layer exceeds its deadline),
+/// so the progress tracker must not outlive the upload future that owns it.
+struct AbortOnDrop(Option<tokio::task::JoinHandle<()>>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        if let Some(handle) = &self.0 {
+            handle.abort();
+        }
+    }
+}
+
+/// Creates a progress tracking task for large layer uploads
+fn create_progress_tracker(
+    layer_size_mb: f64,
+    layer_size_bytes: u64,
+    network_start: std::time::Instant,
+    digest: &str,
+) -> Option<tokio::task::JoinHandle<()>> {
+    if layer_size_mb <= LARGE_LAYER_THRESHOLD_MB {
+        return None;
+    }
+
+    let layer_size_mb_clone = layer_size_mb;
+    let network_start_clone = network_start;
+    let digest_suffix = digest.chars().skip(digest.len() - 8).collect::<String>();
+    let interval_secs = if layer_size_mb > 1000.0 { 
+        LARGE_LAYER_PROGRESS_INTERVAL_SECS 
+    } else { 
+        NORMAL_LAYER_PROGRESS_INTERVAL_SECS 
+    };
+
+    Some(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(interval_secs));
+        let mut progress_counter = 1;
+        
+        loop {
+            interval.tick().await;
+            let elapsed = network_start_clone.elapsed();
+            
+            if elapsed.as_secs() > 0 {
+                let elapsed_min = elapsed.as_secs_f64() / 60.0;
+                let estimated_progress_percent = calculate_upload_progress(elapsed.as_secs(), layer_size_mb_clone);
+                
+                let estimated_transferred_mb = (estimated_progress_percent / 100.0) * layer_size_mb_clone;
+                let estimated_remaining_mb = layer_size_mb_clone - estimated_transferred_mb;
+                let estimated_transferred_bytes = (estimated_progress_percent / 100.0) * layer_size_bytes as f64;
+
+                let current_speed_mbps = if elapsed.as_secs() > 5 {
+                    estimated_transferred_mb / elapsed.as_secs_f64()
+                } else {
+                    ESTIMATED_SPEED_MBPS
+                };
+
+                let remaining_time_min = if current_speed_mbps > 0.0 {
+                    estimated_remaining_mb / current_speed_mbps / 60.0
+                } else {
+                    0.0
+                };
+
+                let (transferred_display, unit) = format_size_display(estimated_transferred_mb);
+                let (total_display, _) = format_size_display(layer_size_mb_clone);
+
+                info!("   ⏳ Upload progress #{}: {:.1}% | {:.1}/{:.1} {} | Speed: ~{:.1} MB/s | ETA: {:.1}min", 
+                    progress_counter,
+                    estimated_progress_percent,
+                    transferred_display,
+                    total_display,
+                    unit,
+                    current_speed_mbps,
+                    remaining_time_min);
+
+                // Show detailed information periodically
+                if progress_counter % 2 == 0 {
+                    info!("   📊 Data transferred: {:.0}/{} bytes | Elapsed: {:.1}min | Layer: ...{}", 
+                        estimated_transferred_bytes,
+                        layer_size_bytes,
+                        elapsed_min,
+                        digest_suffix);
+                }
+
+                // Show network analysis for very large layers
+                if progress_counter % 3 == 0 && layer_size_mb_clone > 1000.0 {
+                    let gb_size = layer_size_mb_clone / 1024.0;
+                    let avg_speed = estimated_transferred_mb / elapsed.as_secs_f64();
+                    let completion_percent = ((estimated_transferred_mb / layer_size_mb_clone) * 100.0).min(95.0);
+                    
+                    info!("   📈 Network: {:.2} GB total | Avg: {:.1} MB/s | Progress: {:.1}% | Large transfer in progress", 
+                        gb_size, avg_speed, completion_percent);
+                }
+
+                progress_counter += 1;
+            }
+        }
+    }))
+}
+
+/// Uploads a large layer with progress tracking and optimization
+async fn upload_large_layer<S: BlobStore>(
+    registry: &registry::RegistryClient,
+    store: &S,
+    digest: &str,
+    layer_size_mb: f64,
+) -> Result<(), PusherError> {
+    info!("   🔄 Streaming large layer ({:.1} MB) directly to registry...", layer_size_mb);
+    
+    let upload_start = std::time::Instant::now();
+    let layer_data = store.get(digest).await?;
+
+    let read_duration = upload_start.elapsed();
+    info!("   📖 File read completed in {:.1}s ({:.1} MB)", 
+        read_duration.as_secs_f64(),
+        layer_data.len() as f64 / (1024.0 * 1024.0)
+    );
+
+    // Show estimated time for very large layers
+    if layer_size_mb > 1000.0 {
+        let estimated_time_min = layer_size_mb / ESTIMATED_SPEED_MBPS / 60.0;
+        info!("   ⏱️  Estimated upload time: {:.1}-{:.1} minutes", 
+            estimated_time_min * 0.5, estimated_time_min * 2.0);
+    }
+
+    let layer_bytes = layer_data.len() as u64;
+    let network_start = std::time::Instant::now();
+    let progress_guard = AbortOnDrop(create_progress_tracker(
+        layer_size_mb, 
+        layer_bytes, 
+        network_start, 
+        digest
+    ));
+
+    // Perform the actual upload
+    let upload_result = registry.push_blob_monolithic(layer_data, digest).await;
+
+    // Cancel progress tracking
+    drop(progress_guard);
+
+    upload_result?;
+
+    let network_duration = network_start.elapsed();
+    let total_duration = upload_start.elapsed();
+    let upload_speed = if network_duration.as_secs() > 0 {
+        (layer_bytes as f64 / (1024.0 * 1024.0)) / network_duration.as_secs_f64()
+    } else {
+        0.0
+    };
+
+    info!("   ⚡ Upload completed! Total: {:.1}s (read: {:.1}s, upload: {:.1}s) @ {:.1} MB/s",
+        total_duration.as_secs_f64(),
+        read_duration.as_secs_f64(),
+        network_duration.as_secs_f64(),
+        upload_speed
+    );
+
+    // Additional success details for very large uploads
+    if layer_size_mb > 1000.0 {
+        let gb_transferred = layer_size_mb / 1024.0;
+        info!("   🎉 Successfully transferred {:.2} GB in {:.1} minutes",
+            gb_transferred, network_duration.as_secs_f64() / 60.0);
+    }
+
+    Ok(())
+}
+
+/// Uploads a small layer with simple timing
+async fn upload_small_layer<S: BlobStore>(
+    registry: &registry::RegistryClient,
+    store: &S,
+    digest: &str,
+    layer_size_mb: f64,
+) -> Result<(), PusherError> {
+    info!("   📤 Uploading layer directly...");
+    
+    let read_start = std::time::Instant::now();
+    let layer_data = store.get(digest).await?;
+
+    let read_duration = read_start.elapsed();
+    let upload_start = std::time::Instant::now();
+
+    registry.push_blob_monolithic(layer_data, digest).await?;
+
+    let upload_duration = upload_start.elapsed();
+    let total_duration = read_start.elapsed();
+    let speed = if total_duration.as_secs() > 0 {
+        layer_size_mb / total_duration.as_secs_f64()
+    } else {
+        0.0
+    };
+
+    info!("   ⚡ Completed in {:.1}s (read: {:.1}ms, upload: {:.1}s) @ {:.1} MB/s",
+        total_duration.as_secs_f64(),
+        read_duration.as_millis(),
+        upload_duration.as_secs_f64(),
+        speed
+    );
+
+    Ok(())
+}
+
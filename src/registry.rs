@@ -0,0 +1,868 @@
+#[cfg(feature = "oci-client")]
+pub mod referrers;
+#[cfg(feature = "oci-client")]
+mod tokens;
+
+#[cfg(feature = "oci-client")]
+pub use tokens::prefetch_tokens;
+
+use crate::PusherError;
+use crate::concurrency::RateLimiter;
+use crate::credentials::{AuthChain, RegistryAuth};
+#[cfg(feature = "oci-client")]
+use crate::credentials::{AuthProvider, RegistryOperation, exchange_identity_token};
+use crate::journal::{JournalEntry, UploadJournal};
+use crate::monitor::{ChunkSizer, MemoryBudget};
+use crate::progress::{ProgressEvent, ProgressReporter};
+#[cfg(feature = "oci-client")]
+use crate::retry::Reauthentication;
+use crate::transport::{
+    RegistryTransport, ReqwestTransport, Timeouts, TransportRequest, TransportResponse,
+};
+use oci_spec::distribution::Reference;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "oci-client")]
+use tokens::{cache_token, cached_token};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace};
+
+/// Size of each PATCH request when streaming a blob from disk
+pub const STREAM_CHUNK_SIZE: usize = 16 * 1024 * 1024; // 16MB
+
+/// Header in which registries announce the smallest chunk they accept (OCI distribution 1.1)
+pub const CHUNK_MIN_LENGTH_HEADER: &str = "oci-chunk-min-length";
+
+/// Manifest and index media types (OCI and Docker) accepted when looking up manifests
+const MANIFEST_MEDIA_TYPES: [&str; 4] = [
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.docker.distribution.manifest.v2+json",
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+
+/// Smallest chunk (except the last) Amazon ECR accepts, as documented for its layer parts
+const ECR_MIN_CHUNK_SIZE: usize = 5 * 1024 * 1024; // 5MB
+
+/// Chunk sizes an upload session accepts
+///
+/// Every chunk but the last has to be at least `min` bytes. Registries that
+/// announce a minimum do so when the session starts; for some others the
+/// documented minimum is built in. None documents a maximum below the sizes
+/// adaptive chunking stays within (see [`crate::monitor::MAX_CHUNK_SIZE`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ChunkLimits {
+    /// Smallest accepted chunk in bytes (0 for no minimum)
+    pub min: usize,
+}
+
+impl ChunkLimits {
+    /// Returns the documented limits of `registry`, none for unknown registries
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use docker_image_pusher::registry::ChunkLimits;
+    /// let ecr = ChunkLimits::for_registry("123456789012.dkr.ecr.eu-west-1.amazonaws.com");
+    /// assert_eq!(ecr.clamp(1024 * 1024), 5 * 1024 * 1024);
+    /// assert_eq!(ChunkLimits::for_registry("ghcr.io"), ChunkLimits::default());
+    /// ```
+    pub fn for_registry(registry: &str) -> Self {
+        let host = registry.split(':').next().unwrap_or(registry);
+        if host.contains(".dkr.ecr.") && host.ends_with(".amazonaws.com") {
+            return Self {
+                min: ECR_MIN_CHUNK_SIZE,
+            };
+        }
+        Self::default()
+    }
+
+    /// Raises the minimum to the one announced in an upload session response, if any
+    pub fn with_announced(mut self, headers: &reqwest::header::HeaderMap) -> Self {
+        let announced = headers
+            .get(CHUNK_MIN_LENGTH_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<usize>().ok());
+        if let Some(min) = announced {
+            self.min = self.min.max(min);
+        }
+        self
+    }
+
+    /// Raises a chunk size to the minimum
+    pub fn clamp(&self, size: usize) -> usize {
+        size.max(self.min)
+    }
+}
+
+/// Minimal Registry v2 HTTP client for operations oci-client doesn't expose
+///
+/// `oci_client::Client::push_blob` takes the whole blob as a byte slice, which
+/// forces the caller to hold an entire layer in memory. This client performs
+/// the chunked upload protocol directly (POST → PATCH… → PUT) reading the layer
+/// from disk one chunk at a time, so memory use stays bounded by the chunk size.
+///
+/// Requests go through a [`RegistryTransport`] ([`ReqwestTransport`] unless
+/// another one is supplied with [`RegistryClient::with_transport`]), which also
+/// attaches credentials. Every request first passes through the registry's
+/// shared [`RateLimiter`].
+pub struct RegistryClient<T = ReqwestTransport> {
+    transport: T,
+    reference: Reference,
+    base_url: String,
+    repository: String,
+    limiter: Arc<RateLimiter>,
+    sessions: UploadSessions,
+    /// Asked for new credentials when a fresh token is rejected
+    credentials: AuthChain,
+}
+
+/// Upload sessions a client started that were neither finalized nor aborted yet
+///
+/// Keyed by an ID per upload, holding the session's latest location (it
+/// moves with every PATCH). Uploads whose future was dropped mid-way leave
+/// their entry behind for [`RegistryClient::abort_open_uploads`]. With a
+/// journal, every change is mirrored to disk so sessions of a crashed run
+/// can be aborted by the next one.
+struct UploadSessions {
+    /// Distinguishes this client's journal keys from other clients' and processes'
+    prefix: String,
+    next_id: AtomicU64,
+    open: Mutex<HashMap<u64, JournalEntry>>,
+    journal: Option<UploadJournal>,
+}
+
+impl UploadSessions {
+    fn new() -> Self {
+        Self {
+            prefix: crate::correlation::new_id(),
+            next_id: AtomicU64::new(0),
+            open: Mutex::new(HashMap::new()),
+            journal: None,
+        }
+    }
+
+    /// Records a new session, returning its ID
+    fn open(&self, entry: JournalEntry) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        if let Some(journal) = &self.journal {
+            journal.record(&self.key(id), &entry);
+        }
+        self.lock().insert(id, entry);
+        id
+    }
+
+    /// Records the location a session moved to
+    fn moved(&self, id: u64, location: &str) {
+        if let Some(entry) = self.lock().get_mut(&id) {
+            entry.location = location.to_string();
+            if let Some(journal) = &self.journal {
+                journal.record(&self.key(id), entry);
+            }
+        }
+    }
+
+    /// Forgets a session, returning its latest location if it was still open
+    fn close(&self, id: u64) -> Option<String> {
+        let entry = self.lock().remove(&id)?;
+        if let Some(journal) = &self.journal {
+            journal.forget(&self.key(id));
+        }
+        Some(entry.location)
+    }
+
+    /// Forgets every open session, returning their locations
+    fn drain(&self) -> Vec<String> {
+        let ids: Vec<u64> = self.lock().keys().copied().collect();
+        ids.into_iter().filter_map(|id| self.close(id)).collect()
+    }
+
+    fn key(&self, id: u64) -> String {
+        format!("{}-{}", self.prefix, id)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, JournalEntry>> {
+        self.open.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl RegistryClient {
+    /// Creates a client for the registry and repository of `reference`
+    ///
+    /// # Arguments
+    ///
+    /// * `reference` - Target image reference (registry and repository are used)
+    /// * `token` - Bearer token returned by `oci_client::Client::auth`, if any
+    /// * `auth` - Credentials to fall back to when no bearer token is available
+    /// * `limiter` - Request rate limiter shared by everything talking to this registry
+    pub fn new(
+        reference: &Reference,
+        token: Option<String>,
+        auth: &RegistryAuth,
+        limiter: Arc<RateLimiter>,
+    ) -> Self {
+        Self::with_transport(reference, ReqwestTransport::new(token, auth), limiter)
+    }
+
+    /// Authenticates for pushing to `reference` and creates a client using the resulting token
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - OCI client used to perform the registry's token handshake
+    /// * `reference` - Target image reference (registry and repository are used)
+    /// * `auth` - Credentials for the target registry; an identity token is
+    ///   exchanged for an access token first
+    /// * `limiter` - Request rate limiter shared by everything talking to this registry
+    ///
+    /// A token obtained for the same repository and credentials earlier (by
+    /// another push of the process or by [`prefetch_tokens`]) is reused
+    /// while it is valid, without a handshake.
+    #[cfg(feature = "oci-client")]
+    pub async fn connect(
+        client: &oci_client::Client,
+        reference: &Reference,
+        auth: &RegistryAuth,
+        limiter: Arc<RateLimiter>,
+    ) -> Result<Self, PusherError> {
+        let operation = RegistryOperation::Push;
+        if let Some(token) = cached_token(reference, auth, operation) {
+            debug!(repository = %reference.repository(), "reusing cached registry token");
+            // A bearer login only stores the token in the client, without a request
+            let _ = client
+                .auth(reference, &RegistryAuth::Bearer(token.clone()), operation)
+                .await;
+            return Ok(Self::new(reference, Some(token), auth, limiter));
+        }
+        let login = exchange_identity_token(reference, auth, operation).await?;
+        limiter.acquire().await;
+        let token = client
+            .auth(reference, &login, operation)
+            .await
+            .map_err(|e| PusherError::registry("Authentication failed", e))?;
+        if let Some(token) = &token {
+            cache_token(reference, auth, operation, token);
+        }
+        Ok(Self::new(reference, token, auth, limiter))
+    }
+
+    /// Applies connect, idle and total request timeouts to every request
+    ///
+    /// See [`ReqwestTransport::with_timeouts`].
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.transport = self.transport.with_timeouts(timeouts);
+        self
+    }
+
+    /// Records open upload sessions in `journal`, so a later run can abort
+    /// them if this one dies mid-upload
+    pub fn with_journal(mut self, journal: UploadJournal) -> Self {
+        self.sessions.journal = Some(journal);
+        self
+    }
+
+    /// Looks the credentials up again in `credentials` when the registry
+    /// rejects them, so rotated passwords are picked up (see [`RegistryClient::reauthenticate`])
+    pub fn with_credentials(mut self, credentials: AuthChain) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// Requests a fresh push token, e.g. after the registry answered 401
+    ///
+    /// For [`Reauthentication::Credentials`] the credentials are first looked
+    /// up again in the chain given to [`RegistryClient::with_credentials`];
+    /// `false` is returned when they haven't changed. Also refreshes the
+    /// token `client` uses for its own requests.
+    #[cfg(feature = "oci-client")]
+    pub async fn reauthenticate(
+        &self,
+        client: &oci_client::Client,
+        stage: Reauthentication,
+    ) -> Result<bool, PusherError> {
+        if stage == Reauthentication::Credentials {
+            let registry = self.reference.registry();
+            let Some(auth) = self.credentials.credentials_for(registry).await? else {
+                return Ok(false);
+            };
+            if auth == self.transport.credentials() {
+                return Ok(false);
+            }
+            tracing::info!("🔑 Picked up new credentials for {}", registry);
+            self.transport.set_credentials(auth);
+        }
+        let login = exchange_identity_token(
+            &self.reference,
+            &self.transport.credentials(),
+            RegistryOperation::Push,
+        )
+        .await?;
+        self.throttle().await;
+        let token = client
+            .auth(&self.reference, &login, RegistryOperation::Push)
+            .await
+            .map_err(|e| PusherError::registry("Re-authentication failed", e))?;
+        // Replaces the cached token the registry just rejected
+        if let Some(token) = &token {
+            cache_token(
+                &self.reference,
+                &self.transport.credentials(),
+                RegistryOperation::Push,
+                token,
+            );
+        }
+        self.transport.set_token(token);
+        Ok(true)
+    }
+}
+
+impl<T: RegistryTransport> RegistryClient<T> {
+    /// Creates a client that sends its requests through `transport`
+    ///
+    /// # Arguments
+    ///
+    /// * `reference` - Target image reference (registry and repository are used)
+    /// * `transport` - HTTP transport, responsible for authentication
+    /// * `limiter` - Request rate limiter shared by everything talking to this registry
+    pub fn with_transport(reference: &Reference, transport: T, limiter: Arc<RateLimiter>) -> Self {
+        Self {
+            transport,
+            reference: reference.clone(),
+            base_url: base_url(reference.resolve_registry()),
+            repository: reference.repository().to_string(),
+            limiter,
+            sessions: UploadSessions::new(),
+            credentials: AuthChain::new(),
+        }
+    }
+
+    /// Waits for the registry's rate limiter before issuing a request
+    ///
+    /// Also used ahead of requests made through `oci_client::Client` so they
+    /// count against the same budget.
+    pub async fn throttle(&self) {
+        self.limiter.acquire().await;
+    }
+
+    /// Pauses every request to this registry if `error` says it is throttling us
+    ///
+    /// Requests made through `oci_client::Client` report their failures here
+    /// so they pause the same queue as this client's own requests.
+    pub async fn back_off(&self, error: &PusherError) {
+        self.limiter.back_off(error).await;
+    }
+
+    /// Sends a request through the rate limiter
+    ///
+    /// A 429 (or 503) response pauses the registry's queue for its
+    /// `Retry-After` and is returned as an error, so callers never mistake it
+    /// for an answer to their request.
+    async fn send(
+        &self,
+        request: TransportRequest,
+        what: impl std::fmt::Display,
+    ) -> Result<TransportResponse, PusherError> {
+        self.throttle().await;
+        let response = self
+            .transport
+            .send(request)
+            .await
+            .map_err(|e| PusherError::transport(&what, e))?;
+        let status = response.status;
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            || status == reqwest::StatusCode::SERVICE_UNAVAILABLE
+        {
+            let error = PusherError::http(what, &response);
+            self.back_off(&error).await;
+            return Err(error);
+        }
+        Ok(response)
+    }
+
+    /// Checks if a blob already exists in the repository via `HEAD /v2/<name>/blobs/<digest>`
+    ///
+    /// 401 and 403 are errors, so a rejected token is refreshed (see
+    /// [`PusherError::is_unauthorized`]) instead of the blob being uploaded
+    /// again only to be rejected as well; so is throttling (429/503), to be
+    /// retried after the pause. Any other response than 200 is treated as
+    /// "not present", so the caller falls back to uploading.
+    ///
+    /// # Arguments
+    ///
+    /// * `digest` - Digest of the blob to check
+    ///
+    /// # Returns
+    ///
+    /// `Result<bool, PusherError>` - true if blob exists in registry, false otherwise
+    pub async fn blob_exists(&self, digest: &str) -> Result<bool, PusherError> {
+        let url = format!("{}/v2/{}/blobs/{}", self.base_url, self.repository, digest);
+        let what = format!("Failed to check blob {}", digest);
+        let response = self.send(TransportRequest::head(&url), &what).await?;
+
+        debug!(digest, status = %response.status, "blob existence check");
+        match response.status {
+            reqwest::StatusCode::OK => Ok(true),
+            reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => {
+                Err(PusherError::http(what, &response))
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Returns the digest of the manifest `reference` (a tag or digest) points to
+    /// via `HEAD /v2/<name>/manifests/<reference>`, or `None` if it doesn't exist
+    ///
+    /// Falls back to hashing the manifest from a `GET` when the registry
+    /// doesn't send `Docker-Content-Digest`.
+    pub async fn manifest_digest(&self, reference: &str) -> Result<Option<String>, PusherError> {
+        let url = format!("{}/v2/{}/manifests/{}", self.base_url, self.repository, reference);
+        let what = format!("Failed to check manifest {}", reference);
+        let accept = MANIFEST_MEDIA_TYPES.join(", ");
+        let response = self
+            .send(
+                TransportRequest::head(&url).header(reqwest::header::ACCEPT, &accept),
+                &what,
+            )
+            .await?;
+        debug!(reference, status = %response.status, "manifest existence check");
+        match response.status {
+            reqwest::StatusCode::NOT_FOUND => return Ok(None),
+            reqwest::StatusCode::OK => {}
+            _ => return Err(PusherError::http(what, &response)),
+        }
+        if let Some(digest) = response
+            .headers
+            .get("Docker-Content-Digest")
+            .and_then(|v| v.to_str().ok())
+        {
+            return Ok(Some(digest.to_string()));
+        }
+
+        let response = self
+            .send(
+                TransportRequest::get(&url).header(reqwest::header::ACCEPT, &accept),
+                &what,
+            )
+            .await?;
+        match response.status {
+            reqwest::StatusCode::NOT_FOUND => Ok(None),
+            reqwest::StatusCode::OK => {
+                Ok(Some(format!("sha256:{:x}", Sha256::digest(&response.body))))
+            }
+            _ => Err(PusherError::http(what, &response)),
+        }
+    }
+
+    /// Pushes a blob produced by any async reader, e.g. data generated on the fly
+    ///
+    /// Nothing is written to the cache directory; the reader is consumed in
+    /// [`STREAM_CHUNK_SIZE`] chunks through the chunked upload protocol.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - Source of the blob bytes
+    /// * `size` - Exact number of bytes `reader` yields
+    /// * `digest` - Digest of the blob, verified by the registry on completion
+    pub async fn push_blob_from_reader<R: AsyncRead + Unpin>(
+        &self,
+        reader: R,
+        size: u64,
+        digest: &str,
+    ) -> Result<(), PusherError> {
+        self.push_blob_streamed(
+            reader,
+            digest,
+            size,
+            STREAM_CHUNK_SIZE,
+            &ProgressReporter::default(),
+            &CancellationToken::new(),
+        )
+        .await
+    }
+
+    /// Pushes a blob held in memory, computing its sha256 digest
+    ///
+    /// # Returns
+    ///
+    /// `Result<String, PusherError>` - The digest the blob was pushed under
+    pub async fn push_blob_from_bytes(&self, data: &[u8]) -> Result<String, PusherError> {
+        let digest = format!("sha256:{:x}", Sha256::digest(data));
+        self.push_blob_from_reader(data, data.len() as u64, &digest)
+            .await?;
+        Ok(digest)
+    }
+
+    /// Resolves a `Location` header, which registries may return as a relative path
+    fn resolve_location(&self, response: &TransportResponse) -> Result<String, PusherError> {
+        let location = response
+            .headers
+            .get(reqwest::header::LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| {
+                PusherError::push_error("Registry response is missing Location header")
+            })?;
+
+        if location.starts_with("http://") || location.starts_with("https://") {
+            Ok(location.to_string())
+        } else {
+            Ok(format!("{}{}", self.base_url, location))
+        }
+    }
+
+    /// Streams a blob to the registry using chunked uploads
+    ///
+    /// Only one chunk of `chunk_size` bytes is held in memory at a time,
+    /// regardless of the blob size.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - Source of the blob bytes (e.g. a cached layer file)
+    /// * `digest` - Digest of the blob (used to finalize the upload)
+    /// * `total` - Size of the blob in bytes, for progress reporting
+    /// * `chunk_size` - Number of bytes sent per PATCH request
+    /// * `progress` - Receives a [`ProgressEvent::LayerBytes`] after every chunk
+    /// * `cancel` - Stops the upload between or during chunks and aborts the upload session
+    ///
+    /// # Returns
+    ///
+    /// `Result<(), PusherError>` - Success or detailed error information
+    pub async fn push_blob_streamed<R: AsyncRead + Unpin>(
+        &self,
+        reader: R,
+        digest: &str,
+        total: u64,
+        chunk_size: usize,
+        progress: &ProgressReporter,
+        cancel: &CancellationToken,
+    ) -> Result<(), PusherError> {
+        let chunks = ChunkSizer::fixed(chunk_size);
+        let budget = MemoryBudget::new(None);
+        self.push_blob_chunked(reader, digest, total, &chunks, &budget, progress, cancel)
+            .await
+    }
+
+    /// Uploads a blob like [`RegistryClient::push_blob_streamed`], sizing chunks with `chunks`
+    ///
+    /// Every PATCH is timed and reported to the [`ChunkSizer`], so with
+    /// [`ChunkSizer::adaptive`] the chunk size follows the link's throughput
+    /// and error rate, also across concurrent uploads sharing the sizer.
+    /// Chunks are kept within the session's [`ChunkLimits`], so registries
+    /// requiring larger chunks don't reject them with 416.
+    ///
+    /// The largest chunk the upload may buffer is reserved from `budget` for
+    /// the whole upload, raised to the registry's minimum chunk size.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn push_blob_chunked<R: AsyncRead + Unpin>(
+        &self,
+        reader: R,
+        digest: &str,
+        total: u64,
+        chunks: &ChunkSizer,
+        budget: &MemoryBudget,
+        progress: &ProgressReporter,
+        cancel: &CancellationToken,
+    ) -> Result<(), PusherError> {
+        let buffered = |limits: ChunkLimits| total.min(limits.clamp(chunks.max_size()) as u64);
+        let documented = buffered(ChunkLimits::for_registry(self.reference.resolve_registry()));
+        let mut reservation = tokio::select! {
+            reservation = budget.reserve(documented) => reservation,
+            _ = cancel.cancelled() => return Err(PusherError::Cancelled),
+        };
+        let (session, location, limits) = self.start_upload(digest).await?;
+        // Reserve again rather than on top, so uploads never hold memory while waiting for more
+        let announced = buffered(limits);
+        if announced > documented {
+            drop(reservation);
+            reservation = tokio::select! {
+                reservation = budget.reserve(announced) => reservation,
+                _ = cancel.cancelled() => {
+                    self.end_upload(session, &Err(PusherError::Cancelled)).await;
+                    return Err(PusherError::Cancelled);
+                }
+            };
+        }
+        let result = self
+            .upload_session(session, location, limits, reader, digest, total, chunks, progress, cancel)
+            .await;
+        self.end_upload(session, &result).await;
+        drop(reservation);
+        result
+    }
+
+    /// Pushes a blob held in memory in a single `PUT`, as `oci_client::Client::push_blob` would
+    ///
+    /// Unlike with oci-client, the upload session is known: a failed upload
+    /// aborts it, and one whose future is dropped (e.g. when its layer
+    /// deadline passes) leaves it to [`RegistryClient::abort_open_uploads`].
+    pub async fn push_blob_monolithic(
+        &self,
+        data: Vec<u8>,
+        digest: &str,
+    ) -> Result<(), PusherError> {
+        let (session, location, _) = self.start_upload(digest).await?;
+        let separator = if location.contains('?') { '&' } else { '?' };
+        let upload_url = format!("{}{}digest={}", location, separator, digest);
+        let what = format!("Failed to upload {}", digest);
+        let request = TransportRequest::put(&upload_url)
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .header(reqwest::header::CONTENT_LENGTH, data.len())
+            .body(data);
+        let result = match self.send(request, &what).await {
+            Ok(response) if response.status == reqwest::StatusCode::CREATED => Ok(()),
+            Ok(response) => Err(PusherError::http(what, &response)),
+            Err(e) => Err(e),
+        };
+        self.end_upload(session, &result).await;
+        result
+    }
+
+    /// Opens an upload session for `digest`, returning its ID, location and chunk limits
+    async fn start_upload(&self, digest: &str) -> Result<(u64, String, ChunkLimits), PusherError> {
+        let upload_url = format!("{}/v2/{}/blobs/uploads/", self.base_url, self.repository);
+        let response = self
+            .send(
+                TransportRequest::post(&upload_url).header(reqwest::header::CONTENT_LENGTH, 0),
+                format!("Failed to start upload session for {}", digest),
+            )
+            .await?;
+
+        if response.status != reqwest::StatusCode::ACCEPTED {
+            return Err(PusherError::http(
+                format!("Failed to start upload session for {}", digest),
+                &response,
+            ));
+        }
+        let location = self.resolve_location(&response)?;
+        let limits =
+            ChunkLimits::for_registry(self.reference.resolve_registry()).with_announced(&response.headers);
+        debug!(digest, location = %location, min_chunk = limits.min, "upload session started");
+        let session = self.sessions.open(JournalEntry {
+            registry: self.reference.resolve_registry().to_string(),
+            repository: self.repository.clone(),
+            digest: digest.to_string(),
+            location: location.clone(),
+            updated_at: 0,
+        });
+        Ok((session, location, limits))
+    }
+
+    /// Closes upload session `session` once its upload returned `result`
+    async fn end_upload(&self, session: u64, result: &Result<(), PusherError>) {
+        match result {
+            Ok(()) => {
+                self.sessions.close(session);
+            }
+            // A retry starts a new session, so free this one
+            Err(_) => {
+                if let Some(location) = self.sessions.close(session) {
+                    self.abort_upload(&location).await;
+                }
+            }
+        }
+    }
+
+    /// Sends the chunks of a blob to the upload session at `location` and finalizes it
+    #[allow(clippy::too_many_arguments)]
+    async fn upload_session<R: AsyncRead + Unpin>(
+        &self,
+        session: u64,
+        mut location: String,
+        limits: ChunkLimits,
+        mut reader: R,
+        digest: &str,
+        total: u64,
+        chunks: &ChunkSizer,
+        progress: &ProgressReporter,
+        cancel: &CancellationToken,
+    ) -> Result<(), PusherError> {
+        let mut buffer = Vec::new();
+        let mut offset = 0u64;
+
+        loop {
+            if cancel.is_cancelled() {
+                return Err(PusherError::Cancelled);
+            }
+            // The registry's limits take precedence over what the sizer learned
+            buffer.resize(limits.clamp(chunks.next_size()), 0);
+            let bytes_read = read_chunk(&mut reader, &mut buffer).await.map_err(|e| {
+                PusherError::cache_error(format!("Failed to read cached layer {}: {}", digest, e))
+            })?;
+            if bytes_read == 0 {
+                break;
+            }
+
+            let end = offset + bytes_read as u64 - 1;
+            let request = TransportRequest::patch(&location)
+                .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+                .header(reqwest::header::CONTENT_LENGTH, bytes_read)
+                .header(
+                    reqwest::header::CONTENT_RANGE,
+                    format!("{}-{}", offset, end),
+                )
+                .body(buffer[..bytes_read].to_vec());
+            let what = format!("Chunk upload for {} failed at offset {}", digest, offset);
+            let started = std::time::Instant::now();
+            let response = tokio::select! {
+                response = self.send(request, &what) => response,
+                _ = cancel.cancelled() => Err(PusherError::Cancelled),
+            };
+            let response = match response {
+                Ok(response) => response,
+                Err(e) => {
+                    // Throttling and cancellation say nothing about the link
+                    if !matches!(e, PusherError::Cancelled) && !e.is_throttled() {
+                        chunks.record_failure();
+                    }
+                    return Err(e);
+                }
+            };
+
+            if response.status != reqwest::StatusCode::ACCEPTED {
+                chunks.record_failure();
+                return Err(PusherError::http(what, &response));
+            }
+            chunks.record_success(bytes_read, started.elapsed());
+            trace!(digest, offset, end, bytes = bytes_read, "chunk uploaded");
+            location = self.resolve_location(&response)?;
+            self.sessions.moved(session, &location);
+            offset = end + 1;
+            progress.emit(ProgressEvent::LayerBytes {
+                digest: digest.to_string(),
+                transferred: offset,
+                total,
+            });
+        }
+
+        if offset != total {
+            return Err(PusherError::push_error(format!(
+                "Blob {} ended after {} bytes, expected {}",
+                digest, offset, total
+            )));
+        }
+
+        let separator = if location.contains('?') { '&' } else { '?' };
+        let finalize_url = format!("{}{}digest={}", location, separator, digest);
+        let response = self
+            .send(
+                TransportRequest::put(&finalize_url).header(reqwest::header::CONTENT_LENGTH, 0),
+                format!("Failed to finalize upload for {}", digest),
+            )
+            .await?;
+
+        if response.status != reqwest::StatusCode::CREATED {
+            return Err(PusherError::http(
+                format!("Failed to finalize upload for {}", digest),
+                &response,
+            ));
+        }
+        debug!(digest, bytes = offset, "streamed upload finalized");
+
+        Ok(())
+    }
+
+    /// Aborts the upload sessions of uploads that stopped without finishing
+    ///
+    /// Failed uploads abort their own session, but an upload whose future is
+    /// dropped (e.g. when its layer deadline passes) can't, and its session
+    /// would linger on the registry, counting against storage quotas until
+    /// the registry expires it. Call this once no uploads are running.
+    ///
+    /// # Returns
+    ///
+    /// `usize` - Number of sessions aborted
+    pub async fn abort_open_uploads(&self) -> usize {
+        let orphaned = self.sessions.drain();
+        for location in &orphaned {
+            self.abort_upload(location).await;
+        }
+        if !orphaned.is_empty() {
+            debug!(sessions = orphaned.len(), "aborted orphaned upload sessions");
+        }
+        orphaned.len()
+    }
+
+    /// Aborts the sessions `journal` holds for this repository that saw no
+    /// activity for `min_age_secs`, e.g. ones left behind by a crashed run
+    ///
+    /// Entries are removed once the registry answered, whether it still knew
+    /// the session or not; ones the registry couldn't be reached for stay
+    /// for the next attempt.
+    ///
+    /// # Returns
+    ///
+    /// `usize` - Number of journaled sessions cleared
+    pub async fn abort_journaled_uploads(&self, journal: &UploadJournal, min_age_secs: u64) -> usize {
+        let mut cleared = 0;
+        for (key, entry) in journal.entries(self.reference.resolve_registry(), &self.repository) {
+            if entry.age_secs() < min_age_secs {
+                continue;
+            }
+            self.throttle().await;
+            if self.abort_upload(&entry.location).await {
+                debug!(digest = %entry.digest, "aborted journaled upload session");
+                journal.forget(&key);
+                cleared += 1;
+            }
+        }
+        cleared
+    }
+
+    /// Cancels an unfinished upload session so the registry can discard its data
+    ///
+    /// Best effort: registries expire abandoned sessions anyway, so failures
+    /// are only logged. Returns whether the registry answered.
+    async fn abort_upload(&self, location: &str) -> bool {
+        match self.transport.send(TransportRequest::delete(location)).await {
+            Ok(response) => {
+                debug!(status = %response.status, "upload session aborted");
+                true
+            }
+            Err(e) => {
+                debug!(error = %e, "failed to abort upload session");
+                false
+            }
+        }
+    }
+}
+
+/// Returns the URL a registry host is reached at
+///
+/// Like Docker, registries on loopback addresses (`localhost`, `127.0.0.1`,
+/// `[::1]`) are spoken to over plain HTTP; everything else uses HTTPS.
+pub fn base_url(registry: &str) -> String {
+    if is_loopback(registry) {
+        format!("http://{}", registry)
+    } else {
+        format!("https://{}", registry)
+    }
+}
+
+/// Checks whether a `host[:port]` registry address is on the local machine
+fn is_loopback(registry: &str) -> bool {
+    let host = match registry.strip_prefix('[') {
+        Some(bracketed) => bracketed.split(']').next().unwrap_or(bracketed),
+        None => registry.split(':').next().unwrap_or(registry),
+    };
+    host.eq_ignore_ascii_case("localhost")
+        || host
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Fills `buffer` from `reader`, returning fewer bytes only at end of input
+async fn read_chunk<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buffer: &mut [u8],
+) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let bytes_read = reader.read(&mut buffer[filled..]).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        filled += bytes_read;
+    }
+    Ok(filled)
+}
@@ -0,0 +1,219 @@
+//! Pushing OCI 1.1 referrers: artifacts attached to an image through `subject`
+//!
+//! Signatures, attestations and SBOMs are pushed as manifests whose
+//! `subject` is the image they describe. Registries implementing the
+//! referrers API index them under the subject on their own. For the others
+//! the distribution spec defines the referrers tag scheme: an image index
+//! tagged after the subject digest (see [`fallback_tag`]) lists the
+//! referrers, and whoever pushes one keeps that index up to date.
+//! [`RegistryClient::push_referrer`] takes care of both.
+
+use super::RegistryClient;
+use crate::PusherError;
+use crate::image;
+use crate::transport::{RegistryTransport, TransportRequest};
+use oci_client::manifest::{
+    IMAGE_MANIFEST_MEDIA_TYPE, OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE, OciDescriptor,
+    OciImageManifest,
+};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info};
+
+/// Returns the tag of the fallback referrers index of the manifest `digest`
+///
+/// The spec's scheme is `<algorithm>-<hex>`, with the hex part cut to 64
+/// characters so sha512 digests still fit in a tag.
+///
+/// # Examples
+///
+/// ```
+/// # use docker_image_pusher::registry::referrers::fallback_tag;
+/// let digest = "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a";
+/// assert_eq!(
+///     fallback_tag(digest).unwrap(),
+///     "sha256-44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+/// );
+/// assert!(fallback_tag("latest").is_err());
+/// ```
+pub fn fallback_tag(digest: &str) -> Result<String, PusherError> {
+    let digest = image::digest::validate(digest, "Referrer subject")?;
+    let hex = digest.hex();
+    Ok(format!(
+        "{}-{}",
+        digest.algorithm().name(),
+        &hex[..hex.len().min(64)]
+    ))
+}
+
+impl<T: RegistryTransport> RegistryClient<T> {
+    /// Returns the descriptor of the image manifest `digest`, to use as a referrer's `subject`
+    pub async fn subject_descriptor(&self, digest: &str) -> Result<OciDescriptor, PusherError> {
+        let (media_type, body) = self
+            .fetch_manifest(digest, &[OCI_IMAGE_MEDIA_TYPE, IMAGE_MANIFEST_MEDIA_TYPE])
+            .await?
+            .ok_or_else(|| {
+                PusherError::PushError(format!(
+                    "Subject {} not found in {}",
+                    digest,
+                    self.reference.repository()
+                ))
+            })?;
+        Ok(OciDescriptor {
+            media_type,
+            digest: digest.to_string(),
+            size: body.len() as i64,
+            urls: None,
+            annotations: None,
+        })
+    }
+
+    /// Checks whether the registry implements the referrers API
+    ///
+    /// Registries that do answer `GET /v2/<name>/referrers/<digest>` with an
+    /// index (empty if nothing refers to `digest`); the others with 404.
+    pub async fn supports_referrers(&self, digest: &str) -> Result<bool, PusherError> {
+        let url = format!(
+            "{}/v2/{}/referrers/{}",
+            self.base_url, self.repository, digest
+        );
+        let what = format!("Failed to list referrers of {}", digest);
+        let response = self.send(TransportRequest::get(&url), &what).await?;
+        debug!(digest, status = %response.status, "referrers API check");
+        match response.status {
+            reqwest::StatusCode::OK => Ok(true),
+            reqwest::StatusCode::NOT_FOUND => Ok(false),
+            _ => Err(PusherError::http(what, &response)),
+        }
+    }
+
+    /// Pushes `manifest` as a referrer of its `subject`, returning the manifest's digest
+    ///
+    /// The manifest should carry an `artifactType` (or a config media type
+    /// naming the artifact) and its blobs must already be uploaded. On
+    /// registries without the referrers API the manifest is also added to
+    /// the subject's [`fallback_tag`] index. Pushing the same manifest twice
+    /// lists it once.
+    pub async fn push_referrer(&self, manifest: &OciImageManifest) -> Result<String, PusherError> {
+        let subject = manifest.subject.as_ref().ok_or_else(|| {
+            PusherError::PushError("A referrer manifest needs a subject".to_string())
+        })?;
+        let media_type = manifest
+            .media_type
+            .clone()
+            .unwrap_or_else(|| OCI_IMAGE_MEDIA_TYPE.to_string());
+        let bytes = serde_json::to_vec(manifest)?;
+        let digest = format!("sha256:{:x}", Sha256::digest(&bytes));
+        let size = bytes.len();
+        self.put_manifest(&digest, &media_type, bytes).await?;
+
+        if self.supports_referrers(&subject.digest).await? {
+            return Ok(digest);
+        }
+        let tag = fallback_tag(&subject.digest)?;
+        let mut index = match self
+            .fetch_manifest(&tag, &[OCI_IMAGE_INDEX_MEDIA_TYPE])
+            .await?
+        {
+            Some((_, body)) => serde_json::from_slice::<serde_json::Value>(&body)?,
+            None => serde_json::json!({
+                "schemaVersion": 2,
+                "mediaType": OCI_IMAGE_INDEX_MEDIA_TYPE,
+                "manifests": [],
+            }),
+        };
+        // Edited as JSON: entries pushed by other tools keep fields oci-client doesn't model
+        let entries = index["manifests"]
+            .as_array_mut()
+            .ok_or_else(|| PusherError::PushError(format!("{} is not a referrers index", tag)))?;
+        if entries
+            .iter()
+            .any(|entry| entry["digest"] == digest.as_str())
+        {
+            return Ok(digest);
+        }
+        let mut entry = serde_json::json!({
+            "mediaType": media_type,
+            "digest": digest,
+            "size": size,
+            "artifactType": manifest
+                .artifact_type
+                .as_deref()
+                .unwrap_or(&manifest.config.media_type),
+        });
+        if let Some(annotations) = &manifest.annotations {
+            entry["annotations"] = serde_json::to_value(annotations)?;
+        }
+        entries.push(entry);
+        self.put_manifest(
+            &tag,
+            OCI_IMAGE_INDEX_MEDIA_TYPE,
+            serde_json::to_vec(&index)?,
+        )
+        .await?;
+        info!(
+            "🏷️  Registry has no referrers API, listed {} under the {} tag",
+            digest, tag
+        );
+        Ok(digest)
+    }
+
+    /// Fetches the manifest `reference` (a tag or digest) with its media type, `None` if it doesn't exist
+    async fn fetch_manifest(
+        &self,
+        reference: &str,
+        accept: &[&str],
+    ) -> Result<Option<(String, Vec<u8>)>, PusherError> {
+        let url = format!(
+            "{}/v2/{}/manifests/{}",
+            self.base_url, self.repository, reference
+        );
+        let what = format!("Failed to fetch manifest {}", reference);
+        let response = self
+            .send(
+                TransportRequest::get(&url).header(reqwest::header::ACCEPT, accept.join(", ")),
+                &what,
+            )
+            .await?;
+        match response.status {
+            reqwest::StatusCode::NOT_FOUND => Ok(None),
+            reqwest::StatusCode::OK => {
+                let media_type = response
+                    .headers
+                    .get(reqwest::header::CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or(accept[0])
+                    .to_string();
+                Ok(Some((media_type, response.body)))
+            }
+            _ => Err(PusherError::http(what, &response)),
+        }
+    }
+
+    /// Uploads a manifest under `reference` (a tag or its digest)
+    async fn put_manifest(
+        &self,
+        reference: &str,
+        media_type: &str,
+        body: Vec<u8>,
+    ) -> Result<(), PusherError> {
+        let url = format!(
+            "{}/v2/{}/manifests/{}",
+            self.base_url, self.repository, reference
+        );
+        let what = format!("Failed to push manifest {}", reference);
+        let response = self
+            .send(
+                TransportRequest::put(&url)
+                    .header(reqwest::header::CONTENT_TYPE, media_type)
+                    .body(body),
+                &what,
+            )
+            .await?;
+        debug!(reference, status = %response.status, "manifest push");
+        if response.status.is_success() {
+            Ok(())
+        } else {
+            Err(PusherError::http(what, &response))
+        }
+    }
+}
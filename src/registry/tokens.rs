@@ -0,0 +1,166 @@
+//! Bearer tokens of the process, cached per repository and credentials
+//!
+//! [`RegistryClient::connect`](super::RegistryClient::connect) and [`prefetch_tokens`] perform the token
+//! handshake through `oci_client::Client` and remember the tokens they got,
+//! so later clients for the same repository start with one.
+
+use crate::PusherError;
+use crate::concurrency::RateLimiter;
+use crate::credentials::{RegistryAuth, RegistryOperation, exchange_identity_token};
+use oci_spec::distribution::Reference;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Bearer tokens are taken to expire this long after they were issued unless they say otherwise
+///
+/// The token specification's default for responses without `expires_in`.
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(60);
+
+/// Cached tokens are only reused while they have at least this much time left
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(15);
+
+/// Registry, repository, operation and fingerprint of the credentials a token was issued for
+type TokenKey = (String, String, bool, String);
+
+/// Bearer tokens of the process, shared by all its clients, with their expiry
+fn tokens() -> std::sync::MutexGuard<'static, HashMap<TokenKey, (String, Instant)>> {
+    static TOKENS: OnceLock<Mutex<HashMap<TokenKey, (String, Instant)>>> = OnceLock::new();
+    TOKENS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+fn token_key(reference: &Reference, auth: &RegistryAuth, operation: RegistryOperation) -> TokenKey {
+    // Passwords aren't kept around in the key, only a hash telling credentials apart
+    let credentials = match auth {
+        RegistryAuth::Anonymous => String::new(),
+        RegistryAuth::Basic(username, password) => format!("basic\0{}\0{}", username, password),
+        RegistryAuth::Bearer(token) => format!("bearer\0{}", token),
+    };
+    (
+        reference.resolve_registry().to_string(),
+        reference.repository().to_string(),
+        matches!(operation, RegistryOperation::Push),
+        format!("{:x}", Sha256::digest(credentials.as_bytes())),
+    )
+}
+
+/// A token for `reference` and `auth` obtained earlier, if it is still valid for a while
+pub(super) fn cached_token(
+    reference: &Reference,
+    auth: &RegistryAuth,
+    operation: RegistryOperation,
+) -> Option<String> {
+    let key = token_key(reference, auth, operation);
+    tokens()
+        .get(&key)
+        .filter(|(_, expires)| *expires > Instant::now() + TOKEN_EXPIRY_MARGIN)
+        .map(|(token, _)| token.clone())
+}
+
+/// Remembers a token the registry issued for `reference` and `auth`
+pub(super) fn cache_token(
+    reference: &Reference,
+    auth: &RegistryAuth,
+    operation: RegistryOperation,
+    token: &str,
+) {
+    let now = Instant::now();
+    let mut tokens = tokens();
+    tokens.retain(|_, (_, expires)| *expires > now);
+    tokens.insert(
+        token_key(reference, auth, operation),
+        (token.to_string(), now + token_lifetime(token)),
+    );
+}
+
+/// How long `token` is valid: until the `exp` claim of a JWT, [`DEFAULT_TOKEN_LIFETIME`] otherwise
+fn token_lifetime(token: &str) -> Duration {
+    use base64::Engine;
+    let expires_at = token
+        .split('.')
+        .nth(1)
+        .and_then(|claims| {
+            base64::engine::general_purpose::URL_SAFE_NO_PAD
+                .decode(claims)
+                .ok()
+        })
+        .and_then(|claims| serde_json::from_slice::<serde_json::Value>(&claims).ok())
+        .and_then(|claims| claims["exp"].as_u64());
+    let Some(expires_at) = expires_at else {
+        return DEFAULT_TOKEN_LIFETIME;
+    };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    Duration::from_secs(expires_at.saturating_sub(now))
+}
+
+/// Authenticates with the repositories of `references` concurrently, ahead of a batch using them
+///
+/// Registries issue bearer tokens per repository, so a batch transferring
+/// many repositories of one registry can call this up front: the tokens are
+/// cached for the process, and every transfer then starts with a token
+/// instead of performing the token handshake at its first request, one
+/// repository after another (see [`RegistryClient::connect`](super::RegistryClient::connect)). oci-client
+/// can't request tokens with combined scopes, so each repository gets its
+/// own handshake; repositories with a valid cached token are skipped, at
+/// most `concurrency` handshakes run at once and each passes `limiter` first.
+///
+/// # Returns
+///
+/// The references whose authentication failed, with the error. They are
+/// authenticated again (and fail properly) when actually used.
+pub async fn prefetch_tokens(
+    client: &oci_client::Client,
+    references: &[Reference],
+    auth: &RegistryAuth,
+    operation: RegistryOperation,
+    limiter: &RateLimiter,
+    concurrency: usize,
+) -> Vec<(Reference, PusherError)> {
+    use futures::stream::{self, StreamExt};
+
+    // One handshake per repository, whatever tag or digest is referenced
+    let mut repositories = std::collections::HashSet::new();
+    let unique: Vec<Reference> = references
+        .iter()
+        .filter(|reference| {
+            repositories.insert((
+                reference.resolve_registry().to_string(),
+                reference.repository().to_string(),
+            ))
+        })
+        .filter(|reference| cached_token(reference, auth, operation).is_none())
+        .cloned()
+        .collect();
+    debug!(repositories = unique.len(), "prefetching registry tokens");
+
+    stream::iter(unique)
+        .map(|reference| async move {
+            let login = match exchange_identity_token(&reference, auth, operation).await {
+                Ok(login) => login,
+                Err(e) => return Err((reference, e)),
+            };
+            limiter.acquire().await;
+            let token = match client.auth(&reference, &login, operation).await {
+                Ok(token) => token,
+                Err(e) => {
+                    return Err((reference, PusherError::registry("Authentication failed", e)));
+                }
+            };
+            if let Some(token) = &token {
+                cache_token(&reference, auth, operation, token);
+            }
+            Ok(token)
+        })
+        .buffer_unordered(concurrency.max(1))
+        .filter_map(|result| async move { result.err() })
+        .collect()
+        .await
+}
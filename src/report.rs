@@ -0,0 +1,163 @@
+use crate::PusherError;
+use serde::Serialize;
+use std::path::Path;
+use std::time::Duration;
+
+/// What happened to a blob during a push
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlobStatus {
+    /// The blob was transferred to the target registry
+    Uploaded,
+    /// The target registry already had the blob
+    Skipped,
+}
+
+/// Per-blob entry of a [`TransferReport`]
+#[derive(Debug, Clone, Serialize)]
+pub struct BlobReport {
+    /// Digest of the blob
+    pub digest: String,
+    /// Blob kind: "layer" or "config"
+    pub kind: &'static str,
+    /// Size of the blob in bytes
+    pub size: u64,
+    /// Whether the blob was uploaded or skipped
+    pub status: BlobStatus,
+    /// Time spent on this blob (existence check included), in seconds
+    pub duration_secs: f64,
+    /// Average upload speed in MB/s (0 for skipped blobs)
+    pub speed_mbps: f64,
+    /// Number of times the blob was rescheduled after exceeding its deadline
+    pub retries: u32,
+}
+
+impl BlobReport {
+    /// Builds an entry, deriving the average speed from size and duration
+    pub fn new(
+        digest: &str,
+        kind: &'static str,
+        size: u64,
+        status: BlobStatus,
+        duration: Duration,
+        retries: u32,
+    ) -> Self {
+        let duration_secs = duration.as_secs_f64();
+        let speed_mbps = if status == BlobStatus::Uploaded && duration_secs > 0.0 {
+            size as f64 / (1024.0 * 1024.0) / duration_secs
+        } else {
+            0.0
+        };
+        Self {
+            digest: digest.to_string(),
+            kind,
+            size,
+            status,
+            duration_secs,
+            speed_mbps,
+            retries,
+        }
+    }
+}
+
+/// Machine-readable summary of a finished push (`--report`)
+///
+/// Lists every blob with its size, outcome, timing and retries together with
+/// the digest and URL of the pushed manifest, so a migration can be audited
+/// after the fact without scraping log output.
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferReport {
+    /// Cached source image name
+    pub source: String,
+    /// Target image reference
+    pub target: String,
+    /// Unix timestamp at which the push started
+    pub started_at: u64,
+    /// Total wall-clock time of the push, in seconds
+    pub duration_secs: f64,
+    /// Bytes actually uploaded (skipped blobs excluded)
+    pub bytes_uploaded: u64,
+    /// Digest of the pushed manifest
+    pub manifest_digest: String,
+    /// URL of the pushed manifest
+    pub manifest_url: String,
+    /// Every blob referenced by the manifest, config last
+    pub blobs: Vec<BlobReport>,
+}
+
+impl TransferReport {
+    /// Writes the report as pretty-printed JSON, atomically replacing any previous file
+    pub fn write(&self, path: &Path) -> Result<(), PusherError> {
+        write_json(self, path)
+    }
+}
+
+/// A blob that could not be transferred, with the class of the error
+#[derive(Debug, Clone, Serialize)]
+pub struct BlobFailure {
+    /// Digest of the blob
+    pub digest: String,
+    /// Blob kind: "layer" or "config"
+    pub kind: &'static str,
+    /// Error class, see [`PusherError::category`]
+    pub category: &'static str,
+    /// Whether the error is transient, i.e. running the push again may succeed
+    pub retryable: bool,
+    /// Error message
+    pub error: String,
+}
+
+impl BlobFailure {
+    /// Records `error` as the reason `digest` failed
+    pub fn new(digest: &str, kind: &'static str, error: &PusherError) -> Self {
+        Self {
+            digest: digest.to_string(),
+            kind,
+            category: error.category(),
+            retryable: error.is_retryable(),
+            error: error.to_string(),
+        }
+    }
+}
+
+/// Per-item outcome of a batch of blob transfers where some items failed
+///
+/// A push keeps uploading the remaining layers when one of them fails, so a
+/// rerun only has to transfer the failed ones. The report lists what made it
+/// and what didn't; it is carried by [`PusherError::BatchFailed`] and written
+/// in place of the [`TransferReport`] by `--report`.
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchReport {
+    /// Cached source image name
+    pub source: String,
+    /// Target image reference
+    pub target: String,
+    /// Blobs that were uploaded or already present, in manifest order
+    pub succeeded: Vec<BlobReport>,
+    /// Blobs that failed, in manifest order
+    pub failed: Vec<BlobFailure>,
+}
+
+impl BatchReport {
+    /// Checks whether some, but not all, items succeeded
+    pub fn is_partial_success(&self) -> bool {
+        !self.succeeded.is_empty() && !self.failed.is_empty()
+    }
+
+    /// Writes the report as pretty-printed JSON, atomically replacing any previous file
+    pub fn write(&self, path: &Path) -> Result<(), PusherError> {
+        write_json(self, path)
+    }
+}
+
+/// Writes `value` as pretty-printed JSON through a temporary file
+fn write_json<T: Serialize>(value: &T, path: &Path) -> Result<(), PusherError> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    std::fs::write(&temp_path, serde_json::to_string_pretty(value)?)?;
+    std::fs::rename(&temp_path, path)?;
+    Ok(())
+}
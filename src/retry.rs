@@ -0,0 +1,218 @@
+use crate::PusherError;
+use crate::circuit::{self, CircuitBreaker, CircuitConfig};
+use crate::metrics;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+/// What a 401 asks of the `reauthenticate` callback of [`RetryPolicy::run_authenticated`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reauthentication {
+    /// Request a new token with the current credentials (the token probably expired)
+    Token,
+    /// A fresh token was rejected too: look the credentials up again, in case
+    /// they were rotated, and request a token with them
+    Credentials,
+}
+
+/// Retry behaviour shared by every registry operation (auth, blobs, manifests)
+///
+/// Failures are classified with [`PusherError::is_retryable`] and
+/// [`PusherError::is_unauthorized`]: server errors, timeouts and dropped
+/// connections are retried with exponential backoff, a 401 refreshes the
+/// token (and if that doesn't help, the credentials) and retries, and
+/// everything else (403, 404, digest mismatches, ...) fails immediately. A `Retry-After` sent with a 429/503
+/// replaces the backoff delay. Policies bound to a registry with
+/// [`RetryPolicy::for_registry`] also stop early while its circuit is open.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every further retry
+    pub initial_backoff: Duration,
+    /// Upper bound for the delay between two attempts
+    pub max_backoff: Duration,
+    /// When to stop sending requests to a registry that keeps failing; `None` never does
+    pub circuit: Option<CircuitConfig>,
+    /// Breaker of the registry the operations go to, set by [`RetryPolicy::for_registry`]
+    pub breaker: Option<Arc<CircuitBreaker>>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            circuit: Some(CircuitConfig::default()),
+            breaker: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that makes a single attempt
+    pub fn never() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Returns this policy with the circuit breaker of `registry` attached
+    ///
+    /// Its operations then fail fast with [`PusherError::CircuitOpen`] while
+    /// the registry's circuit is open, and their outcomes count towards it.
+    pub fn for_registry(&self, registry: &str) -> Self {
+        Self {
+            breaker: self
+                .circuit
+                .map(|config| circuit::breaker(registry, config)),
+            ..self.clone()
+        }
+    }
+
+    /// Runs `operation` until it succeeds, fails fatally or runs out of attempts
+    ///
+    /// Unauthorized errors are fatal; use [`RetryPolicy::run_authenticated`]
+    /// where credentials can be refreshed.
+    ///
+    /// # Arguments
+    ///
+    /// * `what` - Description of the operation for log messages
+    /// * `cancel` - Interrupts the backoff between two attempts
+    /// * `operation` - Creates a fresh attempt each time it is called
+    pub async fn run<T, F, Fut>(
+        &self,
+        what: &str,
+        cancel: &CancellationToken,
+        operation: F,
+    ) -> Result<T, PusherError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, PusherError>>,
+    {
+        self.execute(
+            what,
+            cancel,
+            operation,
+            None::<fn(Reauthentication) -> std::future::Ready<Result<bool, PusherError>>>,
+        )
+        .await
+    }
+
+    /// Like [`RetryPolicy::run`], but calls `reauthenticate` and retries when
+    /// the registry rejects the credentials with 401
+    ///
+    /// The first 401 asks for a new [`Reauthentication::Token`]. If the
+    /// registry rejects that too, [`Reauthentication::Credentials`] gives the
+    /// callback a chance to pick up rotated credentials; it answers `false`
+    /// when it has nothing new, and the 401 is returned.
+    pub async fn run_authenticated<T, F, Fut, A, AFut>(
+        &self,
+        what: &str,
+        cancel: &CancellationToken,
+        operation: F,
+        reauthenticate: A,
+    ) -> Result<T, PusherError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, PusherError>>,
+        A: FnMut(Reauthentication) -> AFut,
+        AFut: Future<Output = Result<bool, PusherError>>,
+    {
+        self.execute(what, cancel, operation, Some(reauthenticate))
+            .await
+    }
+
+    async fn execute<T, F, Fut, A, AFut>(
+        &self,
+        what: &str,
+        cancel: &CancellationToken,
+        mut operation: F,
+        mut reauthenticate: Option<A>,
+    ) -> Result<T, PusherError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, PusherError>>,
+        A: FnMut(Reauthentication) -> AFut,
+        AFut: Future<Output = Result<bool, PusherError>>,
+    {
+        let max_attempts = self.max_attempts.max(1);
+        let mut attempt = 1;
+        let mut reauthentications = 0;
+        loop {
+            if let Some(breaker) = &self.breaker {
+                breaker.check()?;
+            }
+            let result = operation().await;
+            if let Some(breaker) = &self.breaker {
+                breaker.record(&result);
+            }
+            let error = match result {
+                Ok(value) => return Ok(value),
+                Err(error) => error,
+            };
+            if attempt >= max_attempts {
+                return Err(error);
+            }
+
+            if error.is_unauthorized() {
+                let Some(refresh) = reauthenticate.as_mut() else {
+                    return Err(error);
+                };
+                match reauthentications {
+                    0 => {
+                        warn!("🔑 {} was unauthorized, refreshing the token", what);
+                        refresh(Reauthentication::Token).await?;
+                    }
+                    // The fresh token was rejected too: were the credentials rotated?
+                    1 => match refresh(Reauthentication::Credentials).await {
+                        Ok(true) => {
+                            warn!(
+                                "🔑 {} was unauthorized again, retrying with new credentials",
+                                what
+                            )
+                        }
+                        Ok(false) => return Err(error),
+                        Err(e) => {
+                            warn!("⚠️  Failed to look up new credentials: {}", e);
+                            return Err(error);
+                        }
+                    },
+                    // Even new credentials lack permission
+                    _ => return Err(error),
+                }
+                reauthentications += 1;
+            } else if error.is_retryable() {
+                let delay = error.retry_after().unwrap_or_else(|| self.backoff(attempt));
+                warn!(
+                    "⚠️  {} failed: {} - retrying in {:.0}s (attempt {}/{})",
+                    what,
+                    error,
+                    delay.as_secs_f64(),
+                    attempt + 1,
+                    max_attempts
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = cancel.cancelled() => return Err(PusherError::Cancelled),
+                }
+            } else {
+                return Err(error);
+            }
+            metrics::global().record_retry();
+            attempt += 1;
+        }
+    }
+
+    /// Returns the delay after the `attempt`-th failed attempt
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32 << (attempt - 1).min(16);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
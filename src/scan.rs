@@ -0,0 +1,103 @@
+//! Vulnerability scanner gate between caching an image and pushing it
+//!
+//! Runs an external scanner (e.g. `trivy image --input {tar} --exit-code 1
+//! --severity CRITICAL`) against the cached image and refuses the push when
+//! it exits with a non-zero status, so mirrors don't ingest images with
+//! known critical vulnerabilities.
+
+use crate::PusherError;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use tracing::{debug, info};
+
+/// Placeholder replaced by the path of a `docker save` archive of the image
+pub const TAR_PLACEHOLDER: &str = "{tar}";
+
+/// Placeholder replaced by the cached image name
+pub const IMAGE_PLACEHOLDER: &str = "{image}";
+
+/// Runs the scanner `command` against the image cached as `image_name`
+///
+/// `command` is run by the shell (`sh -c`, `cmd /C` on Windows) after
+/// [`TAR_PLACEHOLDER`] and [`IMAGE_PLACEHOLDER`] are substituted; the same
+/// values are available as `SCAN_TAR` and `SCAN_IMAGE` in its environment.
+/// The archive is only written when the command refers to it, next to the
+/// cache, and is removed afterwards. The scanner's output goes to the
+/// terminal.
+///
+/// # Returns
+///
+/// `Result<(), PusherError>` - `ScanRejected` if the scanner exits non-zero
+pub async fn scan_cached_image(
+    cache_dir: &Path,
+    image_name: &str,
+    command: &str,
+) -> Result<(), PusherError> {
+    let needs_archive = command.contains(TAR_PLACEHOLDER);
+    let archive = cache_dir.join(format!("scan_{}.tar", std::process::id()));
+    let (cache_dir, image, command) = (
+        cache_dir.to_path_buf(),
+        image_name.to_string(),
+        command.to_string(),
+    );
+    let archive_path = archive.clone();
+
+    info!("🛡️  Scanning {}...", image_name);
+    let result = tokio::task::spawn_blocking(move || {
+        if needs_archive {
+            let file = std::fs::File::create(&archive_path).map_err(|e| {
+                PusherError::CacheError(format!("Failed to create scan archive: {}", e))
+            })?;
+            crate::export::write_docker_archive(
+                &cache_dir,
+                &image,
+                &image,
+                std::io::BufWriter::new(file),
+            )?
+            .into_inner()
+            .map_err(|e| PusherError::CacheError(format!("Failed to write scan archive: {}", e)))?;
+        }
+
+        let archive_str = archive_path.to_string_lossy();
+        let expanded = command
+            .replace(TAR_PLACEHOLDER, &archive_str)
+            .replace(IMAGE_PLACEHOLDER, &image);
+        let mut shell = shell_command(&expanded);
+        shell
+            .env("SCAN_TAR", archive_str.as_ref())
+            .env("SCAN_IMAGE", &image)
+            .stdin(Stdio::null());
+        debug!(command = %expanded, "running scanner");
+        let status = shell
+            .status()
+            .map_err(|e| PusherError::ConfigError(format!("Failed to run scan command: {}", e)))?;
+        if !status.success() {
+            return Err(PusherError::ScanRejected {
+                image,
+                status: status.to_string(),
+            });
+        }
+        Ok(())
+    })
+    .await
+    .map_err(|e| PusherError::CacheError(format!("Scan task failed: {}", e)))?;
+
+    let _ = std::fs::remove_file(&archive);
+    if result.is_ok() {
+        info!("✅ Scan passed for {}", image_name);
+    }
+    result
+}
+
+/// Builds the platform shell invocation running `command`
+fn shell_command(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C").arg(command);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c").arg(command);
+        shell
+    }
+}
@@ -0,0 +1,291 @@
+//! Cron expressions for scheduled syncs (`serve --sync-manifest`)
+//!
+//! The classic five fields, `minute hour day-of-month month day-of-week`,
+//! each `*`, a number, a range `a-b`, a step `*/n` or `a-b/n`, or a comma
+//! separated list of those; day-of-week runs from 0 (Sunday) to 6, with 7
+//! also meaning Sunday. As in cron, when both day fields are restricted a
+//! day matching either one is enough. `@hourly`, `@daily` (or `@midnight`),
+//! `@weekly`, `@monthly` and `@yearly` are accepted as shorthands. Times are
+//! local.
+
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDateTime, TimeZone, Timelike};
+use std::fmt;
+use std::str::FromStr;
+
+/// A parsed cron expression
+///
+/// # Examples
+///
+/// ```
+/// # use docker_image_pusher::schedule::CronSchedule;
+/// use chrono::{Local, TimeZone};
+///
+/// let every_ten_minutes: CronSchedule = "*/10 * * * *".parse().unwrap();
+/// let at = Local.with_ymd_and_hms(2025, 1, 1, 12, 3, 0).unwrap();
+/// let next = every_ten_minutes.next_after(at).unwrap();
+/// assert_eq!(next, Local.with_ymd_and_hms(2025, 1, 1, 12, 10, 0).unwrap());
+///
+/// let nightly: CronSchedule = "30 2 * * 1-5".parse().unwrap();
+/// // Friday evening: the next run is Monday night
+/// let at = Local.with_ymd_and_hms(2025, 1, 3, 20, 0, 0).unwrap();
+/// let next = nightly.next_after(at).unwrap();
+/// assert_eq!(next, Local.with_ymd_and_hms(2025, 1, 6, 2, 30, 0).unwrap());
+///
+/// assert!("61 * * * *".parse::<CronSchedule>().is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day fields were `*`, deciding how they combine
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// Runs are searched for this far ahead before giving up (e.g. `0 0 30 2 *`)
+const SEARCH_DAYS: i64 = 5 * 366;
+
+impl CronSchedule {
+    /// Returns the first time after `after` (excluded) the schedule fires
+    ///
+    /// `None` for expressions that never match, such as February 30th.
+    /// Local times skipped by a daylight saving change are skipped too.
+    pub fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        let start = after.naive_local().with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let end = start + Duration::days(SEARCH_DAYS);
+        let mut at = start;
+        while at < end {
+            if !contains(self.months, at.month()) {
+                at = first_of_next_month(at)?;
+            } else if !self.day_matches(at) {
+                at = at.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if !contains(self.hours, at.hour()) {
+                at = at.with_minute(0)? + Duration::hours(1);
+            } else if !contains(self.minutes, at.minute()) {
+                at += Duration::minutes(1);
+            } else if let Some(local) = Local.from_local_datetime(&at).earliest() {
+                return Some(local);
+            } else {
+                at += Duration::minutes(1);
+            }
+        }
+        None
+    }
+
+    fn day_matches(&self, at: NaiveDateTime) -> bool {
+        let day = contains(self.days, at.day());
+        let weekday = contains(self.weekdays, at.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (false, true) => day,
+            (true, false) => weekday,
+            (false, false) => day || weekday,
+        }
+    }
+}
+
+fn contains(set: u64, value: u32) -> bool {
+    set & (1 << value) != 0
+}
+
+fn first_of_next_month(at: NaiveDateTime) -> Option<NaiveDateTime> {
+    let (year, month) = match at.month() {
+        12 => (at.year() + 1, 1),
+        month => (at.year(), month + 1),
+    };
+    chrono::NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)
+}
+
+/// Parses one field into a bit set of the values it allows
+fn parse_field(field: &str, name: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut set = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step
+                    .parse()
+                    .ok()
+                    .filter(|step| *step > 0)
+                    .ok_or_else(|| format!("invalid step '{}' in {} field", step, name))?;
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let number = |value: &str| {
+            value
+                .parse::<u32>()
+                .ok()
+                .filter(|value| (min..=max).contains(value))
+                .ok_or_else(|| {
+                    format!("{} field value '{}' is not in {}-{}", name, value, min, max)
+                })
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (number(first)?, number(last)?),
+            // `a/n` runs from a to the end of the range
+            None if step > 1 => (number(range)?, max),
+            None => {
+                let value = number(range)?;
+                (value, value)
+            }
+        };
+        if first > last {
+            return Err(format!("{} field range '{}' is backwards", name, range));
+        }
+        for value in (first..=last).step_by(step as usize) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+impl FromStr for CronSchedule {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let expanded = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!(
+                "'{}' should have 5 fields (minute hour day month weekday), not {}",
+                expression,
+                fields.len()
+            ));
+        };
+        let mut weekdays = parse_field(weekday, "weekday", 0, 7)?;
+        // 7 is Sunday too
+        if contains(weekdays, 7) {
+            weekdays = (weekdays & !(1 << 7)) | 1;
+        }
+        Ok(Self {
+            expression: expression.trim().to_string(),
+            minutes: parse_field(minute, "minute", 0, 59)?,
+            hours: parse_field(hour, "hour", 0, 23)?,
+            days: parse_field(day, "day", 1, 31)?,
+            months: parse_field(month, "month", 1, 12)?,
+            weekdays,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+}
+
+impl fmt::Display for CronSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for CronSchedule {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let expression = String::deserialize(deserializer)?;
+        expression.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Once;
+
+    /// Central European time, changing to summer time at 02:00 on the last Sunday of March
+    ///
+    /// `Local` reads `TZ`, so every test here sets the same zone before using it.
+    fn local(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        static TZ: Once = Once::new();
+        // SAFETY: set once, before any test in this module reads the local zone
+        TZ.call_once(|| unsafe { std::env::set_var("TZ", "CET-1CEST,M3.5.0,M10.5.0/3") });
+        Local
+            .with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    fn runs(expression: &str, after: DateTime<Local>, count: usize) -> Vec<DateTime<Local>> {
+        let schedule: CronSchedule = expression.parse().unwrap();
+        std::iter::successors(schedule.next_after(after), |at| schedule.next_after(*at))
+            .take(count)
+            .collect()
+    }
+
+    #[test]
+    fn restricted_day_fields_match_either() {
+        // The 1st of the month or any Monday; February 1st 2025 is a Saturday
+        assert_eq!(
+            runs("0 0 1 * 1", local(2025, 1, 25, 12, 0), 3),
+            [
+                local(2025, 1, 27, 0, 0),
+                local(2025, 2, 1, 0, 0),
+                local(2025, 2, 3, 0, 0)
+            ]
+        );
+        // With the other day field left as `*` only the restricted one counts
+        assert_eq!(
+            runs("0 0 1 * *", local(2025, 1, 25, 12, 0), 2),
+            [local(2025, 2, 1, 0, 0), local(2025, 3, 1, 0, 0)]
+        );
+        assert_eq!(
+            runs("0 0 * * 7", local(2025, 1, 25, 12, 0), 1),
+            [local(2025, 1, 26, 0, 0)]
+        );
+    }
+
+    #[test]
+    fn steps_from_a_start_run_to_the_end_of_the_range() {
+        assert_eq!(
+            runs("5/20 * * * *", local(2025, 1, 1, 12, 0), 4),
+            [
+                local(2025, 1, 1, 12, 5),
+                local(2025, 1, 1, 12, 25),
+                local(2025, 1, 1, 12, 45),
+                local(2025, 1, 1, 13, 5)
+            ]
+        );
+        assert_eq!(
+            runs("0 9-17/4 * * *", local(2025, 1, 1, 12, 0), 3),
+            [
+                local(2025, 1, 1, 13, 0),
+                local(2025, 1, 1, 17, 0),
+                local(2025, 1, 2, 9, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn zero_steps_are_rejected() {
+        for expression in ["*/0 * * * *", "0 1-5/0 * * *", "0 0 1/0 * *"] {
+            let error = expression.parse::<CronSchedule>().unwrap_err();
+            assert!(error.contains("invalid step"), "{}: {}", expression, error);
+        }
+    }
+
+    #[test]
+    fn impossible_dates_never_run() {
+        let february_30th: CronSchedule = "0 0 30 2 *".parse().unwrap();
+        assert_eq!(february_30th.next_after(local(2025, 1, 1, 0, 0)), None);
+    }
+
+    #[test]
+    fn times_skipped_by_daylight_saving_are_skipped() {
+        // 02:00-03:00 doesn't exist on 2025-03-30
+        assert_eq!(
+            runs("30 2 * * *", local(2025, 3, 29, 12, 0), 2),
+            [local(2025, 3, 31, 2, 30), local(2025, 4, 1, 2, 30)]
+        );
+        assert_eq!(
+            runs("*/20 * * * *", local(2025, 3, 30, 1, 30), 2),
+            [local(2025, 3, 30, 1, 40), local(2025, 3, 30, 3, 0)]
+        );
+    }
+}
@@ -0,0 +1,965 @@
+//! Daemon mode: an HTTP API for submitting and tracking transfer jobs (`serve`)
+//!
+//! Platform teams can run one pusher as a shared service instead of shelling
+//! out to the CLI. Jobs run the same pipeline as [`crate::ImageTransfer`]: a
+//! job with a `target` copies the image (pulling and pushing overlapped, and
+//! skipping layers the target already has, so resubmitting a copy syncs it),
+//! one without only refreshes the local cache.
+//!
+//! | Request | Effect |
+//! |---|---|
+//! | `POST /jobs` | Submits a [`JobRequest`], answers `202` with the queued [`Job`] |
+//! | `GET /jobs` | Lists all jobs, oldest first |
+//! | `GET /jobs/<id>` | Returns one job with its progress, and its report once done |
+//! | `DELETE /jobs/<id>` | Cancels a queued or running job |
+//! | `GET /healthz` | Liveness probe, `200` while the server answers |
+//! | `GET /readyz` | Readiness probe, `503` unless the cache is writable and the configured registries answer |
+//!
+//! Like the metrics endpoint this is a tiny HTTP/1.1 responder (one request
+//! per connection, JSON bodies). When started with an API token, every
+//! request but the probes must carry it as `Authorization: Bearer <token>`.
+//!
+//! Queued copies into the same repository transfer the layers they share
+//! first (see [`crate::popularity`]): each job's manifest is fetched when it
+//! is queued, so a copy finishing later finds the common layers in the
+//! registry instead of uploading them again.
+//!
+//! A sync manifest (see [`sync`]) adds jobs on cron schedules, and a second
+//! listener can serve the cache as a pull-through registry (see [`proxy`]).
+//!
+//! With the `grpc` feature the same jobs can also be driven over gRPC
+//! (see [`grpc`]), with progress streamed instead of polled.
+//!
+//! Jobs are persisted in `.cache/jobs/` (one file per job, updated as its
+//! state changes and its layers complete), so the queue survives restarts:
+//! jobs that were queued, or interrupted by a shutdown or crash, are resumed
+//! when the server starts again. A resumed job redoes little work, since
+//! verified layers in the cache aren't downloaded again and layers the
+//! target already has aren't uploaded again.
+
+use crate::concurrency::RateLimiter;
+use crate::credentials::{AuthChain, exchange_identity_token};
+use crate::popularity::LayerPopularity;
+use crate::progress::ProgressEvent;
+use crate::report::TransferReport;
+use crate::{CACHE_DIR, ImageTransfer, PusherError, correlation, logging, registry};
+use oci_client::Reference;
+use oci_client::secrets::RegistryAuth;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, debug, info, info_span, warn};
+
+#[cfg(feature = "grpc")]
+pub mod grpc;
+mod health;
+pub mod proxy;
+pub mod sync;
+
+/// Directory (inside the cache directory) holding the persisted jobs
+const JOBS_DIR_NAME: &str = "jobs";
+
+/// Largest accepted request head (request line and headers)
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+
+/// Largest accepted request body
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// Finished jobs kept for status queries; older ones are forgotten
+const MAX_FINISHED_JOBS: usize = 1000;
+
+/// Configuration of [`serve`]
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /// Address to listen on (e.g. "0.0.0.0:8080")
+    pub listen: String,
+    /// Jobs transferring at the same time; further jobs wait in the queue
+    pub max_jobs: usize,
+    /// Token clients must send as `Authorization: Bearer <token>`; `None` accepts anyone
+    pub api_token: Option<String>,
+    /// Address to also serve the gRPC interface on
+    #[cfg(feature = "grpc")]
+    pub grpc_listen: Option<String>,
+    /// Images to sync on a schedule
+    pub sync: Option<sync::SyncManifest>,
+    /// Also serve a pull-through cache of a registry
+    pub proxy: Option<proxy::ProxyOptions>,
+    /// Asked for the credentials of registries a job brings none for
+    pub credentials: AuthChain,
+    /// Registries (`host[:port]`) `/readyz` requires to be reachable, besides those of `sync`
+    pub ready_registries: Vec<String>,
+    /// Local image cache shared by all jobs
+    pub cache_dir: PathBuf,
+    /// Takes layers confirmed on a target within this many seconds as present
+    /// (see [`crate::ledger`])
+    pub ledger_ttl_secs: Option<u64>,
+    /// Stops the server: no more requests are accepted and all jobs are cancelled
+    pub cancel: CancellationToken,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            listen: "127.0.0.1:8080".to_string(),
+            max_jobs: 2,
+            api_token: None,
+            #[cfg(feature = "grpc")]
+            grpc_listen: None,
+            sync: None,
+            proxy: None,
+            credentials: AuthChain::new(),
+            ready_registries: Vec::new(),
+            cache_dir: PathBuf::from(CACHE_DIR),
+            ledger_ttl_secs: None,
+            cancel: CancellationToken::new(),
+        }
+    }
+}
+
+/// Body of `POST /jobs`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobRequest {
+    /// Image to pull (e.g. "nginx:latest")
+    pub source: String,
+    /// Full target reference to push to; without it the job only pulls into the cache
+    #[serde(default)]
+    pub target: Option<String>,
+    /// Username for the target registry
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Password for the target registry; never returned by the API
+    ///
+    /// Kept in the job's file (readable by the owner only) until the job
+    /// finishes, so an interrupted job can resume.
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+/// Lifecycle state of a [`Job`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    /// Waiting for a free transfer slot
+    Queued,
+    /// Transferring
+    Running,
+    /// Finished successfully
+    Succeeded,
+    /// Finished with an error
+    Failed,
+    /// Cancelled through `DELETE /jobs/<id>`
+    Cancelled,
+}
+
+impl JobState {
+    fn is_finished(self) -> bool {
+        !matches!(self, JobState::Queued | JobState::Running)
+    }
+}
+
+/// Byte and layer counts of a job, updated from its progress events
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct JobProgress {
+    /// Layers seen so far
+    pub layers: usize,
+    /// Layers whose current phase (pull, then push) completed
+    pub layers_completed: usize,
+    /// Layers the target already had
+    pub layers_skipped: usize,
+    /// Bytes transferred in the current phase of each layer
+    pub bytes_transferred: u64,
+    /// Total size of the layers seen so far
+    pub bytes_total: u64,
+}
+
+/// A transfer job as returned by the API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    /// ID used in `/jobs/<id>`
+    pub id: u64,
+    /// Image pulled
+    pub source: String,
+    /// Image pushed, if any
+    pub target: Option<String>,
+    /// Current state
+    pub state: JobState,
+    /// Unix timestamps of submission, start and end
+    pub submitted_at: u64,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+    /// Progress of the transfer
+    pub progress: JobProgress,
+    /// Error message of a failed job, with credentials scrubbed
+    pub error: Option<String>,
+    /// Class of the error (see [`PusherError::category`])
+    pub error_category: Option<String>,
+    /// Correlation ID sent with the job's registry requests (see [`crate::correlation`])
+    #[serde(default)]
+    pub request_id: String,
+    /// Push report of a successful copy; not kept across server restarts
+    #[serde(skip_deserializing)]
+    pub report: Option<TransferReport>,
+}
+
+/// Contents of a job's file
+#[derive(Serialize, Deserialize)]
+struct StoredJob {
+    job: Job,
+    /// `None` once the job finished, dropping its credentials
+    request: Option<JobRequest>,
+}
+
+/// A job with the state the API doesn't show
+struct Entry {
+    job: Job,
+    request: JobRequest,
+    /// Transferred and total bytes, and whether the current phase completed, per layer
+    layers: HashMap<String, (u64, u64, bool)>,
+    skipped: usize,
+    cancel: CancellationToken,
+    /// Layers counted towards the popularity of the target repository until the job finishes
+    counted_layers: Vec<String>,
+}
+
+impl Entry {
+    fn new(job: Job, request: Option<JobRequest>) -> Self {
+        let request = request.unwrap_or_else(|| JobRequest {
+            source: job.source.clone(),
+            target: job.target.clone(),
+            username: None,
+            password: None,
+        });
+        Self {
+            job,
+            request,
+            layers: HashMap::new(),
+            skipped: 0,
+            cancel: CancellationToken::new(),
+            counted_layers: Vec::new(),
+        }
+    }
+
+    fn observe(&mut self, event: &ProgressEvent) {
+        match event {
+            ProgressEvent::LayerStarted { digest, size } => {
+                self.layers.insert(digest.clone(), (0, *size, false));
+            }
+            ProgressEvent::LayerBytes {
+                digest,
+                transferred,
+                total,
+            } => {
+                let layer = self.layers.entry(digest.clone()).or_default();
+                layer.0 = *transferred;
+                if *total > 0 {
+                    layer.1 = *total;
+                }
+            }
+            ProgressEvent::LayerCompleted {
+                digest,
+                size,
+                skipped,
+            } => {
+                self.layers.insert(digest.clone(), (*size, *size, true));
+                if *skipped {
+                    self.skipped += 1;
+                }
+            }
+            ProgressEvent::ManifestPushed { .. } => {}
+        }
+        let layers = self.layers.values();
+        self.job.progress = JobProgress {
+            layers: self.layers.len(),
+            layers_completed: layers.clone().filter(|layer| layer.2).count(),
+            layers_skipped: self.skipped,
+            bytes_transferred: layers.clone().map(|layer| layer.0).sum(),
+            bytes_total: layers.map(|layer| layer.1).sum(),
+        };
+    }
+}
+
+/// Jobs of a running server, shared by the connection handlers and job tasks
+struct Jobs {
+    entries: Mutex<BTreeMap<u64, Entry>>,
+    slots: Arc<Semaphore>,
+    max_jobs: usize,
+    cache_dir: PathBuf,
+    credentials: AuthChain,
+    /// Directory of the job files
+    dir: PathBuf,
+    cancel: CancellationToken,
+    /// IDs of jobs as they change, for watchers
+    changes: tokio::sync::broadcast::Sender<u64>,
+    /// Layers of the unfinished copies per target repository, shared ones are transferred first
+    popularity: LayerPopularity,
+    ledger_ttl_secs: Option<u64>,
+}
+
+impl Jobs {
+    fn entries(&self) -> std::sync::MutexGuard<'_, BTreeMap<u64, Entry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns job `id`, `None` if unknown
+    fn get(&self, id: u64) -> Option<Job> {
+        self.entries().get(&id).map(|entry| entry.job.clone())
+    }
+
+    /// Changes job `id` and saves it
+    fn update(&self, id: u64, change: impl FnOnce(&mut Entry)) {
+        if let Some(entry) = self.entries().get_mut(&id) {
+            change(entry);
+            self.save(entry);
+        }
+        let _ = self.changes.send(id);
+    }
+
+    /// Applies a progress event to job `id`, saving it when a layer completes
+    fn observe(&self, id: u64, event: &ProgressEvent) {
+        if let Some(entry) = self.entries().get_mut(&id) {
+            entry.observe(event);
+            if matches!(event, ProgressEvent::LayerCompleted { .. }) {
+                self.save(entry);
+            }
+        }
+        let _ = self.changes.send(id);
+    }
+
+    fn path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    /// Writes the file of `entry`, readable by the owner only as it may hold credentials
+    ///
+    /// Best effort like the upload journal: a failure only costs resuming
+    /// the job after a restart.
+    fn save(&self, entry: &Entry) {
+        let stored = StoredJob {
+            job: entry.job.clone(),
+            request: (!entry.job.state.is_finished()).then(|| entry.request.clone()),
+        };
+        let path = self.path(entry.job.id);
+        let result = serde_json::to_vec_pretty(&stored)
+            .map_err(std::io::Error::from)
+            .and_then(|data| write_private(&path, &data));
+        if let Err(e) = result {
+            warn!("⚠️  Failed to save job {}: {}", entry.job.id, e);
+        }
+    }
+
+    /// Loads the persisted jobs, resuming the unfinished ones
+    fn load(self: &Arc<Self>) {
+        let Ok(files) = std::fs::read_dir(&self.dir) else {
+            return;
+        };
+        let mut stored: Vec<StoredJob> = files
+            .flatten()
+            .filter(|file| file.path().extension().is_some_and(|ext| ext == "json"))
+            .filter_map(|file| {
+                let path = file.path();
+                let parsed = std::fs::read(&path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|data| serde_json::from_slice(&data).map_err(|e| e.to_string()));
+                parsed
+                    .inspect_err(|e| {
+                        warn!("⚠️  Ignoring unreadable job file {}: {}", path.display(), e)
+                    })
+                    .ok()
+            })
+            .collect();
+        stored.sort_by_key(|stored| stored.job.id);
+
+        for StoredJob { mut job, request } in stored {
+            let id = job.id;
+            let Some(request) = request.filter(|_| !job.state.is_finished()) else {
+                self.entries().insert(id, Entry::new(job, None));
+                continue;
+            };
+            info!("♻️  Resuming job {}: {}", id, job.source);
+            job.state = JobState::Queued;
+            job.started_at = None;
+            if job.request_id.is_empty() {
+                job.request_id = correlation::new_id();
+            }
+            job.progress = JobProgress::default();
+            let cancel = self.cancel.child_token();
+            let mut entry = Entry::new(job, Some(request.clone()));
+            entry.cancel = cancel.clone();
+            self.save(&entry);
+            let request_id = entry.job.request_id.clone();
+            self.entries().insert(id, entry);
+            self.spawn(id, request_id, request, cancel);
+        }
+        self.prefetch_tokens();
+    }
+
+    /// Queues a job and starts its task
+    fn submit(self: &Arc<Self>, request: JobRequest) -> Job {
+        let cancel = self.cancel.child_token();
+        let job = {
+            let mut entries = self.entries();
+            let finished: Vec<u64> = entries
+                .iter()
+                .filter(|(_, entry)| entry.job.state.is_finished())
+                .map(|(id, _)| *id)
+                .collect();
+            for id in finished
+                .iter()
+                .take((finished.len() + 1).saturating_sub(MAX_FINISHED_JOBS))
+            {
+                entries.remove(id);
+                let _ = std::fs::remove_file(self.path(*id));
+            }
+            let id = entries.keys().next_back().map_or(1, |id| id + 1);
+            let job = Job {
+                id,
+                source: request.source.clone(),
+                target: request.target.clone(),
+                state: JobState::Queued,
+                submitted_at: now(),
+                started_at: None,
+                finished_at: None,
+                progress: JobProgress::default(),
+                error: None,
+                error_category: None,
+                request_id: correlation::new_id(),
+                report: None,
+            };
+            let mut entry = Entry::new(job.clone(), Some(request.clone()));
+            entry.cancel = cancel.clone();
+            self.save(&entry);
+            entries.insert(id, entry);
+            job
+        };
+        info!(
+            "📥 Job {} queued: {} → {}",
+            job.id,
+            job.source,
+            job.target.as_deref().unwrap_or("cache")
+        );
+
+        self.spawn(job.id, job.request_id.clone(), request, cancel);
+        self.prefetch_tokens();
+        job
+    }
+
+    /// Fetches the push tokens for the targets of the queued jobs in the background
+    ///
+    /// Jobs queued together (a burst of submissions, scheduled syncs firing
+    /// at the same time, jobs resumed after a restart) then start with a
+    /// token instead of each performing the handshake once it gets a slot.
+    /// Targets with a valid cached token are skipped, so this is cheap to
+    /// call on every submission.
+    fn prefetch_tokens(self: &Arc<Self>) {
+        let queued: Vec<JobRequest> = self
+            .entries()
+            .values()
+            .filter(|entry| entry.job.state == JobState::Queued)
+            .map(|entry| entry.request.clone())
+            .filter(|request| request.target.is_some())
+            .collect();
+        if queued.is_empty() {
+            return;
+        }
+        let jobs = self.clone();
+        tokio::spawn(async move {
+            // Resolved like the transfer does, so the cached tokens match its credentials
+            let mut batches: Vec<(RegistryAuth, Vec<Reference>)> = Vec::new();
+            for request in queued {
+                let Some(target) = request.target else {
+                    continue;
+                };
+                let Ok(reference) = target.parse::<Reference>() else {
+                    continue;
+                };
+                let auth = match (request.username, request.password) {
+                    (Some(username), Some(password)) => RegistryAuth::Basic(username, password),
+                    _ => match jobs.credentials.resolve_image(&target).await {
+                        Ok(auth) => auth,
+                        Err(e) => {
+                            debug!(error = %e, "no credentials to prefetch a token for {}", target);
+                            continue;
+                        }
+                    },
+                };
+                match batches.iter_mut().find(|(known, _)| *known == auth) {
+                    Some((_, references)) => references.push(reference),
+                    None => batches.push((auth, vec![reference])),
+                }
+            }
+            let client = crate::new_client();
+            let limiter = RateLimiter::new(None);
+            for (auth, references) in batches {
+                let failed = registry::prefetch_tokens(
+                    &client,
+                    &references,
+                    &auth,
+                    oci_client::RegistryOperation::Push,
+                    &limiter,
+                    jobs.max_jobs,
+                )
+                .await;
+                for (reference, e) in failed {
+                    debug!(error = %e, "failed to prefetch the token for {}", reference);
+                }
+            }
+        });
+    }
+
+    /// Starts the task running job `id`
+    fn spawn(
+        self: &Arc<Self>,
+        id: u64,
+        request_id: String,
+        request: JobRequest,
+        cancel: CancellationToken,
+    ) {
+        if let Some(target) = &request.target {
+            self.count_layers(id, request.source.clone(), target.clone());
+        }
+        let jobs = self.clone();
+        let span = info_span!("job", id, request_id = %request_id);
+        tokio::spawn(
+            correlation::scope(request_id, async move {
+                // Scrubbed until the job's outcome is recorded, then forgotten
+                let _secret = request.password.as_deref().map(logging::scope_secret);
+                let result = jobs.run(id, request, cancel).await;
+                jobs.finish(id, result);
+            })
+            .instrument(span),
+        );
+    }
+
+    /// Counts the layers of job `id` towards the popularity of its target repository
+    ///
+    /// Runs while the job waits for a slot, so the copies started after it
+    /// already order their layers by it.
+    fn count_layers(self: &Arc<Self>, id: u64, source: String, target: String) {
+        let jobs = self.clone();
+        tokio::spawn(
+            async move {
+                let layers = match jobs.source_layers(&source).await {
+                    Ok(layers) => layers,
+                    Err(e) => {
+                        debug!(error = %e, "failed to count the layers of {}", source);
+                        return;
+                    }
+                };
+                if let Some(entry) = jobs.entries().get_mut(&id)
+                    && !entry.job.state.is_finished()
+                {
+                    jobs.popularity.add(&target, &layers);
+                    entry.counted_layers = layers;
+                }
+            }
+            .instrument(info_span!("job", id)),
+        );
+    }
+
+    /// Layer digests of `source`, from its manifest in the registry
+    async fn source_layers(&self, source: &str) -> Result<Vec<String>, PusherError> {
+        let reference: oci_client::Reference = source.parse().map_err(|e| {
+            PusherError::ConfigError(format!("Invalid source image reference {}: {}", source, e))
+        })?;
+        let auth = self.credentials.resolve_image(source).await?;
+        let auth =
+            exchange_identity_token(&reference, &auth, oci_client::RegistryOperation::Pull).await?;
+        let (manifest, _) = crate::new_client()
+            .pull_image_manifest(&reference, &auth)
+            .await
+            .map_err(|e| {
+                PusherError::registry(format!("Failed to fetch the manifest of {}", source), e)
+            })?;
+        Ok(manifest
+            .layers
+            .into_iter()
+            .map(|layer| layer.digest)
+            .collect())
+    }
+
+    /// Waits for a transfer slot, then runs the transfer
+    async fn run(
+        self: &Arc<Self>,
+        id: u64,
+        request: JobRequest,
+        cancel: CancellationToken,
+    ) -> Result<Option<TransferReport>, PusherError> {
+        let _slot = tokio::select! {
+            slot = self.slots.clone().acquire_owned() => {
+                slot.map_err(|_| PusherError::Cancelled)?
+            }
+            _ = cancel.cancelled() => return Err(PusherError::Cancelled),
+        };
+        self.update(id, |entry| {
+            entry.job.state = JobState::Running;
+            entry.job.started_at = Some(now());
+        });
+        info!("🚀 Job {} started", id);
+
+        let jobs = self.clone();
+        let mut transfer = ImageTransfer::pull(request.source)
+            .with_cache(self.cache_dir.clone())
+            .with_cancellation(cancel)
+            .with_auth_provider(self.credentials.clone())
+            .with_layer_popularity(self.popularity.clone())
+            .on_progress(Box::new(move |event| jobs.observe(id, &event)));
+        if let Some(target) = request.target {
+            transfer = transfer.push_to(target);
+        }
+        if let (Some(username), Some(password)) = (request.username, request.password) {
+            transfer = transfer.with_auth(username, password);
+        }
+        if let Some(ttl_secs) = self.ledger_ttl_secs {
+            transfer = transfer.with_ledger_ttl(ttl_secs);
+        }
+        transfer.run().await
+    }
+
+    /// Records the outcome of job `id`
+    fn finish(&self, id: u64, result: Result<Option<TransferReport>, PusherError>) {
+        self.update(id, |entry| {
+            if let Some(target) = &entry.job.target {
+                self.popularity.remove(target, &entry.counted_layers);
+            }
+            entry.counted_layers.clear();
+            let job = &mut entry.job;
+            if matches!(result, Err(PusherError::Cancelled)) && self.cancel.is_cancelled() {
+                info!(
+                    "⏸️  Job {} interrupted, it resumes when the server restarts",
+                    id
+                );
+                job.state = JobState::Queued;
+                return;
+            }
+            job.finished_at = Some(now());
+            match result {
+                Ok(report) => {
+                    info!("✅ Job {} succeeded", id);
+                    job.state = JobState::Succeeded;
+                    job.report = report;
+                }
+                Err(PusherError::Cancelled) => {
+                    info!("🛑 Job {} cancelled", id);
+                    job.state = JobState::Cancelled;
+                }
+                Err(e) => {
+                    let message = logging::redact(&e.to_string()).into_owned();
+                    warn!("❌ Job {} failed: {}", id, message);
+                    job.state = JobState::Failed;
+                    job.error = Some(message);
+                    job.error_category = Some(e.category().to_string());
+                }
+            }
+        });
+    }
+
+    /// Cancels job `id`, returning it (`None` if unknown)
+    fn cancel(&self, id: u64) -> Option<Job> {
+        let entries = self.entries();
+        let entry = entries.get(&id)?;
+        if !entry.job.state.is_finished() {
+            info!("🛑 Cancelling job {}", id);
+            entry.cancel.cancel();
+        }
+        Some(entry.job.clone())
+    }
+}
+
+/// Serves the job API until `options.cancel` is cancelled
+///
+/// Unfinished jobs persisted by a previous run are resumed first. On
+/// shutdown, running jobs are cancelled (aborting their upload sessions)
+/// and stay queued on disk; this returns once they have all stopped.
+pub async fn serve(options: ServerOptions) -> Result<(), PusherError> {
+    let listener = tokio::net::TcpListener::bind(&options.listen)
+        .await
+        .map_err(|e| {
+            PusherError::ConfigError(format!(
+                "Failed to bind job API endpoint {}: {}",
+                options.listen, e
+            ))
+        })?;
+    if crate::trust::content_trust_required() {
+        warn!(
+            "⚠️  {} is set: jobs can't verify signatures and will be refused",
+            crate::trust::CONTENT_TRUST_ENV
+        );
+    }
+    let max_jobs = options.max_jobs.max(1);
+    let jobs = Arc::new(Jobs {
+        entries: Mutex::new(BTreeMap::new()),
+        slots: Arc::new(Semaphore::new(max_jobs)),
+        max_jobs,
+        dir: options.cache_dir.join(JOBS_DIR_NAME),
+        cache_dir: options.cache_dir,
+        credentials: options.credentials,
+        cancel: options.cancel.clone(),
+        changes: tokio::sync::broadcast::channel(1024).0,
+        popularity: LayerPopularity::default(),
+        ledger_ttl_secs: options.ledger_ttl_secs,
+    });
+    if let Err(e) = std::fs::create_dir_all(&jobs.dir) {
+        warn!(
+            "⚠️  Failed to create {}, jobs won't survive a restart: {}",
+            jobs.dir.display(),
+            e
+        );
+    }
+    let token = options.api_token.map(Arc::new);
+    let mut registries = options.ready_registries;
+    if let Some(manifest) = &options.sync {
+        registries.extend(manifest.registries());
+    }
+    registries.sort();
+    registries.dedup();
+    let registries = Arc::new(registries);
+    info!(
+        "🛰️  Serving the job API on http://{} ({} concurrent jobs)",
+        options.listen, max_jobs
+    );
+    #[cfg(feature = "grpc")]
+    if let Some(listen) = &options.grpc_listen {
+        grpc::serve(listen, jobs.clone(), token.clone(), options.cancel.clone()).await?;
+    }
+    if let Some(proxy) = options.proxy {
+        proxy::start(
+            proxy,
+            jobs.cache_dir.clone(),
+            jobs.credentials.clone(),
+            options.cancel.clone(),
+        )
+        .await?;
+    }
+    jobs.load();
+    if let Some(manifest) = options.sync {
+        sync::start(manifest, &jobs, &options.cancel);
+    }
+
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("⚠️  Job API accept failed: {}", e);
+                    continue;
+                }
+            },
+            _ = options.cancel.cancelled() => break,
+        };
+        let (jobs, token, registries) = (jobs.clone(), token.clone(), registries.clone());
+        tokio::spawn(async move {
+            let token = token.as_deref().map(String::as_str);
+            handle(stream, &jobs, token, &registries).await;
+        });
+    }
+
+    info!("🛑 Shutting down the job API, waiting for running jobs to stop...");
+    // Every running job holds a slot until its transfer has returned
+    let _ = jobs.slots.acquire_many(jobs.max_jobs as u32).await;
+    Ok(())
+}
+
+/// A parsed HTTP request
+struct Request {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    /// The path without its query string
+    fn route(&self) -> &str {
+        self.path.split('?').next().unwrap_or_default()
+    }
+}
+
+/// Answers one request on `stream`
+async fn handle(
+    mut stream: TcpStream,
+    jobs: &Arc<Jobs>,
+    token: Option<&str>,
+    registries: &[String],
+) {
+    let (status, body) = match read_request(&mut stream).await {
+        Ok(request) if request.method == "GET" && request.route() == "/healthz" => health::live(),
+        Ok(request) if request.method == "GET" && request.route() == "/readyz" => {
+            health::ready(&jobs.cache_dir, registries).await
+        }
+        Ok(request) if !authorized(request.authorization.as_deref(), token) => {
+            (401, error_body("missing or invalid API token"))
+        }
+        Ok(request) => route(&request, jobs),
+        Err(problem) => (400, error_body(&problem)),
+    };
+    let reason = match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "Error",
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Dispatches a request to its endpoint, returning the status and JSON body
+fn route(request: &Request, jobs: &Arc<Jobs>) -> (u16, String) {
+    let segments: Vec<&str> = request.route().trim_matches('/').split('/').collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("POST", ["jobs"]) => match serde_json::from_slice::<JobRequest>(&request.body) {
+            Ok(job) => (202, to_json(&jobs.submit(job))),
+            Err(e) => (400, error_body(&format!("invalid job: {}", e))),
+        },
+        ("GET", ["jobs"]) => {
+            let list: Vec<Job> = jobs
+                .entries()
+                .values()
+                .map(|entry| entry.job.clone())
+                .collect();
+            (200, to_json(&list))
+        }
+        ("GET", ["jobs", id]) => match id.parse().ok().and_then(|id| jobs.get(id)) {
+            Some(job) => (200, to_json(&job)),
+            None => (404, error_body("no such job")),
+        },
+        ("DELETE", ["jobs", id]) => match id.parse().ok().and_then(|id| jobs.cancel(id)) {
+            Some(job) => (202, to_json(&job)),
+            None => (404, error_body("no such job")),
+        },
+        (_, ["jobs"] | ["jobs", _]) => (405, error_body("method not allowed")),
+        _ => (404, error_body("not found")),
+    }
+}
+
+/// Reads the request head and body from `stream`
+async fn read_request(stream: &mut TcpStream) -> Result<Request, String> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let head_end = loop {
+        if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        if buffer.len() > MAX_HEAD_BYTES {
+            return Err("request head too large".to_string());
+        }
+        let read = stream
+            .read(&mut chunk)
+            .await
+            .map_err(|e| format!("failed to read request: {}", e))?;
+        if read == 0 {
+            return Err("connection closed mid-request".to_string());
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..head_end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(path)) = (request_line.next(), request_line.next()) else {
+        return Err("malformed request line".to_string());
+    };
+    let mut content_length = 0;
+    let mut authorization = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value
+                .parse()
+                .map_err(|_| "invalid Content-Length".to_string())?;
+        } else if name.eq_ignore_ascii_case("authorization") {
+            authorization = Some(value.to_string());
+        }
+    }
+    if content_length > MAX_BODY_BYTES {
+        return Err(format!("request body over {} bytes", MAX_BODY_BYTES));
+    }
+
+    let mut body = buffer.split_off(head_end + 4);
+    while body.len() < content_length {
+        let read = stream
+            .read(&mut chunk)
+            .await
+            .map_err(|e| format!("failed to read request body: {}", e))?;
+        if read == 0 {
+            return Err("connection closed mid-body".to_string());
+        }
+        body.extend_from_slice(&chunk[..read]);
+    }
+    body.truncate(content_length);
+    Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        authorization,
+        body,
+    })
+}
+
+/// Checks the bearer token of an `Authorization` value, comparing in constant time
+fn authorized(authorization: Option<&str>, token: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+    let Some(given) = authorization.and_then(|value| value.strip_prefix("Bearer ")) else {
+        return false;
+    };
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+/// Writes `data` to `path` through a temporary file, with owner-only permissions
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&temp_path)?.write_all(data)?;
+    std::fs::rename(&temp_path, path)?;
+    debug!(path = %path.display(), "saved job");
+    Ok(())
+}
+
+fn to_json(value: &impl Serialize) -> String {
+    serde_json::to_string(value).unwrap_or_else(|e| error_body(&e.to_string()))
+}
+
+fn error_body(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,244 @@
+//! gRPC interface to the job API (`serve --grpc-listen`, `grpc` feature)
+//!
+//! Implements `docker_image_pusher.v1.JobService` from `proto/pusher.proto`
+//! (Submit, Watch, Cancel) on the same jobs as the REST endpoints, so
+//! orchestrators in any language can generate a client from the proto and
+//! follow a transfer through a progress stream instead of polling
+//! `GET /jobs/<id>`. The messages below mirror the proto by hand, which keeps
+//! protoc out of the build.
+
+use super::{JobState, Jobs, authorized};
+use crate::PusherError;
+use futures::Stream;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+use tonic::{Request, Response, Status};
+use tracing::{info, warn};
+
+mod generated {
+    include!(concat!(
+        env!("OUT_DIR"),
+        "/docker_image_pusher.v1.JobService.rs"
+    ));
+}
+
+pub use generated::job_service_server::{JobService, JobServiceServer};
+
+/// Shortest time between two updates of a `Watch` stream; changes in between are merged
+const WATCH_INTERVAL: Duration = Duration::from_millis(200);
+
+/// `SubmitRequest` message
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubmitRequest {
+    #[prost(string, tag = "1")]
+    pub source: String,
+    #[prost(string, optional, tag = "2")]
+    pub target: Option<String>,
+    #[prost(string, optional, tag = "3")]
+    pub username: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    pub password: Option<String>,
+}
+
+/// `JobId` message
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct JobId {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+}
+
+/// `JobProgress` message
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct JobProgress {
+    #[prost(uint64, tag = "1")]
+    pub layers: u64,
+    #[prost(uint64, tag = "2")]
+    pub layers_completed: u64,
+    #[prost(uint64, tag = "3")]
+    pub layers_skipped: u64,
+    #[prost(uint64, tag = "4")]
+    pub bytes_transferred: u64,
+    #[prost(uint64, tag = "5")]
+    pub bytes_total: u64,
+}
+
+/// `Job` message
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Job {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(string, tag = "2")]
+    pub source: String,
+    #[prost(string, optional, tag = "3")]
+    pub target: Option<String>,
+    /// A `JobState` value
+    #[prost(int32, tag = "4")]
+    pub state: i32,
+    #[prost(uint64, tag = "5")]
+    pub submitted_at: u64,
+    #[prost(uint64, optional, tag = "6")]
+    pub started_at: Option<u64>,
+    #[prost(uint64, optional, tag = "7")]
+    pub finished_at: Option<u64>,
+    #[prost(message, optional, tag = "8")]
+    pub progress: Option<JobProgress>,
+    #[prost(string, optional, tag = "9")]
+    pub error: Option<String>,
+    #[prost(string, optional, tag = "10")]
+    pub error_category: Option<String>,
+    #[prost(string, optional, tag = "11")]
+    pub manifest_digest: Option<String>,
+    #[prost(string, optional, tag = "12")]
+    pub manifest_url: Option<String>,
+}
+
+impl From<super::Job> for Job {
+    fn from(job: super::Job) -> Self {
+        let state = match job.state {
+            JobState::Queued => 1,
+            JobState::Running => 2,
+            JobState::Succeeded => 3,
+            JobState::Failed => 4,
+            JobState::Cancelled => 5,
+        };
+        Self {
+            id: job.id,
+            source: job.source,
+            target: job.target,
+            state,
+            submitted_at: job.submitted_at,
+            started_at: job.started_at,
+            finished_at: job.finished_at,
+            progress: Some(JobProgress {
+                layers: job.progress.layers as u64,
+                layers_completed: job.progress.layers_completed as u64,
+                layers_skipped: job.progress.layers_skipped as u64,
+                bytes_transferred: job.progress.bytes_transferred,
+                bytes_total: job.progress.bytes_total,
+            }),
+            error: job.error,
+            error_category: job.error_category,
+            manifest_digest: job.report.as_ref().map(|r| r.manifest_digest.clone()),
+            manifest_url: job.report.map(|r| r.manifest_url),
+        }
+    }
+}
+
+/// The `JobService` implementation, backed by the server's jobs
+struct Service {
+    jobs: Arc<Jobs>,
+}
+
+impl Service {
+    fn job(&self, id: u64) -> Result<super::Job, Status> {
+        self.jobs
+            .get(id)
+            .ok_or_else(|| Status::not_found(format!("no job {}", id)))
+    }
+}
+
+#[tonic::async_trait]
+impl JobService for Service {
+    async fn submit(&self, request: Request<SubmitRequest>) -> Result<Response<Job>, Status> {
+        let request = request.into_inner();
+        if request.source.is_empty() {
+            return Err(Status::invalid_argument("source is required"));
+        }
+        let job = self.jobs.submit(super::JobRequest {
+            source: request.source,
+            target: request.target,
+            username: request.username,
+            password: request.password,
+        });
+        Ok(Response::new(job.into()))
+    }
+
+    type WatchStream = Pin<Box<dyn Stream<Item = Result<Job, Status>> + Send>>;
+
+    async fn watch(&self, request: Request<JobId>) -> Result<Response<Self::WatchStream>, Status> {
+        let id = request.into_inner().id;
+        // Subscribed before the first snapshot, so no change falls in between
+        let changes = self.jobs.changes.subscribe();
+        let first = self.job(id)?;
+        let jobs = self.jobs.clone();
+        // Ends after the update showing the job finished
+        let stream = futures::stream::unfold(
+            (Some(first), changes, false),
+            move |(first, mut changes, finished)| {
+                let jobs = jobs.clone();
+                async move {
+                    if finished {
+                        return None;
+                    }
+                    let job = match first {
+                        Some(job) => job,
+                        None => {
+                            tokio::time::sleep(WATCH_INTERVAL).await;
+                            loop {
+                                match changes.recv().await {
+                                    Ok(changed) if changed != id => continue,
+                                    Ok(_) | Err(RecvError::Lagged(_)) => break,
+                                    Err(RecvError::Closed) => return None,
+                                }
+                            }
+                            jobs.get(id)?
+                        }
+                    };
+                    let finished = job.state.is_finished();
+                    Some((Ok(job.into()), (None, changes, finished)))
+                }
+            },
+        );
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn cancel(&self, request: Request<JobId>) -> Result<Response<Job>, Status> {
+        let id = request.into_inner().id;
+        let job = self
+            .jobs
+            .cancel(id)
+            .ok_or_else(|| Status::not_found(format!("no job {}", id)))?;
+        Ok(Response::new(job.into()))
+    }
+}
+
+/// Serves the gRPC interface on `listen` until `cancel` is cancelled
+pub(super) async fn serve(
+    listen: &str,
+    jobs: Arc<Jobs>,
+    token: Option<Arc<String>>,
+    cancel: CancellationToken,
+) -> Result<(), PusherError> {
+    let listener = tokio::net::TcpListener::bind(listen).await.map_err(|e| {
+        PusherError::ConfigError(format!("Failed to bind gRPC endpoint {}: {}", listen, e))
+    })?;
+    let service =
+        JobServiceServer::with_interceptor(Service { jobs }, move |request: Request<()>| {
+            let authorization = request
+                .metadata()
+                .get("authorization")
+                .and_then(|value| value.to_str().ok());
+            if authorized(authorization, token.as_deref().map(String::as_str)) {
+                Ok(request)
+            } else {
+                Err(Status::unauthenticated("missing or invalid API token"))
+            }
+        });
+    info!("🛰️  Serving the gRPC job API on {}", listen);
+    tokio::spawn(async move {
+        let result = tonic::transport::Server::builder()
+            .add_service(service)
+            .serve_with_incoming_shutdown(
+                tonic::transport::server::TcpIncoming::from(listener),
+                cancel.cancelled_owned(),
+            )
+            .await;
+        if let Err(e) = result {
+            warn!("⚠️  gRPC job API failed: {}", e);
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,100 @@
+//! Liveness and readiness probes (`GET /healthz`, `GET /readyz`)
+//!
+//! `/healthz` answers as long as the server accepts requests. `/readyz` also
+//! checks that the cache directory is writable and that every configured
+//! registry answers `GET /v2/` (with any status below 500, since most ask for
+//! credentials first), and answers `503` listing the failed checks otherwise,
+//! so Kubernetes only sends work to a pod that can do it. Probes can't send
+//! the API token, so neither endpoint requires it.
+
+use crate::registry;
+use crate::transport::{RegistryTransport, ReqwestTransport, TransportRequest};
+use oci_client::secrets::RegistryAuth;
+use serde::Serialize;
+use std::path::Path;
+use std::time::Duration;
+use tracing::debug;
+
+/// How long a registry has to answer the readiness check
+const REGISTRY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Outcome of one readiness check
+#[derive(Serialize)]
+struct Check {
+    name: String,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn new(name: &str, result: Result<(), String>) -> Self {
+        Self {
+            name: name.to_string(),
+            ok: result.is_ok(),
+            error: result.err(),
+        }
+    }
+}
+
+/// Answers `/healthz`
+pub(super) fn live() -> (u16, String) {
+    (200, serde_json::json!({ "status": "ok" }).to_string())
+}
+
+/// Answers `/readyz`, checking `cache_dir` and `registries`
+pub(super) async fn ready(cache_dir: &Path, registries: &[String]) -> (u16, String) {
+    let transport = ReqwestTransport::new(None, &RegistryAuth::Anonymous);
+    let (cache, reachable) = tokio::join!(
+        cache_writable(cache_dir),
+        futures::future::join_all(
+            registries
+                .iter()
+                .map(|registry| registry_reachable(&transport, registry))
+        )
+    );
+    let mut checks = vec![Check::new("cache", cache)];
+    checks.extend(
+        registries
+            .iter()
+            .zip(reachable)
+            .map(|(registry, result)| Check::new(registry, result)),
+    );
+    let ready = checks.iter().all(|check| check.ok);
+    if !ready {
+        debug!(checks = %serde_json::to_string(&checks).unwrap_or_default(), "not ready");
+    }
+    let status = if ready { "ok" } else { "unavailable" };
+    let body = serde_json::json!({ "status": status, "checks": checks }).to_string();
+    (if ready { 200 } else { 503 }, body)
+}
+
+/// Creates and removes a file in `cache_dir`
+async fn cache_writable(cache_dir: &Path) -> Result<(), String> {
+    let probe = cache_dir.join(format!(".readyz-{}", std::process::id()));
+    let result = async {
+        tokio::fs::create_dir_all(cache_dir).await?;
+        tokio::fs::write(&probe, b"ready").await?;
+        tokio::fs::remove_file(&probe).await
+    }
+    .await;
+    result.map_err(|e| format!("{} is not writable: {}", cache_dir.display(), e))
+}
+
+/// Sends `GET /v2/` to `registry`
+async fn registry_reachable(transport: &ReqwestTransport, registry: &str) -> Result<(), String> {
+    let url = format!("{}/v2/", registry::base_url(registry));
+    match transport
+        .send(TransportRequest::get(url).timeout(REGISTRY_TIMEOUT))
+        .await
+    {
+        Ok(response) if response.status.is_server_error() => {
+            Err(format!("answered HTTP {}", response.status))
+        }
+        Ok(_) => Ok(()),
+        Err(e) => Err(match std::error::Error::source(&e) {
+            Some(cause) => format!("{}: {}", e, cause),
+            None => e.to_string(),
+        }),
+    }
+}
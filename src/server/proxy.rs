@@ -0,0 +1,445 @@
+//! Registry v2 pull API over the local cache (`serve --proxy-listen`, `serve-cache`)
+//!
+//! Serves the pull half of the Registry v2 API from the local cache, so an
+//! edge site can point Docker (`registry-mirrors`) or containerd at the
+//! pusher instead of running a full registry mirror. Repositories are those
+//! of one upstream registry: `GET /v2/library/nginx/manifests/1.27` answers
+//! with `docker.io/library/nginx:1.27` when the upstream is `docker.io`.
+//!
+//! | Request | Effect |
+//! |---|---|
+//! | `GET /v2/` | API version check |
+//! | `GET`/`HEAD /v2/<name>/manifests/<tag or digest>` | The manifest, pulled into the cache on a miss |
+//! | `GET`/`HEAD /v2/<name>/blobs/<digest>` | A config or layer of a cached manifest |
+//!
+//! A manifest requested by tag is checked against the upstream every time
+//! (one `HEAD` request while it is unchanged) and pulled again when the tag
+//! moved; if the upstream can't be reached, the cached copy is served. A
+//! digest always names the same content, so a cached copy is served without
+//! asking the upstream. Blobs come from the cache only, since clients fetch
+//! the manifest referencing them first.
+//!
+//! Without an upstream (`serve-cache`) only images already in the cache are
+//! served, so an air-gapped cluster can `docker pull` straight from a laptop
+//! that pulled them beforehand. Repositories are then named like the cached
+//! images without their registry (`nginx:latest` is `library/nginx`), or with
+//! it to tell apart images of different registries (`ghcr.io/org/app`).
+//!
+//! The API is read-only and anonymous, so it should only be reachable from
+//! the networks it serves. Upstream credentials come from the server's
+//! credential sources.
+
+use super::read_request;
+use crate::credentials::AuthChain;
+use crate::image::digest::{Algorithm, Digest, Hasher};
+use crate::{ImageTransfer, PusherError, image};
+use oci_client::Reference;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// Media type of manifests that don't declare one
+const DEFAULT_MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+
+/// Configuration of [`serve`]
+#[derive(Debug, Clone)]
+pub struct ProxyOptions {
+    /// Address to serve the Registry v2 API on (e.g. "0.0.0.0:5000")
+    pub listen: String,
+    /// Registry (`host[:port]`) images are pulled from on a miss; `None` only
+    /// serves what is already cached
+    pub upstream: Option<String>,
+}
+
+impl Default for ProxyOptions {
+    fn default() -> Self {
+        Self {
+            listen: "127.0.0.1:5000".to_string(),
+            upstream: Some("docker.io".to_string()),
+        }
+    }
+}
+
+/// State shared by all proxy connections
+struct Proxy {
+    upstream: Option<String>,
+    cache_dir: PathBuf,
+    credentials: AuthChain,
+    cancel: CancellationToken,
+    /// One pull per image at a time; requests arriving meanwhile wait for it
+    pulls: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+/// A complete image in the cache
+struct CachedImage {
+    dir: PathBuf,
+    reference: Reference,
+    /// Digest of the cached `manifest.json`, as served
+    manifest_digest: String,
+}
+
+/// Serves the cache in `cache_dir` over the Registry v2 API until `cancel` is cancelled
+///
+/// # Examples
+///
+/// ```no_run
+/// # async fn run() -> Result<(), docker_image_pusher::PusherError> {
+/// use docker_image_pusher::credentials::AuthChain;
+/// use docker_image_pusher::server::proxy::{self, ProxyOptions};
+/// use tokio_util::sync::CancellationToken;
+///
+/// // Read-only: serve what an earlier `pull` cached
+/// let options = ProxyOptions { listen: "0.0.0.0:5000".to_string(), upstream: None };
+/// proxy::serve(options, ".cache".into(), AuthChain::new(), CancellationToken::new()).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn serve(
+    options: ProxyOptions,
+    cache_dir: PathBuf,
+    credentials: AuthChain,
+    cancel: CancellationToken,
+) -> Result<(), PusherError> {
+    start(options, cache_dir, credentials, cancel.clone()).await?;
+    cancel.cancelled().await;
+    Ok(())
+}
+
+/// Binds `options.listen` and starts serving the cache until `cancel` is cancelled
+pub(super) async fn start(
+    options: ProxyOptions,
+    cache_dir: PathBuf,
+    credentials: AuthChain,
+    cancel: CancellationToken,
+) -> Result<(), PusherError> {
+    let listener = tokio::net::TcpListener::bind(&options.listen)
+        .await
+        .map_err(|e| {
+            PusherError::ConfigError(format!(
+                "Failed to bind registry proxy endpoint {}: {}",
+                options.listen, e
+            ))
+        })?;
+    match &options.upstream {
+        Some(upstream) => info!(
+            "🪞 Serving a pull-through cache of {} on http://{}",
+            upstream, options.listen
+        ),
+        None => info!(
+            "🪞 Serving {} read-only on http://{}",
+            cache_dir.display(),
+            options.listen
+        ),
+    }
+    let proxy = Arc::new(Proxy {
+        upstream: options.upstream,
+        cache_dir,
+        credentials,
+        cancel: cancel.clone(),
+        pulls: Mutex::new(HashMap::new()),
+    });
+    tokio::spawn(async move {
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!("⚠️  Registry proxy accept failed: {}", e);
+                        continue;
+                    }
+                },
+                _ = cancel.cancelled() => break,
+            };
+            let proxy = proxy.clone();
+            tokio::spawn(async move { proxy.handle(stream).await });
+        }
+    });
+    Ok(())
+}
+
+/// Body of a [`Response`]
+enum Body {
+    Bytes(Vec<u8>),
+    /// A cached blob, streamed from disk
+    File(tokio::fs::File, u64),
+}
+
+/// A Registry v2 API response
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Body,
+}
+
+impl Response {
+    fn new(status: u16, content_type: &str, body: Body) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type", content_type.to_string())],
+            body,
+        }
+    }
+
+    /// An error in the registry's `{"errors": [...]}` format
+    fn error(status: u16, code: &str, message: &str) -> Self {
+        let body = serde_json::json!({ "errors": [{ "code": code, "message": message }] });
+        Self::new(
+            status,
+            "application/json",
+            Body::Bytes(body.to_string().into_bytes()),
+        )
+    }
+
+    fn header(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+
+    /// Writes the response to `stream`, leaving out the body for `HEAD` requests
+    async fn write(self, stream: &mut TcpStream, head_only: bool) -> std::io::Result<()> {
+        let length = match &self.body {
+            Body::Bytes(bytes) => bytes.len() as u64,
+            Body::File(_, size) => *size,
+        };
+        let reason = match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            502 => "Bad Gateway",
+            _ => "Error",
+        };
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!(
+            "Docker-Distribution-API-Version: registry/2.0\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            length
+        ));
+        stream.write_all(head.as_bytes()).await?;
+        if !head_only {
+            match self.body {
+                Body::Bytes(bytes) => stream.write_all(&bytes).await?,
+                Body::File(mut file, _) => {
+                    tokio::io::copy(&mut file, stream).await?;
+                }
+            }
+        }
+        stream.shutdown().await
+    }
+}
+
+impl Proxy {
+    /// Answers one request on `stream`
+    async fn handle(&self, mut stream: TcpStream) {
+        let (response, head_only) = match read_request(&mut stream).await {
+            Ok(request) => {
+                let path = request.route().to_string();
+                debug!(method = %request.method, path, "proxy request");
+                (
+                    self.respond(&request.method, &path).await,
+                    request.method == "HEAD",
+                )
+            }
+            Err(problem) => (Response::error(400, "UNSUPPORTED", &problem), false),
+        };
+        if let Err(e) = response.write(&mut stream, head_only).await {
+            debug!(error = %e, "failed to send proxy response");
+        }
+    }
+
+    /// Dispatches a request to its endpoint
+    async fn respond(&self, method: &str, path: &str) -> Response {
+        if method != "GET" && method != "HEAD" {
+            return Response::error(405, "UNSUPPORTED", "the cache is served read-only");
+        }
+        if path == "/v2/" || path == "/v2" {
+            return Response::new(200, "application/json", Body::Bytes(b"{}".to_vec()));
+        }
+        let rest = path.strip_prefix("/v2/").unwrap_or_default();
+        if let Some((name, reference)) = rest.rsplit_once("/manifests/") {
+            return self.manifest(name, reference).await;
+        }
+        if let Some((name, digest)) = rest.rsplit_once("/blobs/") {
+            return self.blob(name, digest).await;
+        }
+        Response::error(404, "UNSUPPORTED", "not found")
+    }
+
+    /// Answers `/v2/<name>/manifests/<reference>`
+    async fn manifest(&self, name: &str, reference: &str) -> Response {
+        // Tags can't contain ':', digests always do
+        let by_digest = reference.contains(':');
+        let cached = self.cached_images(name).await;
+        if by_digest && let Some(image) = cached.iter().find(|i| i.manifest_digest == reference) {
+            return serve_manifest(&image.dir).await;
+        }
+        if let Some(upstream) = &self.upstream {
+            return self
+                .pull_through(upstream, name, reference, by_digest)
+                .await;
+        }
+        match cached
+            .iter()
+            .find(|i| !by_digest && i.reference.tag() == Some(reference))
+        {
+            Some(image) => serve_manifest(&image.dir).await,
+            None => Response::error(
+                404,
+                "MANIFEST_UNKNOWN",
+                &format!("{}:{} is not cached", name, reference),
+            ),
+        }
+    }
+
+    /// Answers a manifest request from `upstream`, pulling the image into the cache first
+    async fn pull_through(
+        &self,
+        upstream: &str,
+        name: &str,
+        reference: &str,
+        by_digest: bool,
+    ) -> Response {
+        let image = if by_digest {
+            format!("{}/{}@{}", upstream, name, reference)
+        } else {
+            format!("{}/{}:{}", upstream, name, reference)
+        };
+        if let Err(e) = image.parse::<Reference>() {
+            return Response::error(400, "NAME_INVALID", &format!("invalid reference: {}", e));
+        }
+        let image_cache_dir = self.cache_dir.join(image::sanitize_image_name(&image));
+        if let Err(e) = self.pull(&image).await {
+            if !image_cache_dir.join("index.json").exists() {
+                warn!("⚠️  Failed to pull {} for the proxy: {}", image, e);
+                return if e.is_not_found() {
+                    Response::error(404, "MANIFEST_UNKNOWN", &format!("{} not found", image))
+                } else {
+                    Response::error(502, "UNKNOWN", &format!("failed to pull {}: {}", image, e))
+                };
+            }
+            warn!(
+                "⚠️  Serving the cached {}, the upstream failed: {}",
+                image, e
+            );
+        }
+        serve_manifest(&image_cache_dir).await
+    }
+
+    /// Answers `/v2/<name>/blobs/<digest>`
+    async fn blob(&self, name: &str, digest: &str) -> Response {
+        let parsed: Digest = match digest.parse() {
+            Ok(parsed) => parsed,
+            Err(e) => return Response::error(400, "DIGEST_INVALID", &e.to_string()),
+        };
+        let file_name = parsed.file_name();
+        // Layers are stored by digest; configs next to the manifest
+        let names = [file_name.clone(), format!("config_{}.json", file_name)];
+        for image in self.cached_images(name).await {
+            for candidate in &names {
+                if let Ok(file) = tokio::fs::File::open(image.dir.join(candidate)).await
+                    && let Ok(metadata) = file.metadata().await
+                {
+                    return Response::new(
+                        200,
+                        "application/octet-stream",
+                        Body::File(file, metadata.len()),
+                    )
+                    .header("Docker-Content-Digest", digest);
+                }
+            }
+        }
+        Response::error(404, "BLOB_UNKNOWN", &format!("{} is not cached", digest))
+    }
+
+    /// Pulls `image` into the cache, or checks that the cached copy is current
+    async fn pull(&self, image: &str) -> Result<(), PusherError> {
+        let lock = self
+            .pulls
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(image.to_string())
+            .or_default()
+            .clone();
+        let _pulling = lock.lock().await;
+        ImageTransfer::pull(image)
+            .with_cache(self.cache_dir.clone())
+            .with_auth_provider(self.credentials.clone())
+            .with_cancellation(self.cancel.child_token())
+            .run()
+            .await
+            .map(|_| ())
+    }
+
+    /// Complete cached images of repository `name`, any tag or digest
+    async fn cached_images(&self, name: &str) -> Vec<CachedImage> {
+        let mut images = Vec::new();
+        let Ok(mut entries) = tokio::fs::read_dir(&self.cache_dir).await else {
+            return images;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if let Some(image) = read_cached_image(entry.path()).await
+                && self.serves(&image.reference, name)
+            {
+                images.push(image);
+            }
+        }
+        images
+    }
+
+    /// Whether `reference` is an image of repository `name` as this proxy names them
+    fn serves(&self, reference: &Reference, name: &str) -> bool {
+        match &self.upstream {
+            Some(upstream) => reference.registry() == upstream && reference.repository() == name,
+            None => {
+                reference.repository() == name
+                    || format!("{}/{}", reference.registry(), reference.repository()) == name
+            }
+        }
+    }
+}
+
+/// Reads the cache index in `dir`; `None` unless it holds a complete image
+async fn read_cached_image(dir: PathBuf) -> Option<CachedImage> {
+    let index = tokio::fs::read(dir.join("index.json")).await.ok()?;
+    let index: serde_json::Value = serde_json::from_slice(&index).ok()?;
+    let reference = index["source_image"].as_str()?.parse().ok()?;
+    let manifest_digest = match index["manifest_file_digest"].as_str() {
+        Some(digest) => digest.to_string(),
+        // Caches written before the digest was recorded
+        None => sha256(&tokio::fs::read(dir.join("manifest.json")).await.ok()?).to_string(),
+    };
+    Some(CachedImage {
+        dir,
+        reference,
+        manifest_digest,
+    })
+}
+
+/// SHA-256 digest of `bytes`
+fn sha256(bytes: &[u8]) -> Digest {
+    let mut hasher = Hasher::new(Algorithm::Sha256);
+    hasher.update(bytes);
+    hasher.finalize()
+}
+
+/// Answers with the manifest cached in `image_cache_dir`, exactly as it was served upstream
+async fn serve_manifest(image_cache_dir: &Path) -> Response {
+    let bytes = match tokio::fs::read(image_cache_dir.join("manifest.json")).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            return Response::error(404, "MANIFEST_UNKNOWN", &format!("not cached: {}", e));
+        }
+    };
+    let media_type = serde_json::from_slice::<serde_json::Value>(&bytes)
+        .ok()
+        .and_then(|manifest| manifest["mediaType"].as_str().map(str::to_string))
+        .unwrap_or_else(|| DEFAULT_MANIFEST_MEDIA_TYPE.to_string());
+    let digest = sha256(&bytes);
+    Response::new(200, &media_type, Body::Bytes(bytes)).header("Docker-Content-Digest", digest)
+}
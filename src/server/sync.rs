@@ -0,0 +1,172 @@
+//! Scheduled syncs from a sync manifest (`serve --sync-manifest`)
+//!
+//! A sync manifest lists images to mirror, each with a cron schedule (see
+//! [`CronSchedule`]): `nginx:stable` nightly, internal images every ten
+//! minutes. Every time an entry fires, a job is submitted to the server's
+//! queue like one from `POST /jobs`, so it shows up in the API and survives
+//! restarts. Runs are delayed by a random jitter so entries sharing a
+//! schedule don't all hit the registry in the same second, and an entry
+//! whose previous job is still queued or running skips its turn.
+//!
+//! ```json
+//! {
+//!   "jitter_secs": 60,
+//!   "entries": [
+//!     { "name": "nginx", "source": "nginx:stable", "target": "registry.company.com/mirror/nginx:stable", "schedule": "0 3 * * *" },
+//!     { "source": "build.company.com/app:main", "target": "registry.company.com/app:main", "schedule": "*/10 * * * *", "username": "deploy", "password": "secret" }
+//!   ]
+//! }
+//! ```
+
+use super::{JobRequest, Jobs};
+use crate::PusherError;
+use crate::schedule::CronSchedule;
+use chrono::Local;
+use serde::Deserialize;
+use std::hash::BuildHasher;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// Contents of a sync manifest
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyncManifest {
+    /// Largest random delay added to each scheduled run, in seconds
+    #[serde(default)]
+    pub jitter_secs: u64,
+    /// Images to sync
+    pub entries: Vec<SyncEntry>,
+}
+
+/// An image synced on a schedule
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyncEntry {
+    /// Name used in log lines; the source image if not set
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Image to pull
+    pub source: String,
+    /// Full target reference to push to; without it the entry only refreshes the cache
+    #[serde(default)]
+    pub target: Option<String>,
+    /// When to sync, e.g. `0 3 * * *` for every night at 3:00
+    pub schedule: CronSchedule,
+    /// Overrides the manifest's `jitter_secs` for this entry
+    #[serde(default)]
+    pub jitter_secs: Option<u64>,
+    /// Credentials for the target registry
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+impl SyncEntry {
+    fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.source)
+    }
+}
+
+impl SyncManifest {
+    /// Reads and validates the sync manifest at `path`
+    pub fn load(path: &Path) -> Result<Self, PusherError> {
+        let data = std::fs::read(path).map_err(|e| {
+            PusherError::ConfigError(format!(
+                "Failed to read sync manifest {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        let manifest: Self = serde_json::from_slice(&data).map_err(|e| {
+            PusherError::ConfigError(format!("Invalid sync manifest {}: {}", path.display(), e))
+        })?;
+        for entry in &manifest.entries {
+            if entry.schedule.next_after(Local::now()).is_none() {
+                return Err(PusherError::ConfigError(format!(
+                    "Schedule '{}' of {} never fires",
+                    entry.schedule,
+                    entry.name()
+                )));
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Registry hosts the entries pull from and push to
+    pub fn registries(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .flat_map(|entry| std::iter::once(&entry.source).chain(&entry.target))
+            .filter_map(|image| image.parse::<oci_client::Reference>().ok())
+            .map(|reference| reference.resolve_registry().to_string())
+            .collect()
+    }
+}
+
+/// Starts a task per entry submitting its jobs to `jobs` until `cancel` is cancelled
+pub(super) fn start(manifest: SyncManifest, jobs: &Arc<Jobs>, cancel: &CancellationToken) {
+    info!(
+        "🗓️  Scheduling {} sync(s) from the sync manifest",
+        manifest.entries.len()
+    );
+    for entry in manifest.entries {
+        let jitter = entry.jitter_secs.unwrap_or(manifest.jitter_secs);
+        let (jobs, cancel) = (jobs.clone(), cancel.clone());
+        tokio::spawn(async move { run(entry, jitter, &jobs, &cancel).await });
+    }
+}
+
+/// Submits a job for `entry` every time its schedule fires
+async fn run(entry: SyncEntry, jitter_secs: u64, jobs: &Arc<Jobs>, cancel: &CancellationToken) {
+    let mut last_job = None;
+    loop {
+        let now = Local::now();
+        let Some(next) = entry.schedule.next_after(now) else {
+            warn!("⚠️  Schedule of {} no longer fires", entry.name());
+            return;
+        };
+        let delay = (next - now).to_std().unwrap_or_default() + jitter(jitter_secs);
+        info!(
+            "🗓️  Next sync of {} at {}",
+            entry.name(),
+            (now + delay).format("%Y-%m-%d %H:%M:%S")
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = cancel.cancelled() => return,
+        }
+
+        if let Some(id) = last_job
+            && jobs.get(id).is_some_and(|job| !job.state.is_finished())
+        {
+            warn!(
+                "⏭️  Skipping scheduled sync of {}: job {} is still running",
+                entry.name(),
+                id
+            );
+            continue;
+        }
+        info!("🗓️  Scheduled sync of {}", entry.name());
+        let job = jobs.submit(JobRequest {
+            source: entry.source.clone(),
+            target: entry.target.clone(),
+            username: entry.username.clone(),
+            password: entry.password.clone(),
+        });
+        last_job = Some(job.id);
+    }
+}
+
+/// A random delay of up to `max_secs` seconds
+fn jitter(max_secs: u64) -> Duration {
+    if max_secs == 0 {
+        return Duration::ZERO;
+    }
+    // Randomly keyed by the standard library; no need for a random number crate
+    let random = std::collections::hash_map::RandomState::new().hash_one(Instant::now());
+    Duration::from_millis(random % (max_secs * 1000 + 1))
+}
@@ -0,0 +1,111 @@
+//! Tar archives on remote hosts (`ssh://[user@]host[:port]/path/image.tar`)
+//!
+//! The archive is read with the system `ssh` client, so `~/.ssh/config`,
+//! agents, jump hosts (`ProxyJump`) and known-hosts checks all apply as they
+//! would interactively. Useful in air-gapped setups where the archive lives
+//! on a jump host.
+
+use crate::PusherError;
+use crate::import::ImportOptions;
+use std::process::{Command, Stdio};
+use tracing::{debug, info};
+
+/// Prefix of remote archive locations
+pub const TRANSPORT_PREFIX: &str = "ssh://";
+
+/// A remote file addressed by an `ssh://` URL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemotePath {
+    /// `[user@]host` as passed to `ssh`
+    pub destination: String,
+    /// SSH port, if not the default
+    pub port: Option<u16>,
+    /// Absolute path on the remote host
+    pub path: String,
+}
+
+impl RemotePath {
+    /// Parses `ssh://[user@]host[:port]/path`
+    pub fn parse(url: &str) -> Result<Self, PusherError> {
+        let invalid = |reason: &str| {
+            PusherError::ConfigError(format!("Invalid SSH location {}: {}", url, reason))
+        };
+        let rest = url
+            .strip_prefix(TRANSPORT_PREFIX)
+            .ok_or_else(|| invalid("expected ssh://[user@]host[:port]/path"))?;
+        let (authority, path) = rest
+            .split_once('/')
+            .ok_or_else(|| invalid("missing remote path"))?;
+        if authority.is_empty() || path.is_empty() {
+            return Err(invalid("expected ssh://[user@]host[:port]/path"));
+        }
+        let (destination, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                Some(port.parse().map_err(|_| invalid("invalid port"))?),
+            ),
+            None => (authority, None),
+        };
+        Ok(Self {
+            destination: destination.to_string(),
+            port,
+            path: format!("/{}", path),
+        })
+    }
+
+    /// Builds the `ssh` invocation that writes the remote file to stdout
+    pub(crate) fn cat_command(&self) -> Command {
+        let mut command = Command::new("ssh");
+        if let Some(port) = self.port {
+            command.arg("-p").arg(port.to_string());
+        }
+        // The remote shell parses the command line, so the path is quoted for it
+        command
+            .arg("--")
+            .arg(&self.destination)
+            .arg(format!("cat -- '{}'", self.path.replace('\'', r"'\''")));
+        command
+    }
+}
+
+/// Imports the tar archive at an `ssh://` location into the cache as `image_name`
+///
+/// The archive is streamed from `ssh` straight into the importer, so it is
+/// never stored locally as a whole. `ssh` prompts and errors go to stderr.
+pub async fn import_remote_tar(
+    url: &str,
+    image_name: &str,
+    options: &ImportOptions,
+) -> Result<(), PusherError> {
+    let remote = RemotePath::parse(url)?;
+
+    info!("🔌 Streaming {} from {}...", remote.path, remote.destination);
+    let mut command = remote.cat_command();
+    debug!(command = ?command, "running ssh");
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| PusherError::TarError(format!("Failed to run ssh (is OpenSSH installed?): {}", e)))?;
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| PusherError::TarError("ssh has no stdout".to_string()))?;
+
+    let result = crate::import::import_tar(stdout, url, image_name, options).await;
+    // An incomplete stream is reported as a tar error; ssh's exit status says why
+    let status = tokio::task::spawn_blocking(move || child.wait())
+        .await
+        .map_err(|e| PusherError::TarError(format!("ssh task failed: {}", e)))??;
+    match result {
+        Ok(()) if !status.success() => Err(PusherError::TarError(format!(
+            "Reading {} over SSH failed ({})",
+            url, status
+        ))),
+        Err(e) if !status.success() => Err(PusherError::TarError(format!(
+            "Reading {} over SSH failed ({}): {}",
+            url, status, e
+        ))),
+        result => result,
+    }
+}
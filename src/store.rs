@@ -0,0 +1,287 @@
+use crate::PusherError;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use crate::image::digest::Hasher;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+/// Free space kept on the cache filesystem beyond what a pull or import writes
+pub const DISK_HEADROOM: u64 = 256 * 1024 * 1024; // 256MB
+
+/// Content-addressed storage for cached layer blobs
+///
+/// The pull path writes layers into a store and the push path reads them back
+/// out, so embedders can keep layers somewhere other than the local filesystem
+/// (a database, an object store, memory for tests) by implementing this trait
+/// and calling [`crate::cache::cache_image_with_store`] /
+/// [`crate::push::push_cached_image_with_store`]. Manifests, configs and the
+/// cache index always stay in the cache directory.
+///
+/// [`FsBlobStore`] is the default, one file per blob.
+pub trait BlobStore: Send + Sync {
+    /// Reader returned by [`BlobStore::stream`]
+    type Reader: AsyncRead + Send + Unpin;
+    /// Writer returned by [`BlobStore::writer`]
+    type Writer: AsyncWrite + Send + Unpin;
+
+    /// Returns the stored size of `digest`, or `None` if the blob is absent
+    fn size(&self, digest: &str) -> impl Future<Output = Result<Option<u64>, PusherError>> + Send;
+
+    /// Reads a whole blob into memory
+    fn get(&self, digest: &str) -> impl Future<Output = Result<Vec<u8>, PusherError>> + Send;
+
+    /// Opens a blob for streaming reads
+    fn stream(&self, digest: &str) -> impl Future<Output = Result<Self::Reader, PusherError>> + Send;
+
+    /// Opens a writer for the blob, which replaces the stored one once [persisted](BlobStore::persist)
+    fn writer(&self, digest: &str) -> impl Future<Output = Result<Self::Writer, PusherError>> + Send;
+
+    /// Checks whether `digest` is stored
+    fn exists(&self, digest: &str) -> impl Future<Output = Result<bool, PusherError>> + Send {
+        async move { Ok(self.size(digest).await?.is_some()) }
+    }
+
+    /// Hashes the stored content of `digest` with the algorithm it names, returning the actual digest
+    ///
+    /// Streams the blob, so memory use doesn't depend on its size.
+    fn compute_digest(&self, digest: &str) -> impl Future<Output = Result<String, PusherError>> + Send {
+        async move {
+            let mut reader = self.stream(digest).await?;
+            let mut hasher = Hasher::for_digest(digest);
+            let mut buffer = vec![0u8; 1024 * 1024];
+            loop {
+                let bytes_read = reader.read(&mut buffer).await?;
+                if bytes_read == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..bytes_read]);
+            }
+            Ok(hasher.finalize().to_string())
+        }
+    }
+
+    /// Makes a blob finished by its writer durable and visible, before the cache index references it
+    ///
+    /// Stores that are durable once the writer is shut down don't need to do anything, which is the default.
+    fn persist(&self, digest: &str) -> impl Future<Output = Result<(), PusherError>> + Send {
+        let _ = digest;
+        async { Ok(()) }
+    }
+
+    /// Checks that `bytes` more can be stored, before a pull starts writing
+    ///
+    /// Stores without a meaningful capacity accept everything, which is the default.
+    fn ensure_capacity(&self, bytes: u64) -> impl Future<Output = Result<(), PusherError>> + Send {
+        let _ = bytes;
+        async { Ok(()) }
+    }
+
+    /// Takes `digest` from blobs shared with other caches instead of downloading it,
+    /// returning whether it was there with `size` bytes
+    ///
+    /// Stores that don't share blobs never have it, which is the default.
+    fn adopt(&self, digest: &str, size: u64) -> impl Future<Output = Result<bool, PusherError>> + Send {
+        let _ = (digest, size);
+        async { Ok(false) }
+    }
+
+    /// Stores a blob held in memory
+    fn put(&self, digest: &str, data: &[u8]) -> impl Future<Output = Result<(), PusherError>> + Send {
+        async move {
+            let mut writer = self.writer(digest).await?;
+            writer.write_all(data).await?;
+            writer.shutdown().await?;
+            self.persist(digest).await
+        }
+    }
+}
+
+/// Filesystem blob store: one file per blob, named after the digest with `:` replaced by `_`
+///
+/// This is the layout the tool has always used inside `.cache/{image}/`.
+#[derive(Debug, Clone)]
+pub struct FsBlobStore {
+    root: PathBuf,
+    shared: Option<PathBuf>,
+}
+
+impl FsBlobStore {
+    /// Creates a store keeping blobs directly inside `root`
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            shared: None,
+        }
+    }
+
+    /// Shares blobs with other stores through hard links in `dir`
+    ///
+    /// Persisted blobs are linked into `dir`, and [`BlobStore::adopt`] links
+    /// them from there, so each blob takes disk space once (see [`crate::namespace`]).
+    pub fn sharing(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.shared = Some(dir.into());
+        self
+    }
+
+    /// Returns the file path of `digest`
+    pub fn path(&self, digest: &str) -> PathBuf {
+        self.root.join(crate::image::digest::file_name(digest))
+    }
+
+    /// Returns the directory blobs are stored in
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+}
+
+impl BlobStore for FsBlobStore {
+    type Reader = tokio::fs::File;
+    type Writer = tokio::fs::File;
+
+    async fn size(&self, digest: &str) -> Result<Option<u64>, PusherError> {
+        match tokio::fs::metadata(self.path(digest)).await {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(PusherError::cache_error(format!(
+                "Failed to get layer metadata {}: {}",
+                digest, e
+            ))),
+        }
+    }
+
+    async fn get(&self, digest: &str) -> Result<Vec<u8>, PusherError> {
+        tokio::fs::read(self.path(digest)).await.map_err(|e| {
+            PusherError::cache_error(format!("Failed to read cached layer {}: {}", digest, e))
+        })
+    }
+
+    async fn stream(&self, digest: &str) -> Result<Self::Reader, PusherError> {
+        tokio::fs::File::open(self.path(digest)).await.map_err(|e| {
+            PusherError::cache_error(format!("Failed to open cached layer {}: {}", digest, e))
+        })
+    }
+
+    async fn writer(&self, digest: &str) -> Result<Self::Writer, PusherError> {
+        // Written beside the blob and renamed over it by `persist`, so an interrupted
+        // download never leaves a truncated blob, and other links to the old content keep it
+        let temp = temp_path(&self.path(digest));
+        let _ = tokio::fs::remove_file(&temp).await;
+        tokio::fs::File::create(&temp).await.map_err(|e| {
+            PusherError::cache_error(format!("Failed to create layer file {}: {}", digest, e))
+        })
+    }
+
+    async fn persist(&self, digest: &str) -> Result<(), PusherError> {
+        let temp = temp_path(&self.path(digest));
+        let file = tokio::fs::File::open(&temp).await;
+        file?.sync_all().await.map_err(|e| {
+            PusherError::cache_error(format!("Failed to sync layer file {}: {}", digest, e))
+        })?;
+        rename_durable(&temp, &self.path(digest)).await.map_err(|e| {
+            PusherError::cache_error(format!("Failed to store layer file {}: {}", digest, e))
+        })?;
+        // Best effort: another store only downloads the blob itself without it
+        if let Some(shared) = &self.shared {
+            let link = shared.join(crate::image::digest::file_name(digest));
+            let linked = match tokio::fs::create_dir_all(shared).await {
+                Ok(()) => {
+                    let _ = tokio::fs::remove_file(&link).await;
+                    tokio::fs::hard_link(self.path(digest), &link).await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = linked {
+                debug!(error = %e, digest, "failed to share layer");
+            }
+        }
+        Ok(())
+    }
+
+    async fn adopt(&self, digest: &str, size: u64) -> Result<bool, PusherError> {
+        let Some(shared) = &self.shared else {
+            return Ok(false);
+        };
+        let source = shared.join(crate::image::digest::file_name(digest));
+        if !matches!(tokio::fs::metadata(&source).await, Ok(metadata) if metadata.len() == size) {
+            return Ok(false);
+        }
+        let _ = tokio::fs::remove_file(self.path(digest)).await;
+        match tokio::fs::hard_link(&source, self.path(digest)).await {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                debug!(error = %e, digest, "failed to link shared layer");
+                Ok(false)
+            }
+        }
+    }
+
+    async fn ensure_capacity(&self, bytes: u64) -> Result<(), PusherError> {
+        ensure_free_space(&self.root, bytes)
+    }
+}
+
+/// Returns the temporary path `path` is written to before it is renamed into place
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    PathBuf::from(temp)
+}
+
+/// Writes `contents` to `path` so that a crash leaves either the old or the new file
+///
+/// The data goes to [`temp_path`] first, is synced to disk and then renamed
+/// over `path` (see [`rename_durable`]).
+pub async fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    let temp = temp_path(path);
+    let mut file = tokio::fs::File::create(&temp).await?;
+    file.write_all(contents.as_ref()).await?;
+    file.sync_all().await?;
+    drop(file);
+    rename_durable(&temp, path).await
+}
+
+/// Renames the synced file `from` to `to` and syncs the directory, so the rename survives a crash
+pub async fn rename_durable(from: &Path, to: &Path) -> std::io::Result<()> {
+    tokio::fs::rename(from, to).await?;
+    sync_dir(to.parent().unwrap_or(Path::new("."))).await
+}
+
+/// Syncs the entries of `dir` to disk (a no-op where directories can't be opened)
+async fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        tokio::fs::File::open(dir).await?.sync_all().await?;
+    }
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Fails unless the filesystem holding `dir` has `required` bytes plus [`DISK_HEADROOM`] free
+///
+/// Checked before pulls and imports so they stop with a clear message
+/// instead of running out of space halfway through. `dir` doesn't have to
+/// exist yet; its closest existing ancestor is checked. If free space can't
+/// be determined the check passes.
+pub fn ensure_free_space(dir: &Path, required: u64) -> Result<(), PusherError> {
+    let Some(existing) = dir.ancestors().find(|path| path.exists()) else {
+        return Ok(());
+    };
+    let available = match fs4::available_space(existing) {
+        Ok(available) => available,
+        Err(e) => {
+            debug!(path = %existing.display(), error = %e, "free space unknown, skipping check");
+            return Ok(());
+        }
+    };
+    debug!(path = %existing.display(), required, available, "disk space check");
+    if available < required.saturating_add(DISK_HEADROOM) {
+        return Err(PusherError::InsufficientDiskSpace {
+            path: dir.to_path_buf(),
+            required,
+            available,
+        });
+    }
+    Ok(())
+}
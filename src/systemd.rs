@@ -0,0 +1,128 @@
+//! systemd service integration: `Type=notify` readiness, watchdog and SIGTERM
+//!
+//! Mirror hosts run transfers from systemd units. With `Type=notify`, systemd
+//! passes a socket in `NOTIFY_SOCKET` and waits for `READY=1` before it
+//! considers the unit started; with `WatchdogSec=` it also sets
+//! `WATCHDOG_USEC` and restarts the service unless `WATCHDOG=1` arrives in
+//! time. [`start`] takes care of both and keeps the unit's status line
+//! (`systemctl status`) updated with the transfer totals. Outside systemd
+//! the variables are unset and everything here does nothing.
+//!
+//! Units are stopped with SIGTERM, which [`terminated`] waits for so the
+//! CLI can cancel transfers as gracefully as on Ctrl-C.
+
+use crate::metrics;
+use std::time::Duration;
+use tracing::debug;
+
+/// Sends `state` (e.g. `READY=1`, newline-separated assignments) to the service manager
+///
+/// Returns whether it was delivered; `false` when not running under a
+/// `Type=notify` unit.
+///
+/// # Examples
+///
+/// ```
+/// # use docker_image_pusher::systemd;
+/// // Shown by `systemctl status`; a no-op outside systemd
+/// systemd::notify("STATUS=Mirroring nginx:latest");
+/// ```
+pub fn notify(state: &str) -> bool {
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        return false;
+    };
+    match send(&socket.to_string_lossy(), state) {
+        Ok(()) => true,
+        Err(e) => {
+            debug!(error = %e, "sd_notify failed");
+            false
+        }
+    }
+}
+
+#[cfg(unix)]
+fn send(socket: &str, state: &str) -> std::io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+    let datagram = UnixDatagram::unbound()?;
+    // A leading '@' names a socket in the abstract namespace
+    #[cfg(target_os = "linux")]
+    if let Some(name) = socket.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+        let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        return datagram
+            .send_to_addr(state.as_bytes(), &address)
+            .map(|_| ());
+    }
+    datagram.send_to(state.as_bytes(), socket).map(|_| ())
+}
+
+#[cfg(not(unix))]
+fn send(_socket: &str, _state: &str) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Returns how often systemd expects a `WATCHDOG=1`, if the unit has a watchdog for this process
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID")
+        && pid.parse() != Ok(std::process::id())
+    {
+        return None;
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Reports the service as ready and, if the unit has a watchdog, keeps it fed
+///
+/// Pings go out at half the watchdog interval, together with a `STATUS=`
+/// line summarizing [`metrics::live_stats`]. They come from a task on the
+/// runtime, so a stalled runtime (rather than a slow transfer, which has
+/// its own timeouts) is what gets the service restarted. Must be called
+/// from within a tokio runtime.
+pub fn start() {
+    if !notify("READY=1\nSTATUS=Starting") {
+        return;
+    }
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    debug!(
+        interval_ms = interval.as_millis() as u64,
+        "systemd watchdog enabled"
+    );
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval / 2);
+        loop {
+            ticks.tick().await;
+            notify(&format!("WATCHDOG=1\nSTATUS={}", status_line()));
+        }
+    });
+}
+
+/// One-line summary of the transfer totals for `systemctl status`
+fn status_line() -> String {
+    let stats = metrics::live_stats();
+    format!(
+        "{} layers uploaded ({} bytes), {} skipped, {} bytes downloaded, {} active transfers, concurrency {}",
+        stats.layers_uploaded,
+        stats.bytes_uploaded,
+        stats.layers_skipped,
+        stats.bytes_downloaded,
+        stats.active_transfers,
+        stats.concurrency
+    )
+}
+
+/// Completes when the process receives SIGTERM; never on platforms without it
+pub async fn terminated() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        if let Ok(mut sigterm) = signal(SignalKind::terminate())
+            && sigterm.recv().await.is_some()
+        {
+            return;
+        }
+    }
+    std::future::pending::<()>().await
+}
@@ -0,0 +1,70 @@
+//! OpenTelemetry trace export (enabled with the `otel` cargo feature)
+//!
+//! Every pull/push/import operation and every layer transfer is already a
+//! `tracing` span carrying the registry host, digest, size and retry attempt.
+//! This module bridges those spans to an OTLP/HTTP collector so transfers show
+//! up in existing distributed tracing dashboards (Jaeger, Tempo, Honeycomb, ...).
+
+use crate::PusherError;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use tracing::Subscriber;
+use tracing_subscriber::Layer;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Service name reported on exported spans
+const SERVICE_NAME: &str = "docker-image-pusher";
+
+/// Flushes and shuts down the tracer provider when dropped
+///
+/// Keep this alive for the duration of the program so buffered spans are
+/// exported before the process exits.
+pub struct TelemetryGuard {
+    provider: SdkTracerProvider,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        let _ = self.provider.shutdown();
+    }
+}
+
+/// Creates a tracing layer exporting spans to the OTLP/HTTP collector at `endpoint`
+///
+/// # Arguments
+///
+/// * `endpoint` - Collector traces URL (e.g. "http://localhost:4318/v1/traces")
+///
+/// # Returns
+///
+/// The layer to install and a guard that flushes spans on drop
+pub fn otlp_layer<S>(
+    endpoint: &str,
+) -> Result<(impl Layer<S>, TelemetryGuard), PusherError>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|e| {
+            PusherError::ConfigError(format!("Failed to create OTLP exporter: {}", e))
+        })?;
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            opentelemetry_sdk::Resource::builder()
+                .with_service_name(SERVICE_NAME)
+                .build(),
+        )
+        .build();
+    let tracer = provider.tracer(SERVICE_NAME);
+
+    Ok((
+        tracing_opentelemetry::layer().with_tracer(tracer),
+        TelemetryGuard { provider },
+    ))
+}
@@ -0,0 +1,632 @@
+//! In-process Registry v2 server for tests (enabled with the `test-util` cargo feature)
+//!
+//! [`MockRegistry`] listens on a loopback port and implements the parts of
+//! the distribution API this crate and oci-client use: the `/v2/` ping,
+//! blob existence checks and downloads, upload sessions (monolithic,
+//! chunked and cross-repository mounts), manifests by tag or digest and tag
+//! listing. Everything is kept in memory, so pushes and pulls can be
+//! exercised without Docker or network access. No authentication is
+//! required; credentials sent by the client are ignored.
+//!
+//! To exercise retries, token refresh and resumed uploads, the registry can
+//! fail requests on purpose: [`MockRegistry::inject_fault`] queues a
+//! [`Fault`] for the next request, and [`MockRegistry::enable_chaos`] fails a
+//! random share of requests from a seeded generator, so a failing CI run can
+//! be replayed with the same seed.
+//!
+//! Loopback registries are reached over plain HTTP (see
+//! [`crate::registry::base_url`]); use [`MockRegistry::client`] for the
+//! `oci_client::Client` passed to pushes and pulls.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), docker_image_pusher::PusherError> {
+//! use docker_image_pusher::test_util::MockRegistry;
+//!
+//! let registry = MockRegistry::start().await?;
+//! let digest = registry.insert_blob("app", b"layer bytes");
+//! let target = registry.reference("app:v1");
+//! // ... push_cached_image(&registry.client(), "app:v1", &target, ...)
+//! assert!(registry.blob("app", &digest).is_some());
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::PusherError;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// Media type reported for manifests pushed without a `Content-Type`
+const DEFAULT_MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+
+/// A failure the mock registry answers a request with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// `401 Unauthorized` with a challenge, as for an expired token
+    Unauthorized,
+    /// `429 Too Many Requests` with `Retry-After: 0`
+    TooManyRequests,
+    /// `500 Internal Server Error`
+    ServerError,
+    /// The connection is closed without a response
+    ConnectionReset,
+    /// The request is handled normally, but answered after a delay
+    Delay(Duration),
+}
+
+/// Settings for failing random requests
+#[derive(Debug, Clone)]
+pub struct ChaosSettings {
+    /// Seed of the generator deciding which requests fail
+    pub seed: u64,
+    /// Share of requests to fail, between 0.0 and 1.0
+    pub rate: f64,
+    /// Faults to pick from, with equal probability
+    pub faults: Vec<Fault>,
+}
+
+impl Default for ChaosSettings {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            rate: 0.1,
+            faults: vec![
+                Fault::Unauthorized,
+                Fault::TooManyRequests,
+                Fault::ServerError,
+                Fault::ConnectionReset,
+                Fault::Delay(Duration::from_millis(200)),
+            ],
+        }
+    }
+}
+
+/// Seeded random fault selection (splitmix64)
+struct Chaos {
+    settings: ChaosSettings,
+    state: u64,
+}
+
+impl Chaos {
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Decides whether the next request fails, and how
+    fn roll(&mut self) -> Option<Fault> {
+        let sample = (self.next() >> 11) as f64 / (1u64 << 53) as f64;
+        if self.settings.faults.is_empty() || sample >= self.settings.rate {
+            return None;
+        }
+        let index = self.next() % self.settings.faults.len() as u64;
+        Some(self.settings.faults[index as usize])
+    }
+}
+
+/// Contents of the mock registry
+#[derive(Default)]
+struct State {
+    /// Blob contents by repository and digest
+    blobs: HashMap<(String, String), Vec<u8>>,
+    /// Media type and contents of manifests by repository and tag or digest
+    manifests: HashMap<(String, String), (String, Vec<u8>)>,
+    /// Open upload sessions: repository and data received so far
+    uploads: HashMap<String, (String, Vec<u8>)>,
+    /// Counter for upload session ids
+    next_upload: u64,
+    /// `METHOD path` of every request received
+    requests: Vec<String>,
+    /// Faults for the next requests, in order
+    faults: VecDeque<Fault>,
+    /// Random faults, when enabled
+    chaos: Option<Chaos>,
+}
+
+/// A parsed HTTP request
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+/// An HTTP response to serialize
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    fn header(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+
+    fn body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+
+    /// Replaces the status of a successful response
+    fn with_status(mut self, status: u16) -> Self {
+        if self.status < 400 {
+            self.status = status;
+        }
+        self
+    }
+
+    /// A registry error response with a `{"errors": [...]}` body
+    fn error(status: u16, code: &str, message: &str) -> Self {
+        let body = serde_json::json!({ "errors": [{ "code": code, "message": message }] });
+        Self::new(status)
+            .header("Content-Type", "application/json")
+            .body(body.to_string().into_bytes())
+    }
+}
+
+/// In-memory Registry v2 server on a loopback port
+///
+/// The server stops when the value is dropped.
+pub struct MockRegistry {
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+    server: JoinHandle<()>,
+}
+
+impl MockRegistry {
+    /// Starts a server on a free port of 127.0.0.1
+    pub async fn start() -> Result<Self, PusherError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(State::default()));
+        let shared = state.clone();
+        let server = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve_connection(stream, shared.clone()));
+            }
+        });
+        Ok(Self {
+            address,
+            state,
+            server,
+        })
+    }
+
+    /// Returns the `host:port` of the registry
+    pub fn address(&self) -> String {
+        self.address.to_string()
+    }
+
+    /// Returns a full image reference on this registry, e.g. `127.0.0.1:1234/app:v1`
+    pub fn reference(&self, name: &str) -> String {
+        format!("{}/{}", self.address, name)
+    }
+
+    /// Returns an `oci_client::Client` that talks to this registry over plain HTTP
+    pub fn client(&self) -> oci_client::Client {
+        oci_client::Client::new(oci_client::client::ClientConfig {
+            protocol: oci_client::client::ClientProtocol::HttpsExcept(vec![self.address()]),
+            platform_resolver: Some(Box::new(oci_client::client::linux_amd64_resolver)),
+            ..Default::default()
+        })
+    }
+
+    /// Returns the contents of a blob, if the repository has it
+    pub fn blob(&self, repository: &str, digest: &str) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        state
+            .blobs
+            .get(&(repository.to_string(), digest.to_string()))
+            .cloned()
+    }
+
+    /// Returns the contents of a manifest by tag or digest
+    pub fn manifest(&self, repository: &str, reference: &str) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        state
+            .manifests
+            .get(&(repository.to_string(), reference.to_string()))
+            .map(|(_, data)| data.clone())
+    }
+
+    /// Stores a blob, e.g. to seed an image to pull, returning its digest
+    pub fn insert_blob(&self, repository: &str, data: &[u8]) -> String {
+        let digest = sha256_digest(data);
+        let mut state = self.state.lock().unwrap();
+        state
+            .blobs
+            .insert((repository.to_string(), digest.clone()), data.to_vec());
+        digest
+    }
+
+    /// Stores a manifest under a tag (and its digest), returning the digest
+    pub fn insert_manifest(&self, repository: &str, tag: &str, media_type: &str, data: &[u8]) -> String {
+        let mut state = self.state.lock().unwrap();
+        state.put_manifest(repository, tag, media_type, data)
+    }
+
+    /// Returns `METHOD path` for every request received so far
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Answers the next request with `fault`
+    ///
+    /// Faults queue up, one per request; the `/v2/` ping used for the
+    /// authentication handshake is never failed.
+    pub fn inject_fault(&self, fault: Fault) {
+        self.state.lock().unwrap().faults.push_back(fault);
+    }
+
+    /// Fails a random share of requests until [`MockRegistry::disable_chaos`]
+    ///
+    /// Which requests fail only depends on the seed and the order requests
+    /// arrive in, so runs are reproducible with a single upload at a time.
+    /// Queued faults from [`MockRegistry::inject_fault`] take precedence.
+    pub fn enable_chaos(&self, settings: ChaosSettings) {
+        let state = settings.seed;
+        self.state.lock().unwrap().chaos = Some(Chaos { settings, state });
+    }
+
+    /// Stops failing random requests
+    pub fn disable_chaos(&self) {
+        self.state.lock().unwrap().chaos = None;
+    }
+}
+
+impl Drop for MockRegistry {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// Computes the `sha256:` digest of `data`
+fn sha256_digest(data: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(data))
+}
+
+/// Serves requests on one connection until the client closes it
+async fn serve_connection(stream: TcpStream, state: Arc<Mutex<State>>) {
+    let mut stream = BufReader::new(stream);
+    while let Ok(Some(request)) = read_request(&mut stream).await {
+        let close = request
+            .headers
+            .get("connection")
+            .is_some_and(|v| v.eq_ignore_ascii_case("close"));
+        let head_only = request.method == "HEAD";
+        let (fault, response) = {
+            let mut state = state.lock().unwrap();
+            let fault = state.next_fault(&request);
+            let response = match fault {
+                None | Some(Fault::Delay(_)) => state.handle(request),
+                Some(Fault::Unauthorized) => Response::error(401, "UNAUTHORIZED", "authentication required")
+                    .header("WWW-Authenticate", "Basic realm=\"mock\""),
+                Some(Fault::TooManyRequests) => {
+                    Response::error(429, "TOOMANYREQUESTS", "too many requests").header("Retry-After", 0)
+                }
+                Some(Fault::ServerError) | Some(Fault::ConnectionReset) => {
+                    Response::error(500, "UNKNOWN", "injected failure")
+                }
+            };
+            (fault, response)
+        };
+        match fault {
+            Some(Fault::ConnectionReset) => return,
+            Some(Fault::Delay(delay)) => tokio::time::sleep(delay).await,
+            _ => {}
+        }
+
+        let mut head = format!("HTTP/1.1 {} Mock\r\n", response.status);
+        for (name, value) in &response.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!("Content-Length: {}\r\n\r\n", response.body.len()));
+        let stream = stream.get_mut();
+        if stream.write_all(head.as_bytes()).await.is_err()
+            || (!head_only && stream.write_all(&response.body).await.is_err())
+            || close
+        {
+            return;
+        }
+    }
+}
+
+/// Reads one request, returning `None` when the connection was closed
+async fn read_request(stream: &mut BufReader<TcpStream>) -> std::io::Result<Option<Request>> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).await? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default().to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        if stream.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let mut body = Vec::new();
+    if headers
+        .get("transfer-encoding")
+        .is_some_and(|v| v.eq_ignore_ascii_case("chunked"))
+    {
+        loop {
+            line.clear();
+            stream.read_line(&mut line).await?;
+            let size = usize::from_str_radix(line.trim().split(';').next().unwrap_or("0"), 16)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            let start = body.len();
+            body.resize(start + size, 0);
+            stream.read_exact(&mut body[start..]).await?;
+            line.clear();
+            stream.read_line(&mut line).await?;
+            if size == 0 {
+                break;
+            }
+        }
+    } else if let Some(length) = headers.get("content-length").and_then(|v| v.parse().ok()) {
+        body.resize(length, 0);
+        stream.read_exact(&mut body).await?;
+    }
+
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), parse_query(query)),
+        None => (target, HashMap::new()),
+    };
+    Ok(Some(Request {
+        method,
+        path,
+        query,
+        headers,
+        body,
+    }))
+}
+
+/// Parses `a=1&b=2`, decoding percent escapes
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (percent_decode(key), percent_decode(value)))
+        .collect()
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (escaped, bytes[i]) {
+            (Some(byte), _) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            (None, b'+') => decoded.push(b' '),
+            (None, byte) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+impl State {
+    /// Picks the fault to answer `request` with, if any, and logs the request
+    fn next_fault(&mut self, request: &Request) -> Option<Fault> {
+        if request.path == "/v2/" || request.path == "/v2" {
+            return None;
+        }
+        let fault = match self.faults.pop_front() {
+            Some(fault) => Some(fault),
+            None => self.chaos.as_mut().and_then(Chaos::roll),
+        };
+        // Delayed requests are still handled, and logged, by `handle`
+        if let Some(fault) = fault.filter(|fault| !matches!(fault, Fault::Delay(_))) {
+            self.requests
+                .push(format!("{} {} ({:?})", request.method, request.path, fault));
+        }
+        fault
+    }
+
+    /// Routes a request to the matching endpoint
+    fn handle(&mut self, request: Request) -> Response {
+        self.requests
+            .push(format!("{} {}", request.method, request.path));
+        let method = request.method.as_str();
+
+        if request.path == "/v2/" || request.path == "/v2" {
+            return Response::new(200)
+                .header("Docker-Distribution-API-Version", "registry/2.0")
+                .header("Content-Type", "application/json")
+                .body(b"{}".to_vec());
+        }
+        let Some(path) = request.path.strip_prefix("/v2/") else {
+            return Response::error(404, "NAME_UNKNOWN", "not a registry path");
+        };
+
+        if let Some((repository, session)) = path.rsplit_once("/blobs/uploads") {
+            let session = session.trim_start_matches('/').to_string();
+            let repository = repository.to_string();
+            return match (method, session.is_empty()) {
+                ("POST", true) => self.start_upload(&repository, &request),
+                ("PATCH", false) => self.append_upload(&repository, &session, &request.body),
+                ("PUT", false) => self.finish_upload(&repository, &session, &request),
+                ("GET", false) => self.upload_status(&repository, &session),
+                ("DELETE", false) => match self.uploads.remove(&session) {
+                    Some(_) => Response::new(204),
+                    None => Response::error(404, "BLOB_UPLOAD_UNKNOWN", "upload unknown"),
+                },
+                _ => Response::error(405, "UNSUPPORTED", "method not allowed"),
+            };
+        }
+        if let Some((repository, digest)) = path.rsplit_once("/blobs/") {
+            let key = (repository.to_string(), digest.to_string());
+            return match (method, self.blobs.get(&key)) {
+                ("GET" | "HEAD", Some(data)) => Response::new(200)
+                    .header("Content-Type", "application/octet-stream")
+                    .header("Docker-Content-Digest", digest)
+                    .body(data.clone()),
+                ("DELETE", Some(_)) => {
+                    self.blobs.remove(&key);
+                    Response::new(202)
+                }
+                (_, None) => Response::error(404, "BLOB_UNKNOWN", "blob unknown to registry"),
+                _ => Response::error(405, "UNSUPPORTED", "method not allowed"),
+            };
+        }
+        if let Some((repository, reference)) = path.rsplit_once("/manifests/") {
+            let key = (repository.to_string(), reference.to_string());
+            return match method {
+                "PUT" => {
+                    if reference.starts_with("sha256:") && sha256_digest(&request.body) != reference {
+                        return Response::error(400, "DIGEST_INVALID", "manifest digest mismatch");
+                    }
+                    let media_type = request
+                        .headers
+                        .get("content-type")
+                        .map(String::as_str)
+                        .unwrap_or(DEFAULT_MANIFEST_MEDIA_TYPE);
+                    let digest = self.put_manifest(repository, reference, media_type, &request.body);
+                    Response::new(201)
+                        .header("Location", format!("/v2/{}/manifests/{}", repository, digest))
+                        .header("Docker-Content-Digest", digest)
+                }
+                "GET" | "HEAD" => match self.manifests.get(&key) {
+                    Some((media_type, data)) => Response::new(200)
+                        .header("Content-Type", media_type)
+                        .header("Docker-Content-Digest", sha256_digest(data))
+                        .body(data.clone()),
+                    None => Response::error(404, "MANIFEST_UNKNOWN", "manifest unknown"),
+                },
+                "DELETE" => match self.manifests.remove(&key) {
+                    Some(_) => Response::new(202),
+                    None => Response::error(404, "MANIFEST_UNKNOWN", "manifest unknown"),
+                },
+                _ => Response::error(405, "UNSUPPORTED", "method not allowed"),
+            };
+        }
+        if let Some(repository) = path.strip_suffix("/tags/list") {
+            let mut tags: Vec<&str> = self
+                .manifests
+                .keys()
+                .filter(|(repo, reference)| repo == repository && !reference.starts_with("sha256:"))
+                .map(|(_, tag)| tag.as_str())
+                .collect();
+            tags.sort_unstable();
+            let body = serde_json::json!({ "name": repository, "tags": tags });
+            return Response::new(200)
+                .header("Content-Type", "application/json")
+                .body(body.to_string().into_bytes());
+        }
+        Response::error(404, "NAME_UNKNOWN", "unknown endpoint")
+    }
+
+    /// Stores a manifest under `reference` and its digest
+    fn put_manifest(&mut self, repository: &str, reference: &str, media_type: &str, data: &[u8]) -> String {
+        let digest = sha256_digest(data);
+        for key in [reference, digest.as_str()] {
+            self.manifests.insert(
+                (repository.to_string(), key.to_string()),
+                (media_type.to_string(), data.to_vec()),
+            );
+        }
+        digest
+    }
+
+    /// Stores a completed blob if it matches `digest`
+    fn store_blob(&mut self, repository: &str, digest: &str, data: Vec<u8>) -> Response {
+        if sha256_digest(&data) != digest {
+            return Response::error(400, "DIGEST_INVALID", "provided digest did not match uploaded content");
+        }
+        self.blobs
+            .insert((repository.to_string(), digest.to_string()), data);
+        Response::new(201)
+            .header("Location", format!("/v2/{}/blobs/{}", repository, digest))
+            .header("Docker-Content-Digest", digest)
+    }
+
+    /// `POST /blobs/uploads/`: mounts, monolithic uploads, or a new session
+    fn start_upload(&mut self, repository: &str, request: &Request) -> Response {
+        if let (Some(digest), Some(from)) = (request.query.get("mount"), request.query.get("from"))
+            && let Some(data) = self.blobs.get(&(from.clone(), digest.clone())).cloned()
+        {
+            return self.store_blob(repository, digest, data);
+        }
+        if let Some(digest) = request.query.get("digest") {
+            return self.store_blob(repository, digest, request.body.clone());
+        }
+
+        self.next_upload += 1;
+        let session = format!("upload-{}", self.next_upload);
+        self.uploads
+            .insert(session.clone(), (repository.to_string(), request.body.clone()));
+        self.upload_status(repository, &session).with_status(202)
+    }
+
+    /// `PATCH`: appends a chunk to a session
+    fn append_upload(&mut self, repository: &str, session: &str, chunk: &[u8]) -> Response {
+        match self.uploads.get_mut(session) {
+            Some((_, data)) => data.extend_from_slice(chunk),
+            None => return Response::error(404, "BLOB_UPLOAD_UNKNOWN", "upload unknown"),
+        }
+        self.upload_status(repository, session).with_status(202)
+    }
+
+    /// `PUT ?digest=`: completes a session with an optional final chunk
+    fn finish_upload(&mut self, repository: &str, session: &str, request: &Request) -> Response {
+        let Some(digest) = request.query.get("digest") else {
+            return Response::error(400, "DIGEST_INVALID", "digest parameter missing");
+        };
+        let Some((_, mut data)) = self.uploads.remove(session) else {
+            return Response::error(404, "BLOB_UPLOAD_UNKNOWN", "upload unknown");
+        };
+        data.extend_from_slice(&request.body);
+        self.store_blob(repository, digest, data)
+    }
+
+    /// `GET`: reports how much of a session was received
+    fn upload_status(&self, repository: &str, session: &str) -> Response {
+        let Some((_, data)) = self.uploads.get(session) else {
+            return Response::error(404, "BLOB_UPLOAD_UNKNOWN", "upload unknown");
+        };
+        Response::new(204)
+            .header("Location", format!("/v2/{}/blobs/uploads/{}", repository, session))
+            .header("Range", format!("0-{}", data.len().saturating_sub(1)))
+            .header("Docker-Upload-UUID", session)
+    }
+}
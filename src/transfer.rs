@@ -0,0 +1,362 @@
+use crate::cache::{self, PullOptions};
+use crate::credentials::{AuthChain, AuthProvider};
+use crate::image;
+use crate::popularity::LayerPopularity;
+use crate::progress::{ProgressEvent, ProgressReporter, ProgressStream};
+use crate::push::{self, PushOptions};
+use crate::report::TransferReport;
+use crate::store::FsBlobStore;
+use crate::{CACHE_DIR, PusherError};
+use oci_client::{Client, Reference};
+use oci_client::secrets::RegistryAuth;
+use std::path::PathBuf;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+/// Entry point of the high-level transfer API
+///
+/// Copies an image from one registry to another through the local cache
+/// without having to wire up the client, cache and push options by hand:
+///
+/// ```no_run
+/// # async fn copy() -> Result<(), docker_image_pusher::PusherError> {
+/// use docker_image_pusher::ImageTransfer;
+///
+/// let report = ImageTransfer::pull("nginx:latest")
+///     .push_to("registry.example.com/nginx:latest")
+///     .with_auth("user", "pass")
+///     .with_cache("/var/cache/pusher")
+///     .run()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ImageTransfer;
+
+impl ImageTransfer {
+    /// Starts a transfer that pulls `source` into the local cache
+    pub fn pull(source: impl Into<String>) -> TransferBuilder {
+        TransferBuilder {
+            source: source.into(),
+            target: None,
+            auth: RegistryAuth::Anonymous,
+            credentials: AuthChain::new(),
+            cache_dir: PathBuf::from(CACHE_DIR),
+            progress: ProgressReporter::default(),
+            cancel: CancellationToken::new(),
+            popularity: None,
+            ledger_ttl_secs: None,
+        }
+    }
+}
+
+/// Configures and runs a pull, optionally followed by a push
+///
+/// Created by [`ImageTransfer::pull`].
+pub struct TransferBuilder {
+    source: String,
+    target: Option<String>,
+    auth: RegistryAuth,
+    credentials: AuthChain,
+    cache_dir: PathBuf,
+    progress: ProgressReporter,
+    cancel: CancellationToken,
+    popularity: Option<LayerPopularity>,
+    ledger_ttl_secs: Option<u64>,
+}
+
+impl TransferBuilder {
+    /// Pushes the pulled image to `target` (full registry path with tag)
+    pub fn push_to(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    /// Authenticates to the target registry with username and password
+    pub fn with_auth(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.auth = RegistryAuth::Basic(username.into(), password.into());
+        self
+    }
+
+    /// Authenticates to the target registry with a pre-obtained bearer token
+    ///
+    /// The token is sent as is, without a login, for registries that only
+    /// hand out short-lived tokens (e.g. GitLab CI JWTs).
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.auth = RegistryAuth::Bearer(token.into());
+        self
+    }
+
+    /// Asks `provider` for the credentials of the source registry, and of the
+    /// target registry unless [`TransferBuilder::with_auth`] or
+    /// [`TransferBuilder::with_token`] set them
+    ///
+    /// Several providers are asked in the order they were added (see
+    /// [`crate::credentials::AuthChain`]); without any, both are accessed anonymously.
+    /// They are asked again when a registry rejects a fresh token, so
+    /// credentials rotated during the transfer are picked up.
+    pub fn with_auth_provider(mut self, provider: impl AuthProvider + 'static) -> Self {
+        self.credentials = self.credentials.with(provider);
+        self
+    }
+
+    /// Uses `dir` instead of `.cache` as the local image cache
+    pub fn with_cache(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cache_dir = dir.into();
+        self
+    }
+
+    /// Registers a callback receiving [`ProgressEvent`]s for both phases
+    pub fn on_progress(mut self, callback: Box<dyn Fn(ProgressEvent) + Send + Sync>) -> Self {
+        self.progress = ProgressReporter::new(callback);
+        self
+    }
+
+    /// Returns a stream of [`ProgressEvent`]s for both phases, replacing any callback
+    ///
+    /// The stream ends when [`TransferBuilder::run`] completes.
+    pub fn progress_stream(&mut self) -> ProgressStream {
+        let (reporter, stream) = ProgressReporter::stream();
+        self.progress = reporter;
+        stream
+    }
+
+    /// Stops the transfer when `token` is cancelled
+    ///
+    /// Running chunked uploads abort their upload sessions before `run` returns
+    /// [`PusherError::Cancelled`].
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancel = token;
+        self
+    }
+
+    /// Transfers the layers that other pending images of the target repository
+    /// share first, as counted in `popularity`
+    ///
+    /// The caller adds each pending image's layers to `popularity` (see
+    /// [`LayerPopularity::add`]) and removes them once it is done.
+    pub fn with_layer_popularity(mut self, popularity: LayerPopularity) -> Self {
+        self.popularity = Some(popularity);
+        self
+    }
+
+    /// Takes layers the target confirmed within `ttl_secs` as present without
+    /// checking again (see [`crate::ledger`])
+    pub fn with_ledger_ttl(mut self, ttl_secs: u64) -> Self {
+        self.ledger_ttl_secs = Some(ttl_secs);
+        self
+    }
+
+    /// Runs the transfer
+    ///
+    /// Layers already present in the cache are not downloaded again, and layers
+    /// the target already has are not uploaded again.
+    ///
+    /// # Returns
+    ///
+    /// `Result<Option<TransferReport>, PusherError>` - The push report, or `None` for a pull only
+    pub async fn run(self) -> Result<Option<TransferReport>, PusherError> {
+        let client = crate::new_client();
+        let source_auth = self.credentials.resolve_image(&self.source).await?;
+        // Credentials set with `with_auth` or `with_token` are the only ones there are for the target
+        let (auth, target_credentials) = match (&self.target, self.auth) {
+            (Some(target), RegistryAuth::Anonymous) => (
+                self.credentials.resolve_image(target).await?,
+                self.credentials.clone(),
+            ),
+            (_, auth) => (auth, AuthChain::new()),
+        };
+
+        let layer_ranking = match (&self.target, &self.popularity) {
+            (Some(target), Some(popularity)) => popularity.ranking(target),
+            _ => Default::default(),
+        };
+
+        let pull_options = PullOptions {
+            cache_dir: self.cache_dir.clone(),
+            auth: source_auth,
+            credentials: self.credentials,
+            progress: self.progress.clone(),
+            cancel: self.cancel.clone(),
+            layer_ranking: layer_ranking.clone(),
+            ..Default::default()
+        };
+        let Some(target) = self.target else {
+            cache::cache_image(&client, &self.source, &pull_options).await?;
+            return Ok(None);
+        };
+        let push_options = PushOptions {
+            cache_dir: self.cache_dir,
+            credentials: target_credentials,
+            progress: self.progress,
+            cancel: self.cancel,
+            layer_ranking,
+            ledger_ttl_secs: self.ledger_ttl_secs,
+            ..Default::default()
+        };
+        copy_image(&client, &self.source, &target, &auth, pull_options, &push_options)
+            .await
+            .map(Some)
+    }
+}
+
+/// Pulls `source_image` into the cache and pushes it to `target_image`, overlapping the two
+///
+/// Each layer is uploaded as soon as its download completes (see
+/// [`push::push_layers_as_cached`]) instead of after the whole pull, so the
+/// copy takes about as long as the slower of the two phases rather than
+/// their sum. The final [`push::push_cached_image`] then only uploads what
+/// is still missing, plus the config and manifest. Both phases share one
+/// authenticated connection to the target, so the token handshake and TLS
+/// setup happen once per copy. Pull-side progress events are forwarded to
+/// `pull_options.progress`.
+///
+/// With [`PushOptions::no_overwrite`] or [`PushOptions::harbor_quota`], the
+/// target tag and quota are checked against the source manifest before
+/// anything is uploaded.
+///
+/// # Returns
+///
+/// `Result<TransferReport, PusherError>` - The report of the final push
+pub async fn copy_image(
+    client: &Client,
+    source_image: &str,
+    target_image: &str,
+    auth: &RegistryAuth,
+    mut pull_options: PullOptions,
+    push_options: &PushOptions,
+) -> Result<TransferReport, PusherError> {
+    // Refused before the target is touched (e.g. a Harbor project created)
+    pull_options.check_content_trust()?;
+    push_options.check_content_trust()?;
+    let target_ref: Reference = target_image
+        .parse()
+        .map_err(|e| PusherError::PushError(format!("Invalid target image reference: {}", e)))?;
+    let registry = push::connect_target(client, &target_ref, auth, push_options).await?;
+
+    // --no-overwrite and the Harbor quota have to refuse the copy before the
+    // early uploads put anything on the target
+    let overlap = if push_options.no_overwrite || push_options.harbor_quota.is_some() {
+        let (bytes, manifest, _) =
+            cache::fetch_manifest(client, source_image, &pull_options).await?;
+        let required = match push_options.harbor_quota {
+            Some(_) => push::missing_bytes(client, &registry, &manifest, push_options).await?,
+            None => 0,
+        };
+        match push::expected_manifest_digest(&bytes, manifest, push_options)? {
+            Some(digest) => {
+                push::preflight(
+                    client,
+                    &registry,
+                    &target_ref,
+                    auth,
+                    &digest,
+                    required,
+                    push_options,
+                )
+                .await?;
+                true
+            }
+            // The new config is only known once cached; the push checks before uploading
+            None => false,
+        }
+    } else {
+        true
+    };
+    if !overlap {
+        cache::cache_image(client, source_image, &pull_options).await?;
+        return push_cached(
+            client,
+            &registry,
+            source_image,
+            target_image,
+            auth,
+            push_options,
+        )
+        .await;
+    }
+    // The quota was checked against the source manifest, so the push doesn't check it again
+    let push_options = &PushOptions {
+        harbor_quota: None,
+        ..push_options.clone()
+    };
+
+    let (layers_tx, layers_rx) = tokio::sync::mpsc::unbounded_channel();
+    let forward = pull_options.progress.clone();
+    pull_options.progress = ProgressReporter::new(Box::new(move |event| {
+        if let ProgressEvent::LayerCompleted { digest, .. } = &event {
+            // The uploader stops listening once it is cancelled
+            let _ = layers_tx.send(digest.clone());
+        }
+        forward.emit(event);
+    }));
+    // Early uploads stop with a failed pull; the caller's token still cancels everything
+    let early_cancel = push_options.cancel.child_token();
+    let early_options = PushOptions {
+        cancel: early_cancel.clone(),
+        ..push_options.clone()
+    };
+
+    let pull = async move {
+        let result = cache::cache_image(client, source_image, &pull_options).await;
+        if result.is_err() {
+            early_cancel.cancel();
+        }
+        // Dropping the options closes the layer channel, ending the early uploads
+        drop(pull_options);
+        result
+    };
+    let early = push::upload_layers_as_cached(
+        client,
+        &registry,
+        &target_ref,
+        source_image,
+        &early_options,
+        layers_rx,
+    );
+    let (pulled, uploaded) = tokio::join!(pull, early);
+    pulled?;
+    match uploaded {
+        Ok(uploaded) if uploaded > 0 => {
+            info!("⏩ {} layer(s) were uploaded while the pull was running", uploaded)
+        }
+        Ok(_) => {}
+        Err(e) => tracing::warn!("⚠️  Early uploads failed, pushing after the pull instead: {}", e),
+    }
+
+    push_cached(
+        client,
+        &registry,
+        source_image,
+        target_image,
+        auth,
+        push_options,
+    )
+    .await
+}
+
+/// Pushes the cached `source_image` through the connected `registry`
+async fn push_cached(
+    client: &Client,
+    registry: &crate::registry::RegistryClient,
+    source_image: &str,
+    target_image: &str,
+    auth: &RegistryAuth,
+    options: &PushOptions,
+) -> Result<TransferReport, PusherError> {
+    let store = FsBlobStore::new(
+        options
+            .cache_dir
+            .join(image::sanitize_image_name(source_image)),
+    );
+    push::push_cached_image_via(
+        client,
+        Some(registry),
+        source_image,
+        target_image,
+        auth,
+        options,
+        &store,
+    )
+    .await
+}
@@ -0,0 +1,405 @@
+use crate::credentials::RegistryAuth;
+use crate::monitor::{self, StallWatch};
+use futures::StreamExt;
+use reqwest::header::{HeaderMap, HeaderName};
+use reqwest::{Method, StatusCode};
+use std::future::Future;
+use std::sync::RwLock;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Size of the pieces request bodies are handed to the connection in while watching for stalls
+const BODY_PIECE_SIZE: usize = 64 * 1024; // 64KB
+
+/// Timeouts for registry requests, set apart so short calls fail fast while big uploads get time
+///
+/// `None` disables a timeout. Metadata requests (`HEAD`, `GET`, `POST`,
+/// `DELETE`) are bounded by `request`; requests that move blob data or
+/// commit it (`PATCH`, `PUT`) by `upload`. A [`TransportRequest::timeout`]
+/// overrides both for a single request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Establishing a connection (TCP and TLS handshake)
+    pub connect: Option<Duration>,
+    /// Time an upload or download may go without moving any data
+    pub idle: Option<Duration>,
+    /// Whole metadata request, response included
+    pub request: Option<Duration>,
+    /// Whole upload request, response included
+    pub upload: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Some(Duration::from_secs(30)),
+            idle: Some(monitor::DEFAULT_STALL_TIMEOUT),
+            request: Some(Duration::from_secs(30)),
+            upload: Some(Duration::from_secs(2 * 60 * 60)),
+        }
+    }
+}
+
+impl Timeouts {
+    /// Timeouts that never fire, which is what a transport uses until configured
+    pub fn disabled() -> Self {
+        Self {
+            connect: None,
+            idle: None,
+            request: None,
+            upload: None,
+        }
+    }
+
+    /// Returns the total timeout for a request with `method`
+    pub fn for_method(&self, method: &Method) -> Option<Duration> {
+        if *method == Method::PATCH || *method == Method::PUT {
+            self.upload
+        } else {
+            self.request
+        }
+    }
+}
+
+/// A raw HTTP request against a registry's `/v2/` API
+#[derive(Debug, Clone)]
+pub struct TransportRequest {
+    /// HTTP method
+    pub method: Method,
+    /// Absolute request URL
+    pub url: String,
+    /// Request headers (authentication is added by the transport)
+    pub headers: Vec<(HeaderName, String)>,
+    /// Request body, empty for bodiless requests
+    pub body: Vec<u8>,
+    /// Total timeout overriding the transport's [`Timeouts`] for this request
+    pub timeout: Option<Duration>,
+}
+
+impl TransportRequest {
+    /// Creates a request with no headers and an empty body
+    pub fn new(method: Method, url: impl Into<String>) -> Self {
+        Self {
+            method,
+            url: url.into(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            timeout: None,
+        }
+    }
+
+    /// Creates a `HEAD` request
+    pub fn head(url: impl Into<String>) -> Self {
+        Self::new(Method::HEAD, url)
+    }
+
+    /// Creates a `GET` request
+    pub fn get(url: impl Into<String>) -> Self {
+        Self::new(Method::GET, url)
+    }
+
+    /// Creates a `POST` request
+    pub fn post(url: impl Into<String>) -> Self {
+        Self::new(Method::POST, url)
+    }
+
+    /// Creates a `PUT` request
+    pub fn put(url: impl Into<String>) -> Self {
+        Self::new(Method::PUT, url)
+    }
+
+    /// Creates a `PATCH` request
+    pub fn patch(url: impl Into<String>) -> Self {
+        Self::new(Method::PATCH, url)
+    }
+
+    /// Creates a `DELETE` request
+    pub fn delete(url: impl Into<String>) -> Self {
+        Self::new(Method::DELETE, url)
+    }
+
+    /// Adds a header
+    pub fn header(mut self, name: HeaderName, value: impl ToString) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+
+    /// Sets the request body
+    pub fn body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+
+    /// Bounds the whole request by `timeout` instead of the transport's default for its method
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+/// A registry response with its body fully read
+#[derive(Debug, Clone)]
+pub struct TransportResponse {
+    /// HTTP status code
+    pub status: StatusCode,
+    /// Response headers
+    pub headers: HeaderMap,
+    /// Response body (empty for `HEAD`)
+    pub body: Vec<u8>,
+}
+
+/// A request that could not be completed (connection, TLS, timeout, ...)
+///
+/// HTTP error statuses are not transport errors; they are returned as
+/// regular [`TransportResponse`]s for the caller to interpret.
+#[derive(Error, Debug)]
+#[error("{message}")]
+pub struct TransportError {
+    message: String,
+    kind: TransportErrorKind,
+    #[source]
+    source: Option<Box<dyn std::error::Error + Send + Sync>>,
+}
+
+/// What kind of failure a [`TransportError`] is, which decides whether it is retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportErrorKind {
+    /// The connection couldn't be established (refused, DNS, unreachable)
+    Connect,
+    /// The request timed out or stalled
+    Timeout,
+    /// The connection was reset or closed before the response was complete
+    Reset,
+    /// Anything else (TLS, invalid requests, redirects, ...), which a retry won't fix
+    Other,
+}
+
+impl TransportError {
+    /// Creates an error without an underlying cause (e.g. from a mock transport)
+    pub fn new(message: impl Into<String>) -> Self {
+        Self::with_kind(TransportErrorKind::Other, message)
+    }
+
+    /// Creates an error of the given kind without an underlying cause
+    pub fn with_kind(kind: TransportErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            kind,
+            source: None,
+        }
+    }
+
+    /// Returns what kind of failure this is
+    pub fn kind(&self) -> TransportErrorKind {
+        self.kind
+    }
+
+    /// Checks whether sending the request again may succeed (connect, timeout and reset errors)
+    pub fn is_retryable(&self) -> bool {
+        self.kind != TransportErrorKind::Other
+    }
+}
+
+impl From<reqwest::Error> for TransportError {
+    fn from(error: reqwest::Error) -> Self {
+        let message = match error.url() {
+            Some(url) => format!("request to {} failed", url),
+            None => "request failed".to_string(),
+        };
+        Self {
+            message,
+            kind: TransportErrorKind::of(&error),
+            source: Some(Box::new(error.without_url())),
+        }
+    }
+}
+
+impl TransportErrorKind {
+    /// Classifies a reqwest error, also those oci-client returns
+    pub fn of(error: &reqwest::Error) -> Self {
+        if error.is_timeout() {
+            return Self::Timeout;
+        }
+        if error.is_connect() {
+            return Self::Connect;
+        }
+        let mut cause: Option<&(dyn std::error::Error + 'static)> = Some(error);
+        while let Some(current) = cause {
+            if let Some(e) = current.downcast_ref::<hyper::Error>()
+                && (e.is_incomplete_message() || e.is_closed() || e.is_canceled())
+            {
+                return Self::Reset;
+            }
+            if let Some(e) = current.downcast_ref::<std::io::Error>() {
+                use std::io::ErrorKind;
+                match e.kind() {
+                    ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::UnexpectedEof => return Self::Reset,
+                    ErrorKind::TimedOut => return Self::Timeout,
+                    _ => {}
+                }
+            }
+            cause = current.source();
+        }
+        Self::Other
+    }
+}
+
+/// Sends raw HTTP requests to a registry
+///
+/// [`crate::registry::RegistryClient`] talks to the registry exclusively
+/// through this trait, so tests and embedders can substitute a mock or a
+/// differently configured HTTP stack. [`ReqwestTransport`] is the default.
+/// Implementations are responsible for attaching credentials.
+pub trait RegistryTransport: Send + Sync {
+    /// Sends `request` and returns the response, whatever its status
+    fn send(
+        &self,
+        request: TransportRequest,
+    ) -> impl Future<Output = Result<TransportResponse, TransportError>> + Send;
+}
+
+/// Default transport backed by `reqwest`, injecting registry credentials
+///
+/// Uses the bearer token obtained by `oci_client::Client::auth`, falling back
+/// to the basic credentials when the registry doesn't issue tokens.
+pub struct ReqwestTransport {
+    http: reqwest::Client,
+    token: RwLock<Option<String>>,
+    auth: RwLock<RegistryAuth>,
+    timeouts: Timeouts,
+}
+
+impl ReqwestTransport {
+    /// Creates a transport authenticating with `token`, or `auth` when there is none
+    pub fn new(token: Option<String>, auth: &RegistryAuth) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            token: RwLock::new(token),
+            auth: RwLock::new(auth.clone()),
+            timeouts: Timeouts::disabled(),
+        }
+    }
+
+    /// Applies `timeouts` to every request
+    ///
+    /// With an idle timeout, request bodies are streamed and responses read
+    /// piece by piece, so a large upload only fails when it stops making
+    /// progress, not because it takes long.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        let mut http = reqwest::Client::builder();
+        if let Some(connect) = timeouts.connect {
+            http = http.connect_timeout(connect);
+        }
+        self.http = http.build().unwrap_or_default();
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Replaces the bearer token, e.g. after it expired
+    pub fn set_token(&self, token: Option<String>) {
+        *self.token.write().unwrap() = token;
+    }
+
+    /// Returns the credentials tokens are requested with
+    pub fn credentials(&self) -> RegistryAuth {
+        self.auth.read().unwrap().clone()
+    }
+
+    /// Replaces the credentials, e.g. after they were rotated
+    pub fn set_credentials(&self, auth: RegistryAuth) {
+        *self.auth.write().unwrap() = auth;
+    }
+
+    /// Applies the bearer token or basic credentials to a request
+    fn authorize(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        let auth = self.auth.read().unwrap();
+        match (self.token.read().unwrap().as_deref(), &*auth) {
+            (Some(token), _) => request.bearer_auth(token),
+            (None, RegistryAuth::Basic(username, password)) => {
+                request.basic_auth(username, Some(password))
+            }
+            (None, RegistryAuth::Bearer(token)) => request.bearer_auth(token),
+            (None, RegistryAuth::Anonymous) => request,
+        }
+    }
+}
+
+impl ReqwestTransport {
+    /// Performs the request, recording on `watch` every piece of data sent or received
+    async fn exchange(
+        &self,
+        request: TransportRequest,
+        watch: &StallWatch,
+    ) -> Result<TransportResponse, TransportError> {
+        let timeout = request.timeout.or(self.timeouts.for_method(&request.method));
+        let mut builder = self.authorize(self.http.request(request.method, &request.url));
+        if let Some(timeout) = timeout {
+            builder = builder.timeout(timeout);
+        }
+        let has_length = request
+            .headers
+            .iter()
+            .any(|(name, _)| *name == reqwest::header::CONTENT_LENGTH);
+        for (name, value) in request.headers {
+            builder = builder.header(name, value);
+        }
+        builder = builder.header(crate::correlation::REQUEST_ID_HEADER, crate::correlation::id());
+        let builder = if watch.timeout().is_some() && !request.body.is_empty() {
+            let body = request.body;
+            let len = body.len();
+            let sent = watch.clone();
+            let pieces = futures::stream::iter((0..len).step_by(BODY_PIECE_SIZE)).map(move |start| {
+                // The connection asks for the next piece once the previous one is written
+                sent.touch();
+                Ok::<_, std::io::Error>(body[start..(start + BODY_PIECE_SIZE).min(len)].to_vec())
+            });
+            // A streamed body would be sent chunked without a length; headers are
+            // appended, so only add it when the caller didn't (strict proxies reject two)
+            if !has_length {
+                builder = builder.header(reqwest::header::CONTENT_LENGTH, len);
+            }
+            builder.body(reqwest::Body::wrap_stream(pieces))
+        } else {
+            builder.body(request.body)
+        };
+        let mut response = builder.send().await?;
+        watch.touch();
+
+        let status = response.status();
+        let headers = response.headers().clone();
+        let mut body = Vec::new();
+        while let Some(piece) = response.chunk().await? {
+            watch.touch();
+            body.extend_from_slice(&piece);
+        }
+        Ok(TransportResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+impl RegistryTransport for ReqwestTransport {
+    async fn send(&self, request: TransportRequest) -> Result<TransportResponse, TransportError> {
+        // A bodiless request may wait on the registry (e.g. verifying a blob on
+        // finalize) without moving data; only its total timeout applies
+        let idle = self.timeouts.idle.filter(|_| !request.body.is_empty());
+        let watch = StallWatch::new(idle);
+        let url = request.url.clone();
+        tokio::select! {
+            result = self.exchange(request, &watch) => result,
+            _ = watch.stalled() => Err(TransportError::with_kind(
+                TransportErrorKind::Timeout,
+                format!(
+                    "request to {} stalled: no data transferred for {}s",
+                    url,
+                    idle.unwrap_or_default().as_secs()
+                ),
+            )),
+        }
+    }
+}
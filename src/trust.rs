@@ -0,0 +1,39 @@
+//! Docker Content Trust (`DOCKER_CONTENT_TRUST`)
+//!
+//! Docker enforces content trust with Notary signatures, which this crate
+//! can't verify, so cosign verification (a trusted key in the pull and push
+//! options) has to stand in for it. Every pull and push checks
+//! [`check_content_trust`] unless its options disable content trust, so an
+//! environment mandating DCT is never bypassed silently, whether the
+//! transfer comes from the CLI, a server job, a scheduled sync or the proxy.
+
+use crate::PusherError;
+
+/// Environment variable Docker reads to require signed images
+pub const CONTENT_TRUST_ENV: &str = "DOCKER_CONTENT_TRUST";
+
+/// Checks whether `DOCKER_CONTENT_TRUST` requires signed images
+pub fn content_trust_required() -> bool {
+    // Same spellings as Go's strconv.ParseBool, which Docker uses
+    std::env::var(CONTENT_TRUST_ENV)
+        .is_ok_and(|value| matches!(value.as_str(), "1" | "t" | "T" | "true" | "TRUE" | "True"))
+}
+
+/// Refuses a transfer that doesn't verify signatures when `DOCKER_CONTENT_TRUST` requires them
+///
+/// `verifying` tells whether the transfer only accepts images with a valid
+/// cosign signature.
+pub fn check_content_trust(verifying: bool) -> Result<(), PusherError> {
+    if verifying || !content_trust_required() {
+        return Ok(());
+    }
+    let remedy = if cfg!(feature = "cosign") {
+        "verify cosign signatures with a trusted key (--verify-signature --trusted-key <key>)"
+    } else {
+        "this build can't verify signatures (it lacks the cosign feature)"
+    };
+    Err(PusherError::ConfigError(format!(
+        "{} requires signed images, but Notary signatures are not supported: {}, or disable content trust (--disable-content-trust)",
+        CONTENT_TRUST_ENV, remedy
+    )))
+}
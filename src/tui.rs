@@ -0,0 +1,319 @@
+//! Full-screen terminal dashboard for long-running transfers (`--tui`)
+//!
+//! Instead of scrolling log lines, the dashboard shows a progress bar per
+//! running layer, a throughput graph, the concurrency adjustments and the
+//! latest log lines, redrawn a few times per second from
+//! [`crate::metrics::live_stats`]. Logs reach it through a [`LogBuffer`]
+//! installed as the terminal log sink (see [`crate::logging::LogTarget`]).
+//!
+//! The terminal is in raw mode while the dashboard runs, so Ctrl-C arrives as
+//! a key press: the first one (or `q`) cancels the transfers gracefully, a
+//! second one exits immediately.
+
+use crate::PusherError;
+use crate::logging::LogSink;
+use crate::metrics::{self, LiveStats};
+use chrono::{DateTime, Local};
+use ratatui::Frame;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, LineGauge, List, ListItem, Paragraph, Sparkline};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::Level;
+
+/// Log lines kept for the log pane
+const LOG_LINES: usize = 500;
+
+/// Throughput samples kept for the graph, one per redraw
+const THROUGHPUT_SAMPLES: usize = 600;
+
+/// Time between two redraws
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Log lines collected for the dashboard's log pane
+///
+/// While no dashboard is running (before it starts and after it stops),
+/// lines are printed to stdout instead, so the outcome of a run stays
+/// visible once the dashboard is gone.
+#[derive(Clone, Default)]
+pub struct LogBuffer {
+    lines: Arc<Mutex<VecDeque<(Level, String)>>>,
+    capturing: Arc<AtomicBool>,
+}
+
+impl LogBuffer {
+    /// Creates an empty buffer that prints lines until a dashboard starts
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lines(&self) -> std::sync::MutexGuard<'_, VecDeque<(Level, String)>> {
+        self.lines.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl LogSink for LogBuffer {
+    fn log(&self, level: Level, message: &str) {
+        if !self.capturing.load(Ordering::Relaxed) {
+            println!("{}", message);
+            return;
+        }
+        let mut lines = self.lines();
+        if lines.len() == LOG_LINES {
+            lines.pop_front();
+        }
+        lines.push_back((level, message.to_string()));
+    }
+}
+
+/// A running dashboard; the terminal is restored when it is stopped or dropped
+pub struct Dashboard {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    logs: LogBuffer,
+}
+
+impl Dashboard {
+    /// Takes over the terminal and draws the dashboard until [`Dashboard::stop`]
+    ///
+    /// `title` heads the screen (e.g. the command being run); `cancel` is
+    /// cancelled when the user presses `q` or Ctrl-C.
+    pub fn start(
+        title: String,
+        logs: LogBuffer,
+        cancel: CancellationToken,
+    ) -> Result<Self, PusherError> {
+        let mut terminal = ratatui::try_init().map_err(|e| {
+            PusherError::ConfigError(format!("Failed to start the dashboard: {}", e))
+        })?;
+        logs.capturing.store(true, Ordering::Relaxed);
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let (stop, logs) = (stop.clone(), logs.clone());
+            std::thread::spawn(move || {
+                let mut throughput = VecDeque::new();
+                while !stop.load(Ordering::Relaxed) {
+                    let stats = metrics::live_stats();
+                    if throughput.len() == THROUGHPUT_SAMPLES {
+                        throughput.pop_front();
+                    }
+                    throughput.push_back(stats.throughput as u64);
+                    let drawn = terminal.draw(|frame| {
+                        draw(
+                            frame,
+                            &title,
+                            &stats,
+                            &throughput,
+                            &logs,
+                            cancel.is_cancelled(),
+                        )
+                    });
+                    if drawn.is_err() {
+                        break;
+                    }
+                    if !event::poll(REDRAW_INTERVAL).unwrap_or(false) {
+                        continue;
+                    }
+                    if let Ok(Event::Key(key)) = event::read()
+                        && key.kind == KeyEventKind::Press
+                    {
+                        let interrupt = key.code == KeyCode::Char('c')
+                            && key.modifiers.contains(KeyModifiers::CONTROL);
+                        if interrupt && cancel.is_cancelled() {
+                            ratatui::restore();
+                            std::process::exit(130);
+                        }
+                        if interrupt || key.code == KeyCode::Char('q') {
+                            cancel.cancel();
+                        }
+                    }
+                }
+                ratatui::restore();
+            })
+        };
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+            logs,
+        })
+    }
+
+    /// Restores the terminal; later log lines are printed again
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        self.logs.capturing.store(false, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Draws one frame of the dashboard
+fn draw(
+    frame: &mut Frame,
+    title: &str,
+    stats: &LiveStats,
+    throughput: &VecDeque<u64>,
+    logs: &LogBuffer,
+    cancelled: bool,
+) {
+    let layer_rows = stats.layers.len().clamp(1, 12) as u16 + 2;
+    let [header, layers, middle, log] = Layout::vertical([
+        Constraint::Length(4),
+        Constraint::Length(layer_rows),
+        Constraint::Length(8),
+        Constraint::Min(5),
+    ])
+    .areas(frame.area());
+    let [graph, adjustments] =
+        Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(middle);
+
+    draw_header(frame, header, title, stats, cancelled);
+    draw_layers(frame, layers, stats);
+
+    let graph_title = format!(
+        " Throughput {}/s (peak {}/s) ",
+        human_bytes(stats.throughput as u64),
+        human_bytes(stats.peak_throughput as u64)
+    );
+    let samples: Vec<u64> = throughput
+        .iter()
+        .rev()
+        .take(graph.width.saturating_sub(2) as usize)
+        .rev()
+        .copied()
+        .collect();
+    frame.render_widget(
+        Sparkline::default()
+            .block(Block::bordered().title(graph_title))
+            .data(samples)
+            .style(Style::new().fg(Color::Cyan)),
+        graph,
+    );
+
+    let items: Vec<ListItem> = stats
+        .adjustments
+        .iter()
+        .rev()
+        .map(|adjustment| {
+            ListItem::new(format!(
+                "{} {} → {} ({})",
+                DateTime::<Local>::from(adjustment.at).format("%H:%M:%S"),
+                adjustment.from,
+                adjustment.to,
+                adjustment.reason
+            ))
+        })
+        .collect();
+    frame.render_widget(
+        List::new(items).block(Block::bordered().title(" Concurrency changes ")),
+        adjustments,
+    );
+
+    let height = log.height.saturating_sub(2) as usize;
+    let lines = logs.lines();
+    let items: Vec<ListItem> = lines
+        .iter()
+        .skip(lines.len().saturating_sub(height))
+        .map(|(level, message)| {
+            let style = match *level {
+                Level::ERROR => Style::new().fg(Color::Red),
+                Level::WARN => Style::new().fg(Color::Yellow),
+                Level::INFO => Style::new(),
+                _ => Style::new().fg(Color::DarkGray),
+            };
+            ListItem::new(message.as_str()).style(style)
+        })
+        .collect();
+    frame.render_widget(
+        List::new(items).block(Block::bordered().title(" Log ")),
+        log,
+    );
+}
+
+/// Draws the title, the totals and the key help
+fn draw_header(frame: &mut Frame, area: Rect, title: &str, stats: &LiveStats, cancelled: bool) {
+    let status = if cancelled {
+        "cancelling, Ctrl-C again to exit".red()
+    } else {
+        "q / Ctrl-C to cancel".dark_gray()
+    };
+    let lines = vec![
+        Line::from(vec![title.bold(), "  ".into(), status]),
+        Line::from(format!(
+            "↑ {} ({} layers, {} skipped)   ↓ {}   concurrency {}   active {}   retries {}",
+            human_bytes(stats.bytes_uploaded),
+            stats.layers_uploaded,
+            stats.layers_skipped,
+            human_bytes(stats.bytes_downloaded),
+            stats.concurrency,
+            stats.active_transfers,
+            stats.retries
+        )),
+    ];
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(" docker-image-pusher ")),
+        area,
+    );
+}
+
+/// Draws a progress bar for each running layer transfer
+fn draw_layers(frame: &mut Frame, area: Rect, stats: &LiveStats) {
+    let block = Block::bordered().title(format!(" Layers ({} running) ", stats.layers.len()));
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+    let rows = Layout::vertical(vec![Constraint::Length(1); inner.height as usize]).split(inner);
+    for (layer, row) in stats.layers.iter().zip(rows.iter()) {
+        let ratio = if layer.total > 0 {
+            (layer.transferred as f64 / layer.total as f64).min(1.0)
+        } else {
+            0.0
+        };
+        let short = layer.digest.get(..19).unwrap_or(&layer.digest);
+        frame.render_widget(
+            LineGauge::default()
+                .ratio(ratio)
+                .label(format!(
+                    "{} {:>9} / {:<9}",
+                    short,
+                    human_bytes(layer.transferred),
+                    human_bytes(layer.total)
+                ))
+                .filled_style(Style::new().fg(Color::Green)),
+            *row,
+        );
+    }
+}
+
+/// Formats a byte count with a binary unit, e.g. "12.3 MB"
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
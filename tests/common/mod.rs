@@ -0,0 +1,64 @@
+//! Helpers shared by the `MockRegistry` tests
+#![allow(dead_code)]
+
+use docker_image_pusher::test_util::MockRegistry;
+use std::path::PathBuf;
+
+const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+
+/// Empty scratch directory for one test
+pub fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "mock-registry-test-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    tokio::runtime::Runtime::new().unwrap().block_on(future)
+}
+
+/// An image seeded in the registry
+pub struct Image {
+    pub config: String,
+    pub layers: Vec<(String, Vec<u8>)>,
+}
+
+/// Stores an image with `layers` as `repository:tag`
+pub fn seed_image(registry: &MockRegistry, repository: &str, tag: &str, layers: &[&[u8]]) -> Image {
+    let config =
+        br#"{"architecture":"amd64","os":"linux","rootfs":{"type":"layers","diff_ids":[]}}"#;
+    let config_digest = registry.insert_blob(repository, config);
+    let layers: Vec<(String, Vec<u8>)> = layers
+        .iter()
+        .map(|data| (registry.insert_blob(repository, data), data.to_vec()))
+        .collect();
+    let manifest = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": MANIFEST_MEDIA_TYPE,
+        "config": {
+            "mediaType": "application/vnd.oci.image.config.v1+json",
+            "digest": config_digest,
+            "size": config.len(),
+        },
+        "layers": layers.iter().map(|(digest, data)| serde_json::json!({
+            "mediaType": "application/vnd.oci.image.layer.v1.tar",
+            "digest": digest,
+            "size": data.len(),
+        })).collect::<Vec<_>>(),
+    });
+    registry.insert_manifest(
+        repository,
+        tag,
+        MANIFEST_MEDIA_TYPE,
+        manifest.to_string().as_bytes(),
+    );
+    Image {
+        config: config_digest,
+        layers,
+    }
+}
@@ -0,0 +1,46 @@
+//! `DOCKER_CONTENT_TRUST` enforced by the library transfer path
+#![cfg(feature = "test-util")]
+
+mod common;
+
+use common::{block_on, scratch, seed_image};
+use docker_image_pusher::cache::{self, PullOptions};
+use docker_image_pusher::test_util::MockRegistry;
+use docker_image_pusher::{ImageTransfer, PusherError};
+
+// One test only: the environment variable is shared by the whole process
+#[test]
+fn content_trust_refuses_unverified_transfers() {
+    let dir = scratch("content-trust");
+    // SAFETY: no other thread of this test binary reads the environment
+    unsafe { std::env::set_var("DOCKER_CONTENT_TRUST", "1") };
+    block_on(async {
+        let registry = MockRegistry::start().await.unwrap();
+        seed_image(&registry, "app", "v1", &[b"layer"]);
+        let source = registry.reference("app:v1");
+
+        // What server jobs, syncs and the proxy run
+        let error = ImageTransfer::pull(source.clone())
+            .push_to(registry.reference("copy:v1"))
+            .with_cache(dir.clone())
+            .run()
+            .await
+            .unwrap_err();
+        assert!(matches!(error, PusherError::ConfigError(_)), "{}", error);
+        assert!(
+            error.to_string().contains("DOCKER_CONTENT_TRUST"),
+            "{}",
+            error
+        );
+        assert!(registry.requests().is_empty());
+
+        let options = PullOptions {
+            cache_dir: dir.clone(),
+            disable_content_trust: true,
+            ..Default::default()
+        };
+        cache::cache_image(&registry.client(), &source, &options)
+            .await
+            .unwrap();
+    });
+}
@@ -0,0 +1,150 @@
+//! `export::extract_path` against crafted layers
+#![cfg(feature = "import")]
+
+use docker_image_pusher::export;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tar::{Builder, EntryType, Header};
+
+/// Empty scratch directory for one test
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("export-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn digest(data: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(data))
+}
+
+/// Caches an image `name` with one plain tar layer per entry of `layers`
+fn cache_image(cache_dir: &Path, name: &str, layers: &[Vec<u8>]) {
+    let image_dir = cache_dir.join(docker_image_pusher::image::sanitize_image_name(name));
+    std::fs::create_dir_all(&image_dir).unwrap();
+    let config = br#"{"architecture":"amd64","os":"linux"}"#;
+    let mut descriptors = Vec::new();
+    for layer in layers {
+        let digest = digest(layer);
+        std::fs::write(image_dir.join(digest.replace(':', "_")), layer).unwrap();
+        descriptors.push(serde_json::json!({
+            "mediaType": "application/vnd.docker.image.rootfs.diff.tar",
+            "size": layer.len(),
+            "digest": digest,
+        }));
+    }
+    let manifest = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+        "config": {
+            "mediaType": "application/vnd.docker.container.image.v1+json",
+            "size": config.len(),
+            "digest": digest(config),
+        },
+        "layers": descriptors,
+    });
+    std::fs::write(image_dir.join("manifest.json"), manifest.to_string()).unwrap();
+}
+
+fn file(layer: &mut Builder<Vec<u8>>, path: &str, data: &[u8]) {
+    let mut header = Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    layer.append_data(&mut header, path, data).unwrap();
+}
+
+fn link(layer: &mut Builder<Vec<u8>>, kind: EntryType, path: &str, target: &Path) {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(kind);
+    header.set_size(0);
+    header.set_mode(0o777);
+    layer.append_link(&mut header, path, target).unwrap();
+}
+
+#[test]
+fn extracts_files_and_links() {
+    let dir = scratch("plain");
+    let mut layer = Builder::new(Vec::new());
+    file(&mut layer, "app/bin/tool", b"tool");
+    link(
+        &mut layer,
+        EntryType::Symlink,
+        "app/current",
+        Path::new("bin"),
+    );
+    link(
+        &mut layer,
+        EntryType::Link,
+        "app/bin/tool2",
+        Path::new("app/bin/tool"),
+    );
+    cache_image(&dir.join("cache"), "app:v1", &[layer.into_inner().unwrap()]);
+
+    let out = dir.join("out");
+    let written = export::extract_path(&dir.join("cache"), "app:v1", "/app", &out).unwrap();
+    assert_eq!(written, 3);
+    assert_eq!(std::fs::read(out.join("app/bin/tool2")).unwrap(), b"tool");
+    assert_eq!(
+        std::fs::read_link(out.join("app/current")).unwrap(),
+        Path::new("bin")
+    );
+}
+
+#[test]
+fn refuses_to_write_through_symlinks() {
+    let dir = scratch("symlink");
+    let outside = dir.join("outside");
+    std::fs::create_dir_all(&outside).unwrap();
+
+    // A symlink out of the tree, then a file "inside" it
+    let mut layer = Builder::new(Vec::new());
+    link(&mut layer, EntryType::Symlink, "app/etc", &outside);
+    file(&mut layer, "app/etc/passwd", b"owned");
+    cache_image(
+        &dir.join("cache"),
+        "evil:v1",
+        &[layer.into_inner().unwrap()],
+    );
+
+    let out = dir.join("out");
+    let error = export::extract_path(&dir.join("cache"), "evil:v1", "/app", &out).unwrap_err();
+    assert!(error.to_string().contains("symlink"), "{}", error);
+    assert!(!outside.join("passwd").exists());
+
+    // The same across layers
+    let mut lower = Builder::new(Vec::new());
+    link(&mut lower, EntryType::Symlink, "app/etc", &outside);
+    let mut upper = Builder::new(Vec::new());
+    file(&mut upper, "app/etc/passwd", b"owned");
+    let layers = [lower.into_inner().unwrap(), upper.into_inner().unwrap()];
+    cache_image(&dir.join("cache"), "evil:v2", &layers);
+
+    let out = dir.join("out2");
+    assert!(export::extract_path(&dir.join("cache"), "evil:v2", "/app", &out).is_err());
+    assert!(!outside.join("passwd").exists());
+}
+
+#[test]
+fn refuses_hard_links_out_of_the_tree() {
+    let dir = scratch("hardlink");
+    let secret = dir.join("secret");
+    std::fs::write(&secret, b"secret").unwrap();
+
+    let mut layer = Builder::new(Vec::new());
+    link(&mut layer, EntryType::Symlink, "app/etc", &dir);
+    link(
+        &mut layer,
+        EntryType::Link,
+        "app/stolen",
+        Path::new("app/etc/secret"),
+    );
+    cache_image(
+        &dir.join("cache"),
+        "evil:v1",
+        &[layer.into_inner().unwrap()],
+    );
+
+    let out = dir.join("out");
+    assert!(export::extract_path(&dir.join("cache"), "evil:v1", "/app", &out).is_err());
+    assert!(!out.join("app/stolen").exists());
+}
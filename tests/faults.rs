@@ -0,0 +1,312 @@
+//! Retries, token refresh and circuit breaking against injected `MockRegistry` faults
+#![cfg(feature = "test-util")]
+
+mod common;
+
+use common::{block_on, scratch, seed_image};
+use docker_image_pusher::PusherError;
+use docker_image_pusher::cache::{self, PullOptions};
+use docker_image_pusher::circuit::CircuitConfig;
+use docker_image_pusher::credentials::{AuthChain, AuthProvider};
+use docker_image_pusher::push::{PushOptions, push_cached_image};
+use docker_image_pusher::retry::RetryPolicy;
+use docker_image_pusher::test_util::{ChaosSettings, Fault, MockRegistry};
+use futures::future::BoxFuture;
+use oci_client::secrets::RegistryAuth;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Retries quickly, so the tests don't sit through real backoff
+fn fast_retry() -> RetryPolicy {
+    RetryPolicy {
+        initial_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(50),
+        ..Default::default()
+    }
+}
+
+/// Caches `app:v1` of `registry` in `dir`, returning its reference
+async fn cache_source(registry: &MockRegistry, dir: &Path) -> String {
+    seed_image(registry, "app", "v1", &[b"first layer", b"second layer"]);
+    let source = registry.reference("app:v1");
+    let options = PullOptions {
+        cache_dir: dir.to_path_buf(),
+        ..Default::default()
+    };
+    cache::cache_image(&registry.client(), &source, &options)
+        .await
+        .unwrap();
+    source
+}
+
+/// Pushes `source` to `copy:v1` of `registry`
+async fn push(
+    registry: &MockRegistry,
+    source: &str,
+    options: PushOptions,
+) -> Result<(), PusherError> {
+    let target = registry.reference("copy:v1");
+    push_cached_image(
+        &registry.client(),
+        source,
+        &target,
+        &RegistryAuth::Anonymous,
+        &options,
+    )
+    .await
+    .map(|_| ())
+}
+
+/// Requests logged since request `since` that start with `prefix`
+fn requests(registry: &MockRegistry, since: usize, prefix: &str) -> Vec<String> {
+    registry.requests()[since..]
+        .iter()
+        .filter(|request| request.starts_with(prefix))
+        .cloned()
+        .collect()
+}
+
+/// Returns the same credentials every time, counting the lookups
+struct RotatedCredentials(Arc<AtomicUsize>);
+
+impl AuthProvider for RotatedCredentials {
+    fn credentials_for<'a>(
+        &'a self,
+        _registry: &'a str,
+    ) -> BoxFuture<'a, Result<Option<RegistryAuth>, PusherError>> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async { Ok(Some(RegistryAuth::Basic("ci".into(), "rotated".into()))) })
+    }
+}
+
+#[test]
+fn server_errors_and_resets_are_retried() {
+    let dir = scratch("faults-retry");
+    block_on(async {
+        let registry = MockRegistry::start().await.unwrap();
+        let source = cache_source(&registry, &dir).await;
+
+        // The tag check is the push's first request
+        let before = registry.requests().len();
+        registry.inject_fault(Fault::ServerError);
+        registry.inject_fault(Fault::ConnectionReset);
+        let options = PushOptions {
+            cache_dir: dir.clone(),
+            retry: fast_retry(),
+            ..Default::default()
+        };
+        push(&registry, &source, options).await.unwrap();
+
+        assert_eq!(
+            requests(&registry, before, "HEAD /v2/copy/manifests/v1"),
+            [
+                "HEAD /v2/copy/manifests/v1 (ServerError)",
+                "HEAD /v2/copy/manifests/v1 (ConnectionReset)",
+                "HEAD /v2/copy/manifests/v1",
+            ]
+        );
+        assert!(registry.manifest("copy", "v1").is_some());
+    });
+}
+
+#[test]
+fn throttling_waits_for_retry_after() {
+    let dir = scratch("faults-throttle");
+    block_on(async {
+        let registry = MockRegistry::start().await.unwrap();
+        let source = cache_source(&registry, &dir).await;
+
+        // `Retry-After: 0` replaces the backoff, which would take a minute
+        let before = registry.requests().len();
+        registry.inject_fault(Fault::TooManyRequests);
+        let options = PushOptions {
+            cache_dir: dir.clone(),
+            retry: RetryPolicy {
+                initial_backoff: Duration::from_secs(60),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let started = Instant::now();
+        push(&registry, &source, options).await.unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(30));
+        assert_eq!(
+            requests(&registry, before, "HEAD /v2/copy/manifests/v1"),
+            [
+                "HEAD /v2/copy/manifests/v1 (TooManyRequests)",
+                "HEAD /v2/copy/manifests/v1",
+            ]
+        );
+    });
+}
+
+#[test]
+fn unauthorized_refreshes_the_token() {
+    let dir = scratch("faults-token");
+    block_on(async {
+        let registry = MockRegistry::start().await.unwrap();
+        let source = cache_source(&registry, &dir).await;
+
+        let before = registry.requests().len();
+        registry.inject_fault(Fault::Unauthorized);
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let options = PushOptions {
+            cache_dir: dir.clone(),
+            retry: fast_retry(),
+            credentials: AuthChain::new().with(RotatedCredentials(lookups.clone())),
+            ..Default::default()
+        };
+        push(&registry, &source, options).await.unwrap();
+
+        // A new token is requested (the handshake starts with the ping) before the retry
+        let log = registry.requests()[before..].to_vec();
+        let rejected = log
+            .iter()
+            .position(|request| request == "HEAD /v2/copy/manifests/v1 (Unauthorized)")
+            .unwrap();
+        assert_eq!(log[rejected + 1], "GET /v2/");
+        assert_eq!(log[rejected + 2], "HEAD /v2/copy/manifests/v1");
+        // The credentials are only looked up again when the fresh token is rejected too
+        assert_eq!(lookups.load(Ordering::SeqCst), 0);
+    });
+}
+
+#[test]
+fn rejected_fresh_token_looks_up_credentials_again() {
+    let dir = scratch("faults-credentials");
+    block_on(async {
+        let registry = MockRegistry::start().await.unwrap();
+        let source = cache_source(&registry, &dir).await;
+
+        registry.inject_fault(Fault::Unauthorized);
+        registry.inject_fault(Fault::Unauthorized);
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let options = PushOptions {
+            cache_dir: dir.clone(),
+            retry: fast_retry(),
+            credentials: AuthChain::new().with(RotatedCredentials(lookups.clone())),
+            ..Default::default()
+        };
+        push(&registry, &source, options).await.unwrap();
+
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+        assert!(registry.manifest("copy", "v1").is_some());
+    });
+}
+
+#[test]
+fn retries_give_up_after_max_attempts() {
+    let dir = scratch("faults-exhausted");
+    block_on(async {
+        let registry = MockRegistry::start().await.unwrap();
+        let source = cache_source(&registry, &dir).await;
+
+        let before = registry.requests().len();
+        for _ in 0..3 {
+            registry.inject_fault(Fault::ServerError);
+        }
+        let options = PushOptions {
+            cache_dir: dir.clone(),
+            retry: RetryPolicy {
+                max_attempts: 3,
+                circuit: None,
+                ..fast_retry()
+            },
+            ..Default::default()
+        };
+        let error = push(&registry, &source, options).await.unwrap_err();
+
+        assert!(error.is_retryable(), "{}", error);
+        assert_eq!(
+            requests(&registry, before, "HEAD /v2/copy/manifests/v1").len(),
+            3
+        );
+        assert!(registry.manifest("copy", "v1").is_none());
+    });
+}
+
+#[test]
+fn failing_registry_trips_the_breaker() {
+    let dir = scratch("faults-breaker");
+    block_on(async {
+        let registry = MockRegistry::start().await.unwrap();
+        let source = cache_source(&registry, &dir).await;
+
+        // The breaker of the registry was created by the pull, with the default threshold
+        let threshold = CircuitConfig::default().failure_threshold as usize;
+        let before = registry.requests().len();
+        for _ in 0..threshold {
+            registry.inject_fault(Fault::ServerError);
+        }
+        let options = PushOptions {
+            cache_dir: dir.clone(),
+            retry: RetryPolicy {
+                max_attempts: 10,
+                ..fast_retry()
+            },
+            ..Default::default()
+        };
+        let error = push(&registry, &source, options.clone()).await.unwrap_err();
+        assert!(
+            matches!(error, PusherError::CircuitOpen { .. }),
+            "{}",
+            error
+        );
+        assert_eq!(
+            requests(&registry, before, "HEAD /v2/copy/manifests/v1").len(),
+            threshold
+        );
+
+        // While the circuit is open, nothing is sent to the registry
+        let before = registry.requests().len();
+        let error = push(&registry, &source, options).await.unwrap_err();
+        assert!(
+            matches!(error, PusherError::CircuitOpen { .. }),
+            "{}",
+            error
+        );
+        assert!(requests(&registry, before, "HEAD ").is_empty());
+    });
+}
+
+#[test]
+fn push_survives_random_faults() {
+    let dir = scratch("faults-chaos");
+    block_on(async {
+        let registry = MockRegistry::start().await.unwrap();
+        let source = cache_source(&registry, &dir).await;
+
+        registry.enable_chaos(ChaosSettings {
+            seed: 7,
+            rate: 0.2,
+            faults: vec![
+                Fault::TooManyRequests,
+                Fault::ServerError,
+                Fault::ConnectionReset,
+            ],
+        });
+        let options = PushOptions {
+            cache_dir: dir.clone(),
+            max_concurrent: Some(1),
+            retry: RetryPolicy {
+                max_attempts: 10,
+                circuit: None,
+                ..fast_retry()
+            },
+            ..Default::default()
+        };
+        push(&registry, &source, options).await.unwrap();
+        registry.disable_chaos();
+
+        let log = registry.requests();
+        assert!(
+            log.iter().any(|request| request.ends_with(')')),
+            "no fault was injected"
+        );
+        let manifest = registry.manifest("copy", "v1").unwrap();
+        assert_eq!(Some(manifest), registry.manifest("app", "v1"));
+    });
+}
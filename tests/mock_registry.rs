@@ -0,0 +1,254 @@
+//! Pulls and pushes through `test_util::MockRegistry`
+#![cfg(feature = "test-util")]
+
+mod common;
+
+use common::{block_on, scratch, seed_image};
+use docker_image_pusher::cache::{self, PullOptions};
+use docker_image_pusher::push::{PushOptions, push_cached_image};
+use docker_image_pusher::report::BlobStatus;
+use docker_image_pusher::test_util::MockRegistry;
+use docker_image_pusher::{PusherError, copy_image};
+use oci_client::secrets::RegistryAuth;
+use sha2::{Digest, Sha256};
+
+/// Upload sessions opened since request `since`
+fn upload_sessions(registry: &MockRegistry, since: usize) -> usize {
+    registry.requests()[since..]
+        .iter()
+        .filter(|request| request.starts_with("POST ") && request.ends_with("/blobs/uploads/"))
+        .count()
+}
+
+#[test]
+fn pull_caches_every_blob() {
+    let dir = scratch("pull");
+    block_on(async {
+        let registry = MockRegistry::start().await.unwrap();
+        let image = seed_image(&registry, "app", "v1", &[b"first layer", b"second layer"]);
+        let source = registry.reference("app:v1");
+        let options = PullOptions {
+            cache_dir: dir.clone(),
+            ..Default::default()
+        };
+        cache::cache_image(&registry.client(), &source, &options)
+            .await
+            .unwrap();
+
+        assert!(cache::has_cached_image(&dir, &source).await.unwrap());
+        let image_dir = dir.join(docker_image_pusher::image::sanitize_image_name(&source));
+        for (digest, data) in &image.layers {
+            let cached = std::fs::read(image_dir.join(digest.replace(':', "_"))).unwrap();
+            assert_eq!(&cached, data);
+        }
+        // The manifest is kept byte for byte
+        let manifest = std::fs::read(image_dir.join("manifest.json")).unwrap();
+        assert_eq!(Some(manifest), registry.manifest("app", "v1"));
+    });
+}
+
+#[test]
+fn push_uploads_the_cached_image() {
+    let dir = scratch("push");
+    block_on(async {
+        let registry = MockRegistry::start().await.unwrap();
+        let image = seed_image(&registry, "app", "v1", &[b"first layer", b"second layer"]);
+        let source = registry.reference("app:v1");
+        let pull = PullOptions {
+            cache_dir: dir.clone(),
+            ..Default::default()
+        };
+        cache::cache_image(&registry.client(), &source, &pull)
+            .await
+            .unwrap();
+
+        let target = registry.reference("copy:v2");
+        let options = PushOptions {
+            cache_dir: dir.clone(),
+            ..Default::default()
+        };
+        let report = push_cached_image(
+            &registry.client(),
+            &source,
+            &target,
+            &RegistryAuth::Anonymous,
+            &options,
+        )
+        .await
+        .unwrap();
+
+        for (digest, data) in &image.layers {
+            assert_eq!(registry.blob("copy", digest).as_ref(), Some(data));
+        }
+        assert_eq!(
+            registry.blob("copy", &image.config),
+            registry.blob("app", &image.config)
+        );
+        assert!(
+            report
+                .blobs
+                .iter()
+                .all(|blob| blob.status == BlobStatus::Uploaded)
+        );
+
+        // The target manifest references the same blobs and is stored under its digest too
+        let stored = registry
+            .manifest("copy", "v2")
+            .expect("manifest pushed under the tag");
+        let manifest: serde_json::Value = serde_json::from_slice(&stored).unwrap();
+        assert_eq!(manifest["config"]["digest"], image.config.as_str());
+        let layers: Vec<&str> = manifest["layers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|layer| layer["digest"].as_str().unwrap())
+            .collect();
+        let expected: Vec<&str> = image
+            .layers
+            .iter()
+            .map(|(digest, _)| digest.as_str())
+            .collect();
+        assert_eq!(layers, expected);
+        assert_eq!(
+            registry.manifest("copy", &report.manifest_digest),
+            Some(stored)
+        );
+    });
+}
+
+#[test]
+fn second_push_skips_present_layers() {
+    let dir = scratch("repush");
+    block_on(async {
+        let registry = MockRegistry::start().await.unwrap();
+        seed_image(&registry, "app", "v1", &[b"only layer"]);
+        let source = registry.reference("app:v1");
+        let pull = PullOptions {
+            cache_dir: dir.clone(),
+            ..Default::default()
+        };
+        cache::cache_image(&registry.client(), &source, &pull)
+            .await
+            .unwrap();
+
+        let target = registry.reference("copy:v1");
+        let options = PushOptions {
+            cache_dir: dir.clone(),
+            ..Default::default()
+        };
+        let client = registry.client();
+        push_cached_image(
+            &client,
+            &source,
+            &target,
+            &RegistryAuth::Anonymous,
+            &options,
+        )
+        .await
+        .unwrap();
+
+        let before = registry.requests().len();
+        let report = push_cached_image(
+            &client,
+            &source,
+            &target,
+            &RegistryAuth::Anonymous,
+            &options,
+        )
+        .await
+        .unwrap();
+        // Only the (small) config is sent again
+        assert_eq!(upload_sessions(&registry, before), 1);
+        let (config, layers) = report.blobs.split_last().unwrap();
+        assert!(layers.iter().all(|blob| blob.status == BlobStatus::Skipped));
+        assert_eq!(report.bytes_uploaded, config.size);
+    });
+}
+
+#[test]
+fn copy_refuses_to_overwrite_before_uploading() {
+    let dir = scratch("no-overwrite");
+    block_on(async {
+        let registry = MockRegistry::start().await.unwrap();
+        seed_image(&registry, "app", "v1", &[b"new layer"]);
+        seed_image(&registry, "copy", "v1", &[b"old layer"]);
+        let existing = registry.manifest("copy", "v1");
+
+        let pull = PullOptions {
+            cache_dir: dir.clone(),
+            ..Default::default()
+        };
+        let push = PushOptions {
+            cache_dir: dir.clone(),
+            no_overwrite: true,
+            ..Default::default()
+        };
+        let before = registry.requests().len();
+        let error = copy_image(
+            &registry.client(),
+            &registry.reference("app:v1"),
+            &registry.reference("copy:v1"),
+            &RegistryAuth::Anonymous,
+            pull,
+            &push,
+        )
+        .await
+        .unwrap_err();
+
+        assert!(error.to_string().contains("--no-overwrite"), "{}", error);
+        assert_eq!(upload_sessions(&registry, before), 0);
+        assert_eq!(registry.manifest("copy", "v1"), existing);
+    });
+}
+
+#[test]
+fn pinned_push_rejects_a_replaced_manifest() {
+    let dir = scratch("pinned");
+    block_on(async {
+        let registry = MockRegistry::start().await.unwrap();
+        seed_image(&registry, "app", "v1", &[b"only layer"]);
+        let manifest = registry.manifest("app", "v1").unwrap();
+        let pinned = format!("sha256:{:x}", Sha256::digest(&manifest));
+        let source = registry.reference(&format!("app@{}", pinned));
+        let pull = PullOptions {
+            cache_dir: dir.clone(),
+            ..Default::default()
+        };
+        cache::cache_image(&registry.client(), &source, &pull)
+            .await
+            .unwrap();
+
+        // Another manifest over the same blobs, with the index updated to match it
+        let image_dir = dir.join(docker_image_pusher::image::sanitize_image_name(&source));
+        let mut replaced: serde_json::Value = serde_json::from_slice(&manifest).unwrap();
+        replaced["annotations"] = serde_json::json!({"replaced": "yes"});
+        let replaced = replaced.to_string();
+        std::fs::write(image_dir.join("manifest.json"), &replaced).unwrap();
+        let index_path = image_dir.join("index.json");
+        let mut index: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&index_path).unwrap()).unwrap();
+        index["manifest_file_digest"] =
+            format!("sha256:{:x}", Sha256::digest(replaced.as_bytes())).into();
+        std::fs::write(&index_path, index.to_string()).unwrap();
+
+        let push = PushOptions {
+            cache_dir: dir.clone(),
+            ..Default::default()
+        };
+        let error = push_cached_image(
+            &registry.client(),
+            &source,
+            &registry.reference("copy:v1"),
+            &RegistryAuth::Anonymous,
+            &push,
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            matches!(&error, PusherError::DigestMismatch { expected, .. } if *expected == pinned),
+            "{}",
+            error
+        );
+        assert!(registry.manifest("copy", "v1").is_none());
+    });
+}
@@ -0,0 +1,82 @@
+//! `ReqwestTransport` requests as they go out on the wire
+
+use docker_image_pusher::credentials::RegistryAuth;
+use docker_image_pusher::transport::{
+    RegistryTransport, ReqwestTransport, Timeouts, TransportRequest,
+};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::time::Duration;
+
+/// Answers one request with `201 Created`, returning its raw head
+fn capture_one_request() -> (String, std::thread::JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!(
+        "http://{}/v2/app/blobs/uploads/1",
+        listener.local_addr().unwrap()
+    );
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut head = String::new();
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                length = value.trim().parse().unwrap();
+            }
+            if line == "\r\n" {
+                break;
+            }
+            head.push_str(&line);
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        reader
+            .get_mut()
+            .write_all(b"HTTP/1.1 201 Created\r\ncontent-length: 0\r\n\r\n")
+            .unwrap();
+        head
+    });
+    (url, server)
+}
+
+fn content_lengths(head: &str) -> Vec<&str> {
+    head.lines()
+        .filter(|line| line.to_ascii_lowercase().starts_with("content-length:"))
+        .collect()
+}
+
+/// Sends `request` through a transport with an idle timeout, so the body is streamed
+fn send_streamed(request: TransportRequest) {
+    let transport = ReqwestTransport::new(None, &RegistryAuth::Anonymous).with_timeouts(Timeouts {
+        idle: Some(Duration::from_secs(60)),
+        ..Timeouts::disabled()
+    });
+    let response = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(transport.send(request))
+        .unwrap();
+    assert_eq!(response.status, 201);
+}
+
+#[test]
+fn streamed_body_with_a_length_sends_it_once() {
+    let (url, server) = capture_one_request();
+    send_streamed(
+        TransportRequest::patch(&url)
+            .header(reqwest::header::CONTENT_LENGTH, 5)
+            .body(b"layer".to_vec()),
+    );
+    let head = server.join().unwrap();
+    assert_eq!(content_lengths(&head), ["content-length: 5"], "{}", head);
+}
+
+#[test]
+fn streamed_body_without_a_length_gets_one() {
+    let (url, server) = capture_one_request();
+    send_streamed(TransportRequest::put(&url).body(b"layer".to_vec()));
+    let head = server.join().unwrap();
+    assert_eq!(content_lengths(&head), ["content-length: 5"], "{}", head);
+}